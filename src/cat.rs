@@ -0,0 +1,43 @@
+//! Print the verified contents of a single file of a layer.
+//!
+//! The contents are verified page by page before being written out, so that
+//! nothing that fails verification reaches the output.
+use std::io::{self, Write};
+
+use anyhow::Result;
+
+use crate::digest::Digest;
+use crate::index::Keys;
+use crate::layer::Layer;
+
+/// Chunk size for reading file contents. Multiple of page size.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Write the contents of a file of a layer to stdout.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `tar` - Path of the tar file.
+/// * `path` - Path of the file within the layer.
+pub fn cat(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    tar: &String,
+    path: &String,
+) -> Result<()> {
+    let layer = Layer::open(index, index_digest, keys, tar)?;
+    let inode = layer.find_file(path)?;
+
+    let mut out = io::stdout().lock();
+    let mut offset = 0;
+    while offset < inode.size {
+        let buf = layer.read(inode, offset, CHUNK_SIZE)?;
+        out.write_all(&buf)?;
+        offset += buf.len() as u64;
+    }
+    out.flush()?;
+    Ok(())
+}
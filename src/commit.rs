@@ -0,0 +1,87 @@
+//! Commit the upper layer of a mount as a new layer.
+//!
+//! The changes held in the writable upper layer of a mount with `--upper`
+//! are written as an OCI layer tar: entries added or changed in the mount,
+//! along with the directories leading to them, `.wh.` whiteouts for removed
+//! entries of the lower layers, and opaque markers in directories that
+//! replace directories of the lower layers. The tar is then indexed, so that
+//! it can be mounted as the top layer over the layers it was made on.
+//!
+//! The upper layer is held by the daemon of the mount. The tar is therefore
+//! written by the daemon, on request over the unix socket given to the mount
+//! with `--commit-socket`. Requests are not taken through the mount itself,
+//! whose users may be the processes of a container. The socket is created
+//! with mode 0600, and the daemon only serves the user running it and root,
+//! as identified by the kernel. The mount should not be written to while a
+//! commit is in progress.
+//!
+//! The tar cannot be written into the mount itself, since the daemon would
+//! wait on its own requests. The daemon rejects targets in the mount, and
+//! only creates new files, without following a symlink at the target.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::digest::{Algorithm, DigestWriter};
+use crate::tar::{self, IndexOptions};
+
+/// Absolute path of a file that may not exist yet, with symlinks resolved.
+///
+/// # Arguments
+/// * `path` - The path.
+fn absolute_path(path: &String) -> Result<String> {
+    if let Ok(resolved) = fs::canonicalize(path) {
+        return Ok(resolved.to_string_lossy().to_string());
+    }
+    let p = Path::new(path);
+    let parent = match p.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = p.file_name().ok_or(anyhow!("invalid path {}", path))?;
+    Ok(fs::canonicalize(parent)
+        .with_context(|| format!("failed to resolve {}", path))?
+        .join(name)
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Commit the upper layer of a mount as a new layer tar and index it.
+///
+/// # Arguments
+/// * `socket` - The commit socket of the mount.
+/// * `tar` - Path of the layer tar to write.
+/// * `options` - Options for indexing the layer tar.
+pub fn commit(
+    socket: &String,
+    tar: &String,
+    options: &IndexOptions,
+) -> Result<()> {
+    let tar = absolute_path(tar)?;
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("failed to connect to {}", socket))?;
+    stream.write_all(tar.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    if reply.trim_end() != "ok" {
+        return Err(anyhow!("failed to commit {}: {}", tar, reply.trim_end()));
+    }
+
+    let mut file =
+        File::open(&tar).with_context(|| format!("failed to open {}", tar))?;
+    let mut writer = DigestWriter::new(io::sink(), Algorithm::Sha256);
+    io::copy(&mut file, &mut writer)?;
+    let digest = writer.finalize()?;
+    println!("wrote {}, digest = {}", tar, digest);
+
+    let options = IndexOptions {
+        digest: Some(digest),
+        ..options.clone()
+    };
+    tar::index(&options, &vec![tar])
+}
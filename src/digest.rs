@@ -0,0 +1,315 @@
+//! Content digests in the `<algorithm>:<hex>` form used by OCI registries.
+//!
+//! Digests supplied on the command line may carry an algorithm prefix
+//! (`sha256:<hex>`, `sha384:<hex>` or `sha512:<hex>`) as emitted by registries
+//! and image tools. A bare hex digest is treated as sha256, or as sha384 or
+//! sha512 if it is 96 or 128 characters long. Digests are validated when parsed: the hex part must consist of hex
+//! digits and have the length of the algorithm's digests.
+//!
+//! Expected digests can also be looked up from checksum files in the format
+//! produced by `sha256sum`/`sha384sum`/`sha512sum`, in either the default or the BSD
+//! (`--tag`) style.
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context as _, Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256, Sha384, Sha512};
+
+/// Supported digest algorithms.
+#[derive(
+    Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq,
+)]
+pub enum Algorithm {
+    /// SHA-256. The default when no prefix is given.
+    #[default]
+    Sha256,
+
+    /// SHA-512.
+    Sha512,
+
+    /// SHA-384.
+    Sha384,
+}
+
+impl Algorithm {
+    /// Name of the algorithm as used in digest prefixes.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Sha384 => "sha384",
+        }
+    }
+
+    /// Number of hex characters in a digest.
+    pub fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+            Algorithm::Sha384 => 96,
+        }
+    }
+}
+
+/// A digest along with the algorithm that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    /// Algorithm of the digest.
+    pub algorithm: Algorithm,
+
+    /// Lower-case hex representation of the digest.
+    pub hex: String,
+}
+
+impl Digest {
+    /// Compute the digest of a byte slice.
+    ///
+    /// # Arguments
+    /// * `algorithm` - Algorithm to use.
+    /// * `buf` - Data to hash.
+    pub fn compute(algorithm: Algorithm, buf: &[u8]) -> Digest {
+        let mut context = Context::new(algorithm);
+        context.update(buf);
+        context.finalize()
+    }
+}
+
+/// Look up the expected digest of a file in a checksum file.
+///
+/// Each line of the checksum file is either `<hex>  <name>`, `<hex> *<name>`
+/// or `SHA256 (<name>) = <hex>`. A line whose name equals the given path is
+/// used. Otherwise, a line whose file name (without directories) equals that
+/// of the path is used, provided it is the only such line.
+///
+/// # Arguments
+/// * `checksum_file` - Path of the checksum file, e.g. SHA256SUMS.
+/// * `path` - Path of the file whose digest is needed.
+pub fn lookup(checksum_file: &String, path: &String) -> Result<Digest> {
+    let contents = fs::read_to_string(checksum_file)
+        .with_context(|| format!("failed to read {}", checksum_file))?;
+    let file_name = Path::new(path).file_name();
+
+    let mut same_file_name = vec![];
+    for line in contents.lines() {
+        // Parse the name and digest from the line.
+        let (name, digest) = if let Some(rest) = line.strip_prefix("SHA") {
+            // BSD style. E.g. SHA256 (layer.tar) = <hex>
+            let parsed = rest.split_once(" (").and_then(|(bits, rest)| {
+                rest.rsplit_once(") = ")
+                    .map(|(name, hex)| (bits, name, hex))
+            });
+            match parsed {
+                Some((bits, name, hex)) => {
+                    (name, format!("sha{}:{}", bits, hex.trim()))
+                }
+                _ => continue,
+            }
+        } else {
+            // Default style. The name may be preceded by '*' (binary mode).
+            match line.split_once(' ') {
+                Some((hex, name)) => {
+                    let name = name.trim_start_matches(' ');
+                    (name.strip_prefix('*').unwrap_or(name), hex.to_string())
+                }
+                _ => continue,
+            }
+        };
+
+        if name.eq(path) {
+            return digest
+                .parse()
+                .with_context(|| format!("{}: bad digest", checksum_file));
+        }
+        if Path::new(name).file_name() == file_name {
+            same_file_name.push((name.to_string(), digest));
+        }
+    }
+
+    match same_file_name.as_slice() {
+        [(_, digest)] => digest
+            .parse()
+            .with_context(|| format!("{}: bad digest", checksum_file)),
+        [] => Err(anyhow!("{}: no digest found for {}", checksum_file, path)),
+        names => Err(anyhow!(
+            "{}: ambiguous digest for {}: found {}",
+            checksum_file,
+            path,
+            names
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+impl FromStr for Digest {
+    type Err = Error;
+
+    /// Parse `sha256:<hex>`, `sha384:<hex>`, `sha512:<hex>` or bare hex.
+    ///
+    /// The hex part must have the length of the algorithm's digests and
+    /// consist of hex digits only.
+    fn from_str(s: &str) -> Result<Digest> {
+        let (algorithm, hex) = match s.split_once(':') {
+            Some(("sha256", hex)) => (Algorithm::Sha256, hex),
+            Some(("sha512", hex)) => (Algorithm::Sha512, hex),
+            Some(("sha384", hex)) => (Algorithm::Sha384, hex),
+            Some((a, _)) => {
+                return Err(anyhow!("unsupported digest algorithm {}", a))
+            }
+            None if s.len() == 128 => (Algorithm::Sha512, s),
+            None if s.len() == 96 => (Algorithm::Sha384, s),
+            None => (Algorithm::Sha256, s),
+        };
+
+        if hex.len() != algorithm.hex_len() {
+            return Err(anyhow!(
+                "invalid {} digest {}: expected {} hex characters, found {}",
+                algorithm.name(),
+                s,
+                algorithm.hex_len(),
+                hex.len()
+            ));
+        }
+        if let Some(c) = hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(anyhow!(
+                "invalid {} digest {}: {:?} is not a hex digit",
+                algorithm.name(),
+                s,
+                c
+            ));
+        }
+
+        Ok(Digest {
+            algorithm,
+            hex: hex.to_lowercase(),
+        })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.hex)
+    }
+}
+
+/// Incremental digest computation for any supported algorithm.
+#[derive(Debug, Clone)]
+pub enum Context {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Sha384(Sha384),
+}
+
+impl Context {
+    /// Create a new context for the given algorithm.
+    pub fn new(algorithm: Algorithm) -> Context {
+        match algorithm {
+            Algorithm::Sha256 => Context::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Context::Sha512(Sha512::new()),
+            Algorithm::Sha384 => Context::Sha384(Sha384::new()),
+        }
+    }
+
+    /// Add data to the digest.
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            Context::Sha256(h) => h.update(buf),
+            Context::Sha512(h) => h.update(buf),
+            Context::Sha384(h) => h.update(buf),
+        }
+    }
+
+    /// Finish the computation and return the digest.
+    pub fn finalize(self) -> Digest {
+        let algorithm = match self {
+            Context::Sha256(_) => Algorithm::Sha256,
+            Context::Sha512(_) => Algorithm::Sha512,
+            Context::Sha384(_) => Algorithm::Sha384,
+        };
+        Digest {
+            algorithm,
+            hex: to_hex(&self.finalize_bytes()),
+        }
+    }
+
+    /// Finish the computation and return the raw digest.
+    pub fn finalize_bytes(self) -> Vec<u8> {
+        match self {
+            Context::Sha256(h) => h.finalize().to_vec(),
+            Context::Sha512(h) => h.finalize().to_vec(),
+            Context::Sha384(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// Lower-case hex representation of bytes.
+///
+/// # Arguments
+/// * `bytes` - The bytes.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of a hex representation. None unless `hex` is an even number of hex
+/// digits.
+///
+/// # Arguments
+/// * `hex` - The hex representation.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2)
+        || !hex.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Writer that computes a digest of all the bytes written through it.
+pub struct DigestWriter<W: Write> {
+    /// Underlying writer.
+    inner: W,
+
+    /// Digest context.
+    context: Context,
+}
+
+impl<W: Write> DigestWriter<W> {
+    /// Create a new DigestWriter.
+    ///
+    /// # Arguments
+    /// * `inner` - Writer to wrap.
+    /// * `algorithm` - Algorithm to compute.
+    pub fn new(inner: W, algorithm: Algorithm) -> DigestWriter<W> {
+        DigestWriter {
+            inner,
+            context: Context::new(algorithm),
+        }
+    }
+
+    /// Flush the writer and return the digest of the bytes written.
+    pub fn finalize(mut self) -> io::Result<Digest> {
+        self.inner.flush()?;
+        Ok(self.context.finalize())
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.context.update(&buf[0..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
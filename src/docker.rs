@@ -0,0 +1,182 @@
+//! Index the layers of `docker save` archives.
+//!
+//! A `docker save` archive is a tar file holding a `manifest.json`, an image
+//! config per image, and the uncompressed tar file of each layer. Older
+//! versions of docker store layers as `<id>/layer.tar`, possibly as symlinks
+//! to identical layers of other images. Newer versions use the OCI image
+//! layout and store layers as `blobs/sha256/<hex>`.
+//!
+//! The digest of each layer is taken from the `rootfs.diff_ids` of the image
+//! config. Each layer is copied out of the archive into a layer store, where
+//! it is checked against its digest and indexed.
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::digest::Digest;
+use crate::index::{FileType, Index, Inode};
+use crate::store::Store;
+use crate::tar::{self, ParseOptions};
+
+/// An image in `manifest.json`.
+#[derive(Deserialize)]
+struct ManifestEntry {
+    /// Path of the image config in the archive.
+    #[serde(rename = "Config")]
+    config: String,
+
+    /// Paths of the layer tar files in the archive, base layer first.
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+/// The part of an image config describing the layers.
+#[derive(Deserialize)]
+struct Config {
+    rootfs: RootFs,
+}
+
+/// Digests of the uncompressed layers, base layer first.
+#[derive(Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
+}
+
+/// A `docker save` archive together with an index of its entries.
+struct Archive {
+    /// Path of the archive.
+    path: String,
+
+    /// The archive.
+    file: File,
+
+    /// Index of the entries of the archive.
+    index: Index,
+}
+
+impl Archive {
+    /// Open an archive and index its entries.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the archive.
+    fn open(path: &String) -> Result<Archive> {
+        let mut index =
+            tar::create_index(&None, path, &ParseOptions::default())?;
+        index.process()?;
+        Ok(Archive {
+            path: path.to_string(),
+            file: File::open(path)
+                .with_context(|| format!("failed to open {}", path))?,
+            index,
+        })
+    }
+
+    /// Find a regular file in the archive. Symlinks and hard links are
+    /// followed.
+    ///
+    /// # Arguments
+    /// * `name` - Path of the file within the archive.
+    fn find(&self, name: &String) -> Result<&Inode> {
+        let mut path = "/".to_owned() + name.trim_start_matches("./");
+        let inodes = &self.index.inodes;
+
+        // Bound the number of links followed to stop on cycles.
+        for _ in 0..40 {
+            let ino = self
+                .index
+                .find(&path, 0, inodes.len())
+                .with_context(|| format!("{}: missing {}", self.path, name))?;
+            let inode = &inodes[ino];
+            let link = inode.extra.as_ref().map_or("", |e| e.link.as_str());
+            match inode.typeflag {
+                FileType::RegularFile => return Ok(inode),
+                FileType::HardLink => path = "/".to_owned() + link,
+                FileType::SymLink if link.starts_with('/') => {
+                    path = link.to_string()
+                }
+                FileType::SymLink => path = inode.parent.clone() + link,
+                _ => break,
+            }
+
+            // Resolve "." and ".." components of link targets.
+            let mut parts: Vec<&str> = vec![];
+            for part in path.split('/') {
+                match part {
+                    "" | "." => (),
+                    ".." => {
+                        parts.pop();
+                    }
+                    p => parts.push(p),
+                }
+            }
+            path = "/".to_owned() + &parts.join("/");
+        }
+        Err(anyhow!("{}: {} is not a regular file", self.path, name))
+    }
+
+    /// Reader of the contents of a regular file in the archive.
+    ///
+    /// # Arguments
+    /// * `name` - Path of the file within the archive.
+    fn open_entry(&self, name: &String) -> Result<impl Read + '_> {
+        let inode = self.find(name)?;
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(inode.offset as u64 * 512))?;
+        Ok(file.take(inode.size))
+    }
+
+    /// Read a JSON document from the archive.
+    ///
+    /// # Arguments
+    /// * `name` - Path of the document within the archive.
+    fn read_json<T: for<'de> Deserialize<'de>>(
+        &self,
+        name: &String,
+    ) -> Result<T> {
+        serde_json::from_reader(self.open_entry(name)?)
+            .with_context(|| format!("{}: bad {}", self.path, name))
+    }
+}
+
+/// Index the layers of a `docker save` archive.
+///
+/// Layers shared by several images of the archive are indexed once. Prints
+/// the paths of the tar file and index of each layer.
+///
+/// # Arguments
+/// * `path` - Path of the archive.
+/// * `dir` - Root directory of the layer store to add the layers to.
+pub fn index(path: &String, dir: &String) -> Result<()> {
+    let archive = Archive::open(path)?;
+    let manifest: Vec<ManifestEntry> =
+        archive.read_json(&String::from("manifest.json"))?;
+    let store = Store::new(dir)?;
+
+    for image in &manifest {
+        let config: Config = archive.read_json(&image.config)?;
+        if config.rootfs.diff_ids.len() != image.layers.len() {
+            return Err(anyhow!(
+                "{}: {} lists {} layers but has {} diff_ids",
+                path,
+                image.config,
+                image.layers.len(),
+                config.rootfs.diff_ids.len()
+            ));
+        }
+
+        for (layer, diff_id) in image.layers.iter().zip(&config.rootfs.diff_ids)
+        {
+            let digest: Digest = diff_id.parse()?;
+            if !store.contains(&digest)? {
+                store
+                    .add(&mut archive.open_entry(layer)?, &digest)
+                    .with_context(|| format!("{}: layer {}", path, layer))?;
+            }
+            println!("tar {}", store.tar_path(&digest)?);
+            println!("index {}", store.index_path(&digest)?);
+        }
+    }
+    Ok(())
+}
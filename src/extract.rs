@@ -0,0 +1,520 @@
+//! Extract the verified contents of a layer to a directory.
+//!
+//! Every page of every file is verified against the index while it is being
+//! extracted, and extraction is aborted on the first mismatch.
+//!
+//! # IMA
+//! Kernels running IMA-appraisal can enforce the integrity of the extracted
+//! tree without cc-fs being in the runtime path. With the `--ima` option, the
+//! sha256 hash of each regular file is written to its `security.ima` extended
+//! attribute in the `IMA_XATTR_DIGEST_NG` format. Writing `security.*`
+//! attributes requires CAP_SYS_ADMIN.
+//!
+//! # Verification stamps
+//! With the `--stamp` option, each regular file gets a `user.ccfs.digest`
+//! extended attribute holding the `sha256:<hex>` digest of its verified
+//! contents, and a `user.ccfs.index-digest` attribute holding the digest of
+//! the index it was verified against. Audits can later detect drift of the
+//! extracted tree by rehashing files, without access to the original tar.
+//!
+//! # Whiteouts
+//! Whiteouts are extracted as OCI `.wh.` files by default. With
+//! `--whiteouts overlay`, they are extracted as 0:0 character devices and
+//! `trusted.overlay.opaque` attributes so that the tree can be used directly
+//! as an overlayfs layer. This requires CAP_MKNOD and CAP_SYS_ADMIN.
+//!
+//! # Metadata
+//! Permission bits and modification times of extracted entries are set from
+//! the index. Ownership is set too when running as root, as tar does. With
+//! `--passwd` and `--group`, owner names are resolved to ids as GNU tar does
+//! (see `owners`).
+//!
+//! # Devices
+//! Character and block devices are created when running as root. Otherwise
+//! they are skipped with a warning, as creating them requires CAP_MKNOD.
+//! Named pipes are always created.
+//!
+//! # Path safety
+//! Entries are only ever created in real directories below the destination.
+//! Extraction is aborted if any directory component of an entry's path is a
+//! symlink, whether it comes from the layer or was already in the destination,
+//! and files, permissions and ownership are never set through a symlink.
+//!
+//! # Reproducibility
+//! Modification times of extracted entries are set from the index. With
+//! `--clamp-mtime <epoch>`, or if `SOURCE_DATE_EPOCH` is set in the
+//! environment, times later than the given epoch are clamped to it so that
+//! extracted trees are reproducible regardless of when the layer was built.
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest as _, Sha256};
+
+use crate::digest::{Algorithm, Digest};
+use crate::index::{FileType, Inode, Keys};
+use crate::layer::Layer;
+use crate::owners::Owners;
+use crate::whiteout::*;
+
+/// Chunk size for copying file contents. Multiple of page size.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// IMA xattr type for a digest with algorithm id.
+const IMA_XATTR_DIGEST_NG: u8 = 0x04;
+
+/// IMA hash algorithm id for sha256.
+const IMA_HASH_ALGO_SHA256: u8 = 0x04;
+
+/// Extended attribute holding the verified digest of a file's contents.
+const STAMP_DIGEST_XATTR: &str = "user.ccfs.digest";
+
+/// Extended attribute holding the digest of the index a file was verified
+/// against.
+const STAMP_INDEX_DIGEST_XATTR: &str = "user.ccfs.index-digest";
+
+/// Options for extracting a layer.
+pub struct ExtractOptions {
+    /// Write `security.ima` hashes for regular files.
+    pub ima: bool,
+
+    /// Clamp modification times to this many seconds since the epoch.
+    pub clamp_mtime: Option<i64>,
+
+    /// Representation of whiteouts.
+    pub whiteouts: Whiteouts,
+
+    /// Write `user.ccfs.*` verification stamps for regular files.
+    pub stamp: bool,
+
+    /// User and group databases to resolve owner names with.
+    pub owners: Option<Owners>,
+}
+
+/// Read the clamping epoch from the `SOURCE_DATE_EPOCH` environment variable.
+pub fn source_date_epoch() -> Result<Option<i64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            Ok(Some(epoch.trim().parse().map_err(|_| {
+                anyhow!("invalid SOURCE_DATE_EPOCH {}", epoch)
+            })?))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Path of an entry below the destination directory.
+///
+/// Fails if a directory component of the path below the destination is a
+/// symlink or not a directory, so that entries cannot be created outside the
+/// destination. Components that do not exist yet are accepted.
+///
+/// # Arguments
+/// * `dest` - Destination directory.
+/// * `parent` - Parent path of the entry in the index, e.g. `/etc/`.
+/// * `name` - Name of the entry.
+/// * `returns` - Path of the entry.
+fn entry_path(dest: &str, parent: &str, name: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::from(dest);
+    for component in parent.split('/').filter(|c| !c.is_empty()) {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => (),
+            Ok(_) => {
+                return Err(anyhow!(
+                    "refusing to extract below {}: not a directory",
+                    path.display()
+                ))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed to inspect {}", path.display())
+                })
+            }
+        }
+    }
+    path.push(name);
+    Ok(path)
+}
+
+/// Check that an extracted entry is not a symlink unless it should be.
+///
+/// # Arguments
+/// * `path` - Path of the entry.
+/// * `inode` - Inode of the entry.
+fn check_not_symlink(path: &Path, inode: &Inode) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("failed to inspect {}", path.display()))?;
+    if metadata.file_type().is_symlink()
+        && !matches!(inode.typeflag, FileType::SymLink)
+    {
+        return Err(anyhow!("refusing to follow symlink {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Set the access and modification times of a file without following
+/// symlinks.
+///
+/// # Arguments
+/// * `path` - Path of the file.
+/// * `sec` - Seconds since the epoch.
+/// * `nsec` - Nanoseconds.
+fn set_mtime(path: &Path, sec: i64, nsec: u32) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let time = libc::timespec {
+        tv_sec: sec as libc::time_t,
+        tv_nsec: nsec as libc::c_long,
+    };
+    let times = [time, time];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "failed to set times of {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Set the owner of a file without following symlinks.
+///
+/// # Arguments
+/// * `path` - Path of the file.
+/// * `uid` - User id of the owner.
+/// * `gid` - Group id of the owner.
+fn set_owner(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(anyhow!(
+            "failed to set owner of {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Set an extended attribute of a file without following symlinks.
+///
+/// # Arguments
+/// * `path` - Path of the file.
+/// * `name` - Name of the attribute.
+/// * `value` - Value of the attribute.
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "failed to set {} on {}: {}",
+            name,
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Create a whiteout.
+///
+/// # Arguments
+/// * `path` - Path of the whiteout.
+/// * `inode` - Inode of the whiteout.
+/// * `whiteouts` - Representation of whiteouts.
+fn create_whiteout(
+    path: &Path,
+    inode: &Inode,
+    whiteouts: Whiteouts,
+) -> Result<()> {
+    if let Whiteouts::Oci = whiteouts {
+        create_file(path)?;
+        return Ok(());
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mode = libc::S_IFCHR | (inode.mode & 0o7777) as libc::mode_t;
+    if unsafe { libc::mknod(c_path.as_ptr(), mode, libc::makedev(0, 0)) } != 0 {
+        return Err(anyhow!(
+            "failed to create {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Create or truncate a regular file without following a symlink.
+///
+/// # Arguments
+/// * `path` - Path of the file.
+/// * `returns` - The file, opened for writing.
+fn create_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))
+}
+
+/// Create a device or named pipe.
+///
+/// # Arguments
+/// * `path` - Path of the node.
+/// * `inode` - Inode of the node.
+fn create_node(path: &Path, inode: &Inode) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let kind = match inode.typeflag {
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::Fifo => libc::S_IFIFO,
+        _ => libc::S_IFCHR,
+    };
+    let mode = kind | (inode.mode & 0o7777) as libc::mode_t;
+    let (major, minor) = inode.device();
+    let dev = libc::makedev(major, minor);
+    if unsafe { libc::mknod(c_path.as_ptr(), mode, dev) } != 0 {
+        return Err(anyhow!(
+            "failed to create {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether an inode is a device that is not created, because devices can
+/// only be created by root.
+///
+/// # Arguments
+/// * `inode` - The inode.
+/// * `root` - Whether running as root.
+fn is_skipped_device(inode: &Inode, root: bool) -> bool {
+    let device =
+        matches!(inode.typeflag, FileType::CharDevice | FileType::BlockDevice);
+    device && !root && !is_whiteout(inode)
+}
+
+/// Extract a layer to a directory.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `tar` - Path of the tar file.
+/// * `dest` - Destination directory. Created if it does not exist.
+/// * `options` - Extraction options.
+pub fn extract(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    tar: &String,
+    dest: &String,
+    options: &ExtractOptions,
+) -> Result<()> {
+    let mut layer = Layer::open(index, index_digest, keys, tar)?;
+    if let Some(owners) = &options.owners {
+        owners.resolve(&mut layer.index);
+    }
+    let inodes = &layer.index.inodes;
+    let same_owner = unsafe { libc::geteuid() } == 0;
+    fs::create_dir_all(dest)
+        .with_context(|| format!("failed to create {}", dest))?;
+
+    // The index file is read again to stamp its digest, unless it has been
+    // pinned already.
+    let stamp_index_digest = match (options.stamp, index_digest) {
+        (false, _) => None,
+        (true, Some(digest)) => Some(digest.to_string()),
+        (true, None) => {
+            let bytes = fs::read(index)
+                .with_context(|| format!("failed to read {}", index))?;
+            Some(Digest::compute(Algorithm::Sha256, &bytes).to_string())
+        }
+    };
+
+    // Inodes are sorted by depth. Hence directories are created before their
+    // children. Hard links are created at the end since their targets may
+    // appear later.
+    for inode in &inodes[2..] {
+        let name = match export_name(inode, options.whiteouts) {
+            Some(name) => name,
+            None => {
+                // Opaque marker exported as an attribute of the parent.
+                let parent = entry_path(dest, &inode.parent, "")?;
+                set_xattr(&parent, OVERLAY_OPAQUE_XATTR, b"y")?;
+                continue;
+            }
+        };
+        let path = entry_path(dest, &inode.parent, &name)?;
+        let path = path.as_path();
+        if is_whiteout(inode) {
+            create_whiteout(path, inode, options.whiteouts)?;
+            continue;
+        }
+        match inode.typeflag {
+            FileType::Directory => {
+                if let Err(e) = fs::create_dir(path) {
+                    if e.kind() != ErrorKind::AlreadyExists {
+                        return Err(e).with_context(|| {
+                            format!("failed to create {}", path.display())
+                        });
+                    }
+                    check_not_symlink(path, inode)?;
+                }
+                if is_opaque_dir(inode) {
+                    match options.whiteouts {
+                        Whiteouts::Oci => {
+                            create_file(&path.join(OPAQUE_WHITEOUT))?;
+                        }
+                        Whiteouts::Overlay => {
+                            set_xattr(path, OVERLAY_OPAQUE_XATTR, b"y")?
+                        }
+                    }
+                }
+            }
+            FileType::RegularFile => {
+                let mut writer = BufWriter::new(create_file(path)?);
+                let mut hasher = Sha256::new();
+                let mut offset = 0;
+                while offset < inode.size {
+                    let buf = layer.read(inode, offset, CHUNK_SIZE)?;
+                    writer.write_all(&buf)?;
+                    hasher.update(&buf);
+                    offset += buf.len() as u64;
+                }
+                writer.flush()?;
+                drop(writer);
+
+                let sum = hasher.finalize();
+                if options.ima {
+                    let mut value =
+                        vec![IMA_XATTR_DIGEST_NG, IMA_HASH_ALGO_SHA256];
+                    value.extend_from_slice(&sum);
+                    set_xattr(path, "security.ima", &value)?;
+                }
+                if let Some(index_digest) = &stamp_index_digest {
+                    let digest = Digest {
+                        algorithm: Algorithm::Sha256,
+                        hex: sum.iter().map(|b| format!("{:02x}", b)).collect(),
+                    };
+                    set_xattr(
+                        path,
+                        STAMP_DIGEST_XATTR,
+                        digest.to_string().as_bytes(),
+                    )?;
+                    set_xattr(
+                        path,
+                        STAMP_INDEX_DIGEST_XATTR,
+                        index_digest.as_bytes(),
+                    )?;
+                }
+            }
+            FileType::SymLink => {
+                let link = inode.extra.as_ref().map_or("", |e| e.link.as_str());
+                symlink(link, path).with_context(|| {
+                    format!("failed to create {}", path.display())
+                })?;
+            }
+            FileType::CharDevice | FileType::BlockDevice => {
+                if is_skipped_device(inode, same_owner) {
+                    eprintln!(
+                        "warning: skipping device {}: not running as root",
+                        path.display()
+                    );
+                } else {
+                    create_node(path, inode)?;
+                }
+            }
+            FileType::Fifo => create_node(path, inode)?,
+            _ => (),
+        }
+    }
+
+    // Create hard links.
+    for inode in &inodes[2..] {
+        if let FileType::HardLink = inode.typeflag {
+            if inode.target_ino == 0 {
+                return Err(anyhow!(
+                    "invalid hard link {}{}",
+                    inode.parent,
+                    inode.name
+                ));
+            }
+            let target = &inodes[inode.target_ino as usize];
+            if is_skipped_device(target, same_owner) {
+                continue;
+            }
+            let target = entry_path(dest, &target.parent, &target.name)?;
+            let path = entry_path(dest, &inode.parent, &inode.name)?;
+            fs::hard_link(&target, &path).with_context(|| {
+                format!("failed to create {}", path.display())
+            })?;
+        }
+    }
+
+    // Set ownership, permissions and modification times. Children are
+    // visited before their parents so that directory times are not disturbed
+    // by later changes, and read-only directories are not entered after
+    // being made read-only. Hard links share the metadata of their targets.
+    // Skipped devices have no metadata to set.
+    for inode in inodes[2..].iter().rev() {
+        let name = match export_name(inode, options.whiteouts) {
+            Some(name) => name,
+            None => continue,
+        };
+        let (sec, nsec) = match options.clamp_mtime {
+            Some(epoch) if (inode.mtime, inode.mtime_nsec) > (epoch, 0) => {
+                (epoch, 0)
+            }
+            _ => (inode.mtime, inode.mtime_nsec),
+        };
+        if let FileType::HardLink = inode.typeflag {
+            continue;
+        }
+        if is_skipped_device(inode, same_owner) {
+            continue;
+        }
+        let path = entry_path(dest, &inode.parent, &name)?;
+        let path = path.as_path();
+        check_not_symlink(path, inode)?;
+
+        // Changing the owner clears set-user-id and set-group-id bits.
+        // Hence the owner is set before the permissions.
+        if same_owner {
+            set_owner(path, inode.uid, inode.gid)?;
+        }
+        if !matches!(inode.typeflag, FileType::SymLink) {
+            let mode = fs::Permissions::from_mode(inode.mode & 0o7777);
+            fs::set_permissions(path, mode).with_context(|| {
+                format!("failed to set permissions of {}", path.display())
+            })?;
+        }
+        set_mtime(path, sec, nsec)?;
+    }
+
+    println!("extracted {} to {}", tar, dest);
+    Ok(())
+}
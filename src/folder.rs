@@ -0,0 +1,246 @@
+//! Tar streams of folders.
+//!
+//! A folder is indexed as if it were a tar file of its contents: the folder
+//! is walked and written as a canonical tar stream (see `recompose`), which
+//! is indexed like any other tar stream. The digest of a folder is the
+//! digest of that stream. The stream is not stored anywhere.
+//! - The folder itself is the root entry `./`, followed by its contents.
+//!   Entries of a directory are sorted by name, and directories are
+//!   followed by their contents.
+//! - Regular files, directories, symbolic links, devices and named pipes
+//!   are stored with their modes, owner ids, modification times and
+//!   extended attributes. Owner names are not looked up.
+//! - Regular files with several links in the folder are stored once, and
+//!   their other paths link to the first.
+//! - Sockets cannot be stored in tar files and are skipped with a warning.
+//!
+//! Files must not change while the folder is indexed. Files whose size
+//! changes are detected and fail indexing.
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::index::{Extra, Inode};
+use crate::recompose::TarWriter;
+
+/// String table of the written entries. Owner names are left empty.
+static NAMES: [String; 1] = [String::new()];
+
+/// Call a listxattr or getxattr style function, first for the size of the
+/// result and then for the result itself.
+///
+/// # Arguments
+/// * `call` - The function, taking a buffer and its size.
+fn xattr_call(
+    mut call: impl FnMut(*mut libc::c_void, usize) -> isize,
+) -> io::Result<Vec<u8>> {
+    let size = call(std::ptr::null_mut(), 0);
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; size as usize];
+    let size = call(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(size as usize);
+    Ok(buf)
+}
+
+/// Extended attributes of a file. Symbolic links are not followed.
+///
+/// # Arguments
+/// * `path` - Path of the file.
+fn read_xattrs(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let names = match xattr_call(|buf, size| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => vec![],
+        Err(e) => {
+            return Err(anyhow!(
+                "failed to list xattrs of {}: {}",
+                path.display(),
+                e
+            ))
+        }
+    };
+
+    let mut xattrs = vec![];
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = xattr_call(|buf, size| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf, size)
+        })
+        .with_context(|| {
+            format!(
+                "failed to get xattr {} of {}",
+                String::from_utf8_lossy(name),
+                path.display()
+            )
+        })?;
+        xattrs.push((String::from_utf8_lossy(name).to_string(), value));
+    }
+    Ok(xattrs)
+}
+
+/// Major and minor numbers of a device number.
+///
+/// # Arguments
+/// * `rdev` - The device number, as encoded by glibc.
+fn decode_dev(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Write an entry of a folder, and the contents of directories.
+///
+/// # Arguments
+/// * `writer` - Writer for the tar stream.
+/// * `file` - Path of the entry on disk.
+/// * `path` - Path of the entry in the archive. Directories do not end with
+///   '/' yet.
+/// * `links` - Archive paths of files with several links written so far, by
+///   device and inode number.
+fn write_entry<W: Write>(
+    writer: &mut TarWriter<W>,
+    file: &Path,
+    path: String,
+    links: &mut HashMap<(u64, u64), String>,
+) -> Result<()> {
+    let meta = fs::symlink_metadata(file)
+        .with_context(|| format!("failed to stat {}", file.display()))?;
+    let file_type = meta.file_type();
+    let mut inode = Inode {
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mode: meta.mode() & 0o7777,
+        mtime: meta.mtime(),
+        mtime_nsec: meta.mtime_nsec() as u32,
+        ..Inode::default()
+    };
+    if file_type.is_socket() {
+        eprintln!("warning: skipping socket {}", file.display());
+        return Ok(());
+    }
+    let xattrs = read_xattrs(file)?;
+
+    // Further links of a file link to the first.
+    if !file_type.is_dir() && meta.nlink() > 1 {
+        let key = (meta.dev(), meta.ino());
+        if let Some(first) = links.get(&key) {
+            return writer.write_entry(&path, b'1', &inode, 0, first, &xattrs);
+        }
+        links.insert(key, path.clone());
+    }
+
+    if file_type.is_dir() {
+        let dir = path.clone() + "/";
+        writer.write_entry(&dir, b'5', &inode, 0, "", &xattrs)?;
+        let mut names = fs::read_dir(file)
+            .with_context(|| format!("failed to read {}", file.display()))?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+        let prefix = match path.as_str() {
+            "." => String::new(),
+            _ => path + "/",
+        };
+        for name in names {
+            let child = file.join(&name);
+            let name = name
+                .to_str()
+                .ok_or(anyhow!("{} is not valid UTF-8", child.display()))?;
+            write_entry(writer, &child, prefix.clone() + name, links)?;
+        }
+        return Ok(());
+    }
+
+    if file_type.is_symlink() {
+        let target = fs::read_link(file)?;
+        let target = target.to_str().ok_or(anyhow!(
+            "target of {} is not valid UTF-8",
+            file.display()
+        ))?;
+        return writer.write_entry(&path, b'2', &inode, 0, target, &xattrs);
+    }
+
+    let typeflag = match file_type {
+        t if t.is_char_device() => b'3',
+        t if t.is_block_device() => b'4',
+        t if t.is_fifo() => b'6',
+        _ => b'0',
+    };
+    if typeflag != b'0' {
+        inode.extra = Some(Extra {
+            device: Some(decode_dev(meta.rdev())).filter(|d| *d != (0, 0)),
+            ..Extra::default()
+        });
+        return writer.write_entry(&path, typeflag, &inode, 0, "", &xattrs);
+    }
+
+    let size = meta.len();
+    let contents = File::open(file)
+        .with_context(|| format!("failed to open {}", file.display()))?;
+    writer.write_entry(&path, b'0', &inode, size, "", &xattrs)?;
+    let copied = io::copy(&mut contents.take(size), &mut writer.out)?;
+    if copied != size {
+        return Err(anyhow!("{} changed while being read", file.display()));
+    }
+    writer.write_padding(size)
+}
+
+/// Write a folder as a tar stream.
+///
+/// # Arguments
+/// * `folder` - Path of the folder.
+/// * `out` - Writer for the tar stream.
+fn write_tar<W: Write>(folder: &Path, out: W) -> Result<()> {
+    let mut writer = TarWriter {
+        out,
+        names: &NAMES,
+        clamp_mtime: None,
+    };
+    write_entry(&mut writer, folder, String::from("."), &mut HashMap::new())?;
+
+    // End of archive.
+    writer.out.write_all(&[0u8; 1024])?;
+    writer.out.flush()?;
+    Ok(())
+}
+
+/// Stream a folder as a tar stream.
+///
+/// The folder is written on a thread, into a pipe. The reader must be
+/// dropped before joining the thread.
+///
+/// # Arguments
+/// * `folder` - Path of the folder.
+/// * `returns` - Reader of the tar stream, and the thread writing it.
+pub fn stream(folder: &Path) -> Result<(File, JoinHandle<Result<()>>)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(anyhow!(
+            "failed to create pipe: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let (reader, writer) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let folder = folder.to_path_buf();
+    let handle = thread::Builder::new()
+        .name(String::from("cc-fs-folder"))
+        .spawn(move || write_tar(&folder, BufWriter::new(writer)))?;
+    Ok((reader, handle))
+}
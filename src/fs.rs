@@ -1,407 +1,3085 @@
 //! Fuse-based confidential container file-system backed by tar files or folders.
+use std::borrow::Cow;
 use std::cmp::min;
-use std::ffi::OsStr;
-use std::fs::File;
-use std::os::unix::fs::FileExt;
-use std::time::{Duration, UNIX_EPOCH};
-
-use anyhow::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::mem;
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path};
+use std::process;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
 use fuser::{
-    consts::FOPEN_KEEP_CACHE, FileAttr, FileType, Filesystem, MountOption,
-    ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, Request,
+    consts::{FOPEN_CACHE_DIR, FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE},
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use libc::{
+    c_int, EACCES, EEXIST, EINVAL, EIO, EISDIR, EMFILE, ENAMETOOLONG, ENODATA,
+    ENODEV, ENOENT, ENOSYS, ENOTDIR, ENOTEMPTY, EOPNOTSUPP, EOVERFLOW, EPERM,
+    ERANGE, EROFS, EXDEV,
 };
-use libc::{ENAMETOOLONG, ENOENT};
+use memmap2::Mmap;
+use unicode_normalization::UnicodeNormalization;
 
+use crate::digest::Digest;
+use crate::guard::{self, Guard, OnPanic};
+use crate::hash::Hasher;
+use crate::idmap::IdMap;
 use crate::index::{self, *};
+use crate::lock;
+use crate::owners::Owners;
+use crate::pool::{self, CpuList, Pool};
+use crate::recompose::{Kind, TarWriter, CHUNK_SIZE};
+use crate::stats::Stats;
+use crate::subtree;
+use crate::union;
+use crate::upper::{Node, Upper, UpperStore};
+#[cfg(feature = "io-uring")]
+use crate::uring::Ring;
+use crate::whiteout::{self, WhiteoutMode, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
 
 /// Maximum permitted length of a name.
 const MAX_NAME_LENGTH: u32 = 255;
 
-/// FUSE file system with integrity protection backed by a tar file.
+/// Exit code used when the file-system is not ready within the mount timeout.
+pub const EXIT_MOUNT_TIMEOUT: i32 = 124;
+
+/// When the contents of files are verified.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyPolicy {
+    /// Verify the pages read on every read that reaches the file-system.
+    EveryRead,
+
+    /// Verify the whole file when it is opened, and serve reads from the
+    /// verified contents until it is closed.
+    OnOpen,
+
+    /// Verify each page on its first read only. Later reads of the page from
+    /// the tar file are trusted.
+    Once,
+}
+
+/// What to do after the contents of a file fail verification.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnVerifyFailure {
+    /// Panic. The panic is handled according to `--on-panic`.
+    Panic,
+
+    /// Fail the read with EIO, along with all later reads of the file.
+    Eio,
+
+    /// Unmount the file-system and exit.
+    Unmount,
+}
+
+/// How the tar file is read.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Read into a buffer with pread, or io_uring when built with the
+    /// `io-uring` feature.
+    Pread,
+
+    /// Map the tar file and copy reads out of the mapping.
+    Mmap,
+
+    /// Read the contents of files from the files of the folder the index
+    /// was created from, e.g. a root file-system extracted ahead of time.
+    Dir,
+}
+
+/// Options for mounting a file-system.
+pub struct MountOptions {
+    /// Expected digest of the index file. Mount is refused if the index file
+    /// does not hash to this value.
+    pub index_digest: Option<Digest>,
+
+    /// Expected digest of the layer tar file. Mount is refused unless the
+    /// index was created from a layer with this digest. Only meaningful if the
+    /// index is authenticated or pinned by `index_digest`.
+    pub digest: Option<Digest>,
+
+    /// Keys the index must be authenticated with. Mount is refused if the
+    /// index lacks a matching HMAC or signature for a supplied key.
+    pub keys: Keys,
+
+    /// Seconds to wait for the file-system to become ready. If the index is
+    /// not loaded and the FUSE handshake is not complete by then, the process
+    /// exits with `EXIT_MOUNT_TIMEOUT`.
+    pub timeout: Option<u64>,
+
+    /// Hold shared advisory locks on the index and tar file while mounted.
+    pub lock: bool,
+
+    /// Path to write a JSON statistics report to at unmount.
+    pub stats: Option<String>,
+
+    /// Number of threads used to verify pages. Pages are verified on the
+    /// FUSE thread if zero.
+    pub verify_threads: usize,
+
+    /// CPUs to confine all threads of the file-system to.
+    pub pin_cpus: Option<CpuList>,
+
+    /// Already open file descriptor of the tar file. If supplied, the tar
+    /// file is not opened by path.
+    pub tar_fd: Option<RawFd>,
+
+    /// Maximum number of files open at the same time. Unlimited if None.
+    pub max_open_files: Option<u64>,
+
+    /// Find names stored in one Unicode normalization form (NFC or NFD) when
+    /// looked up in the other.
+    pub normalize_names: bool,
+
+    /// Time the kernel caches looked up entries for. Larger values result in
+    /// faster file-system performance.
+    pub entry_timeout: Duration,
+
+    /// Time the kernel caches attributes for.
+    pub attr_timeout: Duration,
+
+    /// Ask the kernel to keep cached file contents and directory listings
+    /// across opens. Only done without an upper layer.
+    pub keep_cache: bool,
+
+    /// Open files for direct I/O, bypassing the page cache. Every read
+    /// reaches the file-system.
+    pub direct_io: bool,
+
+    /// What to do after a FUSE callback panics.
+    pub on_panic: OnPanic,
+
+    /// When the contents of files are verified.
+    pub verify_policy: VerifyPolicy,
+
+    /// What to do after the contents of a file fail verification.
+    pub on_verify_failure: OnVerifyFailure,
+
+    /// Key of keyed (HMAC) measurements. Required to mount indexes created
+    /// with a measurement key.
+    pub measure_key: Option<Vec<u8>>,
+
+    /// How the tar file is read.
+    pub backend: Backend,
+
+    /// How whiteouts are shown.
+    pub whiteouts: WhiteoutMode,
+
+    /// Writable upper layer over the layers. The mount is read-only if None.
+    pub upper: Option<UpperStore>,
+
+    /// Path of a unix socket to accept requests to commit the upper layer on.
+    /// Only processes of the user running the daemon and of root may connect.
+    pub commit_socket: Option<String>,
+
+    /// Directory of the index mounted as the root. The whole index is
+    /// mounted if None.
+    pub subdir: Option<String>,
+
+    /// Mapping of stored owner ids to shown owner ids.
+    pub ids: IdMap,
+
+    /// User and group databases to resolve owner names with.
+    pub owners: Option<Owners>,
+}
+
+/// Take ownership of an already open tar file descriptor.
+///
+/// Descriptors opened with `O_PATH` cannot be read from and are reopened for
+/// reading through procfs instead.
+///
+/// # Arguments
+/// * `fd` - The file descriptor.
+/// * `path` - procfs path of the file descriptor.
+fn open_tar_fd(fd: RawFd, path: &String) -> Result<File> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(anyhow!(
+            "invalid tar file descriptor {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        ));
+    }
+    if flags & libc::O_PATH != 0 {
+        // The original descriptor is left open so that the procfs path keeps
+        // referring to the tar file.
+        return File::open(path)
+            .with_context(|| format!("failed to reopen {}", path));
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// A layer of the file-system: the tar file backing its contents and the
+/// hash states they are verified against.
+struct Backing {
+    /// Hash states of the layer. Shared with the verification workers.
+    hasher: Arc<Hasher>,
+
+    /// Tar file backing store for the layer, or the folder with
+    /// `Backend::Dir`.
+    tar: File,
+
+    /// Mapping of the tar file, with `Backend::Mmap`.
+    map: Option<Mmap>,
+
+    /// How the contents of files are read.
+    backend: Backend,
+
+    /// Index file kept open to hold its lock while mounted.
+    _index_file: File,
+
+    /// Path of the tar file.
+    tar_path: String,
+
+    /// Length, device and inode number of the tar file at mount time.
+    /// Used to detect modification of the backing store.
+    tar_id: (u64, u64, u64),
+
+    /// Bitmap of the pages verified so far, by position of the state before
+    /// the page. Only maintained with `VerifyPolicy::Once`.
+    verified: Vec<u64>,
+}
+
+impl Backing {
+    /// Open the index and tar file of a layer.
+    ///
+    /// # Arguments
+    /// * `index` - The index file to use for enforcing integrity.
+    /// * `tar` - The tar file to use for file content backing store.
+    /// * `options` - Mount options.
+    /// * `returns` - The unprocessed index, without its hash states, and the
+    ///   layer.
+    fn open(
+        index: &String,
+        tar: &String,
+        options: &MountOptions,
+    ) -> Result<(Index, Backing)> {
+        let mut index_file = File::open(index)
+            .with_context(|| format!("failed to open {}", index))?;
+        let file = match options.tar_fd {
+            Some(fd) => open_tar_fd(fd, tar)?,
+            None => File::open(tar)
+                .with_context(|| format!("failed to open {}", tar))?,
+        };
+        if options.lock {
+            lock::lock(&index_file, index, false)?;
+            lock::lock(&file, tar, false)?;
+        }
+
+        let mut index = Index::read(
+            &mut index_file,
+            index,
+            &options.index_digest,
+            &options.keys,
+        )?;
+        let mut hasher = mem::take(&mut index.hasher);
+        hasher.set_key(options.measure_key.clone())?;
+
+        // The index must be for the attested layer.
+        let layer_digest = Digest {
+            algorithm: hasher.algorithm,
+            hex: hasher.digest.clone(),
+        };
+        if let Some(digest) = &options.digest {
+            if layer_digest.ne(digest) {
+                return Err(anyhow!(
+                    "{}: index layer digest {} != supplied digest {}",
+                    tar,
+                    layer_digest,
+                    digest
+                ));
+            }
+        }
+
+        // Pages verified under the verify-once policy are tracked in a bitmap.
+        let verified = match options.verify_policy {
+            VerifyPolicy::Once => vec![0; hasher.num_states().div_ceil(64)],
+            _ => vec![],
+        };
+
+        let meta = file.metadata()?;
+        match options.backend {
+            Backend::Dir if !meta.is_dir() => {
+                return Err(anyhow!("{} is not a folder", tar))
+            }
+            Backend::Pread | Backend::Mmap if meta.is_dir() => {
+                return Err(anyhow!("{} is a folder, use --backend dir", tar))
+            }
+            _ => (),
+        }
+        let map = match options.backend {
+            Backend::Mmap => Some(
+                unsafe { Mmap::map(&file) }
+                    .with_context(|| format!("failed to map {}", tar))?,
+            ),
+            Backend::Pread | Backend::Dir => None,
+        };
+
+        let backing = Backing {
+            hasher: Arc::new(hasher),
+            tar: file,
+            map,
+            backend: options.backend,
+            _index_file: index_file,
+            tar_path: tar.to_string(),
+            tar_id: (meta.len(), meta.dev(), meta.ino()),
+            verified,
+        };
+        Ok((index, backing))
+    }
+
+    /// Check whether the tar file has been modified since mount.
+    ///
+    /// The open tar file is checked for truncation or growth, and the tar
+    /// path is checked for deletion or replacement by another file. Folders
+    /// are only checked for replacement.
+    fn check(&self) -> Result<()> {
+        let (len, dev, ino) = self.tar_id;
+        let meta = self.tar.metadata()?;
+        if !meta.is_dir() && meta.len() != len {
+            return Err(anyhow!(
+                "{} changed size from {} to {} bytes",
+                self.tar_path,
+                len,
+                meta.len()
+            ));
+        }
+
+        match std::fs::metadata(&self.tar_path) {
+            Ok(m) if m.dev() == dev && m.ino() == ino => Ok(()),
+            Ok(_) => Err(anyhow!("{} has been replaced", self.tar_path)),
+            Err(e) => {
+                Err(anyhow!("{} is not accessible: {}", self.tar_path, e))
+            }
+        }
+    }
+
+    /// Open the file of a regular file in the folder of the layer. Symbolic
+    /// links are not followed.
+    ///
+    /// # Arguments
+    /// * `inode` - Inode of the file.
+    fn open_file(&self, inode: &Inode) -> std::io::Result<File> {
+        let path = CString::new(inode.parent[1..].to_owned() + &inode.name)?;
+        let fd = unsafe {
+            libc::openat(
+                self.tar.as_raw_fd(),
+                path.as_ptr(),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(std::io::Error::new(
+                e.kind(),
+                format!("{}{}: {}", inode.parent, inode.name, e),
+            ));
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Check whether consecutive pages have all been verified before.
+    ///
+    /// # Arguments
+    /// * `pos` - Position of the state before the first page.
+    /// * `count` - Number of pages.
+    fn is_verified(&self, pos: u32, count: u32) -> bool {
+        (pos..pos + count).all(|p| {
+            self.verified
+                .get(p as usize / 64)
+                .is_some_and(|w| w & (1 << (p % 64)) != 0)
+        })
+    }
+
+    /// Mark consecutive pages as verified.
+    ///
+    /// # Arguments
+    /// * `pos` - Position of the state before the first page.
+    /// * `count` - Number of pages.
+    fn set_verified(&mut self, pos: u32, count: u32) {
+        for p in pos..pos + count {
+            if let Some(w) = self.verified.get_mut(p as usize / 64) {
+                *w |= 1 << (p % 64);
+            }
+        }
+    }
+}
+
+/// FUSE file system with integrity protection backed by tar files.
 struct CcFs {
-    /// Index for the tar file.
+    /// Index of the file-system. The union of the layers if several layers
+    /// are mounted.
     index: Index,
 
-    /// Tar file backing store for the layer.
-    tar: File,
+    /// The layers, top layer first. Inodes refer to their layer by position.
+    layers: Vec<Backing>,
+
+    /// Writable upper layer over the layers. None if read-only.
+    upper: Option<Upper>,
+
+    /// Ring the tar files are read through. None if io_uring is unavailable.
+    #[cfg(feature = "io-uring")]
+    ring: Option<Ring>,
+
+    /// Set once the backing store has been found to be modified. All
+    /// subsequent reads fail.
+    modified: Option<String>,
+
+    /// Inodes whose contents failed verification. All subsequent reads of
+    /// them fail.
+    poisoned: HashSet<u64>,
+
+    /// What to do after the contents of a file fail verification.
+    on_verify_failure: OnVerifyFailure,
+
+    /// Directory the file-system is mounted at.
+    mount_point: String,
 
     /// The next available file handle.
     next_file_handle: u64,
+
+    /// Number of files currently open.
+    open_files: u64,
+
+    /// Maximum number of files open at the same time.
+    max_open_files: Option<u64>,
+
+    /// Retry failed lookups with other normalization forms of the name.
+    normalize_names: bool,
+
+    /// Time the kernel caches looked up entries, and their attributes, for.
+    entry_timeout: Duration,
+
+    /// Time the kernel caches attributes for.
+    attr_timeout: Duration,
+
+    /// Keep cached file contents and directory listings across opens.
+    keep_cache: bool,
+
+    /// Open files for direct I/O.
+    direct_io: bool,
+
+    /// Notified once the FUSE handshake completes.
+    ready: Option<Sender<()>>,
+
+    /// Statistics of the mount.
+    stats: Stats,
+
+    /// Path to write the statistics report to.
+    stats_path: Option<String>,
+
+    /// Verification workers.
+    pool: Option<Pool>,
+
+    /// Open files whose verified contents are kept, by inode number.
+    whole_files: HashMap<u64, WholeFile>,
+
+    /// When the contents of files are verified.
+    verify_policy: VerifyPolicy,
+
+    /// Mapping of stored owner ids to shown owner ids.
+    ids: IdMap,
+
+    /// Number of 4096 byte blocks of the regular files of the lower layers.
+    blocks: u64,
+
+    /// Open directories, by file handle.
+    dir_handles: HashMap<u64, DirHandle>,
+
+    /// Number of lookups of inodes known to the kernel, by inode number.
+    /// Each entry reply counts as a lookup, until forgotten.
+    lookups: HashMap<u64, u64>,
+}
+
+/// Entries of an open directory, as of when it was opened.
+enum DirHandle {
+    /// Children of a directory of the lower layers: the inode number of the
+    /// first child and the number of children.
+    Range(u64, u64),
+
+    /// Merged entries of a directory changed in the upper layer, as names
+    /// and inode numbers.
+    Entries(Vec<(String, u64)>),
+}
+
+/// An open file whose verified contents are kept while it is open.
+#[derive(Default)]
+struct WholeFile {
+    /// Number of open handles of the file.
+    handles: u32,
+
+    /// Verified contents of the file, once read. Kept until the last handle
+    /// is released.
+    contents: Option<Vec<u8>>,
 }
 
 impl CcFs {
-    /// Create a new CcFs instance backed by a tar file.
+    /// Create a new CcFs instance backed by tar files.
+    ///
+    /// Several layers are mounted as their union, the upper layers over the
+    /// lower ones.
     ///
     /// # Arguments
-    /// * `index` - The index file to use for enforcing integrity.
-    /// * `tar` - The tar file to use for file content backing store.
-    pub fn new(index: &String, tar: &String) -> Result<CcFs> {
-        let mut fs = CcFs {
-            index: Index::from_file(&index)?,
-            tar: File::open(tar)?,
+    /// * `indexes` - The index files to use for enforcing integrity, one per
+    ///   layer, top layer first.
+    /// * `tars` - The tar files to use for file content backing store, in the
+    ///   order of the indexes.
+    /// * `options` - Mount options.
+    pub fn new(
+        indexes: &[String],
+        tars: &[String],
+        options: &MountOptions,
+    ) -> Result<CcFs> {
+        if indexes.len() != tars.len() {
+            return Err(anyhow!(
+                "{} indexes but {} tar files supplied",
+                indexes.len(),
+                tars.len()
+            ));
+        }
+        if indexes.len() > 1 && options.index_digest.is_some() {
+            return Err(anyhow!("--index-digest requires a single index"));
+        }
+        if indexes.len() > 1 && options.digest.is_some() {
+            return Err(anyhow!("--digest requires a single index"));
+        }
+
+        let mut layers = vec![];
+        let mut layer_indexes = vec![];
+        for (index, tar) in indexes.iter().zip(tars) {
+            let (index, layer) = Backing::open(index, tar, options)?;
+            layer_indexes.push(index);
+            layers.push(layer);
+        }
+
+        // Process the index, merging the layers first.
+        let mut index = match layer_indexes.len() {
+            1 => layer_indexes.remove(0),
+            _ => union::merge(&layer_indexes)?,
+        };
+        drop(layer_indexes);
+        whiteout::translate(&mut index, options.whiteouts);
+        if let Some(subdir) = &options.subdir {
+            subtree::restrict(&mut index, subdir)?;
+        }
+        index.process()?;
+        if let Some(owners) = &options.owners {
+            owners.resolve(&mut index);
+        }
+
+        let blocks = index
+            .inodes
+            .iter()
+            .filter(|i| matches!(i.typeflag, index::FileType::RegularFile))
+            .map(|i| i.size.div_ceil(4096))
+            .sum();
+
+        let upper = options
+            .upper
+            .clone()
+            .map(|store| Upper::new(store, index.inodes.len() as u64));
+        Ok(CcFs {
+            index,
+            layers,
+            upper,
+            #[cfg(feature = "io-uring")]
+            ring: match Ring::new() {
+                Ok(ring) => Some(ring),
+                Err(e) => {
+                    eprintln!(
+                        "io_uring unavailable, reading synchronously: {}",
+                        e
+                    );
+                    None
+                }
+            },
+            modified: None,
+            poisoned: HashSet::new(),
+            on_verify_failure: options.on_verify_failure,
+            mount_point: String::new(),
             next_file_handle: 1,
+            open_files: 0,
+            max_open_files: options.max_open_files,
+            normalize_names: options.normalize_names,
+            entry_timeout: options.entry_timeout,
+            attr_timeout: options.attr_timeout,
+            keep_cache: options.keep_cache && options.upper.is_none(),
+            direct_io: options.direct_io,
+            ready: None,
+            stats: Stats::default(),
+            stats_path: options.stats.clone(),
+            whole_files: HashMap::new(),
+            verify_policy: options.verify_policy,
+            ids: options.ids.clone(),
+            blocks,
+            dir_handles: HashMap::new(),
+            lookups: HashMap::new(),
+            pool: match options.verify_threads {
+                0 => None,
+                n => Some(Pool::new(n)?),
+            },
+        })
+    }
+
+    /// Write the statistics report, if requested.
+    fn write_stats(&self) {
+        if let Some(path) = &self.stats_path {
+            if let Err(e) = self.stats.to_file(path) {
+                eprintln!("Error: {:#}", e);
+            }
+        }
+    }
+
+    /// Record that the backing store has been modified.
+    ///
+    /// # Arguments
+    /// * `reason` - Description of the modification.
+    fn set_modified(&mut self, reason: String) {
+        eprintln!("Error: backing store modified: {}", reason);
+        self.modified = Some(reason);
+    }
+
+    /// Record a failed read of a tar file. Fails all subsequent reads.
+    ///
+    /// # Arguments
+    /// * `layer` - Position of the layer of the tar file.
+    /// * `e` - The error of the read.
+    fn read_failed(&mut self, layer: u16, e: std::io::Error) {
+        let layer = &self.layers[layer as usize];
+        let reason = match layer.check() {
+            Err(m) => m.to_string(),
+            _ => format!("read of {} failed: {}", layer.tar_path, e),
         };
+        self.set_modified(reason);
+    }
 
-        // Process the index.
-        fs.index.process()?;
+    /// Record a failed verification. All later reads of the file fail, or
+    /// all reads if the backing store has been modified. Then act according
+    /// to the failure policy.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number of the file.
+    /// * `page_num` - Position of the page that failed verification.
+    /// * `returns` - The error to fail the request with.
+    fn verification_failed(&mut self, ino: usize, page_num: u32) -> c_int {
+        self.stats.failed_pages += 1;
+        self.write_stats();
 
-        Ok(fs)
+        // Distinguish modification of the backing store from tampering of
+        // individual pages.
+        let layer = self.index.inodes[ino].layer as usize;
+        let message = match self.layers[layer].check() {
+            Err(m) => {
+                self.set_modified(m.to_string());
+                format!("backing store modified: {}", m)
+            }
+            Ok(()) => {
+                let message = format!(
+                    "integrity verification failed for {:+?} at page_num {}",
+                    self.index.inodes[ino], page_num
+                );
+                eprintln!("Error: {}", message);
+                message
+            }
+        };
+        self.poisoned.insert(ino as u64);
+        match self.on_verify_failure {
+            OnVerifyFailure::Panic => panic!("{}", message),
+            OnVerifyFailure::Eio => EIO,
+            OnVerifyFailure::Unmount => guard::unmount(&self.mount_point),
+        }
     }
 
-    /// Map from CcFs FileType to FUSE FileType.
+    /// Read the exact number of bytes required to fill a buffer from the tar
+    /// file of a layer.
     ///
     /// # Arguments
-    /// * `typeflag` - The CcFs FileType of the inode.
+    /// * `layer` - The layer.
+    /// * `buf` - Buffer to read into.
+    /// * `offset` - Offset in the tar file to read from.
+    fn read_tar(
+        &self,
+        layer: &Backing,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> std::io::Result<()> {
+        if let Some(map) = &layer.map {
+            return CcFs::copy_mapped(map, offset, buf);
+        }
+        #[cfg(feature = "io-uring")]
+        if let Some(ring) = &self.ring {
+            return ring.read_exact_at(&layer.tar, buf, offset);
+        }
+        layer.tar.read_exact_at(buf, offset)
+    }
+
+    /// Read the exact number of bytes required to fill a buffer from the
+    /// stored contents of a regular file.
     ///
-    fn to_file_type(typeflag: &index::FileType) -> FileType {
-        match typeflag {
-            index::FileType::RegularFile => FileType::RegularFile,
-            index::FileType::Directory => FileType::Directory,
-            index::FileType::SymLink => FileType::Symlink,
-            index::FileType::HardLink => FileType::RegularFile,
-            _ => panic!("unhandled typeflag {:#?}", typeflag),
+    /// With `Backend::Dir`, the contents are read from the file of the inode
+    /// in the folder. The stored contents of sparse files are gathered from
+    /// the data regions of the file.
+    ///
+    /// # Arguments
+    /// * `layer` - The layer of the inode.
+    /// * `inode` - Inode of the file.
+    /// * `buf` - Buffer to read into.
+    /// * `offset` - Offset in the stored contents to read from.
+    fn read_stored(
+        &self,
+        layer: &Backing,
+        inode: &Inode,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> std::io::Result<()> {
+        if layer.backend != Backend::Dir {
+            return self.read_tar(
+                layer,
+                buf,
+                inode.offset as u64 * 512 + offset,
+            );
+        }
+
+        let file = layer.open_file(inode)?;
+        let whole = [(0, inode.stored_size())];
+        let regions = inode.sparse_map().map_or(&whole[..], |m| &m[..]);
+        let end = offset + buf.len() as u64;
+        let mut stored = 0;
+        for (start, len) in regions {
+            // Part of the region within the buffer, in stored offsets.
+            let (from, to) = (stored.max(offset), (stored + len).min(end));
+            if from < to {
+                let part =
+                    &mut buf[(from - offset) as usize..(to - offset) as usize];
+                file.read_exact_at(part, start + from - stored)?;
+            }
+            stored += len;
         }
+        Ok(())
     }
 
-    /// Fetch FUSE attributes for an inode.
+    /// Copy bytes of the mapped tar file into a buffer.
+    ///
+    /// The bytes are copied with `process_vm_readv` rather than read from the
+    /// mapping directly, so that pages past the end of a truncated tar file
+    /// fail the copy with EFAULT instead of raising SIGBUS. The copy is
+    /// private, so what is verified is what is served, whatever happens to
+    /// the tar file in between.
     ///
     /// # Arguments
-    /// * `ino` - Number of the inode.
-    /// * `inode` - The inode.
-    fn inode_to_attr(ino: u64, inode: &Inode) -> FileAttr {
-        let mtime = UNIX_EPOCH + Duration::from_secs(inode.mtime);
-        let size = match &inode.typeflag {
-            // Show directory size as 4096
-            index::FileType::Directory => 4096,
-            index::FileType::SymLink => {
-                if let Some(e) = &inode.extra {
-                    e.link.len() as u64
-                } else {
-                    panic!("empty link")
+    /// * `map` - Mapping of the tar file.
+    /// * `offset` - Offset in the tar file of the first byte.
+    /// * `buf` - Buffer to copy into.
+    fn copy_mapped(
+        map: &Mmap,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<()> {
+        let start = offset as usize;
+        match start.checked_add(buf.len()) {
+            Some(end) if end <= map.len() => (),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "read beyond end of mapping",
+                ))
+            }
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let local = libc::iovec {
+                iov_base: buf[done..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len() - done,
+            };
+            let remote = libc::iovec {
+                iov_base: map[start + done..].as_ptr() as *mut libc::c_void,
+                iov_len: buf.len() - done,
+            };
+            let n = unsafe {
+                libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0)
+            };
+            match n {
+                n if n < 0 => return Err(std::io::Error::last_os_error()),
+                0 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "tar file truncated",
+                    ))
+                }
+                n => done += n as usize,
+            }
+        }
+        Ok(())
+    }
+
+    /// Contents of a range of a file, given the stored contents covering it.
+    /// Holes of sparse files are filled with zeros.
+    ///
+    /// # Arguments
+    /// * `inode` - Inode of the file.
+    /// * `range` - The range within the file.
+    /// * `data` - Stored contents covering the range.
+    fn fill_holes<'a>(
+        inode: &Inode,
+        range: &Range<u64>,
+        data: &'a [u8],
+    ) -> Cow<'a, [u8]> {
+        match inode.sparse_map() {
+            Some(_) => {
+                Cow::Owned(inode.fill_holes(range.start, range.end, data))
+            }
+            None => Cow::Borrowed(data),
+        }
+    }
+
+    /// Read and verify the whole contents of a regular file.
+    ///
+    /// # Arguments
+    /// * `inode` - Inode of the file.
+    /// * `returns` - The stored contents padded with zeros to a multiple of
+    ///   512 bytes, and the position of the first page that fails
+    ///   verification, if any.
+    fn read_whole(
+        &self,
+        inode: &Inode,
+    ) -> std::io::Result<(Vec<u8>, Result<(), u32>)> {
+        let size = inode.stored_size() as usize;
+        let mut buf = vec![0u8; size.div_ceil(512) * 512];
+        let layer = &self.layers[inode.layer as usize];
+        self.read_stored(layer, inode, &mut buf[..size], 0)?;
+
+        let pos = inode.hash_index;
+        let hasher = &layer.hasher;
+        let result = match inode.granularity {
+            _ if size == 0 => Ok(()),
+            Granularity::File => {
+                match hasher.verify_range(Some(pos), &buf, pos + 1) {
+                    Ok(true) => Ok(()),
+                    _ => Err(pos),
                 }
             }
-            _ => inode.size as u64,
+            Granularity::Page => hasher.verify_pages(pos, &buf),
         };
-        FileAttr {
-            ino: ino,
-            size: size,
-            blocks: size / 4096,
-            atime: mtime,
-            mtime: mtime,
-            ctime: mtime,
-            crtime: mtime,
-            kind: CcFs::to_file_type(&inode.typeflag),
-            perm: inode.mode as u16,
-            nlink: inode.links as u32,
-            uid: inode.uid,
-            gid: inode.gid,
-            rdev: 0,  // TODO
-            flags: 0, // MacOS only
-            blksize: 4096,
+        Ok((buf, result))
+    }
+
+    /// Extended attributes of an inode.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode. Hard links are resolved.
+    /// * `returns` - The attributes, or None if the inode does not exist.
+    fn xattrs(&self, ino: u64) -> Option<&[(String, Vec<u8>)]> {
+        let (_, inode) = self.inode(ino)?;
+        Some(inode.extra.as_ref().map_or(&[], |e| &e.xattrs[..]))
+    }
+
+    /// An inode, from the upper layer if there.
+    ///
+    /// A hard link and its target must share the same inode. Therefore, hard
+    /// links of the lower layers are resolved to their targets.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    /// * `returns` - The number of the inode after resolving hard links and
+    ///   the inode, or None if the inode does not exist.
+    fn inode(&self, ino: u64) -> Option<(u64, &Inode)> {
+        let upper = self.upper.as_ref();
+        if let Some(node) = upper.and_then(|u| u.node(ino)) {
+            return Some((ino, &node.inode));
+        }
+        if ino as usize >= self.index.inodes.len() {
+            return None;
+        }
+        let ino = self.index.get_hard_link_target(ino as u32) as u64;
+        match upper.and_then(|u| u.node(ino)) {
+            Some(node) => Some((ino, &node.inode)),
+            None => Some((ino, &self.index.inodes[ino as usize])),
+        }
+    }
+
+    /// Find a child of a directory in the lower layers.
+    ///
+    /// # Arguments
+    /// * `parent` - Inode number of the directory.
+    /// * `name` - Name of the child.
+    /// * `returns` - Inode number of the child. Hard links are not resolved.
+    fn lower_child(&self, parent: u64, name: &str) -> Option<u64> {
+        // Fetch the parent node, and the starting and ending indices of
+        // children.
+        let inode = self.index.inodes.get(parent as usize)?;
+        let child_start = inode.child_inode as usize;
+        let child_end = child_start + inode.num_children as usize;
+
+        // Search for node within given name in the set of children. Unless
+        // found, try the NFC and NFD forms of the name. The name is returned
+        // by readdir in its stored form.
+        let children = &self.index.inodes[child_start..child_end];
+        let mut found =
+            children.binary_search_by(|a| a.name.as_str().cmp(name));
+        if found.is_err() && self.normalize_names {
+            for form in [name.nfc().collect::<String>(), name.nfd().collect()] {
+                found = children.binary_search_by(|a| a.name.cmp(&form));
+                if found.is_ok() {
+                    break;
+                }
+            }
+        }
+        found.ok().map(|idx| (child_start + idx) as u64)
+    }
+
+    /// Find a child of a directory, in the upper layer first.
+    ///
+    /// # Arguments
+    /// * `parent` - Inode number of the directory.
+    /// * `name` - Name of the child.
+    /// * `returns` - Inode number of the child. Hard links are not resolved.
+    fn child(&self, parent: u64, name: &str) -> Option<u64> {
+        let upper = self.upper.as_ref();
+        if let Some(entry) = upper.and_then(|u| u.entry(parent, name)) {
+            return entry;
+        }
+
+        // Entries of the lower layers are recorded in the upper layer by
+        // their stored names, which may be in another normalization form.
+        let child = self.lower_child(parent, name)?;
+        let stored = &self.index.inodes[child as usize].name;
+        match upper.and_then(|u| u.entry(parent, stored)) {
+            Some(entry) => entry,
+            None => Some(child),
+        }
+    }
+
+    /// Entries of a directory, merged from the upper and lower layers.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number of the directory.
+    /// * `returns` - Inode numbers of the entries, by name. Hard links are
+    ///   not resolved.
+    fn entries(&self, ino: u64) -> BTreeMap<String, u64> {
+        let mut entries = BTreeMap::new();
+        if let Some(dir) = self.index.inodes.get(ino as usize) {
+            let first = dir.child_inode as u64;
+            for child in first..first + dir.num_children as u64 {
+                let name = self.index.inodes[child as usize].name.clone();
+                entries.insert(name, child);
+            }
+        }
+        let upper = self.upper.as_ref().and_then(|u| u.entries(ino));
+        for (name, entry) in upper.into_iter().flatten() {
+            match entry {
+                Some(child) => entries.insert(name.clone(), *child),
+                None => entries.remove(name),
+            };
+        }
+        entries
+    }
+
+    /// Count a lookup of an inode by the kernel.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    fn looked_up(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_default() += 1;
+    }
+
+    /// Drop an inode from the upper layer once it has no links, open handles
+    /// or lookups left.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    fn collect(&mut self, ino: u64) {
+        let known = self.lookups.contains_key(&ino);
+        if let Some(upper) = &mut self.upper {
+            upper.collect(ino, known);
+        }
+    }
+
+    /// Snapshot the entries of a directory.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number of the directory.
+    fn dir_handle(&self, ino: u64) -> Result<DirHandle, c_int> {
+        match self.inode(ino) {
+            Some((_, i))
+                if matches!(i.typeflag, index::FileType::Directory) => {}
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        }
+
+        // Directories changed in the upper layer are listed from their
+        // merged entries.
+        let upper = self.upper.as_ref();
+        if upper.is_some_and(|u| u.is_new(ino) || u.entries(ino).is_some()) {
+            return Ok(DirHandle::Entries(
+                self.entries(ino).into_iter().collect(),
+            ));
+        }
+        let inode = &self.index.inodes[ino as usize];
+        Ok(DirHandle::Range(
+            inode.child_inode as u64,
+            inode.num_children as u64,
+        ))
+    }
+
+    /// Inode number of the directory containing a directory.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number of the directory.
+    fn parent_of(&self, ino: u64) -> u64 {
+        if let Some(upper) = &self.upper {
+            if let Some(node) = upper.node(ino).filter(|_| upper.is_new(ino)) {
+                return node.parent;
+            }
+        }
+        let inode = &self.index.inodes[ino as usize];
+        match self.index.find(&inode.parent, 0, ino as usize) {
+            Ok(p) => p as u64,
+            _ => panic!("Could not find parent."),
+        }
+    }
+
+    /// An inode of the upper layer.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    fn node_mut(&mut self, ino: u64) -> Result<&mut Node, c_int> {
+        let upper = self.upper.as_mut().ok_or(EROFS)?;
+        upper.node_mut(ino).ok_or(ENOENT)
+    }
+
+    /// Copy the attributes of an inode up to the upper layer, unless there
+    /// already.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    /// * `returns` - The number of the inode after resolving hard links.
+    fn copy_up(&mut self, ino: u64) -> Result<u64, c_int> {
+        let (ino, _) = self.inode(ino).ok_or(ENOENT)?;
+        let upper = self.upper.as_mut().ok_or(EROFS)?;
+        if let Some(inode) = self.index.inodes.get(ino as usize) {
+            upper.copy_up(ino, || Node {
+                inode: inode.clone(),
+                data: None,
+                parent: 0,
+            });
+        }
+        Ok(ino)
+    }
+
+    /// Copy the attributes and contents of a regular file up to the upper
+    /// layer, unless there already. The contents are verified first.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    /// * `returns` - The number of the inode after resolving hard links.
+    fn copy_up_data(&mut self, ino: u64) -> Result<u64, c_int> {
+        let ino = self.copy_up(ino)?;
+        let node = self.node_mut(ino)?;
+        if node.data.is_some()
+            || !matches!(node.inode.typeflag, index::FileType::RegularFile)
+        {
+            return Ok(ino);
+        }
+        if self.modified.is_some() || self.poisoned.contains(&ino) {
+            return Err(EIO);
+        }
+
+        // Read and verify the contents, and fill in the holes of sparse
+        // files.
+        let inode = &self.index.inodes[ino as usize];
+        let (buf, result) = match self.read_whole(inode) {
+            Ok(r) => r,
+            Err(e) => {
+                self.read_failed(inode.layer, e);
+                return Err(EIO);
+            }
+        };
+        if let Err(page_num) = result {
+            return Err(self.verification_failed(ino as usize, page_num));
+        }
+        self.stats.verified_pages += buf.len().div_ceil(4096) as u64;
+        let stored = &buf[..inode.stored_size() as usize];
+        let contents = CcFs::fill_holes(inode, &(0..inode.size), stored);
+
+        let upper = self.upper.as_mut().ok_or(EROFS)?;
+        let mut data = upper.new_contents().map_err(CcFs::errno)?;
+        data.write(0, &contents).map_err(CcFs::errno)?;
+        let node = upper.node_mut(ino).ok_or(ENOENT)?;
+        node.data = Some(data);
+        if let Some(extra) = &mut node.inode.extra {
+            extra.sparse = None;
+            extra.sha256 = None;
+        }
+        Ok(ino)
+    }
+
+    /// Change the link count of an inode, copying it up.
+    ///
+    /// # Arguments
+    /// * `ino` - The number of the inode.
+    /// * `delta` - Links added, or removed if negative.
+    fn add_links(&mut self, ino: u64, delta: i32) -> Result<(), c_int> {
+        let ino = self.copy_up(ino)?;
+        let node = self.node_mut(ino)?;
+        node.inode.links = node.inode.links.saturating_add_signed(delta);
+        Ok(())
+    }
+
+    /// Name of a new entry.
+    ///
+    /// # Arguments
+    /// * `name` - The name.
+    fn entry_name(name: &OsStr) -> Result<&str, c_int> {
+        if name.len() > MAX_NAME_LENGTH as usize {
+            return Err(ENAMETOOLONG);
+        }
+        name.to_str().ok_or(EINVAL)
+    }
+
+    /// Create an inode in the upper layer and add it to a directory.
+    ///
+    /// The inode is owned by the caller. In set-group-ID directories, it is
+    /// owned by the group of the directory instead, and new directories
+    /// inherit the set-group-ID bit.
+    ///
+    /// # Arguments
+    /// * `req` - Request creating the inode.
+    /// * `parent` - Inode number of the directory.
+    /// * `name` - Name of the new entry.
+    /// * `typeflag` - File type of the inode.
+    /// * `mode` - Permission bits of the inode.
+    /// * `extra` - Link target or device numbers of the inode.
+    /// * `returns` - The number of the inode and its attributes.
+    fn create_node(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        typeflag: index::FileType,
+        mode: u32,
+        extra: Option<Extra>,
+    ) -> Result<(u64, FileAttr), c_int> {
+        if self.upper.is_none() {
+            return Err(EROFS);
+        }
+        let name = CcFs::entry_name(name)?;
+        let (parent, dir) = self.inode(parent).ok_or(ENOENT)?;
+        if !matches!(dir.typeflag, index::FileType::Directory) {
+            return Err(ENOTDIR);
+        }
+        if self.child(parent, name).is_some() {
+            return Err(EEXIST);
+        }
+
+        // Owners are stored unshifted.
+        let uid = self.ids.stored_uid(req.uid()).ok_or(EOVERFLOW)?;
+        let req_gid = self.ids.stored_gid(req.gid()).ok_or(EOVERFLOW)?;
+        let is_dir = matches!(typeflag, index::FileType::Directory);
+        let (gid, mode) = match dir.mode & libc::S_ISGID {
+            0 => (req_gid, mode),
+            _ if is_dir => (dir.gid, mode | libc::S_ISGID),
+            _ => (dir.gid, mode),
+        };
+        let (mtime, mtime_nsec) = CcFs::from_system_time(SystemTime::now());
+        let inode = Inode {
+            typeflag,
+            name: name.to_string(),
+            uid,
+            gid,
+            mode: mode & 0o7777,
+            mtime,
+            mtime_nsec,
+            links: if is_dir { 2 } else { 1 },
+            extra,
+            ..Inode::default()
+        };
+
+        let attr = self.inode_to_attr(0, &inode);
+        let upper = self.upper.as_mut().ok_or(EROFS)?;
+        let data = match inode.typeflag {
+            index::FileType::RegularFile => {
+                Some(upper.new_contents().map_err(CcFs::errno)?)
+            }
+            _ => None,
+        };
+        let ino = upper.add(Node {
+            inode,
+            data,
+            parent,
+        });
+        upper.set_entry(parent, name, Some(ino));
+        if is_dir {
+            self.add_links(parent, 1)?;
+        }
+        Ok((ino, FileAttr { ino, ..attr }))
+    }
+
+    /// Remove an entry from a directory. Entries of the lower layers are
+    /// hidden by a whiteout.
+    ///
+    /// # Arguments
+    /// * `parent` - Inode number of the directory.
+    /// * `name` - Name of the entry.
+    fn remove_entry(&mut self, parent: u64, name: &str) {
+        let lower = self
+            .lower_child(parent, name)
+            .map(|c| self.index.inodes[c as usize].name.clone());
+        if let Some(upper) = &mut self.upper {
+            upper.clear_entry(parent, name);
+            if let Some(stored) = lower {
+                upper.set_entry(parent, &stored, None);
+            }
+        }
+    }
+
+    /// Drop a link to an inode removed from a directory. The inode is
+    /// dropped from the upper layer once unused.
+    ///
+    /// # Arguments
+    /// * `parent` - Inode number of the directory.
+    /// * `ino` - The number of the inode, after resolving hard links.
+    fn drop_link(&mut self, parent: u64, ino: u64) -> Result<(), c_int> {
+        let (_, inode) = self.inode(ino).ok_or(ENOENT)?;
+        let is_dir = matches!(inode.typeflag, index::FileType::Directory);
+        let links = inode.links;
+        let upper = self.upper.as_mut().ok_or(EROFS)?;
+        if is_dir {
+            if let Some(node) = upper.node_mut(ino) {
+                node.inode.links = 0;
+            }
+            self.add_links(parent, -1)?;
+        } else if links > 1 || upper.node(ino).is_some() {
+            // Lower inodes without other links are merely hidden.
+            self.add_links(ino, -1)?;
+        }
+        self.collect(ino);
+        Ok(())
+    }
+
+    /// Remove an entry of a directory, checking its type first.
+    ///
+    /// # Arguments
+    /// * `parent` - Inode number of the directory.
+    /// * `name` - Name of the entry.
+    /// * `dir` - Whether the entry must be a directory, as with rmdir, or
+    ///   must not be, as with unlink.
+    fn remove(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        dir: bool,
+    ) -> Result<(), c_int> {
+        if self.upper.is_none() {
+            return Err(EROFS);
+        }
+        let name = name.to_str().ok_or(ENOENT)?;
+        let child = self.child(parent, name).ok_or(ENOENT)?;
+        let (ino, inode) = self.inode(child).ok_or(ENOENT)?;
+        match (dir, &inode.typeflag) {
+            (true, index::FileType::Directory) => {
+                if !self.entries(ino).is_empty() {
+                    return Err(ENOTEMPTY);
+                }
+            }
+            (true, _) => return Err(ENOTDIR),
+            (false, index::FileType::Directory) => return Err(EISDIR),
+            (false, _) => (),
+        }
+        self.remove_entry(parent, name);
+        self.drop_link(parent, ino)
+    }
+
+    /// Collect the entries of a layer tar holding the changes below a
+    /// directory, in archive order.
+    ///
+    /// Entries added or replaced in the upper layer, and inodes copied up,
+    /// are collected along with the directories leading to them. Removed
+    /// entries of the lower layers are collected as whiteouts, and
+    /// directories replacing directories of the lower layers are followed by
+    /// opaque markers.
+    ///
+    /// # Arguments
+    /// * `dir` - Inode number of the directory.
+    /// * `path` - Archive path of the directory. Empty for the root, ending
+    ///   with '/' otherwise.
+    /// * `pending` - Directories leading to the directory that have not been
+    ///   collected yet.
+    /// * `changes` - The archive paths, inode numbers and kinds of the
+    ///   entries collected.
+    fn collect_changes(
+        &self,
+        dir: u64,
+        path: &str,
+        pending: &mut Vec<(String, u64, Kind)>,
+        changes: &mut Vec<(String, u64, Kind)>,
+    ) {
+        let upper = match &self.upper {
+            Some(upper) => upper,
+            None => return,
+        };
+
+        // Whiteouts carry the metadata of their directory.
+        let recorded = upper.entries(dir).into_iter().flatten();
+        for (name, _) in recorded.filter(|(_, entry)| entry.is_none()) {
+            changes.append(pending);
+            let whiteout = path.to_owned() + WHITEOUT_PREFIX + name;
+            changes.push((whiteout, dir, Kind::Whiteout));
+        }
+
+        for (name, child) in self.entries(dir) {
+            let (ino, inode) = match self.inode(child) {
+                Some(found) => found,
+                None => continue,
+            };
+            let is_dir = matches!(inode.typeflag, index::FileType::Directory);
+            let child_path = match is_dir {
+                true => format!("{}{}/", path, name),
+                false => path.to_owned() + &name,
+            };
+            let len = pending.len();
+            if upper.entry(dir, &name).is_some() || upper.node(ino).is_some() {
+                changes.append(pending);
+                changes.push((child_path.clone(), child, Kind::Inode));
+                let replaced = self
+                    .lower_child(dir, &name)
+                    .map(|c| &self.index.inodes[c as usize].typeflag);
+                if is_dir
+                    && upper.is_new(ino)
+                    && matches!(replaced, Some(index::FileType::Directory))
+                {
+                    let marker = child_path.clone() + OPAQUE_WHITEOUT;
+                    changes.push((marker, ino, Kind::OpaqueMarker));
+                }
+            } else if is_dir {
+                pending.push((child_path.clone(), child, Kind::Inode));
+            }
+            if is_dir {
+                self.collect_changes(ino, &child_path, pending, changes);
+            }
+            pending.truncate(len);
+        }
+    }
+
+    /// Write entries collected by `collect_changes` as a tar stream.
+    ///
+    /// Of a set of hard links, the first entry carries the contents and the
+    /// others link to it. The contents of all regular files must have been
+    /// copied up.
+    ///
+    /// # Arguments
+    /// * `out` - Writer for the tar stream.
+    /// * `changes` - The entries to write.
+    fn write_changes<W: Write>(
+        &self,
+        out: W,
+        changes: &[(String, u64, Kind)],
+    ) -> Result<()> {
+        let upper = self.upper.as_ref().ok_or(anyhow!("no upper layer"))?;
+        let mut writer = TarWriter {
+            out,
+            names: &self.index.names,
+            clamp_mtime: None,
+        };
+
+        // Archive paths of files already written, by inode number.
+        let mut written: HashMap<u64, String> = HashMap::new();
+
+        for (path, ino, kind) in changes {
+            let (ino, inode) =
+                self.inode(*ino).ok_or(anyhow!("{} not found", path))?;
+            let xattrs =
+                inode.extra.as_ref().map_or(vec![], |e| e.xattrs.clone());
+            let link = inode.extra.as_ref().map_or("", |e| e.link.as_str());
+
+            match (kind, &inode.typeflag) {
+                (Kind::Whiteout | Kind::OpaqueMarker, _) => {
+                    writer.write_entry(path, b'0', inode, 0, "", &[])?
+                }
+                (_, index::FileType::Directory) => {
+                    writer.write_entry(path, b'5', inode, 0, "", &xattrs)?
+                }
+                (_, index::FileType::SymLink) => {
+                    writer.write_entry(path, b'2', inode, 0, link, &xattrs)?
+                }
+                (_, index::FileType::CharDevice) => {
+                    writer.write_entry(path, b'3', inode, 0, "", &xattrs)?
+                }
+                (_, index::FileType::BlockDevice) => {
+                    writer.write_entry(path, b'4', inode, 0, "", &xattrs)?
+                }
+                (_, index::FileType::Fifo) => {
+                    writer.write_entry(path, b'6', inode, 0, "", &xattrs)?
+                }
+                (
+                    _,
+                    index::FileType::RegularFile | index::FileType::HardLink,
+                ) => {
+                    if let Some(first) = written.get(&ino) {
+                        writer.write_entry(
+                            path, b'1', inode, 0, first, &xattrs,
+                        )?;
+                        continue;
+                    }
+                    let data = upper
+                        .node(ino)
+                        .and_then(|n| n.data.as_ref())
+                        .ok_or(anyhow!("{} not copied up", path))?;
+                    let size = inode.size;
+                    writer.write_entry(path, b'0', inode, size, "", &xattrs)?;
+                    let mut offset = 0;
+                    while offset < size {
+                        let buf = data.read(offset, CHUNK_SIZE, size)?;
+                        writer.out.write_all(&buf)?;
+                        offset += buf.len() as u64;
+                    }
+                    writer.write_padding(offset)?;
+                    written.insert(ino, path.clone());
+                }
+            }
+        }
+
+        // End of archive.
+        writer.out.write_all(&[0u8; 1024])?;
+        writer.out.flush()?;
+        Ok(())
+    }
+
+    /// Write the changes held in the upper layer as an OCI layer tar.
+    ///
+    /// The contents of files of the lower layers in the tar are copied up,
+    /// after verification, first.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the tar file to write.
+    fn commit(&mut self, path: &str) -> Result<()> {
+        let mut changes = vec![];
+        self.collect_changes(FUSE_ROOT_ID, "", &mut vec![], &mut changes);
+        for (path, ino, kind) in &changes {
+            let is_file = matches!(
+                self.inode(*ino),
+                Some((_, inode))
+                    if matches!(inode.typeflag, index::FileType::RegularFile)
+            );
+            if matches!(kind, Kind::Inode) && is_file {
+                self.copy_up_data(*ino).map_err(|e| {
+                    anyhow!(
+                        "failed to copy up {}: {}",
+                        path,
+                        std::io::Error::from_raw_os_error(e)
+                    )
+                })?;
+            }
+        }
+
+        // Existing files, and symlinks that could lead into the mount, are
+        // never written through.
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .with_context(|| format!("failed to create {}", path))?;
+        let result = self.write_changes(BufWriter::new(file), &changes);
+        if result.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+
+    /// Check whether the process of a request is in a group. FUSE requests
+    /// carry only the primary group, so supplementary groups are read from
+    /// procfs. The pid of a request is 0 if the process is outside the pid
+    /// namespace of the daemon.
+    ///
+    /// # Arguments
+    /// * `req` - Request object. Holds the ids of the process.
+    /// * `gid` - The group id.
+    fn in_group(req: &Request, gid: u32) -> bool {
+        if req.gid() == gid {
+            return true;
+        }
+        if req.pid() == 0 {
+            return false;
+        }
+        let status = fs::read_to_string(format!("/proc/{}/status", req.pid()));
+        status.ok().is_some_and(|status| {
+            CcFs::groups(&status, req.uid(), req.gid())
+                .is_some_and(|groups| groups.contains(&gid))
+        })
+    }
+
+    /// Supplementary groups of a process.
+    ///
+    /// # Arguments
+    /// * `status` - Contents of /proc/<pid>/status of the process.
+    /// * `uid` - File-system user id of the process making the request.
+    /// * `gid` - File-system group id of the process making the request.
+    /// * `returns` - The groups, or None if the ids of the process do not
+    ///   match, e.g. because the process exited and its pid was reused.
+    fn groups(status: &str, uid: u32, gid: u32) -> Option<Vec<u32>> {
+        // The ids are listed as real, effective, saved and file-system ids.
+        let field = |name| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(|ids| ids.split_whitespace().map(str::parse::<u32>))
+        };
+        let fs_id = |name| field(name)?.nth(3)?.ok();
+        if fs_id("Uid:") != Some(uid) || fs_id("Gid:") != Some(gid) {
+            return None;
+        }
+        field("Groups:")?.collect::<Result<_, _>>().ok()
+    }
+
+    /// Convert an I/O error to an error number.
+    ///
+    /// # Arguments
+    /// * `e` - The error.
+    fn errno(e: std::io::Error) -> c_int {
+        e.raw_os_error().unwrap_or(EIO)
+    }
+
+    /// Reply to getxattr or listxattr with data or its size.
+    ///
+    /// # Arguments
+    /// * `data` - The data.
+    /// * `size` - Size of the caller's buffer. Zero asks for the size only.
+    /// * `reply` - The ReplyXattr to populate.
+    fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(data);
+        }
+    }
+
+    /// Map from CcFs FileType to FUSE FileType.
+    ///
+    /// # Arguments
+    /// * `typeflag` - The CcFs FileType of the inode.
+    ///
+    fn to_file_type(typeflag: &index::FileType) -> FileType {
+        match typeflag {
+            index::FileType::RegularFile => FileType::RegularFile,
+            index::FileType::Directory => FileType::Directory,
+            index::FileType::SymLink => FileType::Symlink,
+            index::FileType::HardLink => FileType::RegularFile,
+            index::FileType::CharDevice => FileType::CharDevice,
+            index::FileType::BlockDevice => FileType::BlockDevice,
+            index::FileType::Fifo => FileType::NamedPipe,
+        }
+    }
+
+    /// Encode device numbers the way the kernel expects them in FUSE
+    /// attributes.
+    ///
+    /// # Arguments
+    /// * `(major, minor)` - Major and minor numbers of the device.
+    fn encode_dev((major, minor): (u32, u32)) -> u32 {
+        (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+    }
+
+    /// Convert a signed modification time to SystemTime.
+    ///
+    /// # Arguments
+    /// * `secs` - Seconds since the epoch. Negative for times before 1970.
+    /// * `nsecs` - Nanoseconds part of the time.
+    fn to_system_time(secs: i64, nsecs: u32) -> SystemTime {
+        let nsecs = Duration::from_nanos(nsecs as u64);
+        if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64) + nsecs
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nsecs
+        }
+    }
+
+    /// Convert SystemTime to a signed modification time.
+    ///
+    /// # Arguments
+    /// * `time` - The time.
+    /// * `returns` - Seconds since the epoch, and the nanoseconds part.
+    fn from_system_time(time: SystemTime) -> (i64, u32) {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+            Err(e) => {
+                let d = e.duration();
+                match d.subsec_nanos() {
+                    0 => (-(d.as_secs() as i64), 0),
+                    n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+                }
+            }
+        }
+    }
+
+    /// Fetch FUSE attributes for an inode. Owner ids are shifted.
+    ///
+    /// # Arguments
+    /// * `ino` - Number of the inode.
+    /// * `inode` - The inode.
+    fn inode_to_attr(&self, ino: u64, inode: &Inode) -> FileAttr {
+        let mtime = CcFs::to_system_time(inode.mtime, inode.mtime_nsec);
+        let size = match &inode.typeflag {
+            // Show directory size as 4096
+            index::FileType::Directory => 4096,
+            index::FileType::SymLink => {
+                if let Some(e) = &inode.extra {
+                    e.link.len() as u64
+                } else {
+                    panic!("empty link")
+                }
+            }
+            _ => inode.size,
+        };
+        FileAttr {
+            ino: ino,
+            size: size,
+            blocks: size / 4096,
+            atime: mtime,
+            mtime: mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: CcFs::to_file_type(&inode.typeflag),
+            perm: inode.mode as u16,
+            nlink: inode.links,
+            uid: self.ids.uid(inode.uid),
+            gid: self.ids.gid(inode.gid),
+            rdev: CcFs::encode_dev(inode.device()),
+            flags: 0, // MacOS only
+            blksize: 4096,
+        }
+    }
+}
+
+impl Filesystem for CcFs {
+    /// Initialize the file-system.
+    ///
+    /// Called when the FUSE handshake with the kernel completes.
+    fn init(
+        &mut self,
+        _req: &Request,
+        _config: &mut KernelConfig,
+    ) -> Result<(), c_int> {
+        // Signal readiness.
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(());
+        }
+        Ok(())
+    }
+
+    /// Clean up the file-system on unmount.
+    fn destroy(&mut self) {
+        self.write_stats();
+    }
+
+    /// Forget lookups of an inode. Batches of forgets are passed here one
+    /// inode at a time.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The number of the inode.
+    /// * `nlookup` - The number of lookups to forget.
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        if let Some(lookups) = self.lookups.get_mut(&ino) {
+            *lookups = lookups.saturating_sub(nlookup);
+            if *lookups == 0 {
+                self.lookups.remove(&ino);
+                self.collect(ino);
+            }
+        }
+    }
+
+    /// Report statistics of the file-system, e.g. for df.
+    ///
+    /// Blocks are 4096 bytes. The used blocks hold the regular files of the
+    /// index, and the files are its inodes. Nothing is free without an upper
+    /// layer. With an upper layer, the free space of its store is reported,
+    /// and its changes count as used.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `_ino` - The number of an inode. Unused.
+    /// * `reply` - The ReplyStatfs to populate.
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        // The first inode is a placeholder.
+        let files = self.index.inodes.len() as u64 - 1;
+        let (used, files, free, free_files) = match &self.upper {
+            None => (self.blocks, files, 0, 0),
+            Some(upper) => {
+                let free = match upper.free_blocks() {
+                    Ok(free) => free,
+                    Err(e) => {
+                        reply.error(CcFs::errno(e));
+                        return;
+                    }
+                };
+                // Inodes are only limited by memory.
+                let (blocks, created) = upper.usage();
+                let files = files + created;
+                (self.blocks + blocks, files, free, u32::MAX as u64 - files)
+            }
+        };
+        reply.statfs(
+            used + free,
+            free,
+            free,
+            files + free_files,
+            free_files,
+            4096,
+            MAX_NAME_LENGTH,
+            4096,
+        );
+    }
+
+    /// Lookup a child with given name in the parent inode.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the child.
+    /// * `reply` - The ReplyEntry to populate.
+    fn lookup(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        // Enforce name length.
+        if name.len() > MAX_NAME_LENGTH as usize {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+
+        // Ensure that name is a valid string.
+        let name = match name.to_str() {
+            Some(s) => s.to_string(),
+            _ => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // TODO: Handle `.` and `..`.
+
+        // Search for the child in the upper layer, then in the lower
+        // layers. Hard links are resolved.
+        match self.child(parent, &name).and_then(|ino| self.inode(ino)) {
+            Some((ino, child)) => {
+                // Return data to FUSE.
+                let attr = self.inode_to_attr(ino, child);
+                self.looked_up(ino);
+                reply.entry(&self.entry_timeout, &attr, 0);
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    /// Get the attributes of a given inode.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - Number of the inode.
+    /// * `reply` - The ReplyAttr to populate.
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        // Resolve hard-links.
+        // TODO: This can likely be removed since the inode number of the link
+        // is never passed to FUSE.
+        match self.inode(ino) {
+            // Return the attributes of the inode.
+            Some((ino, inode)) => {
+                reply.attr(&self.attr_timeout, &self.inode_to_attr(ino, inode))
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    /// Check the permissions of the requesting process on an inode.
+    ///
+    /// Permissions are checked against the shown mode and owners of the
+    /// inode, as the kernel does with `DefaultPermissions`. Root may read and
+    /// write anything, and execute files with any execute bit set. The group
+    /// bits apply to members of the group, including supplementary ones.
+    ///
+    /// # Arguments
+    /// * `req` - Request object. Holds the ids of the process.
+    /// * `ino` - The number of the inode.
+    /// * `mask` - The permissions to check, `F_OK` or an or of `R_OK`,
+    ///   `W_OK` and `X_OK`.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn access(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mask: i32,
+        reply: ReplyEmpty,
+    ) {
+        let attr = match self.inode(ino) {
+            Some((ino, inode)) => self.inode_to_attr(ino, inode),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        if mask & libc::W_OK != 0 && self.upper.is_none() {
+            reply.error(EROFS);
+            return;
+        }
+
+        let mask = mask as u16 & 0o7;
+        let allowed = if req.uid() == 0 {
+            mask & libc::X_OK as u16 == 0
+                || matches!(attr.kind, FileType::Directory)
+                || attr.perm & 0o111 != 0
+        } else {
+            let bits = if req.uid() == attr.uid {
+                attr.perm >> 6
+            } else if CcFs::in_group(req, attr.gid) {
+                attr.perm >> 3
+            } else {
+                attr.perm
+            };
+            mask & !bits == 0
+        };
+        match allowed {
+            true => reply.ok(),
+            false => reply.error(EACCES),
+        }
+    }
+
+    /// Open a directory. Its entries are snapshotted into a handle, so that
+    /// they stay stable while the directory is read.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The inode number of the directory.
+    /// * `_flags` - Flags to open. Unused.
+    /// * `reply` - The ReplyOpen to populate.
+    fn opendir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _flags: i32,
+        reply: ReplyOpen,
+    ) {
+        match self.dir_handle(ino) {
+            Ok(handle) => {
+                let fh = self.next_file_handle;
+                self.next_file_handle += 1;
+                self.dir_handles.insert(fh, handle);
+
+                // Let the kernel cache the listing of read-only directories.
+                let flags = match self.keep_cache {
+                    true => FOPEN_CACHE_DIR,
+                    false => 0,
+                };
+                reply.opened(fh, flags);
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Read the contents of a given directory.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The inode number of the directoy.
+    /// * `fh` - The file handle of the directory. Directories read without
+    ///   a handle are snapshotted on every call.
+    /// * `offset` - A hint supplied to FUSE in previous readdir call.
+    /// * `reply` - The ReplyDirectory to populate.
+    ///
+    /// The entries of the directory may not be read in a single readdir call.
+    /// The strategy is to provide the `offset` of next child along with each
+    /// child  until the buffer is full or there are no more children.
+    /// The next readdir will be called back with the offset of the next child
+    /// to read.
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let snapshot;
+        let handle = match self.dir_handles.get(&fh) {
+            Some(handle) => handle,
+            None => match self.dir_handle(ino) {
+                Ok(handle) => {
+                    snapshot = handle;
+                    &snapshot
+                }
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                }
+            },
+        };
+
+        // Populate `.` and `..`.
+        if offset <= 2 {
+            let _ = reply.add(ino, 2, FileType::Directory, ".");
+            let parent = self.parent_of(ino);
+            let _ = reply.add(parent, 3, FileType::Directory, "..");
+        }
+
+        // Loop through the entries. Begin processing only after specified
+        // offset has been reached.
+        let count = match handle {
+            DirHandle::Range(_, count) => *count,
+            DirHandle::Entries(entries) => entries.len() as u64,
+        };
+        for i in 0..count {
+            let o = i as i64 + 2;
+            if o < offset {
+                continue;
+            }
+            let (child_ino, name) = match handle {
+                DirHandle::Range(first, _) => {
+                    (first + i, &self.index.inodes[(first + i) as usize].name)
+                }
+                DirHandle::Entries(entries) => {
+                    (entries[i as usize].1, &entries[i as usize].0)
+                }
+            };
+            // Entries removed since the directory was opened are skipped.
+            let kind = match self.inode(child_ino) {
+                Some((_, child)) => CcFs::to_file_type(&child.typeflag),
+                None => continue,
+            };
+            // Try adding the child node.
+            if reply.add(child_ino, o + 1, kind, name) {
+                // Failure indicates that the buffer is full.
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    /// Release an open directory.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `_ino` - The inode number of the directory. Unused.
+    /// * `fh` - The file handle of the directory.
+    /// * `_flags` - Flags of the handle. Unused.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn releasedir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    /// Read a link.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The inode number of the link.
+    /// * `reply` - The ReplyData to populate.
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        // Ensure that the ino is valid.
+        let inode = match self.inode(ino) {
+            Some((_, inode)) => inode,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // Check whether the inode is a symlink.
+        if let index::FileType::SymLink = inode.typeflag {
+            match &inode.extra {
+                Some(e) => {
+                    // Write out the link target as-is.
+                    reply.data(&e.link.as_bytes());
+                    return;
+                }
+                _ => (),
+            };
+        }
+        reply.error(ENOENT);
+    }
+
+    /// Get the value of an extended attribute of an inode.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The number of the inode.
+    /// * `name` - Name of the attribute.
+    /// * `size` - Size of the caller's buffer. Zero asks for the size only.
+    /// * `reply` - The ReplyXattr to populate.
+    fn getxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let xattrs = match self.xattrs(ino) {
+            Some(xattrs) => xattrs,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        match xattrs.iter().find(|(n, _)| name == n.as_str()) {
+            Some((_, value)) => CcFs::reply_xattr(value, size, reply),
+            None => reply.error(ENODATA),
+        }
+    }
+
+    /// List the names of the extended attributes of an inode.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The number of the inode.
+    /// * `size` - Size of the caller's buffer. Zero asks for the size only.
+    /// * `reply` - The ReplyXattr to populate.
+    fn listxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let xattrs = match self.xattrs(ino) {
+            Some(xattrs) => xattrs,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // The names are separated by nulls.
+        let mut names = vec![];
+        for (name, _) in xattrs {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        CcFs::reply_xattr(&names, size, reply);
+    }
+
+    /// Open a given inode.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The number of the inode.
+    /// * `flags` - Flags to open. Unused.
+    /// * `reply` - The ReplyData to populate.
+    fn open(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _flags: i32,
+        reply: ReplyOpen,
+    ) {
+        // Ensure that the inode is valid.
+        let ino_usize = ino as usize;
+        if self.inode(ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+
+        // Enforce the limit on open files.
+        if self.max_open_files.is_some_and(|m| self.open_files >= m) {
+            reply.error(EMFILE);
+            return;
+        }
+
+        if let Some(upper) = &mut self.upper {
+            upper.opened(ino);
+        }
+
+        // Unless writable or disabled, since file-system is read only, ask
+        // that the kernel does not flush the cache on every open. Direct I/O
+        // bypasses the cache altogether.
+        let open_flags = match (self.direct_io, self.keep_cache) {
+            (true, _) => FOPEN_DIRECT_IO,
+            (false, true) => FOPEN_KEEP_CACHE,
+            (false, false) => 0,
+        };
+
+        // Files of the upper layer are not verified.
+        let copied = self.upper.as_ref().and_then(|u| u.node(ino));
+        if copied.is_some_and(|n| n.data.is_some())
+            || ino_usize >= self.index.inodes.len()
+        {
+            reply.opened(self.next_file_handle, open_flags);
+            self.next_file_handle += 1;
+            self.open_files += 1;
+            self.stats.opens += 1;
+            return;
+        }
+
+        // Fail opens of files that failed verification.
+        if self.poisoned.contains(&ino) {
+            reply.error(EIO);
+            return;
+        }
+
+        // Keep the verified contents of files verified as a whole, or
+        // verified on open, while they are open.
+        let inode = &self.index.inodes[ino_usize];
+        let regular = matches!(inode.typeflag, index::FileType::RegularFile);
+        let on_open = regular && self.verify_policy == VerifyPolicy::OnOpen;
+        let keep = on_open || regular && inode.granularity == Granularity::File;
+
+        // Verify the whole file unless its verified contents are kept
+        // already.
+        let cached =
+            self.whole_files.get(&ino).and_then(|f| f.contents.as_ref());
+        if on_open && cached.is_none() {
+            if self.modified.is_some() {
+                reply.error(EIO);
+                return;
+            }
+            let (buf, result) = match self.read_whole(inode) {
+                Ok(r) => r,
+                Err(e) => {
+                    self.read_failed(inode.layer, e);
+                    reply.error(EIO);
+                    return;
+                }
+            };
+            if let Err(page_num) = result {
+                reply.error(self.verification_failed(ino_usize, page_num));
+                return;
+            }
+            self.stats.verified_pages += buf.len().div_ceil(4096) as u64;
+            self.whole_files.entry(ino).or_default().contents = Some(buf);
+        }
+        if keep {
+            self.whole_files.entry(ino).or_default().handles += 1;
+        }
+
+        // Generate a new handle number and return it.
+        // TODO: Handle cc-passthrough scenario.
+        reply.opened(self.next_file_handle, open_flags);
+        self.next_file_handle += 1;
+        self.open_files += 1;
+        self.stats.opens += 1;
+    }
+
+    /// Release an open file.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The inode number of the file.
+    /// * `_fh` - File handle. Unused.
+    /// * `_flags` - Ignored.
+    /// * `_lock_owner` - Ignored.
+    /// * `_flush` - Ignored.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        if let Some(file) = self.whole_files.get_mut(&ino) {
+            file.handles -= 1;
+            if file.handles == 0 {
+                self.whole_files.remove(&ino);
+            }
+        }
+        if let Some(upper) = &mut self.upper {
+            upper.released(ino);
+        }
+        self.collect(ino);
+        self.open_files = self.open_files.saturating_sub(1);
+        reply.ok();
+    }
+
+    /// Read bytes from given inode.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The inode number of the file.
+    /// * `_fh` - File handle. Unused.
+    /// * `offset` - The offset to read from.
+    /// * `size` - Number of bytes to read.
+    /// * `_flags` - Ignored.
+    /// * `_lock_owner` - Ignored.
+    /// * `reply` - The ReplyData to populate.
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        // Contents copied up to the upper layer are read from there.
+        let node = self.upper.as_ref().and_then(|u| u.node(ino));
+        if let Some(Node {
+            inode,
+            data: Some(data),
+            ..
+        }) = node
+        {
+            match data.read(offset as u64, size as u64, inode.size) {
+                Ok(buf) => {
+                    reply.data(&buf);
+                    self.stats.record_read(ino, buf.len() as u64);
+                }
+                Err(e) => reply.error(CcFs::errno(e)),
+            }
+            return;
+        }
+
+        // Ensure that the inode is valid.
+        let ino_usize = ino as usize;
+        if ino_usize >= self.index.inodes.len() {
+            reply.error(ENOENT);
+            return;
+        }
+
+        // Fail reads once the backing store is known to be modified, or the
+        // file failed verification.
+        if self.modified.is_some() || self.poisoned.contains(&ino) {
+            reply.error(EIO);
+            return;
+        }
+
+        // Ensure the the inode is a regular file.
+        let inode = &self.index.inodes[ino_usize];
+        match inode.typeflag {
+            index::FileType::RegularFile => (),
+            _ => {
+                reply.error(ENOENT);
+                return;
+            }
+        }
+
+        // Compute the end offset clipped to file size.
+        let end = min(offset + size as i64, inode.size as i64);
+
+        // Holes of sparse files are not stored. Read the stored contents
+        // covering the range instead, and fill in the holes when replying.
+        let range = min(offset, end) as u64..end as u64;
+        let (offset, end) = (
+            inode.stored_offset(offset as u64) as i64,
+            inode.stored_offset(end as u64) as i64,
+        );
+
+        // Serve files whose verified contents are kept from those contents.
+        let cached =
+            self.whole_files.get(&ino).and_then(|f| f.contents.as_ref());
+        if let Some(contents) = cached {
+            let data = CcFs::fill_holes(
+                inode,
+                &range,
+                &contents[min(offset, end) as usize..end as usize],
+            );
+            reply.data(&data);
+            self.stats.record_read(ino, data.len() as u64);
+            return;
+        }
+
+        // Range aligned to page boundaries, so that whole pages are
+        // verified even for reads smaller than a page. Files verified as a
+        // whole are read in full.
+        let stored_size = inode.stored_size() as i64;
+        let (start, read_end) = match inode.granularity {
+            Granularity::Page => (
+                (offset / 4096) * 4096,
+                min((end + 4095) / 4096 * 4096, stored_size),
+            ),
+            Granularity::File => (0, stored_size),
+        };
+
+        // Bytes to read.
+        let bytes = read_end - start;
+
+        // Buffer size. Aligned to 512 byte-boundary.
+        let buf_size = (bytes + 511) / 512 * 512;
+
+        // Offset within tar.
+        let tar_offset = inode.offset as u64 * 512 + start as u64;
+
+        // Read bytes from the tar file of the layer of the inode. Mapped
+        // pages are copied including the padding that follows the file in
+        // the tar file.
+        let layer = &self.layers[inode.layer as usize];
+        let mut buf = vec![0u8; buf_size as usize];
+        let read = match &layer.map {
+            Some(map) => CcFs::copy_mapped(map, tar_offset, &mut buf),
+            None => {
+                let part = &mut buf[0..bytes as usize];
+                self.read_stored(layer, inode, part, start as u64)
+            }
+        };
+        if let Err(e) = read {
+            self.read_failed(inode.layer, e);
+            reply.error(EIO);
+            return;
+        }
+
+        // Verify the pages, or the whole file, before replying. With the
+        // verify-once policy, pages verified by an earlier read are trusted.
+        let pages = buf.len().div_ceil(4096) as u64;
+        let first_page = (start / 4096) as u32 + inode.hash_index;
+        let num_states = match inode.granularity {
+            Granularity::Page => pages as u32,
+            Granularity::File => 1,
+        };
+        let trusted = self.verify_policy == VerifyPolicy::Once
+            && layer.is_verified(first_page, num_states);
+        let shared;
+        let (buf, result) = match (inode.granularity, &self.pool) {
+            _ if trusted => (&buf[..], Ok(())),
+            (Granularity::File, _) => {
+                let ok = matches!(
+                    layer.hasher.verify_range(
+                        Some(first_page),
+                        &buf,
+                        first_page + 1,
+                    ),
+                    Ok(true)
+                );
+                (&buf[..], if ok { Ok(()) } else { Err(first_page) })
+            }
+            (_, Some(pool)) => {
+                shared = Arc::new(buf);
+                let result =
+                    pool.verify(&layer.hasher, first_page, shared.clone());
+                (&shared[..], result)
+            }
+            (_, None) => {
+                (&buf[..], layer.hasher.verify_pages(first_page, &buf))
+            }
+        };
+        if let Err(page_num) = result {
+            reply.error(self.verification_failed(ino_usize, page_num));
+            return;
+        }
+        if inode.granularity == Granularity::File {
+            if let Some(file) = self.whole_files.get_mut(&ino) {
+                file.contents = Some(buf.to_vec());
+            }
+        }
+
+        // Send the verified bytes.
+        let data = CcFs::fill_holes(
+            inode,
+            &range,
+            &buf[(offset - start) as usize..(end - start) as usize],
+        );
+        reply.data(&data);
+        self.stats.record_read(ino, data.len() as u64);
+
+        let layer = inode.layer as usize;
+        if trusted {
+            self.stats.trusted_pages += pages;
+        } else {
+            self.stats.verified_pages += pages;
+            if self.verify_policy == VerifyPolicy::Once {
+                self.layers[layer].set_verified(first_page, num_states);
+            }
+        }
+    }
+
+    /// Set the attributes of an inode in the upper layer.
+    ///
+    /// Changing the size copies the contents of a file up from the lower
+    /// layers. Access times are not kept.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The number of the inode.
+    /// * `mode` - New permission bits.
+    /// * `uid` - New owner.
+    /// * `gid` - New group.
+    /// * `size` - New size of a regular file.
+    /// * `mtime` - New modification time.
+    /// * `reply` - The ReplyAttr to populate.
+    /// * Other arguments are ignored.
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        if self.upper.is_none() {
+            reply.error(EROFS);
+            return;
+        }
+        // Nothing is copied up unless changed.
+        let changed = mode.is_some()
+            || uid.is_some()
+            || gid.is_some()
+            || size.is_some()
+            || mtime.is_some();
+        if !changed {
+            self.getattr(_req, ino, reply);
+            return;
+        }
+        // Owners are stored unshifted.
+        let (uid, gid) = match (
+            uid.map(|uid| self.ids.stored_uid(uid)),
+            gid.map(|gid| self.ids.stored_gid(gid)),
+        ) {
+            (Some(None), _) | (_, Some(None)) => {
+                reply.error(EOVERFLOW);
+                return;
+            }
+            (uid, gid) => (uid.flatten(), gid.flatten()),
+        };
+        let result = match size {
+            Some(_) => self.copy_up_data(ino),
+            None => self.copy_up(ino),
+        };
+        let ino = match result {
+            Ok(ino) => ino,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+        let node = match self.node_mut(ino) {
+            Ok(node) => node,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        if let Some(size) = size {
+            match &mut node.data {
+                Some(data) => {
+                    if let Err(e) = data.set_len(size) {
+                        reply.error(CcFs::errno(e));
+                        return;
+                    }
+                }
+                None if matches!(
+                    node.inode.typeflag,
+                    index::FileType::Directory
+                ) =>
+                {
+                    reply.error(EISDIR);
+                    return;
+                }
+                None => {
+                    reply.error(EINVAL);
+                    return;
+                }
+            }
+            node.inode.size = size;
+            (node.inode.mtime, node.inode.mtime_nsec) =
+                CcFs::from_system_time(SystemTime::now());
+        }
+        if let Some(mode) = mode {
+            node.inode.mode = mode & 0o7777;
+        }
+        if let Some(uid) = uid {
+            node.inode.uid = uid;
+        }
+        if let Some(gid) = gid {
+            node.inode.gid = gid;
+        }
+        match mtime {
+            Some(TimeOrNow::SpecificTime(time)) => {
+                (node.inode.mtime, node.inode.mtime_nsec) =
+                    CcFs::from_system_time(time)
+            }
+            Some(TimeOrNow::Now) => {
+                (node.inode.mtime, node.inode.mtime_nsec) =
+                    CcFs::from_system_time(SystemTime::now())
+            }
+            None => (),
+        }
+        let inode = node.inode.clone();
+        reply.attr(&self.attr_timeout, &self.inode_to_attr(ino, &inode));
+    }
+
+    /// Create a regular file, device or named pipe in the upper layer.
+    ///
+    /// # Arguments
+    /// * `req` - Request creating the inode.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the new entry.
+    /// * `mode` - File type and permission bits.
+    /// * `umask` - Permission bits to clear.
+    /// * `rdev` - Device number of a device.
+    /// * `reply` - The ReplyEntry to populate.
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        if self.upper.is_none() {
+            reply.error(EROFS);
+            return;
+        }
+        let typeflag = match mode & libc::S_IFMT {
+            libc::S_IFREG => index::FileType::RegularFile,
+            libc::S_IFCHR => index::FileType::CharDevice,
+            libc::S_IFBLK => index::FileType::BlockDevice,
+            libc::S_IFIFO => index::FileType::Fifo,
+            // Sockets cannot be stored in tar files.
+            _ => {
+                reply.error(EPERM);
+                return;
+            }
+        };
+
+        // Decode the device number encoded by the kernel.
+        let device = (
+            (rdev >> 8) & 0xfff,
+            (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+        );
+        let extra = match device {
+            (0, 0) => None,
+            _ => Some(Extra {
+                device: Some(device),
+                ..Extra::default()
+            }),
+        };
+        let mode = mode & !umask;
+        match self.create_node(req, parent, name, typeflag, mode, extra) {
+            Ok((ino, attr)) => {
+                self.looked_up(ino);
+                reply.entry(&self.entry_timeout, &attr, 0)
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Create a directory in the upper layer.
+    ///
+    /// # Arguments
+    /// * `req` - Request creating the directory.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the new directory.
+    /// * `mode` - Permission bits.
+    /// * `umask` - Permission bits to clear.
+    /// * `reply` - The ReplyEntry to populate.
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let typeflag = index::FileType::Directory;
+        match self.create_node(req, parent, name, typeflag, mode & !umask, None)
+        {
+            Ok((ino, attr)) => {
+                self.looked_up(ino);
+                reply.entry(&self.entry_timeout, &attr, 0)
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Create a symbolic link in the upper layer.
+    ///
+    /// # Arguments
+    /// * `req` - Request creating the link.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the new link.
+    /// * `link` - Target of the link.
+    /// * `reply` - The ReplyEntry to populate.
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let link = match link.to_str() {
+            Some(link) => link.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let extra = Some(Extra {
+            link,
+            ..Extra::default()
+        });
+        let typeflag = index::FileType::SymLink;
+        match self.create_node(req, parent, name, typeflag, 0o777, extra) {
+            Ok((ino, attr)) => {
+                self.looked_up(ino);
+                reply.entry(&self.entry_timeout, &attr, 0)
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Create and open a regular file in the upper layer.
+    ///
+    /// # Arguments
+    /// * `req` - Request creating the file.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the new file.
+    /// * `mode` - File type and permission bits.
+    /// * `umask` - Permission bits to clear.
+    /// * `_flags` - Flags to open. Unused.
+    /// * `reply` - The ReplyCreate to populate.
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        // Enforce the limit on open files.
+        if self.max_open_files.is_some_and(|m| self.open_files >= m) {
+            reply.error(EMFILE);
+            return;
+        }
+
+        let typeflag = index::FileType::RegularFile;
+        let mode = mode & !umask;
+        let (ino, attr) =
+            match self.create_node(req, parent, name, typeflag, mode, None) {
+                Ok(r) => r,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                }
+            };
+        if let Some(upper) = &mut self.upper {
+            upper.opened(ino);
+        }
+        self.looked_up(ino);
+        let open_flags = match self.direct_io {
+            true => FOPEN_DIRECT_IO,
+            false => 0,
+        };
+        reply.created(
+            &self.entry_timeout,
+            &attr,
+            0,
+            self.next_file_handle,
+            open_flags,
+        );
+        self.next_file_handle += 1;
+        self.open_files += 1;
+        self.stats.opens += 1;
+    }
+
+    /// Create a hard link in the upper layer.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `ino` - The number of the inode to link to.
+    /// * `newparent` - Inode number of the directory of the new link.
+    /// * `newname` - Name of the new link.
+    /// * `reply` - The ReplyEntry to populate.
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let result = (|| {
+            if self.upper.is_none() {
+                return Err(EROFS);
+            }
+            let name = CcFs::entry_name(newname)?;
+            let (ino, inode) = self.inode(ino).ok_or(ENOENT)?;
+            if let index::FileType::Directory = inode.typeflag {
+                return Err(EPERM);
+            }
+            if self.child(newparent, name).is_some() {
+                return Err(EEXIST);
+            }
+            self.add_links(ino, 1)?;
+            if let Some(upper) = &mut self.upper {
+                upper.set_entry(newparent, name, Some(ino));
+            }
+            Ok(ino)
+        })();
+        match result.map(|ino| self.inode(ino)) {
+            Ok(Some((ino, inode))) => {
+                let attr = self.inode_to_attr(ino, inode);
+                self.looked_up(ino);
+                reply.entry(&self.entry_timeout, &attr, 0)
+            }
+            Ok(None) => reply.error(ENOENT),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Remove a file from a directory.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the entry.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn unlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        match self.remove(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
     }
-}
 
-/// Time to retain lookups for.
-/// Larger values result in faster file-system performance.
-/// Default value is 1 seconds, consistent with libfuse.
-const TTL: Duration = Duration::new(1, 0);
-
-impl Filesystem for CcFs {
-    /// Lookup a child with given name in the parent inode.
+    /// Remove an empty directory.
     ///
     /// # Arguments
     /// * `_req` - Request object. Unused.
     /// * `parent` - Inode number of the parent directory.
-    /// * `name` - Name of the child.
-    /// * `reply` - The ReplyEntry to populate.
-    fn lookup(
+    /// * `name` - Name of the directory.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn rmdir(
         &mut self,
         _req: &Request,
         parent: u64,
         name: &OsStr,
-        reply: ReplyEntry,
+        reply: ReplyEmpty,
     ) {
-        // Enforce name length.
-        if name.len() > MAX_NAME_LENGTH as usize {
-            reply.error(ENAMETOOLONG);
-            return;
-        }
-
-        // Check that the parent is valid.
-        let parent_usize = parent as usize;
-        if parent_usize >= self.index.inodes.len() {
-            reply.error(ENOENT);
-            return;
+        match self.remove(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
+    }
 
-        // Ensure that name is a valid string.
-        let name = match name.to_str() {
-            Some(s) => s.to_string(),
-            _ => {
-                reply.error(ENOENT);
-                return;
+    /// Rename an entry, replacing the entry at the new name.
+    ///
+    /// As with overlayfs, directories of the lower layers cannot be renamed.
+    /// The rename fails with EXDEV, upon which tools such as `mv` copy the
+    /// directory instead.
+    ///
+    /// # Arguments
+    /// * `_req` - Request object. Unused.
+    /// * `parent` - Inode number of the parent directory.
+    /// * `name` - Name of the entry.
+    /// * `newparent` - Inode number of the new parent directory.
+    /// * `newname` - New name of the entry.
+    /// * `flags` - Flags of renameat2. Only RENAME_NOREPLACE is supported.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let result = (|| {
+            if self.upper.is_none() {
+                return Err(EROFS);
+            }
+            if flags & !libc::RENAME_NOREPLACE != 0 {
+                return Err(EINVAL);
+            }
+            let name = name.to_str().ok_or(ENOENT)?;
+            let newname = CcFs::entry_name(newname)?;
+            let child = self.child(parent, name).ok_or(ENOENT)?;
+            let (ino, inode) = self.inode(child).ok_or(ENOENT)?;
+            let is_dir = matches!(inode.typeflag, index::FileType::Directory);
+            if is_dir && self.upper.as_ref().is_some_and(|u| !u.is_new(ino)) {
+                return Err(EXDEV);
             }
-        };
-
-        // TODO: Handle `.` and `..`.
-
-        // Fetch the parent node, and the starting and ending indices of
-        // children.
-        let inode = &self.index.inodes[parent_usize];
-        let child_start = inode.child_inode as usize;
-        let child_end = child_start + inode.num_children as usize;
 
-        // Search for node within given name in the set of children.
-        let children = &self.index.inodes[child_start..child_end];
-        match children.binary_search_by(|a| a.name.cmp(&name)) {
-            Ok(idx) => {
-                let mut child_ino = (child_start + idx) as u32;
-                // If the child node is a hard-link, resolve it.
-                let resolved_ino = self.index.get_hard_link_target(child_ino);
-
-                // A hard-link and its target must share the same inode.
-                // Therefore, for hard-link, use the target's inode number as
-                // well as the inode object.
-                let child = if resolved_ino > 0 {
-                    child_ino = resolved_ino;
-                    &self.index.inodes[resolved_ino as usize]
-                } else {
-                    &self.index.inodes[child_ino as usize]
-                };
+            // Replace the entry at the new name.
+            if let Some(old) = self.child(newparent, newname) {
+                let (old, inode) = self.inode(old).ok_or(ENOENT)?;
+                if old == ino {
+                    return Ok(());
+                }
+                if flags & libc::RENAME_NOREPLACE != 0 {
+                    return Err(EEXIST);
+                }
+                match (is_dir, &inode.typeflag) {
+                    (true, index::FileType::Directory) => {
+                        if !self.entries(old).is_empty() {
+                            return Err(ENOTEMPTY);
+                        }
+                    }
+                    (true, _) => return Err(ENOTDIR),
+                    (false, index::FileType::Directory) => return Err(EISDIR),
+                    (false, _) => (),
+                }
+                self.remove_entry(newparent, newname);
+                self.drop_link(newparent, old)?;
+            }
 
-                // Return data to FUSE.
-                let attr = CcFs::inode_to_attr(child_ino as u64, child);
-                reply.entry(&TTL, &attr, 0);
-                return;
+            self.remove_entry(parent, name);
+            if let Some(upper) = &mut self.upper {
+                upper.set_entry(newparent, newname, Some(child));
+                if let Some(node) = upper.node_mut(ino) {
+                    node.inode.name = newname.to_string();
+                    if is_dir {
+                        node.parent = newparent;
+                    }
+                }
             }
-            _ => (),
+            if is_dir && parent != newparent {
+                self.add_links(parent, -1)?;
+                self.add_links(newparent, 1)?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-        reply.error(ENOENT);
     }
 
-    /// Get the attributes of a given inode.
+    /// Write to a regular file in the upper layer. The contents of the file
+    /// are copied up from the lower layers first.
     ///
     /// # Arguments
     /// * `_req` - Request object. Unused.
-    /// * `ino` - Number of the inode.
-    /// * `reply` - The ReplyAttr to populate.
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        // Resolve hard-links.
-        // TODO: This can likely be removed since the inode number of the link
-        // is never passed to FUSE.
-        let ino = self.index.get_hard_link_target(ino as u32) as u64;
-        let ino_usize = ino as usize;
-
-        // Ensure valid index.
-        if ino_usize >= self.index.inodes.len() {
-            reply.error(ENOENT);
-            return;
+    /// * `ino` - The inode number of the file.
+    /// * `_fh` - File handle. Unused.
+    /// * `offset` - The offset to write at.
+    /// * `data` - Bytes to write.
+    /// * `_write_flags` - Ignored.
+    /// * `_flags` - Ignored.
+    /// * `_lock_owner` - Ignored.
+    /// * `reply` - The ReplyWrite to populate.
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let result = (|| {
+            let ino = self.copy_up_data(ino)?;
+            let node = self.node_mut(ino)?;
+            let contents = node.data.as_mut().ok_or(EINVAL)?;
+            contents.write(offset as u64, data).map_err(CcFs::errno)?;
+            node.inode.size =
+                node.inode.size.max(offset as u64 + data.len() as u64);
+            (node.inode.mtime, node.inode.mtime_nsec) =
+                CcFs::from_system_time(SystemTime::now());
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(e),
         }
-
-        // Return the attributes of the inode.
-        let inode = &self.index.inodes[ino_usize];
-        reply.attr(&TTL, &CcFs::inode_to_attr(ino, &inode))
     }
 
-    /// Read the contents of a given directory.
+    /// Allocate space for a regular file in the upper layer. The file is
+    /// extended with zeros unless `FALLOC_FL_KEEP_SIZE` is given. Other modes,
+    /// such as punching holes, are not supported.
     ///
     /// # Arguments
     /// * `_req` - Request object. Unused.
-    /// * `ino` - The inode number of the directoy.
-    /// * `_fh` - The file handle of the directory. Unused.
-    /// * `offset` - A hint supplied to FUSE in previous readdir call.
-    /// * `reply` - The ReplyDirectory to populate.
-    ///
-    /// The entries of the directory may not be read in a single readdir call.
-    /// The strategy is to provide the `offset` of next child along with each
-    /// child  until the buffer is full or there are no more children.
-    /// The next readdir will be called back with the offset of the next child
-    /// to read.
-    fn readdir(
+    /// * `ino` - The inode number of the file.
+    /// * `_fh` - File handle. Unused.
+    /// * `offset` - Start of the range to allocate.
+    /// * `length` - Length of the range to allocate.
+    /// * `mode` - 0 or `FALLOC_FL_KEEP_SIZE`.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn fallocate(
         &mut self,
         _req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
     ) {
-        // Ensure valid inode number.
-        let ino_usize = ino as usize;
-        if ino_usize >= self.index.inodes.len() {
-            reply.error(ENOENT);
-            return;
-        }
-
-        // Populate `.` and `..`.
-        let inode = &self.index.inodes[ino_usize];
-        if offset <= 2 {
-            let _ = reply.add(ino, 2, FileType::Directory, ".");
-            match self.index.find(&inode.parent, 0, ino as usize) {
-                Ok(p) => reply.add(p as u64, 3, FileType::Directory, ".."),
-                _ => panic!("Could not find parent."),
-            };
-        }
-
-        // Loop through the child nodes. Begin processing only after specified
-        // offset has been reached.
-        for i in 0..inode.num_children as i64 {
-            let o = i + 2;
-            if o >= offset {
-                // Get the child inode.
-                let child_ino = inode.child_inode as usize + i as usize;
-                let child = &self.index.inodes[child_ino];
-                let kind = CcFs::to_file_type(&child.typeflag);
-                // Try adding the child node.
-                if reply.add(child_ino as u64, o + 1, kind, &child.name) {
-                    // Failure indicates that the buffer is full.
-                    break;
-                }
+        let result = (|| {
+            if self.upper.is_none() {
+                return Err(EROFS);
             }
+            match mode {
+                0 => (),
+                libc::FALLOC_FL_KEEP_SIZE => return Ok(()),
+                _ => return Err(EOPNOTSUPP),
+            }
+            let ino = self.copy_up_data(ino)?;
+            let node = self.node_mut(ino)?;
+            let contents = node.data.as_mut().ok_or(ENODEV)?;
+            let end = (offset + length) as u64;
+            if end > node.inode.size {
+                contents.set_len(end).map_err(CcFs::errno)?;
+                node.inode.size = end;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-
-        reply.ok();
     }
 
-    /// Read a link.
+    /// Copy a range of a file to another file. Ranges are copied by the
+    /// kernel, through read and write, instead.
     ///
     /// # Arguments
-    /// * `_req` - Request object. Unused.
-    /// * `ino` - The inode number of the link.
-    /// * `reply` - The ReplyData to populate.
-    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
-        // Ensure that the ino is valid.
-        let ino_usize = ino as usize;
-        if ino_usize >= self.index.inodes.len() {
-            reply.error(ENOENT);
-            return;
-        }
-
-        // Check whether the inode is a symlink.
-        let inode = &self.index.inodes[ino_usize];
-        if let index::FileType::SymLink = inode.typeflag {
-            match &inode.extra {
-                Some(e) => {
-                    // Write out the link target as-is.
-                    reply.data(&e.link.as_bytes());
-                    return;
-                }
-                _ => (),
-            };
+    /// * `reply` - The ReplyWrite to populate.
+    /// * Other arguments are ignored.
+    fn copy_file_range(
+        &mut self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match self.upper {
+            None => reply.error(EROFS),
+            Some(_) => reply.error(ENOSYS),
         }
-        reply.error(ENOENT);
     }
 
-    /// Open a given inode.
+    /// Set an extended attribute of an inode in the upper layer.
     ///
     /// # Arguments
     /// * `_req` - Request object. Unused.
     /// * `ino` - The number of the inode.
-    /// * `flags` - Flags to open. Unused.
-    /// * `reply` - The ReplyData to populate.
-    fn open(
+    /// * `name` - Name of the attribute.
+    /// * `value` - Value of the attribute.
+    /// * `flags` - XATTR_CREATE or XATTR_REPLACE, if any.
+    /// * `_position` - Ignored.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn setxattr(
         &mut self,
         _req: &Request,
         ino: u64,
-        _flags: i32,
-        reply: ReplyOpen,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
     ) {
-        // Ensure that the inode is valid.
-        let ino_usize = ino as usize;
-        if ino_usize >= self.index.inodes.len() {
-            reply.error(ENOENT);
-            return;
+        let result = (|| {
+            let name = name.to_str().ok_or(EINVAL)?;
+            let ino = self.copy_up(ino)?;
+            let node = self.node_mut(ino)?;
+            let xattrs =
+                &mut node.inode.extra.get_or_insert_with(Extra::default).xattrs;
+            let value = value.to_vec();
+            match xattrs.iter_mut().find(|(n, _)| n == name) {
+                Some(_) if flags & libc::XATTR_CREATE != 0 => Err(EEXIST),
+                Some((_, v)) => {
+                    *v = value;
+                    Ok(())
+                }
+                None if flags & libc::XATTR_REPLACE != 0 => Err(ENODATA),
+                None => {
+                    xattrs.push((name.to_string(), value));
+                    Ok(())
+                }
+            }
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-
-        // Since file-system is read only, ask that the kernel does not flush
-        // the cache on every open.
-        let open_flags = FOPEN_KEEP_CACHE;
-
-        // Generate a new handle number and return it.
-        // TODO: Handle cc-passthrough scenario.
-        let _inode = &self.index.inodes[ino_usize];
-        reply.opened(self.next_file_handle, open_flags);
-        self.next_file_handle += 1;
     }
 
-    /// Read bytes from given inode.
+    /// Remove an extended attribute of an inode in the upper layer.
     ///
     /// # Arguments
     /// * `_req` - Request object. Unused.
-    /// * `ino` - The inode number of the file.
-    /// * `_fh` - File handle. Unused.
-    /// * `offset` - The offset to read from.
-    /// * `size` - Number of bytes to read.
-    /// * `_flags` - Ignored.
-    /// * `_lock_owner` - Ignored.
-    /// * `reply` - The ReplyData to populate.
-    fn read(
+    /// * `ino` - The number of the inode.
+    /// * `name` - Name of the attribute.
+    /// * `reply` - The ReplyEmpty to populate.
+    fn removexattr(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
+        name: &OsStr,
+        reply: ReplyEmpty,
     ) {
-        // Ensure that the inode is valid.
-        let ino_usize = ino as usize;
-        if ino_usize >= self.index.inodes.len() {
-            reply.error(ENOENT);
-            return;
-        }
-
-        // Ensure the the inode is a regular file.
-        let inode = &self.index.inodes[ino_usize];
-        match inode.typeflag {
-            index::FileType::RegularFile => (),
-            _ => {
-                reply.error(ENOENT);
-                return;
+        let result = (|| {
+            if self.upper.is_none() {
+                return Err(EROFS);
             }
-        }
-
-        // Compute the end offset clipped to file size.
-        let end = min(offset + size as i64, inode.size as i64);
-
-        // Starting offset aligned to page boundary.
-        let start = (offset / 4096) * 4096;
-
-        // Bytes to read.
-        let bytes = end - start;
-
-        // Buffer size. Aligned to 512 byte-boundary.
-        let buf_size = (bytes + 511) / 512 * 512;
-        let mut buf = vec![0u8; buf_size as usize];
-
-        // Offset within tar.
-        let tar_offset = (inode.offset * 512 + start as u32) as u64;
-
-        // Read bytes.
-        let reader = &self.tar;
-        let slice = &mut buf[0..bytes as usize];
-        let _ = reader.read_exact_at(slice, tar_offset);
-
-        // Send read bytes.
-        reply.data(&slice[offset as usize % 4096..]);
-
-        // Verify the pages.
-        let mut page_num = start as u32 / 4096 + inode.hash_index;
-        let mut pos = 0;
-        while pos < buf.len() {
-            let len = min(buf.len() - pos, 4096);
-            match self.index.hasher.verify(page_num, &buf[pos..pos + len]) {
-                Ok(true) => (),
-                _ => panic!(
-                    "integrity verification failed for {:+?} at page_num {}",
-                    inode, page_num
-                ),
+            if !self
+                .xattrs(ino)
+                .ok_or(ENOENT)?
+                .iter()
+                .any(|(n, _)| name == n.as_str())
+            {
+                return Err(ENODATA);
+            }
+            let ino = self.copy_up(ino)?;
+            let node = self.node_mut(ino)?;
+            if let Some(extra) = &mut node.inode.extra {
+                extra.xattrs.retain(|(n, _)| name != n.as_str());
             }
-            page_num += 1;
-            pos += 4096;
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
     }
 }
@@ -409,21 +3087,48 @@ impl Filesystem for CcFs {
 /// Mount a Confidential Container file-system.
 ///
 /// # Arguments
-/// * `index` - Path of the index file.
-/// * `tar` - The tar file which will act as the backing store.
+/// * `indexes` - Paths of the index files of the layers, top layer first.
+/// * `tars` - The tar files which will act as the backing store, in the
+///   order of the indexes.
 /// * `mount_point` - The directory to mount to.
+/// * `options` - Mount options.
 ///
 /// Mount currently only supports tar backed file-system. It is not too much
 /// work to support a filtered passthrough file-system that will add integrity
 /// protection to an existing directory.
-pub fn mount(index: &String, tar: &String, mount_point: &String) -> Result<()> {
-    let options = vec![
+pub fn mount(
+    indexes: &[String],
+    tars: &[String],
+    mount_point: &String,
+    options: &MountOptions,
+) -> Result<()> {
+    // Confine all threads, including the watchdog, to the given CPUs.
+    if let Some(cpus) = &options.pin_cpus {
+        pool::pin_cpus(cpus)?;
+    }
+
+    // Start a watchdog that exits the process unless the file-system becomes
+    // ready in time.
+    let (ready, ready_rx) = channel();
+    if let Some(timeout) = options.timeout {
+        thread::spawn(move || {
+            if ready_rx.recv_timeout(Duration::from_secs(timeout)).is_err() {
+                eprintln!("Error: mount not ready within {} seconds", timeout);
+                process::exit(EXIT_MOUNT_TIMEOUT);
+            }
+        });
+    }
+
+    let fuse_options = vec![
         MountOption::FSName("cc-fs".to_string()),
         // Enable permission checking in the kernel.
         // This avoids having to implement permissions checking in the file-system.
         MountOption::DefaultPermissions,
-        // Read-only.
-        MountOption::RO,
+        // Read-only, unless writable through an upper layer.
+        match options.upper {
+            Some(_) => MountOption::RW,
+            None => MountOption::RO,
+        },
         // Honor set-user-id and set-groupd-id bits on files.
         MountOption::Suid,
         // Allow execution of binaries.
@@ -434,7 +3139,168 @@ pub fn mount(index: &String, tar: &String, mount_point: &String) -> Result<()> {
         MountOption::Async,
     ];
 
-    let tarfs = CcFs::new(index, tar)?;
-    fuser::mount2(tarfs, mount_point, &options)?;
+    // Commit targets are checked against the resolved mount point.
+    let mount_point = fs::canonicalize(mount_point)
+        .with_context(|| format!("failed to resolve {}", mount_point))?
+        .to_string_lossy()
+        .to_string();
+    if options.commit_socket.is_some() && options.upper.is_none() {
+        return Err(anyhow!("--commit-socket requires --upper"));
+    }
+    let mut tarfs = CcFs::new(indexes, tars, options)?;
+    tarfs.ready = Some(ready);
+    tarfs.mount_point = mount_point.clone();
+    let guard = Guard::new(tarfs, &mount_point, options.on_panic);
+
+    // Bound before mounting, so that the socket exists once the mount does.
+    // The mode only narrows who may connect; peers are checked regardless.
+    if let Some(socket) = &options.commit_socket {
+        let listener = UnixListener::bind(socket)
+            .with_context(|| format!("failed to bind {}", socket))?;
+        fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+        let tarfs = guard.shared();
+        let mount_point = mount_point.clone();
+        thread::spawn(move || serve_commits(listener, tarfs, mount_point));
+    }
+    let result = fuser::mount2(guard, &mount_point, &fuse_options);
+    if let Some(socket) = &options.commit_socket {
+        let _ = fs::remove_file(socket);
+    }
+    result?;
     Ok(())
 }
+
+/// User id of the process at the other end of a unix socket.
+///
+/// # Arguments
+/// * `stream` - The connected socket.
+fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(cred.uid)
+}
+
+/// Commit the upper layer to a tar file on request of a client.
+///
+/// The target must be absolute, without `..` components, and outside the
+/// mount. The daemon serves the requests of the mount itself, so creating the
+/// target in the mount would deadlock it. The device check also catches other
+/// mounts of the file-system, and is made before locking the file-system,
+/// since stat of the mount point is served by the FUSE loop.
+///
+/// # Arguments
+/// * `tarfs` - The file-system, shared with the FUSE loop.
+/// * `mount_point` - Resolved directory the file-system is mounted at.
+/// * `path` - Path of the tar file to write.
+fn commit_to(tarfs: &Mutex<CcFs>, mount_point: &str, path: &str) -> Result<()> {
+    let target = Path::new(path);
+    if !target.is_absolute()
+        || target.components().any(|c| c == Component::ParentDir)
+        || target.starts_with(mount_point)
+    {
+        return Err(anyhow!("invalid commit target {}", path));
+    }
+    let dir = target.parent().unwrap_or(Path::new("/"));
+    let dir_dev = fs::metadata(dir)
+        .with_context(|| format!("failed to stat {}", dir.display()))?
+        .dev();
+    if dir_dev == fs::metadata(mount_point)?.dev() {
+        return Err(anyhow!("cannot commit {} into the mount", path));
+    }
+    let mut tarfs = tarfs.lock().unwrap_or_else(|e| e.into_inner());
+    tarfs.commit(path)
+}
+
+/// Serve requests to commit the upper layer on a unix socket.
+///
+/// A client sends the absolute path of the tar file to write and closes its
+/// end for writing. The reply is "ok", or the error of the commit. Commits
+/// write files with the credentials of the daemon, and hence are only
+/// accepted from its user and root, as identified by the kernel for the
+/// socket. FUSE requests wait while a commit is in progress.
+///
+/// # Arguments
+/// * `listener` - The bound socket.
+/// * `tarfs` - The file-system, shared with the FUSE loop.
+/// * `mount_point` - Resolved directory the file-system is mounted at.
+fn serve_commits(
+    listener: UnixListener,
+    tarfs: Arc<Mutex<CcFs>>,
+    mount_point: String,
+) {
+    let uid = unsafe { libc::getuid() };
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let result = match peer_uid(&stream) {
+            Ok(peer) if peer == 0 || peer == uid => {
+                let mut path = String::new();
+                match stream.read_to_string(&mut path) {
+                    Ok(_) => commit_to(&tarfs, &mount_point, &path),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Ok(peer) => Err(anyhow!("user {} may not commit", peer)),
+            Err(e) => Err(e),
+        };
+        let reply = match result {
+            Ok(()) => String::from("ok\n"),
+            Err(e) => {
+                eprintln!("Error: commit failed: {:#}", e);
+                format!("{:#}\n", e)
+            }
+        };
+        let _ = stream.write_all(reply.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_are_read_from_status() {
+        let status = "Name:\tsh\nUid:\t1000\t1000\t1000\t1000\n\
+                      Gid:\t100\t100\t100\t100\nGroups:\t10 27 100 \n";
+        assert_eq!(CcFs::groups(status, 1000, 100), Some(vec![10, 27, 100]));
+
+        // The ids of another process, e.g. one that reused the pid.
+        assert_eq!(CcFs::groups(status, 1001, 100), None);
+        assert_eq!(CcFs::groups(status, 1000, 101), None);
+
+        // Only the file-system ids are compared.
+        let status = "Uid:\t0\t0\t0\t1000\nGid:\t0\t0\t0\t100\nGroups:\n";
+        assert_eq!(CcFs::groups(status, 1000, 100), Some(vec![]));
+        assert_eq!(CcFs::groups(status, 0, 0), None);
+    }
+
+    #[test]
+    fn groups_of_this_process() {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut expected = vec![0; 1024];
+        let n = unsafe {
+            libc::getgroups(expected.len() as i32, expected.as_mut_ptr())
+        };
+        expected.truncate(n as usize);
+        assert_eq!(CcFs::groups(&status, uid, gid), Some(expected));
+    }
+}
@@ -0,0 +1,519 @@
+//! Containment of panics in FUSE callbacks.
+//!
+//! All requests of a mount are served by a single FUSE loop. A panic in any
+//! callback would end the loop and leave the mount point wedged for every
+//! container sharing it. `Guard` wraps a file-system and runs each callback
+//! under `catch_unwind`. The reply of a panicking callback is dropped without
+//! being sent, upon which fuser replies with EIO. Each panic is logged as a
+//! JSON line on stderr.
+//!
+//! With `--on-panic unmount`, the file-system is instead lazily unmounted and
+//! the process exits after the first panic.
+use std::any::Any;
+use std::ffi::{CString, OsStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite,
+    ReplyXattr, Request, TimeOrNow,
+};
+use libc::{c_int, EIO};
+
+/// What to do after a callback panics.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnPanic {
+    /// Fail the request with EIO and keep serving.
+    Eio,
+
+    /// Unmount the file-system and exit.
+    Unmount,
+}
+
+/// Extract the message of a panic.
+///
+/// # Arguments
+/// * `payload` - Payload of the panic.
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(s) => s.clone(),
+            None => String::from("unknown panic"),
+        },
+    }
+}
+
+/// Quote a string for use in JSON.
+///
+/// # Arguments
+/// * `s` - The string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                quoted.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Lazily unmount a file-system and exit.
+///
+/// # Arguments
+/// * `mount_point` - Directory the file-system is mounted at.
+pub fn unmount(mount_point: &str) -> ! {
+    let detached = match CString::new(mount_point.as_bytes()) {
+        Ok(path) => unsafe {
+            libc::umount2(path.as_ptr(), libc::MNT_DETACH) == 0
+        },
+        Err(_) => false,
+    };
+
+    // Unprivileged mounts are unmounted using fusermount.
+    if !detached {
+        let _ = Command::new("fusermount")
+            .args(["-u", "-z", mount_point])
+            .status();
+    }
+    eprintln!(
+        "{{\"event\":\"unmount\",\"mount_point\":{}}}",
+        json_string(mount_point)
+    );
+    process::exit(1);
+}
+
+/// A file-system whose callbacks are protected against panics.
+pub struct Guard<F: Filesystem> {
+    /// The protected file-system. Shared with threads serving requests other
+    /// than FUSE callbacks, e.g. commits.
+    inner: Arc<Mutex<F>>,
+
+    /// Directory the file-system is mounted at.
+    mount_point: String,
+
+    /// What to do after a callback panics.
+    on_panic: OnPanic,
+}
+
+impl<F: Filesystem> Guard<F> {
+    /// Wrap a file-system.
+    ///
+    /// # Arguments
+    /// * `inner` - The file-system to protect.
+    /// * `mount_point` - Directory the file-system is mounted at.
+    /// * `on_panic` - What to do after a callback panics.
+    pub fn new(inner: F, mount_point: &String, on_panic: OnPanic) -> Guard<F> {
+        Guard {
+            inner: Arc::new(Mutex::new(inner)),
+            mount_point: mount_point.to_string(),
+            on_panic,
+        }
+    }
+
+    /// The protected file-system, for use outside FUSE callbacks. Callbacks
+    /// wait while it is locked.
+    pub fn shared(&self) -> Arc<Mutex<F>> {
+        self.inner.clone()
+    }
+
+    /// Run a callback of the protected file-system, containing panics.
+    ///
+    /// # Arguments
+    /// * `op` - Name of the callback. Used in logs.
+    /// * `f` - Function invoking the callback.
+    /// * `returns` - Result of the callback, or None if it panicked.
+    fn contain<R>(
+        &mut self,
+        op: &str,
+        f: impl FnOnce(&mut F) -> R,
+    ) -> Option<R> {
+        // Panics are caught while the lock is held, so it is never poisoned by
+        // a callback.
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut inner))) {
+            Ok(r) => Some(r),
+            Err(payload) => {
+                eprintln!(
+                    "{{\"event\":\"panic\",\"op\":\"{}\",\"message\":{}}}",
+                    op,
+                    json_string(&panic_message(&payload))
+                );
+                if self.on_panic == OnPanic::Unmount {
+                    unmount(&self.mount_point);
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<F: Filesystem> Filesystem for Guard<F> {
+    fn init(
+        &mut self,
+        req: &Request,
+        config: &mut KernelConfig,
+    ) -> Result<(), c_int> {
+        self.contain("init", |fs| fs.init(req, config))
+            .unwrap_or(Err(EIO))
+    }
+
+    fn destroy(&mut self) {
+        self.contain("destroy", |fs| fs.destroy());
+    }
+
+    fn lookup(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.contain("lookup", |fs| fs.lookup(req, parent, name, reply));
+    }
+
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.contain("forget", |fs| fs.forget(req, ino, nlookup));
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.contain("getattr", |fs| fs.getattr(req, ino, reply));
+    }
+
+    fn access(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mask: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("access", |fs| fs.access(req, ino, mask, reply));
+    }
+
+    fn opendir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        flags: i32,
+        reply: ReplyOpen,
+    ) {
+        self.contain("opendir", |fs| fs.opendir(req, ino, flags, reply));
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        self.contain("readdir", |fs| fs.readdir(req, ino, fh, offset, reply));
+    }
+
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        self.contain("statfs", |fs| fs.statfs(req, ino, reply));
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("releasedir", |fs| {
+            fs.releasedir(req, ino, fh, flags, reply)
+        });
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        self.contain("readlink", |fs| fs.readlink(req, ino, reply));
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.contain("open", |fs| fs.open(req, ino, flags, reply));
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("release", |fs| {
+            fs.release(req, ino, fh, flags, lock_owner, flush, reply)
+        });
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.contain("read", |fs| {
+            fs.read(req, ino, fh, offset, size, flags, lock_owner, reply)
+        });
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.contain("getxattr", |fs| fs.getxattr(req, ino, name, size, reply));
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.contain("listxattr", |fs| fs.listxattr(req, ino, size, reply));
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.contain("setattr", |fs| {
+            fs.setattr(
+                req, ino, mode, uid, gid, size, atime, mtime, ctime, fh,
+                crtime, chgtime, bkuptime, flags, reply,
+            )
+        });
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.contain("mknod", |fs| {
+            fs.mknod(req, parent, name, mode, umask, rdev, reply)
+        });
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.contain("mkdir", |fs| {
+            fs.mkdir(req, parent, name, mode, umask, reply)
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.contain("symlink", |fs| {
+            fs.symlink(req, parent, name, link, reply)
+        });
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.contain("create", |fs| {
+            fs.create(req, parent, name, mode, umask, flags, reply)
+        });
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.contain("link", |fs| fs.link(req, ino, newparent, newname, reply));
+    }
+
+    fn unlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("unlink", |fs| fs.unlink(req, parent, name, reply));
+    }
+
+    fn rmdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("rmdir", |fs| fs.rmdir(req, parent, name, reply));
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("rename", |fs| {
+            fs.rename(req, parent, name, newparent, newname, flags, reply)
+        });
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.contain("write", |fs| {
+            fs.write(
+                req,
+                ino,
+                fh,
+                offset,
+                data,
+                write_flags,
+                flags,
+                lock_owner,
+                reply,
+            )
+        });
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("fallocate", |fs| {
+            fs.fallocate(req, ino, fh, offset, length, mode, reply)
+        });
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.contain("copy_file_range", |fs| {
+            fs.copy_file_range(
+                req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out,
+                len, flags, reply,
+            )
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("setxattr", |fs| {
+            fs.setxattr(req, ino, name, value, flags, position, reply)
+        });
+    }
+
+    fn removexattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.contain("removexattr", |fs| fs.removexattr(req, ino, name, reply));
+    }
+}
@@ -1,21 +1,359 @@
-//! Provide sha256 digest computation using sha2 crate.
+//! Provide SHA-2 digest computation using sha2 crate.
 //!
 //! The main rationale for the existence of this modules is to allow saving
 //! intermediate states of sha256 computation. Intermediate states are useful
 //! in implementing integrity enforced file-systems directly on top of OCI layer
 //! tar files.
+//!
+//! The algorithm follows the digest of the layer: sha256, sha384 or sha512.
+//! sha384 and sha512 share the 512-bit state and 128-byte blocks of the
+//! SHA-512 compression function, and differ only in their initial state and
+//! the length of the digest. Their saved states are therefore twice as large
+//! as those of sha256.
+//!
+//! The saved states can be split off into a separate states file, so that
+//! they need not be held in memory. States are then read from the file on
+//! demand, a page at a time. The digest of each page of the states file is
+//! kept in memory, and every page read is checked against its digest.
+//!
+//! In Merkle mode, the hash of the data between consecutive save points is
+//! saved instead of the intermediate state at each point. These leaf hashes
+//! do not depend on the data before them, so each chunk is checked on its own
+//! without loading a `before` state. The leaves form a binary Merkle tree
+//! whose root is recorded next to the digest of the whole stream, i.e. the
+//! layer digest. Leaves are hashed as `H(0x00 || chunk)` and inner nodes as
+//! `H(0x01 || left || right)`, where H is the algorithm of the layer digest.
+//! A node without a sibling is promoted to the next level unchanged. When an
+//! index is loaded, the leaves are checked against the root (see
+//! `check_root`).
+//!
+//! The root and the layer digest are computed from the same stream when the
+//! index is created. Both are recorded in the index header, whose HMAC or
+//! signature binds the root to the layer digest, and mount checks the layer
+//! digest against the one supplied with `--digest`. Leaves are computed in
+//! parallel when indexing. Pages are verified in parallel with
+//! `--verify-threads`, as in chained mode. There is one leaf per page, as
+//! there is one state per page in chained mode, so indexes are only smaller
+//! for sha384, whose leaves are shorter than its states.
+//!
+//! In HMAC mode, the leaves are HMAC-SHA256s of the chunks under a secret key
+//! held by the tenant, and no tree is built. Pages can only be verified with
+//! the key, so an index obtained without the key is of no use. The index
+//! records an HMAC of a fixed message under the key so that a wrong key is
+//! rejected up front.
+//!
+//! Compression is done by the sha2 crate, which detects CPU extensions at
+//! runtime and falls back to a portable implementation without them. On x86
+//! and x86_64, sha256 uses the SHA extensions (SHA-NI) and sha512 and sha384
+//! use AVX2. On aarch64, the SHA2 and SHA3 extensions are only used if sha2
+//! is built with its `asm` feature.
+use std::borrow::Cow;
+use std::env;
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
 use std::slice;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
-use anyhow::{anyhow, Result};
-use generic_array::{typenum::U64, GenericArray};
+use anyhow::{anyhow, Context as _, Result};
+use clap::ValueEnum;
+use generic_array::{
+    typenum::{U128, U64},
+    GenericArray,
+};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use sha2::compress256;
+use sha2::{compress256, compress512, Digest as _, Sha256};
 
-/// Intermediate state of sha256 computation. 256 bits.
+use crate::digest::{self, Algorithm, Context};
+use crate::pool::Pool;
+
+/// Intermediate state of a SHA-2 computation.
 /// See [Comparison of SHA functions](https://en.wikipedia.org/wiki/SHA-2#Comparison_of_SHA_functions)
-pub type State = [u32; 8];
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// sha256 state. 256 bits.
+    Sha256([u32; 8]),
+
+    /// sha512 and sha384 state. 512 bits.
+    Sha512([u64; 8]),
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::initial(Algorithm::Sha256)
+    }
+}
+
+impl State {
+    /// Initial state of the computation.
+    ///
+    /// # Arguments
+    /// * `algorithm` - The algorithm.
+    fn initial(algorithm: Algorithm) -> State {
+        match algorithm {
+            Algorithm::Sha256 => State::Sha256([
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f,
+                0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ]),
+            Algorithm::Sha512 => State::Sha512([
+                0x6a09e667f3bcc908,
+                0xbb67ae8584caa73b,
+                0x3c6ef372fe94f82b,
+                0xa54ff53a5f1d36f1,
+                0x510e527fade682d1,
+                0x9b05688c2b3e6c1f,
+                0x1f83d9abfb41bd6b,
+                0x5be0cd19137e2179,
+            ]),
+            Algorithm::Sha384 => State::Sha512([
+                0xcbbb9d5dc1059ed8,
+                0x629a292a367cd507,
+                0x9159015a3070dd17,
+                0x152fecd8f70e5939,
+                0x67332667ffc00b31,
+                0x8eb44a8768581511,
+                0xdb0c2e0d64f98fa7,
+                0x47b5481dbefa4fa4,
+            ]),
+        }
+    }
+
+    /// Size of a state in bytes.
+    ///
+    /// # Arguments
+    /// * `algorithm` - The algorithm.
+    fn len(algorithm: Algorithm) -> usize {
+        match algorithm {
+            Algorithm::Sha256 => 32,
+            _ => 64,
+        }
+    }
+
+    /// Size of the blocks processed by the compression function.
+    fn block_len(&self) -> usize {
+        match self {
+            State::Sha256(_) => 64,
+            State::Sha512(_) => 128,
+        }
+    }
+
+    /// Process a given chunk of data.
+    ///
+    /// # Arguments
+    /// * `buf` : Chunk of data. Length must be multiple of the block size.
+    fn compress(&mut self, buf: &[u8]) -> Result<()> {
+        // TODO: Can this be turned into a compile-time check?
+        if buf.len() % self.block_len() != 0 {
+            return Err(anyhow!(
+                "buffer size must be multiple of {}",
+                self.block_len()
+            ));
+        }
+        unsafe {
+            // Cast the slice into generic arrays and call sha2 crate's
+            // compress function.
+            match self {
+                State::Sha256(state) => {
+                    let raw_ptr =
+                        buf as *const _ as *const GenericArray<u8, U64>;
+                    compress256(
+                        state,
+                        slice::from_raw_parts(raw_ptr, buf.len() / 64),
+                    );
+                }
+                State::Sha512(state) => {
+                    let raw_ptr =
+                        buf as *const _ as *const GenericArray<u8, U128>;
+                    compress512(
+                        state,
+                        slice::from_raw_parts(raw_ptr, buf.len() / 128),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the state to bytes as big-endian words.
+    ///
+    /// # Arguments
+    /// * `bytes` - Bytes to append to.
+    fn write(&self, bytes: &mut Vec<u8>) {
+        match self {
+            State::Sha256(state) => {
+                state.iter().for_each(|w| bytes.extend(w.to_be_bytes()))
+            }
+            State::Sha512(state) => {
+                state.iter().for_each(|w| bytes.extend(w.to_be_bytes()))
+            }
+        }
+    }
 
-/// Hasher computes the sha256 sum of a byte stream.
+    /// Read a state written by `write`.
+    ///
+    /// # Arguments
+    /// * `algorithm` - The algorithm.
+    /// * `bytes` - The state as big-endian words.
+    fn read(algorithm: Algorithm, bytes: &[u8]) -> State {
+        let mut state = State::initial(algorithm);
+        match &mut state {
+            State::Sha256(words) => {
+                for (word, b) in words.iter_mut().zip(bytes.chunks(4)) {
+                    *word = u32::from_be_bytes(b.try_into().unwrap());
+                }
+            }
+            State::Sha512(words) => {
+                for (word, b) in words.iter_mut().zip(bytes.chunks(8)) {
+                    *word = u64::from_be_bytes(b.try_into().unwrap());
+                }
+            }
+        }
+        state
+    }
+}
+
+/// Name of the compression backend the sha2 crate uses for an algorithm on
+/// this CPU.
+///
+/// # Arguments
+/// * `algorithm` - The algorithm.
+pub fn backend(algorithm: Algorithm) -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    match algorithm {
+        Algorithm::Sha256
+            if is_x86_feature_detected!("sha")
+                && is_x86_feature_detected!("sse2")
+                && is_x86_feature_detected!("ssse3")
+                && is_x86_feature_detected!("sse4.1") =>
+        {
+            return "sha-ni"
+        }
+        Algorithm::Sha512 | Algorithm::Sha384
+            if is_x86_feature_detected!("avx2") =>
+        {
+            return "avx2"
+        }
+        _ => (),
+    }
+    let _ = algorithm;
+    "portable"
+}
+
+/// Size of the pages of a states file that are read and checked at once.
+const STATES_PAGE_LEN: usize = 4096;
+
+/// What is saved at each save point.
+#[derive(
+    ValueEnum,
+    Serialize,
+    Deserialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+)]
+pub enum HashMode {
+    /// Intermediate states of the digest computation of the stream.
+    #[default]
+    Chained,
+
+    /// Leaf hashes of a Merkle tree over the chunks between save points.
+    Merkle,
+
+    /// HMAC-SHA256 of the chunks between save points under a secret key.
+    Hmac,
+}
+
+/// Environment variable holding the hex measurement key. Used when no key
+/// file is given.
+const MEASURE_KEY_ENV: &str = "CC_FS_MEASURE_KEY";
+
+/// Message whose HMAC under the measurement key identifies the key.
+const KEY_CHECK_MESSAGE: &[u8] = b"cc-fs measurement key";
+
+/// Read the key of keyed (HMAC) measurements.
+///
+/// # Arguments
+/// * `path` - Path of the file holding the key. Its bytes are the key. If
+///   None, the hex key in the `CC_FS_MEASURE_KEY` environment variable is
+///   used, if set.
+pub fn read_measure_key(path: &Option<String>) -> Result<Option<Vec<u8>>> {
+    let key = match (path, env::var(MEASURE_KEY_ENV)) {
+        (Some(path), _) => fs::read(path)
+            .with_context(|| format!("failed to read key {}", path))?,
+        (None, Ok(hex)) => {
+            let hex = hex.trim();
+            if hex.len() % 2 != 0 {
+                return Err(anyhow!("{} must be hex", MEASURE_KEY_ENV));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| anyhow!("{} must be hex", MEASURE_KEY_ENV))?
+        }
+        (None, Err(_)) => return Ok(None),
+    };
+    if key.is_empty() {
+        return Err(anyhow!("measurement key is empty"));
+    }
+    Ok(Some(key))
+}
+
+/// Computation of a leaf hash.
+#[derive(Debug, Clone)]
+enum Leaf {
+    /// Leaf of a Merkle tree.
+    Hash(Context),
+
+    /// Keyed leaf.
+    Hmac(Hmac<Sha256>),
+}
+
+impl Leaf {
+    /// Add data to the leaf.
+    ///
+    /// # Arguments
+    /// * `buf` - Data to add.
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            Leaf::Hash(context) => context.update(buf),
+            Leaf::Hmac(hmac) => hmac.update(buf),
+        }
+    }
+
+    /// Finish the leaf and return its hash.
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Leaf::Hash(context) => context.finalize_bytes(),
+            Leaf::Hmac(hmac) => hmac.finalize().into_bytes().to_vec(),
+        }
+    }
+}
+
+/// Saved states stored in a separate file.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SplitStates {
+    /// Name of the states file, relative to the directory of the index.
+    pub file_name: String,
+
+    /// Number of states in the file.
+    num_states: u64,
+
+    /// sha256 digest of each page of the file.
+    page_digests: Vec<[u8; 32]>,
+
+    /// The open states file.
+    #[serde(skip)]
+    file: Option<Arc<File>>,
+}
+
+/// Hasher computes the SHA-2 sum of a byte stream.
 ///
 /// Intermediate states can be selectively saved before and after processing
 /// a chunk of data (typically a page). Integrity can be later verified by
@@ -23,8 +361,8 @@ pub type State = [u32; 8];
 /// the state matches the saved `after` state.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Hasher {
-    /// Set of saved intermediate states.
-    states: Vec<State>,
+    /// Saved intermediate states, each `slot_len()` bytes.
+    states: Vec<u8>,
 
     /// Current state.
     state: State,
@@ -32,8 +370,34 @@ pub struct Hasher {
     /// Length of processed data.
     len: u64,
 
-    /// Computed sha256 sum.
+    /// Computed hex digest.
     pub digest: String,
+
+    /// Saved states stored in a separate file instead of `states`.
+    pub split: Option<SplitStates>,
+
+    /// What is saved at each save point. In Merkle and HMAC modes, `states`
+    /// holds the leaf hashes.
+    pub mode: HashMode,
+
+    /// Hex Merkle tree root of the leaf hashes. Empty in chained mode.
+    pub root: String,
+
+    /// Algorithm of the digest and the saved states.
+    pub algorithm: Algorithm,
+
+    /// Hex HMAC of a fixed message under the measurement key, in HMAC mode.
+    /// Identifies the key without revealing it.
+    pub key_check: String,
+
+    /// Key of the leaf HMACs, in HMAC mode. Not stored in the index.
+    #[serde(skip)]
+    key: Option<Vec<u8>>,
+
+    /// Leaf hash of the data since the last save point, in Merkle and HMAC
+    /// modes.
+    #[serde(skip)]
+    leaf: Option<Leaf>,
 }
 
 impl Hasher {
@@ -42,38 +406,153 @@ impl Hasher {
     /// # Arguments
     /// * `hint_num_states` - Expected number of intermediate states.
     ///    A reasonable approximation is file-size divided by 4096.
-    pub fn new(hint_num_states: u32) -> Result<Hasher> {
-        Ok(Hasher {
-            states: Vec::with_capacity(hint_num_states as usize),
-            // Initialize state to sha256 initial values.
-            state: [
-                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f,
-                0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
-            ],
+    /// * `mode` - What to save at each save point.
+    /// * `algorithm` - Algorithm of the digest.
+    /// * `key` - Key of the leaf HMACs. Required in HMAC mode only.
+    pub fn new(
+        hint_num_states: u32,
+        mode: HashMode,
+        algorithm: Algorithm,
+        key: &Option<Vec<u8>>,
+    ) -> Result<Hasher> {
+        match (mode, key) {
+            (HashMode::Hmac, None) => {
+                return Err(anyhow!("keyed measurement requires a key"))
+            }
+            (HashMode::Hmac, _) | (_, None) => (),
+            (_, Some(_)) => {
+                return Err(anyhow!("a measurement key requires hmac mode"))
+            }
+        }
+
+        let mut hasher = Hasher {
+            states: vec![],
+            state: State::initial(algorithm),
             len: 0,
             digest: String::from(""),
-        })
+            split: None,
+            mode,
+            root: String::new(),
+            algorithm,
+            key_check: String::new(),
+            key: key.clone(),
+            leaf: None,
+        };
+        hasher.states =
+            Vec::with_capacity(hint_num_states as usize * hasher.slot_len());
+        if let Some(key) = key {
+            hasher.key_check = Hasher::key_check(key)?;
+        }
+        hasher.leaf = hasher.new_leaf();
+        Ok(hasher)
     }
 
-    #[doc(hidden)]
-    /// Process a given chunk of data.
+    /// Identify a measurement key.
     ///
     /// # Arguments
-    /// * `buf` : Chunk of data. Length must be multiple of 64 bytes (512 bits).
-    fn compress(state: &mut State, buf: &[u8]) -> Result<()> {
-        // TODO: Can this be turned into a compile-time check?
-        if buf.len() % 64 != 0 {
-            return Err(anyhow!("buffer size must be multiple of 32"));
+    /// * `key` - The key.
+    fn key_check(key: &[u8]) -> Result<String> {
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key)?;
+        hmac.update(KEY_CHECK_MESSAGE);
+        Ok(digest::to_hex(&hmac.finalize().into_bytes()))
+    }
+
+    /// Supply the key of the leaf HMACs of a loaded index.
+    ///
+    /// Keys are ignored unless the index is in HMAC mode.
+    ///
+    /// # Arguments
+    /// * `key` - The key.
+    pub fn set_key(&mut self, key: Option<Vec<u8>>) -> Result<()> {
+        if self.mode != HashMode::Hmac {
+            return Ok(());
         }
-        unsafe {
-            // Cast the slice into a generic array.
-            let raw_ptr = buf as *const _ as *const GenericArray<u8, U64>;
-            let slice = slice::from_raw_parts(raw_ptr, buf.len() / 64);
+        match key {
+            None => Err(anyhow!(
+                "index measurements are keyed; supply the key with \
+                 --measure-key or {}",
+                MEASURE_KEY_ENV
+            )),
+            Some(key) if Hasher::key_check(&key)? != self.key_check => {
+                Err(anyhow!("wrong measurement key"))
+            }
+            key => {
+                self.key = key;
+                Ok(())
+            }
+        }
+    }
 
-            // Call sha2 crate's compress function.
-            compress256(state, slice);
+    /// Size of each saved state, or leaf hash in Merkle and HMAC modes.
+    fn slot_len(&self) -> usize {
+        match self.mode {
+            HashMode::Chained => State::len(self.algorithm),
+            HashMode::Merkle => self.algorithm.hex_len() / 2,
+            HashMode::Hmac => 32,
         }
-        Ok(())
+    }
+
+    /// Start the hash of a node of the Merkle tree.
+    ///
+    /// # Arguments
+    /// * `prefix` - 0 for leaves, 1 for inner nodes.
+    fn new_node(&self, prefix: u8) -> Context {
+        let mut node = Context::new(self.algorithm);
+        node.update(&[prefix]);
+        node
+    }
+
+    /// Start a leaf hash. None in chained mode, or without the key in HMAC
+    /// mode.
+    fn new_leaf(&self) -> Option<Leaf> {
+        match (self.mode, &self.key) {
+            (HashMode::Chained, _) => None,
+            (HashMode::Merkle, _) => Some(Leaf::Hash(self.new_node(0))),
+            (HashMode::Hmac, key) => {
+                Hmac::<Sha256>::new_from_slice(key.as_ref()?)
+                    .ok()
+                    .map(Leaf::Hmac)
+            }
+        }
+    }
+
+    /// Leaf hash of a chunk of data. None if it cannot be computed.
+    ///
+    /// # Arguments
+    /// * `buf` - Chunk of data.
+    fn leaf_hash(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        let mut leaf = self.new_leaf()?;
+        leaf.update(buf);
+        Some(leaf.finalize())
+    }
+
+    /// Compute the Merkle tree root of leaf hashes.
+    ///
+    /// # Arguments
+    /// * `leaves` - The leaf hashes, in order.
+    fn merkle_root(&self, leaves: &[u8]) -> String {
+        let mut level: Vec<Vec<u8>> = leaves
+            .chunks(self.slot_len())
+            .map(|leaf| leaf.to_vec())
+            .collect();
+        if level.is_empty() {
+            level.push(self.new_node(0).finalize_bytes());
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut node = self.new_node(1);
+                        node.update(left);
+                        node.update(right);
+                        node.finalize_bytes()
+                    }
+                    _ => pair[0].clone(),
+                })
+                .collect();
+        }
+        digest::to_hex(&level[0])
     }
 
     /// Save the current state.
@@ -106,81 +585,287 @@ impl Hasher {
     /// hasher.save_state();
     /// ```
     pub fn save_state(&mut self) -> u32 {
-        self.states.push(self.state);
-        self.states.len() as u32 - 1
+        match self.mode {
+            HashMode::Chained => self.state.write(&mut self.states),
+            _ => {
+                let next = self.new_leaf();
+                if let Some(leaf) = std::mem::replace(&mut self.leaf, next) {
+                    self.states.extend(leaf.finalize());
+                }
+            }
+        }
+        self.num_states() as u32 - 1
     }
 
     /// Measure a given chunk of data.
     ///
     /// # Arguments
-    /// * `buf` : Chunk of data. Length must be multiple of 64 bytes (512 bits).
+    /// * `buf` : Chunk of data. Length must be multiple of the block size,
+    ///   i.e. 64 bytes for sha256 and 128 bytes for sha384 and sha512.
     pub fn measure(&mut self, buf: &[u8]) -> Result<()> {
         // Measure slice and update length of processed data.
-        Hasher::compress(&mut self.state, buf)?;
+        self.state.compress(buf)?;
         self.len += buf.len() as u64;
+        if let Some(leaf) = &mut self.leaf {
+            leaf.update(buf);
+        }
         Ok(())
     }
 
-    /// Finalize the sha256 computation.
+    /// Finalize the digest computation.
     ///
     /// This involves appending a 1 bit, followed by padding 0 bits, followed by
-    /// the length in bits of processed data as a u64 such that the total length
-    /// of the bit stream is a multiple of 512.
+    /// the length in bits of processed data (as a u64 for sha256 and a u128
+    /// for sha384 and sha512) such that the total length of the bit stream is
+    /// a multiple of the block size.
     ///
     /// See [SHA-2](https://en.wikipedia.org/wiki/SHA-2#Pseudocode).
     ///
+    /// In Merkle mode, the root of the tree of saved leaves is computed too.
+    ///
     /// TODO: Consume the hasher object after finalization.
     pub fn finalize(&mut self) -> Result<&String> {
-        // The data processed so far is a multiple of 64 bytes.
-        // Add another 64 bytes to the stream.
-        let mut buf = [0u8; 64];
+        // The data processed so far is a multiple of the block size.
+        // Add another block to the stream.
+        let block_len = self.state.block_len();
+        let mut buf = vec![0u8; block_len];
 
         // Add a 1 bit.
         buf[0] = 0x80;
 
         // Append length to the stream.
-        let bits = self.len * 8 as u64;
-        buf[56] = ((bits >> (8 * 7)) & 0xff) as u8;
-        buf[57] = ((bits >> (8 * 6)) & 0xff) as u8;
-        buf[58] = ((bits >> (8 * 5)) & 0xff) as u8;
-        buf[59] = ((bits >> (8 * 4)) & 0xff) as u8;
-        buf[60] = ((bits >> (8 * 3)) & 0xff) as u8;
-        buf[61] = ((bits >> (8 * 2)) & 0xff) as u8;
-        buf[62] = ((bits >> (8 * 1)) & 0xff) as u8;
-        buf[63] = ((bits >> (8 * 0)) & 0xff) as u8;
-
-        // Measure this chunk.
+        let bits = self.len as u128 * 8;
+        let len_bytes = block_len / 8;
+        buf[block_len - len_bytes..]
+            .copy_from_slice(&bits.to_be_bytes()[16 - len_bytes..]);
+
+        // Measure this chunk. The leaf of the trailing data is discarded.
+        self.leaf = None;
         self.measure(&buf)?;
 
-        // Convert the state to hex representation to obtain the digest.
-        let hash = self.state;
-        self.digest = format!(
-            "{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
-            hash[0],
-            hash[1],
-            hash[2],
-            hash[3],
-            hash[4],
-            hash[5],
-            hash[6],
-            hash[7]
-        );
+        // The digest is the state, truncated for sha384.
+        let mut hash = vec![];
+        self.state.write(&mut hash);
+        hash.truncate(self.algorithm.hex_len() / 2);
+        self.digest = digest::to_hex(&hash);
+        if self.mode == HashMode::Merkle {
+            self.root = self.merkle_root(&self.states);
+        }
 
         Ok(&self.digest)
     }
 
-    /// Verify the hash of a given chunk.
+    /// Check that the saved leaves hash to the recorded Merkle tree root.
+    ///
+    /// Leaves in a states file are read in full and checked against the
+    /// digests of its pages. Does nothing outside Merkle mode.
+    pub fn check_root(&self) -> Result<()> {
+        if self.mode != HashMode::Merkle {
+            return Ok(());
+        }
+        let leaves = self.states(0, self.num_states())?;
+        if self.merkle_root(&leaves) != self.root {
+            return Err(anyhow!("hash states do not match the merkle root"));
+        }
+        Ok(())
+    }
+
+    /// Verify a chunk of data lying between two saved states.
+    ///
+    /// Used for data such as tar headers that is measured without saving a
+    /// state before each page.
     ///
-    /// Load the saved state at specified position, process the given chunk,
-    /// and then check that the new state is equal to saves state at pos + 1.
+    /// In Merkle and HMAC modes, the states must be adjacent.
     ///
     /// # Arguments
-    /// * `pos` - The position of the `before` state for the chunk.
-    /// * `buf` - Chunk of data. Length must be multiple of 64 bytes (512 bits).
-    pub fn verify(&self, pos: u32, buf: &[u8]) -> Result<bool> {
-        let mut state = self.states[pos as usize];
-        Hasher::compress(&mut state, buf)?;
-        Ok(state == self.states[pos as usize + 1])
+    /// * `before` - The position of the state before the chunk. None for the
+    ///   start of the data.
+    /// * `buf` - Chunk of data. Length must be multiple of the block size.
+    /// * `after` - The position of the state after the chunk.
+    pub fn verify_range(
+        &self,
+        before: Option<u32>,
+        buf: &[u8],
+        after: u32,
+    ) -> Result<bool> {
+        if self.mode != HashMode::Chained {
+            // Leaves cover the data between adjacent save points only.
+            if before.map_or(0, |b| b + 1) != after {
+                return Err(anyhow!(
+                    "hash states {:?} and {} are not adjacent",
+                    before,
+                    after
+                ));
+            }
+            let leaf = self.states(after, 1)?;
+            return Ok(self.leaf_hash(buf).as_deref() == Some(&leaf[..]));
+        }
+        let mut state = match before {
+            Some(pos) => State::read(self.algorithm, &self.states(pos, 1)?),
+            None => State::initial(self.algorithm),
+        };
+        state.compress(buf)?;
+        Ok(state == State::read(self.algorithm, &self.states(after, 1)?))
+    }
+
+    /// Verify consecutive pages of data.
+    ///
+    /// For each page, load the saved state before it, process the page, and
+    /// then check that the new state is equal to the saved state after it.
+    /// In Merkle and HMAC modes, the leaf hash of each page is checked against
+    /// the leaf saved after it instead.
+    ///
+    /// # Arguments
+    /// * `pos` - The position of the `before` state for the first page.
+    /// * `buf` - Pages of data. The last page may be shorter than 4096 bytes
+    ///   but its length must be a multiple of the block size.
+    /// * `returns` - The position of the first page that fails verification.
+    pub fn verify_pages(&self, pos: u32, buf: &[u8]) -> Result<(), u32> {
+        let num_pages = buf.len().div_ceil(4096);
+        let len = self.slot_len();
+        if self.mode != HashMode::Chained {
+            let leaves = self.states(pos + 1, num_pages).map_err(|_| pos)?;
+            for (i, (page, leaf)) in
+                buf.chunks(4096).zip(leaves.chunks(len)).enumerate()
+            {
+                if self.leaf_hash(page).as_deref() != Some(leaf) {
+                    return Err(pos + i as u32);
+                }
+            }
+            return Ok(());
+        }
+        let states = self.states(pos, num_pages + 1).map_err(|_| pos)?;
+        for (i, page) in buf.chunks(4096).enumerate() {
+            let mut state =
+                State::read(self.algorithm, &states[i * len..(i + 1) * len]);
+            let after = &states[(i + 1) * len..(i + 2) * len];
+            if state.compress(page).is_err()
+                || state != State::read(self.algorithm, after)
+            {
+                return Err(pos + i as u32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Saved states at consecutive positions, as bytes.
+    ///
+    /// States in a states file are read and checked against the digests of
+    /// the pages holding them.
+    ///
+    /// # Arguments
+    /// * `pos` - Position of the first state.
+    /// * `count` - Number of states.
+    fn states(&self, pos: u32, count: usize) -> Result<Cow<'_, [u8]>> {
+        let len = self.slot_len();
+        let (start, end) = (pos as usize * len, (pos as usize + count) * len);
+        let split = match &self.split {
+            Some(split) => split,
+            None => {
+                return self
+                    .states
+                    .get(start..end)
+                    .map(Cow::Borrowed)
+                    .ok_or(anyhow!("invalid hash state {}", pos))
+            }
+        };
+        let file_len = split.num_states as usize * len;
+        if end > file_len {
+            return Err(anyhow!("invalid hash state {}", pos));
+        }
+        let file = split
+            .file
+            .as_ref()
+            .ok_or(anyhow!("states file {} is not open", split.file_name))?;
+
+        // Read whole pages so that they can be checked.
+        let first_page = start / STATES_PAGE_LEN;
+        let last_page = (end - 1) / STATES_PAGE_LEN;
+        let offset = first_page * STATES_PAGE_LEN;
+        let mut buf =
+            vec![0u8; file_len.min((last_page + 1) * STATES_PAGE_LEN) - offset];
+        file.read_exact_at(&mut buf, offset as u64)?;
+        for (i, page) in buf.chunks(STATES_PAGE_LEN).enumerate() {
+            if Sha256::digest(page)[..] != split.page_digests[first_page + i] {
+                return Err(anyhow!(
+                    "integrity verification failed for states file {}",
+                    split.file_name
+                ));
+            }
+        }
+
+        buf.truncate(end - offset);
+        buf.drain(..start - offset);
+        Ok(Cow::Owned(buf))
+    }
+
+    /// Move the saved states to a states file.
+    ///
+    /// The states file is written next to the index and replaced atomically.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the states file.
+    pub fn split_states(&mut self, path: &String) -> Result<()> {
+        let bytes = &self.states;
+        let tmp_path = format!("{}.tmp", path);
+        let result = fs::write(&tmp_path, bytes)
+            .with_context(|| format!("failed to write {}", tmp_path))
+            .and_then(|_| {
+                fs::rename(&tmp_path, path).with_context(|| {
+                    format!("failed to rename {} to {}", tmp_path, path)
+                })
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result?;
+
+        let file_name = Path::new(path)
+            .file_name()
+            .ok_or(anyhow!("invalid states file path {}", path))?;
+        self.split = Some(SplitStates {
+            file_name: file_name.to_string_lossy().to_string(),
+            num_states: self.num_states() as u64,
+            page_digests: bytes
+                .chunks(STATES_PAGE_LEN)
+                .map(|page| Sha256::digest(page).into())
+                .collect(),
+            file: None,
+        });
+        self.states = vec![];
+        Ok(())
+    }
+
+    /// Open the states file, if the states are split off.
+    ///
+    /// # Arguments
+    /// * `dir` - Directory of the index.
+    pub fn open_states(&mut self, dir: &Path) -> Result<()> {
+        let slot_len = self.slot_len() as u64;
+        let split = match &mut self.split {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let path = dir.join(&split.file_name);
+        let file = File::open(&path).with_context(|| {
+            format!("failed to open states file {}", path.display())
+        })?;
+        if file.metadata()?.len() != split.num_states * slot_len {
+            return Err(anyhow!(
+                "states file {} has unexpected size",
+                path.display()
+            ));
+        }
+        split.file = Some(Arc::new(file));
+        Ok(())
+    }
+
+    /// Number of saved states.
+    pub fn num_states(&self) -> usize {
+        match &self.split {
+            Some(split) => split.num_states as usize,
+            None => self.states.len() / self.slot_len(),
+        }
     }
 
     /// Relinquish extra capacity.
@@ -190,3 +875,301 @@ impl Hasher {
         self.states.shrink_to_fit();
     }
 }
+
+/// Amount of data handed over to the threads of a pipeline at once.
+const BATCH_LEN: usize = 1024 * 1024;
+
+/// Number of batches queued for the threads of a pipeline before the reader
+/// waits for them to catch up.
+const MAX_QUEUED_BATCHES: usize = 16;
+
+/// Measured data handed over to the threads of a pipeline.
+#[derive(Default)]
+struct Batch {
+    /// The data.
+    data: Vec<u8>,
+
+    /// Ends of the chunks of data that are followed by a save point.
+    saves: Vec<usize>,
+
+    /// Position of the save point at the end of the first chunk.
+    first: u32,
+
+    /// Whether the data continues a chunk started in an earlier batch.
+    continued: bool,
+}
+
+impl Batch {
+    /// Chunks of the batch followed by save points. The first chunk is the
+    /// end of the chunk started in an earlier batch if `continued`.
+    fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        let starts = std::iter::once(0).chain(self.saves.iter().copied());
+        starts
+            .zip(self.saves.iter())
+            .map(|(start, end)| &self.data[start..*end])
+    }
+
+    /// Data after the last save point.
+    fn tail(&self) -> &[u8] {
+        &self.data[self.saves.last().copied().unwrap_or(0)..]
+    }
+}
+
+/// Leaf hashes at consecutive positions.
+type Leaves = (u32, Vec<u8>);
+
+/// Result of the digest thread of a pipeline: the hasher and the leaves
+/// computed by the thread.
+type Digested = Result<(Hasher, Vec<Leaves>)>;
+
+/// Hashing of a stream spread over threads.
+///
+/// The caller reads and parses the stream, and hands the data and save
+/// points over in batches. The digest of the stream, and the saved states in
+/// chained mode, are computed on a thread of their own, since each state
+/// depends on all data before it. In Merkle and HMAC modes, the leaf hashes
+/// are independent of each other and are computed on a pool of workers. The
+/// leaves are put in order when the pipeline finishes.
+///
+/// A chunk larger than a batch is split across batches. Its leaf is computed
+/// by the digest thread, which sees all batches in order.
+pub struct Pipeline {
+    /// Data and save points not yet handed over.
+    batch: Batch,
+
+    /// Number of save points so far.
+    num_states: u32,
+
+    /// Queue of batches of the digest thread.
+    batches: Option<SyncSender<Arc<Batch>>>,
+
+    /// The digest thread. Returns the hasher and the leaves it computed.
+    digest_thread: Option<JoinHandle<Digested>>,
+
+    /// Workers computing leaf hashes, and the start of a leaf. None in
+    /// chained mode.
+    workers: Option<(Pool, Leaf)>,
+
+    /// Sender and receiver of the leaves computed by the workers.
+    leaves: (Sender<Leaves>, Receiver<Leaves>),
+
+    /// Leaves received from the workers.
+    received: Vec<Leaves>,
+
+    /// Number of batches handed to the workers whose leaves have not been
+    /// received.
+    pending: usize,
+}
+
+impl Pipeline {
+    /// Start a pipeline.
+    ///
+    /// # Arguments
+    /// * `hasher` - The hasher. Returned by `finish`.
+    /// * `threads` - Number of threads, including the digest thread. Must be
+    ///   at least 2. Threads beyond the digest thread compute leaf hashes.
+    pub fn new(mut hasher: Hasher, threads: usize) -> Result<Pipeline> {
+        if threads < 2 {
+            return Err(anyhow!("a pipeline needs at least 2 threads"));
+        }
+        // The digest thread only computes leaves of chunks split across
+        // batches.
+        let leaf = hasher.leaf.take();
+        let workers = match &leaf {
+            Some(leaf) => Some((Pool::new(threads - 1)?, leaf.clone())),
+            None => None,
+        };
+
+        let (batches, queue) = sync_channel(MAX_QUEUED_BATCHES);
+        let digest_thread = thread::Builder::new()
+            .name(String::from("cc-fs-digest"))
+            .spawn(move || Pipeline::digest(hasher, leaf, queue))?;
+
+        Ok(Pipeline {
+            batch: Batch::default(),
+            num_states: 0,
+            batches: Some(batches),
+            digest_thread: Some(digest_thread),
+            workers,
+            leaves: channel(),
+            received: vec![],
+            pending: 0,
+        })
+    }
+
+    /// Compute the digest of the stream, the saved states in chained mode,
+    /// and the leaves of chunks split across batches in the other modes.
+    ///
+    /// # Arguments
+    /// * `hasher` - The hasher.
+    /// * `leaf` - Start of a leaf. None in chained mode.
+    /// * `queue` - Queue of batches.
+    /// * `returns` - The hasher and the leaves computed.
+    fn digest(
+        mut hasher: Hasher,
+        leaf: Option<Leaf>,
+        queue: Receiver<Arc<Batch>>,
+    ) -> Digested {
+        let mut leaves = vec![];
+        let mut open: Option<Leaf> = None;
+        for batch in queue {
+            if leaf.is_none() {
+                for chunk in batch.chunks() {
+                    hasher.measure(chunk)?;
+                    hasher.save_state();
+                }
+                hasher.measure(batch.tail())?;
+                continue;
+            }
+
+            hasher.measure(&batch.data)?;
+            if batch.continued {
+                if let Some(open) = &mut open {
+                    open.update(match batch.saves.first() {
+                        Some(end) => &batch.data[..*end],
+                        None => &batch.data,
+                    });
+                }
+                if batch.saves.is_empty() {
+                    continue;
+                }
+                if let Some(open) = open.take() {
+                    leaves.push((batch.first, open.finalize()));
+                }
+            }
+            if !batch.tail().is_empty() {
+                open = leaf.clone();
+                if let Some(open) = &mut open {
+                    open.update(batch.tail());
+                }
+            }
+        }
+        Ok((hasher, leaves))
+    }
+
+    /// Compute the leaves of the chunks of a batch that lie wholly within it.
+    ///
+    /// # Arguments
+    /// * `batch` - The batch.
+    /// * `leaf` - Start of a leaf.
+    /// * `returns` - The leaves, starting at the position of the first.
+    fn leaves(batch: &Batch, leaf: &Leaf) -> Leaves {
+        let skip = batch.continued as usize;
+        let mut leaves = vec![];
+        for chunk in batch.chunks().skip(skip) {
+            let mut leaf = leaf.clone();
+            leaf.update(chunk);
+            leaves.extend(leaf.finalize());
+        }
+        (batch.first + skip as u32, leaves)
+    }
+
+    /// Measure a chunk of data.
+    ///
+    /// # Arguments
+    /// * `buf` : Chunk of data. Length must be multiple of the block size.
+    ///   Misaligned data fails the digest thread, which is reported by the
+    ///   next hand-over or by `finish`.
+    pub fn measure(&mut self, buf: &[u8]) -> Result<()> {
+        self.batch.data.extend_from_slice(buf);
+        if self.batch.data.len() >= BATCH_LEN {
+            self.hand_over()?;
+        }
+        Ok(())
+    }
+
+    /// Add a save point.
+    ///
+    /// * `returns` - The position of the save point.
+    pub fn save_state(&mut self) -> Result<u32> {
+        self.batch.saves.push(self.batch.data.len());
+        self.num_states += 1;
+        if self.batch.data.len() >= BATCH_LEN {
+            self.hand_over()?;
+        }
+        Ok(self.num_states - 1)
+    }
+
+    /// Hand the current batch over to the threads.
+    fn hand_over(&mut self) -> Result<()> {
+        let continued = !self.batch.tail().is_empty()
+            || (self.batch.continued && self.batch.saves.is_empty());
+        let next = Batch {
+            data: Vec::with_capacity(BATCH_LEN),
+            saves: vec![],
+            first: self.num_states,
+            continued,
+        };
+        let batch = Arc::new(std::mem::replace(&mut self.batch, next));
+
+        if let Some((pool, leaf)) = &self.workers {
+            // Wait for the workers to catch up.
+            if self.pending >= MAX_QUEUED_BATCHES {
+                let leaves = self.leaves.1.recv()?;
+                self.received.push(leaves);
+                self.pending -= 1;
+            }
+            let (batch, leaf) = (batch.clone(), leaf.clone());
+            let leaves = self.leaves.0.clone();
+            pool.execute(move || {
+                let _ = leaves.send(Pipeline::leaves(&batch, &leaf));
+            })?;
+            self.pending += 1;
+        }
+
+        let sent = match &self.batches {
+            Some(batches) => batches.send(batch).is_ok(),
+            None => false,
+        };
+        if !sent {
+            // The digest thread stopped early. Report its error.
+            return Err(self
+                .join()
+                .err()
+                .unwrap_or(anyhow!("hashing stopped")));
+        }
+        Ok(())
+    }
+
+    /// Wait for the digest thread to finish.
+    fn join(&mut self) -> Digested {
+        self.batches = None;
+        self.digest_thread
+            .take()
+            .ok_or(anyhow!("hashing stopped"))?
+            .join()
+            .map_err(|_| anyhow!("digest thread panicked"))?
+    }
+
+    /// Wait for all data to be hashed and put the leaves in order.
+    ///
+    /// * `returns` - The hasher, ready to be finalized.
+    pub fn finish(mut self) -> Result<Hasher> {
+        self.hand_over()?;
+        let (mut hasher, mut leaves) = self.join()?;
+        drop(self.leaves.0);
+        for _ in 0..self.pending {
+            self.received.push(self.leaves.1.recv()?);
+        }
+        if self.workers.is_none() {
+            return Ok(hasher);
+        }
+
+        leaves.append(&mut self.received);
+        leaves.sort_by_key(|(pos, _)| *pos);
+        hasher.states.clear();
+        for (pos, leaf) in leaves {
+            if pos as usize != hasher.num_states() {
+                return Err(anyhow!(
+                    "missing leaf hash {}",
+                    hasher.num_states()
+                ));
+            }
+            hasher.states.extend(leaf);
+        }
+        if hasher.num_states() != self.num_states as usize {
+            return Err(anyhow!("missing leaf hash {}", hasher.num_states()));
+        }
+        Ok(hasher)
+    }
+}
@@ -0,0 +1,420 @@
+//! Shifting of owner ids at mount time.
+//!
+//! The owners stored in a layer are usually those of the image, e.g. root.
+//! Rootless and user-namespaced runtimes expect the files of their
+//! containers to be owned by ids in the range mapped to their user
+//! namespace. Owner ids are shifted into that range when shown, either by
+//! fixed offsets or by a mapping file. Ids outside the mapped ranges are
+//! shown as the overflow id 65534, as by the kernel.
+//!
+//! A mapping file has one range per line, in the form of `/proc/PID/uid_map`
+//! prefixed by `u` for user ids or `g` for group ids: the first stored id,
+//! the first shown id, and the number of ids. Empty lines and lines starting
+//! with '#' are ignored.
+//! ```text
+//! u 0 100000 65536
+//! g 0 100000 65536
+//! ```
+//!
+//! The mapping file may also be the OCI runtime spec (`config.json`) of a
+//! container, e.g. of runc or kata, whose `linux.uidMappings` and
+//! `linux.gidMappings` are used.
+//!
+//! Alternatively, the mapping of a user namespace is used, e.g. that of the
+//! sandbox of a container. The namespace is given by path, e.g.
+//! `/proc/PID/ns/user` or `/proc/self/fd/N` for a file descriptor passed by
+//! the runtime. Its mapping is read by a child process joining it, which
+//! requires `CAP_SYS_ADMIN` over the namespace. FUSE mounts cannot be
+//! idmapped by the kernel, so the mapping is applied by the file-system
+//! instead.
+//!
+//! Owners set in the upper layer of a mount are shifted back, so that
+//! committed layers hold stored ids.
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// Id shown for stored ids outside the mapped ranges.
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A range of consecutive ids.
+#[derive(Clone, Debug)]
+pub struct IdRange {
+    /// First stored id.
+    pub first: u32,
+
+    /// Id the first stored id is shown as.
+    pub shifted: u32,
+
+    /// Number of ids.
+    pub count: u32,
+}
+
+impl IdRange {
+    /// Create a range.
+    ///
+    /// # Arguments
+    /// * `first` - First stored id.
+    /// * `shifted` - Id the first stored id is shown as.
+    /// * `count` - Number of ids.
+    fn new(first: u32, shifted: u32, count: u32) -> Result<IdRange> {
+        let valid = count > 0
+            && first.checked_add(count - 1).is_some()
+            && shifted.checked_add(count - 1).is_some();
+        if !valid {
+            return Err(anyhow!(
+                "invalid id range {} {} {}",
+                first,
+                shifted,
+                count
+            ));
+        }
+        Ok(IdRange {
+            first,
+            shifted,
+            count,
+        })
+    }
+}
+
+/// Shift an id through a list of ranges. Ids are unchanged if there are no
+/// ranges.
+///
+/// # Arguments
+/// * `ranges` - The ranges.
+/// * `id` - The id.
+/// * `from` - Start of a range on the side of the id.
+/// * `to` - Start of a range on the other side.
+fn shift(
+    ranges: &[IdRange],
+    id: u32,
+    from: fn(&IdRange) -> u32,
+    to: fn(&IdRange) -> u32,
+) -> Option<u32> {
+    if ranges.is_empty() {
+        return Some(id);
+    }
+    ranges
+        .iter()
+        .find(|r| id >= from(r) && id - from(r) < r.count)
+        .map(|r| to(r) + (id - from(r)))
+}
+
+/// Check that ranges do not overlap, on either side.
+///
+/// # Arguments
+/// * `ranges` - The ranges.
+fn check_overlaps(ranges: &[IdRange]) -> Result<()> {
+    let overlap = |a: u32, b: u32, count_a: u32, count_b: u32| {
+        a as u64 + count_a as u64 > b as u64
+            && b as u64 + count_b as u64 > a as u64
+    };
+    for (i, a) in ranges.iter().enumerate() {
+        for b in &ranges[i + 1..] {
+            if overlap(a.first, b.first, a.count, b.count)
+                || overlap(a.shifted, b.shifted, a.count, b.count)
+            {
+                return Err(anyhow!(
+                    "id ranges {} {} {} and {} {} {} overlap",
+                    a.first,
+                    a.shifted,
+                    a.count,
+                    b.first,
+                    b.shifted,
+                    b.count
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ioctl returning the type of a namespace.
+const NS_GET_NSTYPE: libc::c_ulong = 0xb703;
+
+/// A range of ids in an OCI runtime spec.
+#[derive(Deserialize)]
+struct OciIdMapping {
+    #[serde(rename = "containerID")]
+    container_id: u32,
+
+    #[serde(rename = "hostID")]
+    host_id: u32,
+
+    size: u32,
+}
+
+/// The part of an OCI runtime spec describing the user namespace.
+#[derive(Deserialize)]
+struct OciSpec {
+    #[serde(default)]
+    linux: OciLinux,
+}
+
+/// Id mappings of the user namespace of an OCI runtime spec.
+#[derive(Default, Deserialize)]
+struct OciLinux {
+    #[serde(rename = "uidMappings", default)]
+    uid_mappings: Vec<OciIdMapping>,
+
+    #[serde(rename = "gidMappings", default)]
+    gid_mappings: Vec<OciIdMapping>,
+}
+
+/// Parse ranges in the form of `/proc/PID/uid_map`.
+///
+/// # Arguments
+/// * `text` - The ranges, one per line.
+/// * `name` - Name of the ranges for errors.
+fn parse_ranges(text: &str, name: &str) -> Result<Vec<IdRange>> {
+    let mut ranges = vec![];
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let numbers = line
+            .split_whitespace()
+            .map(|n| n.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|n| n.len() == 3)
+            .ok_or(anyhow!("{}: invalid id range {}", name, line.trim()))?;
+        ranges.push(IdRange::new(numbers[0], numbers[1], numbers[2])?);
+    }
+    Ok(ranges)
+}
+
+/// Read the id mappings of a user namespace, from a child process joining
+/// it. Processes with several threads cannot join user namespaces.
+///
+/// # Arguments
+/// * `ns` - The user namespace.
+/// * `returns` - Contents of `uid_map` and `gid_map` in the namespace.
+fn read_maps_in(ns: &File) -> Result<(String, String)> {
+    let maps = [
+        CString::new("/proc/self/uid_map")?,
+        CString::new("/proc/self/gid_map")?,
+    ];
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(anyhow!(
+            "failed to create pipe: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let (mut reader, writer) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(anyhow!("failed to fork: {}", io::Error::last_os_error()));
+    }
+    if pid == 0 {
+        // Only async-signal-safe calls in the child: the maps are copied to
+        // the pipe, separated by a NUL byte.
+        unsafe {
+            if libc::setns(ns.as_raw_fd(), libc::CLONE_NEWUSER) != 0 {
+                libc::_exit(1);
+            }
+            let mut buf = [0u8; 4096];
+            for (i, map) in maps.iter().enumerate() {
+                let fd = libc::open(map.as_ptr(), libc::O_RDONLY);
+                if fd < 0 {
+                    libc::_exit(2);
+                }
+                loop {
+                    let n = libc::read(fd, buf.as_mut_ptr() as _, buf.len());
+                    if n < 0 {
+                        libc::_exit(2);
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                    if libc::write(
+                        writer.as_raw_fd(),
+                        buf.as_ptr() as _,
+                        n as _,
+                    ) != n
+                    {
+                        libc::_exit(3);
+                    }
+                }
+                if i == 0
+                    && libc::write(writer.as_raw_fd(), [0u8].as_ptr() as _, 1)
+                        != 1
+                {
+                    libc::_exit(3);
+                }
+            }
+            libc::_exit(0);
+        }
+    }
+
+    drop(writer);
+    let mut maps = String::new();
+    let read = reader.read_to_string(&mut maps);
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, 0) };
+    let code = match libc::WIFEXITED(status) {
+        true => libc::WEXITSTATUS(status),
+        false => -1,
+    };
+    match code {
+        0 => (),
+        1 => return Err(anyhow!("cannot join the user namespace")),
+        2 => return Err(anyhow!("cannot read the mappings")),
+        _ => return Err(anyhow!("child process failed")),
+    }
+    read?;
+    let (uids, gids) =
+        maps.split_once('\0').ok_or(anyhow!("truncated mappings"))?;
+    Ok((uids.to_string(), gids.to_string()))
+}
+
+/// Mapping of stored owner ids to shown owner ids.
+#[derive(Clone, Debug, Default)]
+pub struct IdMap {
+    /// Ranges of user ids. User ids are not shifted if empty.
+    pub uids: Vec<IdRange>,
+
+    /// Ranges of group ids. Group ids are not shifted if empty.
+    pub gids: Vec<IdRange>,
+}
+
+impl IdMap {
+    /// Mapping shifting all ids by fixed offsets.
+    ///
+    /// # Arguments
+    /// * `uid_offset` - Offset added to user ids.
+    /// * `gid_offset` - Offset added to group ids.
+    pub fn offsets(
+        uid_offset: Option<u32>,
+        gid_offset: Option<u32>,
+    ) -> Result<IdMap> {
+        let range = |offset: Option<u32>| -> Result<Vec<IdRange>> {
+            offset
+                .map(|o| IdRange::new(0, o, u32::MAX - o))
+                .into_iter()
+                .collect()
+        };
+        Ok(IdMap {
+            uids: range(uid_offset)?,
+            gids: range(gid_offset)?,
+        })
+    }
+
+    /// Read a mapping file, or the mappings of an OCI runtime spec.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file.
+    pub fn read(path: &String) -> Result<IdMap> {
+        let mut text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path))?;
+        let mut map = IdMap::default();
+        if text.trim_start().starts_with('{') {
+            let spec: OciSpec = serde_json::from_str(&text)
+                .with_context(|| format!("{}: bad runtime spec", path))?;
+            let ranges = |mappings: &[OciIdMapping]| {
+                mappings
+                    .iter()
+                    .map(|m| IdRange::new(m.container_id, m.host_id, m.size))
+                    .collect::<Result<Vec<_>>>()
+            };
+            map.uids = ranges(&spec.linux.uid_mappings)?;
+            map.gids = ranges(&spec.linux.gid_mappings)?;
+            text.clear();
+        }
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let name = format!("{}:{}", path, n + 1);
+            let (kind, range) = line.split_once(' ').unwrap_or((line, ""));
+            let ranges = parse_ranges(range, &name)?;
+            match kind {
+                "u" if ranges.len() == 1 => map.uids.extend(ranges),
+                "g" if ranges.len() == 1 => map.gids.extend(ranges),
+                _ => return Err(anyhow!("{}: invalid id range", name)),
+            }
+        }
+        check_overlaps(&map.uids)?;
+        check_overlaps(&map.gids)?;
+        Ok(map)
+    }
+
+    /// Mapping of a user namespace. Ids inside the namespace are stored
+    /// ids, shown as the ids outside.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the namespace, e.g. `/proc/PID/ns/user`.
+    pub fn from_userns(path: &String) -> Result<IdMap> {
+        let ns = File::open(path)
+            .with_context(|| format!("failed to open {}", path))?;
+        let ns_type = unsafe { libc::ioctl(ns.as_raw_fd(), NS_GET_NSTYPE) };
+        if ns_type != libc::CLONE_NEWUSER {
+            return Err(anyhow!("{} is not a user namespace", path));
+        }
+
+        // A process cannot join its own user namespace.
+        let own = fs::metadata("/proc/self/ns/user")?;
+        let meta = ns.metadata()?;
+        let (uids, gids) = if (own.dev(), own.ino()) == (meta.dev(), meta.ino())
+        {
+            (
+                fs::read_to_string("/proc/self/uid_map")?,
+                fs::read_to_string("/proc/self/gid_map")?,
+            )
+        } else {
+            read_maps_in(&ns).with_context(|| {
+                format!("failed to read id mappings of {}", path)
+            })?
+        };
+        let map = IdMap {
+            uids: parse_ranges(&uids, "uid_map")?,
+            gids: parse_ranges(&gids, "gid_map")?,
+        };
+        if map.uids.is_empty() {
+            return Err(anyhow!("user namespace {} has no id mappings", path));
+        }
+        Ok(map)
+    }
+
+    /// User id a stored user id is shown as.
+    ///
+    /// # Arguments
+    /// * `uid` - The stored user id.
+    pub fn uid(&self, uid: u32) -> u32 {
+        shift(&self.uids, uid, |r| r.first, |r| r.shifted)
+            .unwrap_or(OVERFLOW_ID)
+    }
+
+    /// Group id a stored group id is shown as.
+    ///
+    /// # Arguments
+    /// * `gid` - The stored group id.
+    pub fn gid(&self, gid: u32) -> u32 {
+        shift(&self.gids, gid, |r| r.first, |r| r.shifted)
+            .unwrap_or(OVERFLOW_ID)
+    }
+
+    /// Stored user id of a shown user id.
+    ///
+    /// # Arguments
+    /// * `uid` - The shown user id.
+    /// * `returns` - None if the id is outside the mapped ranges.
+    pub fn stored_uid(&self, uid: u32) -> Option<u32> {
+        shift(&self.uids, uid, |r| r.shifted, |r| r.first)
+    }
+
+    /// Stored group id of a shown group id.
+    ///
+    /// # Arguments
+    /// * `gid` - The shown group id.
+    /// * `returns` - None if the id is outside the mapped ranges.
+    pub fn stored_gid(&self, gid: u32) -> Option<u32> {
+        shift(&self.gids, gid, |r| r.shifted, |r| r.first)
+    }
+}
@@ -12,17 +12,57 @@
 //! the number of children.
 //!
 //! Indexes are serialized/deserialized using [bincode](https://crates.io/crates/bincode)
-//! which is a fast, compact binary format. Due to use of `serde` derive, use of many
-//! other formats (cbor, messagepack, postcard, json) is possible.
+//! by default, which is a fast, compact binary format. For consumption by other
+//! tooling, indexes can also be written as [cbor](https://crates.io/crates/serde_cbor),
+//! [json](https://crates.io/crates/serde_json) or [postcard](https://crates.io/crates/postcard).
+//! The format is recorded in the header of the index file.
+//!
+//! The inode table can optionally be stored front-coded: the path of each inode
+//! is stored as the length of the prefix it shares with the preceding inode's
+//! path, followed by the remaining suffix. Since consecutive inodes usually
+//! share long parent paths, this considerably reduces the size of indexes of
+//! deep trees. Paths are reconstructed when the index is loaded.
+//!
+//! Index files start with a 16-byte header holding a magic number, the version
+//! of the layout, the algorithm of the hash states (that of the layer digest),
+//! the serialization format
+//! and flags (see `Header`). The header is followed by a sha256 digest of the
+//! contents, which is checked whenever the index is loaded. Indexes written
+//! with a key additionally embed an HMAC-SHA256 of the digest, which is checked
+//! when mounting with the key. Similarly, indexes written with an Ed25519
+//! private key embed a signature of the header and digest, which is checked
+//! when mounting with the public key. Indexes of other versions, and indexes
+//! without header, are rejected.
+//!
+//! Index files can optionally be compressed with [zstd](https://crates.io/crates/zstd).
+//! Compression is recorded in the flags of the header, so either form can be
+//! used wherever an index is expected.
+//!
+//! The hash states can be split off into a states file next to the index, which
+//! is read on demand instead of being loaded with the index. The index records
+//! the name of the states file and the digest of each of its pages (see
+//! `hash::SplitStates`).
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::{anyhow, Result};
-use bincode::{deserialize_from, serialize_into};
+use anyhow::{anyhow, Context, Error, Result};
+use bincode::{deserialize, serialize_into};
+use clap::ValueEnum;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 
-use crate::hash::Hasher;
+use crate::digest::{self, Algorithm, Digest};
+use crate::hash::{HashMode, Hasher};
+use crate::lock;
 
 /// Type of an item in the file-system.
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
@@ -44,17 +84,61 @@ pub enum FileType {
     /// A character device.
     CharDevice,
 
+    /// A block device.
+    BlockDevice,
+
+    /// A named pipe.
+    Fifo,
+
     /// A directory.
     Directory,
 }
 
+/// Granularity at which the contents of a regular file are verified.
+#[derive(
+    Serialize,
+    Deserialize,
+    ValueEnum,
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+)]
+pub enum Granularity {
+    /// A hash state is saved after each page. Any page can be verified on
+    /// its own.
+    #[default]
+    Page,
+
+    /// Hash states are saved only at the start and end of the file. The whole
+    /// file is read to verify any part of it.
+    File,
+}
+
 /// Infrequent properties of an item. Usually specified using PAX extensions.
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Extra {
     pub link: String,
-    pub uname: String,
-    pub gname: String,
-    pub xattrs: Vec<(String, String)>,
+
+    /// Extended attributes as (name, value) pairs. Values may be binary.
+    pub xattrs: Vec<(String, Vec<u8>)>,
+
+    /// sha256 digest of the contents of a regular file, if recorded.
+    pub sha256: Option<[u8; 32]>,
+
+    /// Data regions of a sparse file as (offset, length) pairs, in order.
+    /// Only the data regions are stored in the tar file, one after another.
+    /// The rest of the file is holes, which read as zeros.
+    pub sparse: Option<Vec<(u64, u64)>>,
+
+    /// Major and minor numbers of a device, unless both are 0.
+    pub device: Option<(u32, u32)>,
+
+    /// Typeflag of the tar entry, if other than the usual one of the file
+    /// type. E.g. '7' for contiguous files.
+    pub typeflag: Option<u8>,
 }
 
 /// Index node (Inode) of an item in file system.
@@ -71,11 +155,23 @@ pub struct Inode {
     pub parent: String,
 
     // Stat fields.
-    pub size: u32,
+    pub size: u64,
     pub uid: u32,
     pub gid: u32,
     pub mode: u32,
-    pub mtime: u64,
+
+    /// Modification time in seconds since the epoch. Negative for times
+    /// before 1970.
+    pub mtime: i64,
+
+    /// Nanoseconds part of modification time.
+    pub mtime_nsec: u32,
+
+    /// Owner user name. Position in the index's string table.
+    pub uname: u32,
+
+    /// Owner group name. Position in the index's string table.
+    pub gname: u32,
 
     /// Infrequently occuring properties.
     pub extra: Option<Extra>,
@@ -86,6 +182,9 @@ pub struct Inode {
     /// Index of starting hash state.
     pub hash_index: u32,
 
+    /// Granularity of the hash states saved for a regular file.
+    pub granularity: Granularity,
+
     /// Inode number of first child.
     pub child_inode: u32,
 
@@ -100,14 +199,100 @@ pub struct Inode {
     pub depth: u16,
 
     /// Number of hard links to this inode.
-    pub links: u16,
+    pub links: u32,
 
     /// Inode number of hard-link target.
     pub target_ino: u32,
+
+    /// Layer the inode comes from in a union of layers, top layer first.
+    /// Not stored in index files.
+    #[serde(skip)]
+    pub layer: u16,
 }
 
 /// Implementation.
 impl Inode {
+    /// Number of hash states saved after the start of a regular file.
+    pub fn num_states(&self) -> u32 {
+        let size = self.stored_size();
+        match self.granularity {
+            Granularity::Page => size.div_ceil(4096) as u32,
+            Granularity::File => (size > 0) as u32,
+        }
+    }
+
+    /// Major and minor numbers of a device.
+    pub fn device(&self) -> (u32, u32) {
+        self.extra.as_ref().and_then(|e| e.device).unwrap_or((0, 0))
+    }
+
+    /// Data regions of a sparse file. None unless the file is sparse.
+    pub fn sparse_map(&self) -> Option<&Vec<(u64, u64)>> {
+        self.extra.as_ref().and_then(|e| e.sparse.as_ref())
+    }
+
+    /// Number of bytes of the contents stored in the tar file. Less than the
+    /// size for sparse files, whose holes are not stored.
+    pub fn stored_size(&self) -> u64 {
+        match self.sparse_map() {
+            Some(map) => map.iter().map(|(_, len)| len).sum(),
+            None => self.size,
+        }
+    }
+
+    /// Map an offset within the file to an offset within the stored
+    /// contents. Offsets within holes map to the start of the following
+    /// data region.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset within the file.
+    pub fn stored_offset(&self, offset: u64) -> u64 {
+        match self.sparse_map() {
+            Some(map) => map
+                .iter()
+                .map(|(start, len)| offset.saturating_sub(*start).min(*len))
+                .sum(),
+            None => offset,
+        }
+    }
+
+    /// Expand stored contents of a sparse file into the contents of a range
+    /// of the file. Holes are filled with zeros.
+    ///
+    /// # Arguments
+    /// * `offset` - Start of the range within the file.
+    /// * `end` - End of the range within the file.
+    /// * `stored` - Stored contents from `stored_offset(offset)` to
+    ///   `stored_offset(end)`.
+    pub fn fill_holes(&self, offset: u64, end: u64, stored: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; (end - offset) as usize];
+        let first = self.stored_offset(offset);
+        let mut pos = 0;
+        for (start, len) in self.sparse_map().into_iter().flatten() {
+            // Part of the data region within the range.
+            let (from, to) = ((*start).max(offset), (start + len).min(end));
+            if from < to {
+                let src = (pos + from - start - first) as usize;
+                let dst = (from - offset) as usize;
+                let n = (to - from) as usize;
+                buf[dst..dst + n].copy_from_slice(&stored[src..src + n]);
+            }
+            pos += len;
+        }
+        buf
+    }
+
+    /// Add a link to the inode.
+    ///
+    /// # Arguments
+    /// * `returns` - Error if the link count would overflow.
+    fn add_link(&mut self) -> Result<()> {
+        self.links = self.links.checked_add(1).ok_or_else(|| {
+            anyhow!("too many links to {}{}", self.parent, self.name)
+        })?;
+        Ok(())
+    }
+
     /// Check whether the inode has given path.
     pub fn path_eq(&self, path: &String) -> bool {
         // Unless the path is "/", remove trailing '/'.
@@ -124,14 +309,435 @@ impl Inode {
     }
 }
 
+/// Storage mode of the inode table in an index file.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Store inodes as is.
+    Plain,
+
+    /// Front-code the path of each inode against the preceding inode.
+    FrontCoded,
+}
+
+/// Serialization format of an index file.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// [bincode](https://crates.io/crates/bincode).
+    Bincode = 0,
+
+    /// [cbor](https://cbor.io).
+    Cbor = 1,
+
+    /// [json](https://www.json.org).
+    Json = 2,
+
+    /// [postcard](https://crates.io/crates/postcard).
+    Postcard = 3,
+}
+
+/// Magic number at the start of index files.
+const MAGIC: [u8; 8] = *b"ccfs-idx";
+
+/// Version of the layout of index files. Must be incremented whenever the
+/// layout of the serialized contents changes.
+const VERSION: u16 = 14;
+
+/// Length of the header of index files.
+const HEADER_LEN: usize = 16;
+
+/// Flag marking contents compressed with zstd.
+const FLAG_ZSTD: u32 = 1;
+
+/// Flag marking a sha256 digest of the contents following the header.
+const FLAG_DIGEST: u32 = 2;
+
+/// Flag marking an HMAC-SHA256 of the header and the digest of the contents
+/// following the header. Since the digest binds the contents, the HMAC
+/// authenticates them.
+const FLAG_HMAC: u32 = 4;
+
+/// Flag marking an Ed25519 signature of the header and the digest of the
+/// contents.
+const FLAG_SIGNATURE: u32 = 8;
+
+/// Flag marking the Merkle tree root of the hash states followed by the layer
+/// digest, both as raw bytes of the length of the layer digest, at the end of
+/// the header. Being part of the header, they are covered by the HMAC and the
+/// signature, which thereby bind the root to the layer digest.
+const FLAG_ROOT: u32 = 16;
+
+/// Length of the digest and HMAC following the header.
+const MAC_LEN: usize = 32;
+
+/// Length of the signature following the digest and HMAC.
+const SIGNATURE_LEN: usize = 64;
+
+/// Keys for authenticating index files.
+#[derive(Default, Clone)]
+pub struct Keys {
+    /// Secret key for the HMAC. An index written with the key embeds an
+    /// HMAC; an index read with the key must embed a matching HMAC.
+    pub hmac: Option<Vec<u8>>,
+
+    /// Ed25519 key to sign indexes with when writing them.
+    pub signing: Option<SigningKey>,
+
+    /// Ed25519 public key the signature of indexes must verify with when
+    /// reading them.
+    pub verifying: Option<VerifyingKey>,
+}
+
+/// Writer that computes the digest of the bytes written through it.
+struct DigestWriter<W: Write> {
+    /// Where to write to.
+    inner: W,
+
+    /// Digest of the bytes written so far.
+    sha: Sha256,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.sha.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read a key for authenticating indexes.
+///
+/// # Arguments
+/// * `path` - Path of the file holding the key. Its bytes are the key.
+pub fn read_key(path: &Option<String>) -> Result<Option<Vec<u8>>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let key = fs::read(path)
+        .with_context(|| format!("failed to read key {}", path))?;
+    if key.is_empty() {
+        return Err(anyhow!("key {} is empty", path));
+    }
+    Ok(Some(key))
+}
+
+/// Read an Ed25519 private key for signing indexes.
+///
+/// # Arguments
+/// * `path` - Path of the key in PKCS#8 PEM format, e.g. as produced by
+///   `openssl genpkey -algorithm ed25519`.
+pub fn read_signing_key(path: &Option<String>) -> Result<Option<SigningKey>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let pem = fs::read_to_string(path)
+        .with_context(|| format!("failed to read key {}", path))?;
+    let key = SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|e| anyhow!("invalid ed25519 private key {}: {}", path, e))?;
+    Ok(Some(key))
+}
+
+/// Read an Ed25519 public key for verifying signatures of indexes.
+///
+/// # Arguments
+/// * `path` - Path of the key in SPKI PEM format, e.g. as produced by
+///   `openssl pkey -pubout`.
+pub fn read_verifying_key(
+    path: &Option<String>,
+) -> Result<Option<VerifyingKey>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let pem = fs::read_to_string(path)
+        .with_context(|| format!("failed to read key {}", path))?;
+    let key = VerifyingKey::from_public_key_pem(&pem)
+        .map_err(|e| anyhow!("invalid ed25519 public key {}: {}", path, e))?;
+    Ok(Some(key))
+}
+
+/// Header at the start of index files.
+///
+/// Layout: magic (8 bytes), version (u16), algorithm of the hash states (u8),
+/// serialization format (u8), flags (u32) and, with `FLAG_ROOT`, the Merkle
+/// tree root and the layer digest. Integers are little endian.
+struct Header {
+    /// Algorithm of the hash states.
+    algorithm: Algorithm,
+
+    /// Serialization format of the contents.
+    format: Format,
+
+    /// Flags, e.g. `FLAG_ZSTD`.
+    flags: u32,
+
+    /// Merkle tree root of the hash states. Empty without `FLAG_ROOT`.
+    root: Vec<u8>,
+
+    /// Digest of the layer the tree was computed over. Empty without
+    /// `FLAG_ROOT`.
+    layer_digest: Vec<u8>,
+}
+
+/// Digest, HMAC and signature following the header of an index file.
+struct Macs<'a> {
+    /// The header, including the Merkle tree root and the layer digest.
+    header: &'a [u8],
+
+    /// sha256 digest of the contents.
+    digest: Option<&'a [u8]>,
+
+    /// HMAC-SHA256 of the digest of the contents.
+    hmac: Option<&'a [u8]>,
+
+    /// Ed25519 signature of the header and the digest of the contents.
+    signature: Option<&'a [u8]>,
+
+    /// The contents.
+    contents: &'a [u8],
+}
+
+impl Header {
+    /// Length of the header together with the digest, HMAC and signature
+    /// following it.
+    fn len(&self) -> usize {
+        self.root.len() + self.layer_digest.len() + self.macs_len()
+    }
+
+    /// Length of the fixed part of the header together with the digest, HMAC
+    /// and signature.
+    fn macs_len(&self) -> usize {
+        [
+            (FLAG_DIGEST, MAC_LEN),
+            (FLAG_HMAC, MAC_LEN),
+            (FLAG_SIGNATURE, SIGNATURE_LEN),
+        ]
+        .iter()
+        .filter(|(f, _)| self.flags & f != 0)
+        .map(|(_, len)| len)
+        .sum::<usize>()
+            + HEADER_LEN
+    }
+
+    /// Split the bytes following the header into digest, HMAC, signature and
+    /// contents.
+    ///
+    /// # Arguments
+    /// * `bytes` - Contents of the index file, including the header.
+    /// * `path` - Path of index file. Used in error messages.
+    fn split<'a>(&self, bytes: &'a [u8], path: &String) -> Result<Macs<'a>> {
+        if bytes.len() < self.len() {
+            return Err(anyhow!("{}: truncated index header", path));
+        }
+        let header_len = HEADER_LEN + self.root.len() + self.layer_digest.len();
+        let mut rest = &bytes[header_len..];
+        let mut take = |flag, len| match self.flags & flag {
+            0 => None,
+            _ => {
+                let (mac, r) = rest.split_at(len);
+                rest = r;
+                Some(mac)
+            }
+        };
+        let digest = take(FLAG_DIGEST, MAC_LEN);
+        let hmac = take(FLAG_HMAC, MAC_LEN);
+        let signature = take(FLAG_SIGNATURE, SIGNATURE_LEN);
+        Ok(Macs {
+            header: &bytes[..header_len],
+            digest,
+            hmac,
+            signature,
+            contents: rest,
+        })
+    }
+
+    /// Serialize the header. The current version is written.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; HEADER_LEN];
+        bytes[..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[10] = match self.algorithm {
+            Algorithm::Sha256 => 1,
+            Algorithm::Sha512 => 2,
+            Algorithm::Sha384 => 3,
+        };
+        bytes[11] = self.format as u8;
+        bytes[12..].copy_from_slice(&self.flags.to_le_bytes());
+        bytes.extend_from_slice(&self.root);
+        bytes.extend_from_slice(&self.layer_digest);
+        bytes
+    }
+
+    /// Parse the header of an index file.
+    ///
+    /// # Arguments
+    /// * `bytes` - Contents of the index file.
+    /// * `path` - Path of index file. Used in error messages.
+    fn parse(bytes: &[u8], path: &String) -> Result<Header> {
+        if !bytes.starts_with(&MAGIC) {
+            return Err(anyhow!(
+                "{}: not an index, or an index written by an older version \
+                 of cc-fs; re-create the index",
+                path
+            ));
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(anyhow!("{}: truncated index header", path));
+        }
+
+        let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+        if version != VERSION {
+            return Err(anyhow!(
+                "{}: index version {} is not supported, expected version {}; \
+                 re-create the index with this version of cc-fs",
+                path,
+                version,
+                VERSION
+            ));
+        }
+        let algorithm = match bytes[10] {
+            1 => Algorithm::Sha256,
+            2 => Algorithm::Sha512,
+            3 => Algorithm::Sha384,
+            a => return Err(anyhow!("{}: unknown hash algorithm {}", path, a)),
+        };
+        let format = match bytes[11] {
+            0 => Format::Bincode,
+            1 => Format::Cbor,
+            2 => Format::Json,
+            3 => Format::Postcard,
+            f => return Err(anyhow!("{}: unknown index format {}", path, f)),
+        };
+        let flags =
+            u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        let known =
+            FLAG_ZSTD | FLAG_DIGEST | FLAG_HMAC | FLAG_SIGNATURE | FLAG_ROOT;
+        if flags & !known != 0 {
+            return Err(anyhow!("{}: unknown index flags {:#x}", path, flags));
+        }
+        let len = match flags & FLAG_ROOT {
+            0 => 0,
+            _ => algorithm.hex_len() / 2,
+        };
+        let (root, layer_digest) = bytes
+            .get(HEADER_LEN..HEADER_LEN + 2 * len)
+            .ok_or_else(|| anyhow!("{}: truncated index header", path))?
+            .split_at(len);
+        Ok(Header {
+            algorithm,
+            format,
+            flags,
+            root: root.to_vec(),
+            layer_digest: layer_digest.to_vec(),
+        })
+    }
+}
+
+/// Compression of an index file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Not compressed.
+    None,
+
+    /// Compressed with zstd at the given level.
+    Zstd(i32),
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    /// Parse `none`, `zstd` or `zstd:<level>`.
+    fn from_str(s: &str) -> Result<Compression> {
+        let level = match s.split_once(':') {
+            None if s == "none" => return Ok(Compression::None),
+            None if s == "zstd" => zstd::DEFAULT_COMPRESSION_LEVEL,
+            Some(("zstd", level)) => level
+                .parse()
+                .map_err(|_| anyhow!("invalid zstd level {}", level))?,
+            _ => return Err(anyhow!("unsupported compression {}", s)),
+        };
+        let range = zstd::compression_level_range();
+        if !range.contains(&level) {
+            return Err(anyhow!(
+                "zstd level {} is not in {}..={}",
+                level,
+                range.start(),
+                range.end()
+            ));
+        }
+        Ok(Compression::Zstd(level))
+    }
+}
+
+/// An inode whose path has been front-coded.
+///
+/// The `parent` of the inode is empty and the `name` holds the suffix of the
+/// path that is not shared with the preceding inode.
+#[derive(Serialize, Deserialize)]
+struct CodedInode {
+    /// Length of prefix shared with the path of the preceding inode.
+    shared: u32,
+
+    /// Length of the parent part of the path.
+    parent_len: u32,
+
+    /// The inode.
+    inode: Inode,
+}
+
+/// Inode table as written to an index file.
+#[derive(Serialize)]
+enum InodeTableRef<'a> {
+    Plain(&'a [Inode]),
+    FrontCoded(Vec<CodedInode>),
+}
+
+/// Inode table as read from an index file.
+/// Must mirror the layout of `InodeTableRef`.
+#[derive(Deserialize)]
+enum InodeTable {
+    Plain(Vec<Inode>),
+    FrontCoded(Vec<CodedInode>),
+}
+
+/// Layout of an index file for writing.
+#[derive(Serialize)]
+struct IndexFileRef<'a> {
+    inodes: InodeTableRef<'a>,
+    names: &'a [String],
+    hasher: &'a Hasher,
+}
+
+/// Layout of an index file for reading.
+/// Must mirror the layout of `IndexFileRef`.
+#[derive(Deserialize)]
+struct IndexFile {
+    inodes: InodeTable,
+    names: Vec<String>,
+    hasher: Hasher,
+}
+
 /// Index of a confidential container file-system.
-#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[derive(Default, Debug, Clone)]
 pub struct Index {
     /// List of inodes.
     pub inodes: Vec<Inode>,
 
+    /// String table of user and group names referenced by inodes.
+    /// The first entry is the empty string.
+    pub names: Vec<String>,
+
     /// Hasher instance for integrity verification.
     pub hasher: Hasher,
+
+    /// Entries replaced by later entries with the same path. Filled by
+    /// `process`; not part of the file-system.
+    pub replaced: Vec<Inode>,
 }
 
 /// Implemenation of Index.
@@ -141,34 +747,442 @@ impl Index {
     /// # Arguments
     /// * `hint_num_inodes` - Reserve memory for so many inodes.
     /// * `hint_num_states` - Estimated number of intermediate hash states.
-    pub fn new(hint_num_inodes: u32, hint_num_states: u32) -> Result<Index> {
+    /// * `hash_mode` - What the hasher saves at each save point.
+    /// * `algorithm` - Algorithm of the layer digest and hash states.
+    /// * `measure_key` - Key of keyed (HMAC) measurements.
+    pub fn new(
+        hint_num_inodes: u32,
+        hint_num_states: u32,
+        hash_mode: HashMode,
+        algorithm: Algorithm,
+        measure_key: &Option<Vec<u8>>,
+    ) -> Result<Index> {
         Ok(Index {
             inodes: Vec::<Inode>::with_capacity(hint_num_inodes as usize),
-            hasher: Hasher::new(hint_num_states)?,
+            names: vec![String::new()],
+            hasher: Hasher::new(
+                hint_num_states,
+                hash_mode,
+                algorithm,
+                measure_key,
+            )?,
+            replaced: vec![],
         })
     }
 
-    /// Write the index to given file. Overwrites existing file.
+    /// Write the index to given file. Replaces existing file atomically.
     ///
     /// # Arguments
     /// * `path` - Path of file to write.
+    /// * `storage` - Storage mode of the inode table.
+    /// * `format` - Serialization format.
+    /// * `compression` - Compression of the file.
+    /// * `keys` - Keys to authenticate the index with. An HMAC and a
+    ///   signature are embedded in addition to the digest if the respective
+    ///   key is supplied.
     /// * `returns` - Number of bytes written.
-    pub fn to_file(&self, path: &String) -> Result<u64> {
-        let file = &File::create(path)?;
-        serialize_into(BufWriter::new(file), self)?;
-        Ok(file.metadata().unwrap().len())
+    pub fn to_file(
+        &self,
+        path: &String,
+        storage: Storage,
+        format: Format,
+        compression: Compression,
+        keys: &Keys,
+    ) -> Result<u64> {
+        let inodes = match storage {
+            Storage::Plain => InodeTableRef::Plain(&self.inodes),
+            Storage::FrontCoded => {
+                InodeTableRef::FrontCoded(Index::front_code(&self.inodes))
+            }
+        };
+        let contents = IndexFileRef {
+            inodes,
+            names: &self.names,
+            hasher: &self.hasher,
+        };
+        let root = digest::from_hex(&self.hasher.root).ok_or_else(|| {
+            anyhow!("invalid merkle root {}", self.hasher.root)
+        })?;
+        let layer_digest = match root.is_empty() {
+            true => vec![],
+            false => {
+                digest::from_hex(&self.hasher.digest).ok_or_else(|| {
+                    anyhow!("invalid layer digest {}", self.hasher.digest)
+                })?
+            }
+        };
+
+        // Refuse to replace an index that is locked by a mount.
+        if let Ok(existing) = File::open(path) {
+            lock::lock(&existing, path, true)?;
+        }
+
+        // Write to a temporary file in the same directory and rename it into
+        // place, so that a partially written index is never observed.
+        let tmp_path = format!("{}.tmp", path);
+        let result = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path))
+            .and_then(|file| {
+                let header = Header {
+                    algorithm: self.hasher.algorithm,
+                    format,
+                    flags: FLAG_DIGEST
+                        | match compression {
+                            Compression::None => 0,
+                            Compression::Zstd(_) => FLAG_ZSTD,
+                        }
+                        | match keys.hmac {
+                            Some(_) => FLAG_HMAC,
+                            None => 0,
+                        }
+                        | match keys.signing {
+                            Some(_) => FLAG_SIGNATURE,
+                            None => 0,
+                        }
+                        | match root.is_empty() {
+                            true => 0,
+                            false => FLAG_ROOT,
+                        },
+                    root,
+                    layer_digest,
+                };
+
+                // Leave room for the digest, HMAC and signature, which are
+                // filled in once the contents have been written.
+                let header_bytes = header.to_bytes();
+                let mut buffered = BufWriter::new(&file);
+                buffered.write_all(&header_bytes)?;
+                buffered
+                    .write_all(&vec![0u8; header.macs_len() - HEADER_LEN])?;
+                let mut writer = DigestWriter {
+                    inner: buffered,
+                    sha: Sha256::new(),
+                };
+                match compression {
+                    Compression::None => {
+                        Index::serialize(&mut writer, &contents, format)?
+                    }
+                    Compression::Zstd(level) => {
+                        let mut encoder =
+                            zstd::Encoder::new(&mut writer, level)?;
+                        Index::serialize(&mut encoder, &contents, format)?;
+                        encoder.finish()?;
+                    }
+                }
+                writer.flush()?;
+                let digest = writer.sha.finalize();
+                drop(writer.inner);
+
+                let mut macs = digest.to_vec();
+                let message = [&header_bytes[..], &digest].concat();
+                if let Some(key) = &keys.hmac {
+                    macs.extend(Index::hmac(key, &message)?);
+                }
+                if let Some(key) = &keys.signing {
+                    macs.extend(key.sign(&message).to_bytes());
+                }
+                file.write_all_at(&macs, header_bytes.len() as u64)?;
+                Ok(file.metadata()?.len())
+            })
+            .and_then(|bytes| {
+                fs::rename(&tmp_path, path).with_context(|| {
+                    format!("failed to rename {} to {}", tmp_path, path)
+                })?;
+                Ok(bytes)
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Compute the HMAC of the header and the digest of the contents of an
+    /// index file.
+    ///
+    /// # Arguments
+    /// * `key` - The key.
+    /// * `message` - The header followed by the sha256 digest of the contents.
+    fn hmac(key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key)?;
+        hmac.update(message);
+        Ok(hmac.finalize().into_bytes().to_vec())
+    }
+
+    /// Serialize the contents of an index file.
+    ///
+    /// # Arguments
+    /// * `writer` - Where to write to.
+    /// * `contents` - The contents.
+    /// * `format` - Serialization format.
+    fn serialize<W: Write>(
+        writer: W,
+        contents: &IndexFileRef,
+        format: Format,
+    ) -> Result<()> {
+        match format {
+            Format::Bincode => serialize_into(writer, contents)?,
+            Format::Cbor => serde_cbor::to_writer(writer, contents)?,
+            Format::Json => serde_json::to_writer(writer, contents)?,
+            Format::Postcard => {
+                postcard::to_io(contents, writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Front-code the paths of given inodes.
+    ///
+    /// # Arguments
+    /// * `inodes` - Inodes to encode.
+    fn front_code(inodes: &[Inode]) -> Vec<CodedInode> {
+        let mut coded = Vec::with_capacity(inodes.len());
+        let mut prev = String::new();
+        for inode in inodes {
+            let path = inode.parent.clone() + &inode.name;
+
+            // Find length of the common prefix, at a character boundary.
+            let mut shared = path
+                .bytes()
+                .zip(prev.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            while !path.is_char_boundary(shared) {
+                shared -= 1;
+            }
+
+            coded.push(CodedInode {
+                shared: shared as u32,
+                parent_len: inode.parent.len() as u32,
+                inode: Inode {
+                    name: path[shared..].to_string(),
+                    parent: String::new(),
+                    ..inode.clone()
+                },
+            });
+            prev = path;
+        }
+        coded
+    }
+
+    /// Reconstruct inodes from front-coded inodes.
+    ///
+    /// # Arguments
+    /// * `coded` - Front-coded inodes.
+    fn front_decode(coded: Vec<CodedInode>) -> Result<Vec<Inode>> {
+        let mut inodes = Vec::<Inode>::with_capacity(coded.len());
+        let mut prev = String::new();
+        for c in coded {
+            let (shared, parent_len) =
+                (c.shared as usize, c.parent_len as usize);
+            if shared > prev.len() || !prev.is_char_boundary(shared) {
+                return Err(anyhow!("corrupt front-coded inode table"));
+            }
+            let path = prev[0..shared].to_string() + &c.inode.name;
+            if parent_len > path.len() || !path.is_char_boundary(parent_len) {
+                return Err(anyhow!("corrupt front-coded inode table"));
+            }
+
+            inodes.push(Inode {
+                parent: path[0..parent_len].to_string(),
+                name: path[parent_len..].to_string(),
+                ..c.inode
+            });
+            prev = path;
+        }
+        Ok(inodes)
+    }
+
+    /// Check the embedded digest, HMAC and signature of an index file.
+    ///
+    /// # Arguments
+    /// * `macs` - Header, digest, HMAC, signature and contents of the index
+    ///   file.
+    /// * `keys` - Keys the index must be authenticated with, if any.
+    /// * `path` - Path of index file. Used in error messages.
+    fn authenticate(macs: &Macs, keys: &Keys, path: &String) -> Result<()> {
+        if macs.digest.is_none()
+            && keys.hmac.is_none()
+            && keys.verifying.is_none()
+        {
+            return Ok(());
+        }
+
+        let digest = Sha256::digest(macs.contents);
+        if macs.digest.is_some_and(|d| d != &digest[..]) {
+            return Err(anyhow!(
+                "{}: index digest mismatch, corrupt index",
+                path
+            ));
+        }
+        let message = [macs.header, &digest].concat();
+        match (&keys.hmac, macs.hmac) {
+            (None, _) => (),
+            (Some(_), None) => {
+                return Err(anyhow!("{}: index is not authenticated", path))
+            }
+            (Some(key), Some(hmac)) => {
+                let mut expected = Hmac::<Sha256>::new_from_slice(key)?;
+                expected.update(&message);
+                expected.verify_slice(hmac).map_err(|_| {
+                    anyhow!("{}: index authentication failed", path)
+                })?;
+            }
+        }
+        match (&keys.verifying, macs.signature) {
+            (None, _) => (),
+            (Some(_), None) => {
+                return Err(anyhow!("{}: index is not signed", path))
+            }
+            (Some(key), Some(signature)) => {
+                let signature = Signature::from_slice(signature)?;
+                key.verify_strict(&message, &signature).map_err(|_| {
+                    anyhow!("{}: index signature verification failed", path)
+                })?;
+            }
+        }
+        Ok(())
     }
 
     /// Read index from given file.
     ///
     /// # Arguments
     /// * `path` - Path of index file.
-    pub fn from_file(path: &String) -> Result<Index> {
-        let mut index: Index =
-            deserialize_from(&mut BufReader::new(&File::open(path)?))?;
+    /// * `digest` - Expected digest of the index file's bytes.
+    ///   If supplied, the index is rejected unless the digest matches.
+    /// * `keys` - Keys the index must be authenticated with.
+    pub fn from_file(
+        path: &String,
+        digest: &Option<Digest>,
+        keys: &Keys,
+    ) -> Result<Index> {
+        let mut file = File::open(path)
+            .with_context(|| format!("failed to open {}", path))?;
+        Index::read(&mut file, path, digest, keys)
+    }
+
+    /// Read index from an open file.
+    ///
+    /// # Arguments
+    /// * `file` - The open index file.
+    /// * `path` - Path of index file. Used in error messages.
+    /// * `digest` - Expected digest of the index file's bytes.
+    ///   If supplied, the index is rejected unless the digest matches.
+    /// * `keys` - Keys the index must be authenticated with, if any.
+    pub fn read(
+        file: &mut File,
+        path: &String,
+        digest: &Option<Digest>,
+        keys: &Keys,
+    ) -> Result<Index> {
+        // Read the file once. The digest and authentication checks below and
+        // deserialization must all see the same bytes.
+        let mut data = vec![];
+        file.read_to_end(&mut data)
+            .with_context(|| format!("failed to read {}", path))?;
+
+        // Check the index against the pinned digest before trusting it.
+        if let Some(digest) = digest {
+            let computed = Digest::compute(digest.algorithm, &data);
+            if computed.ne(digest) {
+                return Err(anyhow!(
+                    "{}: Computed index digest {} != supplied digest {}",
+                    path,
+                    computed.hex,
+                    digest.hex
+                ));
+            }
+        }
+
+        let header = Header::parse(&data, path)?;
+        let macs = header.split(&data, path)?;
+        Index::authenticate(&macs, keys, path)?;
+
+        let mut bytes = Cow::Borrowed(macs.contents);
+        if header.flags & FLAG_ZSTD != 0 {
+            bytes =
+                Cow::Owned(zstd::decode_all(&bytes[..]).with_context(
+                    || format!("failed to decompress {}", path),
+                )?);
+        }
+
+        let contents: Result<IndexFile> = match header.format {
+            Format::Bincode => deserialize(&bytes).map_err(Error::from),
+            Format::Cbor => serde_cbor::from_slice(&bytes).map_err(Error::from),
+            Format::Json => serde_json::from_slice(&bytes).map_err(Error::from),
+            Format::Postcard => {
+                postcard::from_bytes(&bytes).map_err(Error::from)
+            }
+        };
+        let contents =
+            contents.with_context(|| format!("{}: corrupt index", path))?;
+        drop(bytes);
+        drop(data);
+        let mut index = Index {
+            inodes: match contents.inodes {
+                InodeTable::Plain(inodes) => inodes,
+                InodeTable::FrontCoded(coded) => Index::front_decode(coded)?,
+            },
+            names: contents.names,
+            hasher: contents.hasher,
+            replaced: vec![],
+        };
+        if index.hasher.algorithm != header.algorithm {
+            return Err(anyhow!(
+                "{}: corrupt index: {} header but {} hash states",
+                path,
+                header.algorithm.name(),
+                index.hasher.algorithm.name()
+            ));
+        }
+
+        // Owner names are positions in the string table.
+        let names = index.names.len();
+        if index
+            .inodes
+            .iter()
+            .any(|i| i.uname as usize >= names || i.gname as usize >= names)
+        {
+            return Err(anyhow!(
+                "{}: corrupt index: owner name out of range",
+                path
+            ));
+        }
+
+        // States files are found next to the index.
+        let dir = match Path::new(path).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        index.hasher.open_states(dir)?;
+
+        // Merkle indexes record the root and the layer digest in the
+        // authenticated header too. The leaves must hash to the root, so that
+        // a page is never verified against a leaf that is not part of the
+        // tree.
+        let merkle = index.hasher.mode == HashMode::Merkle;
+        if merkle != (header.flags & FLAG_ROOT != 0)
+            || digest::to_hex(&header.root) != index.hasher.root
+        {
+            return Err(anyhow!(
+                "{}: corrupt index: merkle root does not match the header",
+                path
+            ));
+        }
+        if merkle && digest::to_hex(&header.layer_digest) != index.hasher.digest
+        {
+            return Err(anyhow!(
+                "{}: corrupt index: layer digest does not match the header",
+                path
+            ));
+        }
+        index
+            .hasher
+            .check_root()
+            .with_context(|| format!("{}: corrupt index", path))?;
 
         // Give up an extra reserved memory.
         index.hasher.shrink_to_fit();
+        index.names.shrink_to_fit();
         index.inodes.shrink_to_fit();
         Ok(index)
     }
@@ -282,13 +1296,38 @@ impl Index {
     /// Processing involves the following steps.
     ///  - Sort inodes in lexicographical order of depth, parent length, parent
     ///    and name.
+    ///  - Drop all but the last occurrence of each path, as `tar -x` does.
     ///  - For each directory inode, find the index of the first child, as
     ///    well as the number of children.
+    ///  - Set the link count of each directory to 2 plus the number of
+    ///    subdirectories.
     ///  - For each hard-link, increment the link count of the target and hold
     pub fn process(&mut self) -> Result<()> {
-        // Sort the inodes.
+        // Sort the inodes. The sort is stable, so several entries with the
+        // same path, e.g. from appended archives, stay in archive order.
         self.inodes.sort_by(Index::cmp_inodes);
 
+        // Later entries replace earlier ones. The two root nodes are kept.
+        let mut entries = self.inodes.split_off(2);
+        entries.dedup_by(|later, kept| {
+            let same = Index::cmp_inodes(later, kept) == Ordering::Equal;
+            if same {
+                mem::swap(later, kept);
+                self.replaced.push(later.clone());
+            }
+            same
+        });
+        self.inodes.append(&mut entries);
+
+        // Directories have links for '.' and '..'. Other items have a single
+        // link unless hard links to them are found.
+        for inode in self.inodes.iter_mut() {
+            inode.links = match inode.typeflag {
+                FileType::Directory => 2,
+                _ => 1,
+            };
+        }
+
         // Start with the root node as the current parent.
         let mut cur_parent = 1;
         // The child of the root node immediately follows it.
@@ -304,6 +1343,19 @@ impl Index {
                 // in the slice preceeding the current node.
                 cur_parent = self.find(&self.inodes[i].parent, 1, i)?;
 
+                // Only directories have children. Anything else, e.g. a
+                // symlink, would redirect its children elsewhere.
+                if !matches!(
+                    self.inodes[cur_parent].typeflag,
+                    FileType::Directory
+                ) {
+                    return Err(anyhow!(
+                        "parent of {}{} is not a directory",
+                        self.inodes[i].parent,
+                        self.inodes[i].name
+                    ));
+                }
+
                 // Assert that the parent's child has not been determined.
                 assert!(self.inodes[cur_parent].child_inode == 0);
 
@@ -313,21 +1365,20 @@ impl Index {
 
             // Increment child count.
             self.inodes[cur_parent].num_children += 1;
-        }
 
-        // Set number of links of root node to 2 ('.' and '..')
-        self.inodes[1].links = 2;
+            // Each subdirectory links to its parent via '..'.
+            if let FileType::Directory = self.inodes[i].typeflag {
+                self.inodes[cur_parent].add_link()?;
+            }
+        }
 
         // Process each hard link.
         for i in 2..self.inodes.len() as u32 {
-            // Set number of links to 1.
-            self.inodes[i as usize].links = 1;
-
             // If this inode is a hard-link, fetch the target.
             let ino = self.get_hard_link_target(i);
             if ino > 0 && ino != i {
                 // Increment link count of the target.
-                self.inodes[ino as usize].links += 1;
+                self.inodes[ino as usize].add_link()?;
 
                 // Use the child_inode field to point to target.
                 self.inodes[i as usize].target_ino = ino;
@@ -337,3 +1388,185 @@ impl Index {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An unprocessed index of the two root nodes followed by entries.
+    ///
+    /// # Arguments
+    /// * `entries` - The entries.
+    fn index_of(entries: Vec<Inode>) -> Index {
+        let mut index =
+            Index::new(0, 0, HashMode::Chained, Algorithm::Sha256, &None)
+                .unwrap();
+        let root = Inode {
+            typeflag: FileType::Directory,
+            name: String::from("/"),
+            ..Inode::default()
+        };
+        index.inodes = vec![root.clone(), root];
+        index.inodes.extend(entries);
+        index
+    }
+
+    /// An entry at a path.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the entry, e.g. `/etc/passwd`.
+    /// * `typeflag` - Type of the entry.
+    /// * `link` - Target of a hard link, or empty.
+    fn entry(path: &str, typeflag: FileType, link: &str) -> Inode {
+        let (parent, name) = path.rsplit_once('/').unwrap();
+        let parent = parent.to_string() + "/";
+        let extra = match link {
+            "" => None,
+            _ => Some(Extra {
+                link: link.to_string(),
+                ..Extra::default()
+            }),
+        };
+        Inode {
+            typeflag,
+            name: name.to_string(),
+            depth: (parent.split('/').count() - 1) as u16,
+            parent,
+            extra,
+            ..Inode::default()
+        }
+    }
+
+    /// Link count of the inode at a path of a processed index.
+    ///
+    /// # Arguments
+    /// * `index` - The index.
+    /// * `path` - Path of the inode.
+    fn links(index: &Index, path: &str) -> u32 {
+        let ino = index.find(&path.to_string(), 1, index.inodes.len());
+        index.inodes[ino.unwrap()].links
+    }
+
+    #[test]
+    fn process_counts_links() {
+        let mut index = index_of(vec![
+            entry("/d", FileType::Directory, ""),
+            entry("/d/e", FileType::Directory, ""),
+            entry("/d/f", FileType::Directory, ""),
+            entry("/file", FileType::RegularFile, ""),
+            entry("/d/a", FileType::HardLink, "file"),
+            entry("/d/b", FileType::HardLink, "/d/a"),
+        ]);
+        index.process().unwrap();
+
+        // Directories link to themselves, and from their parent and each
+        // subdirectory.
+        assert_eq!(links(&index, "/"), 3);
+        assert_eq!(links(&index, "/d"), 4);
+        assert_eq!(links(&index, "/d/e"), 2);
+
+        // Hard links, also through other hard links, add to their target.
+        assert_eq!(links(&index, "/file"), 3);
+    }
+
+    #[test]
+    fn process_rejects_non_directory_parents() {
+        let mut index = index_of(vec![
+            entry("/a", FileType::SymLink, ""),
+            entry("/a/passwd", FileType::RegularFile, ""),
+        ]);
+        let e = index.process().unwrap_err();
+        assert_eq!(e.to_string(), "parent of /a/passwd is not a directory");
+    }
+
+    #[test]
+    fn read_rejects_names_out_of_range() {
+        let mut index = index_of(vec![Inode {
+            gname: 1,
+            ..entry("/file", FileType::RegularFile, "")
+        }]);
+        let path = std::env::temp_dir()
+            .join(format!("cc-fs-names-{}.index", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        index.names.push(String::from("root"));
+        index
+            .to_file(
+                &path,
+                Storage::Plain,
+                Format::Bincode,
+                Compression::None,
+                &Keys::default(),
+            )
+            .unwrap();
+        Index::from_file(&path, &None, &Keys::default()).unwrap();
+
+        index.names.pop();
+        index
+            .to_file(
+                &path,
+                Storage::Plain,
+                Format::Bincode,
+                Compression::None,
+                &Keys::default(),
+            )
+            .unwrap();
+        let e = Index::from_file(&path, &None, &Keys::default()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!("{}: corrupt index: owner name out of range", path)
+        );
+    }
+
+    #[test]
+    fn add_link_rejects_overflow() {
+        let mut inode = Inode {
+            links: u32::MAX - 1,
+            ..entry("/file", FileType::RegularFile, "")
+        };
+        inode.add_link().unwrap();
+        assert_eq!(inode.links, u32::MAX);
+
+        let e = inode.add_link().unwrap_err();
+        assert_eq!(e.to_string(), "too many links to /file");
+        assert_eq!(inode.links, u32::MAX);
+    }
+
+    #[test]
+    fn read_checks_the_layer_digest_in_the_header() {
+        let mut index =
+            Index::new(0, 0, HashMode::Merkle, Algorithm::Sha256, &None)
+                .unwrap();
+        index.hasher.finalize().unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("cc-fs-root-{}.index", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        index
+            .to_file(
+                &path,
+                Storage::Plain,
+                Format::Bincode,
+                Compression::None,
+                &Keys::default(),
+            )
+            .unwrap();
+        let read = Index::from_file(&path, &None, &Keys::default()).unwrap();
+        assert_eq!(read.hasher.digest, index.hasher.digest);
+
+        // The layer digest follows the root.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_LEN + 32] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        let e = Index::from_file(&path, &None, &Keys::default()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "{}: corrupt index: layer digest does not match the header",
+                path
+            )
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Inspect the contents of an index without mounting it.
+//!
+//! Only the index is read. The tar file is not needed, and nothing is
+//! verified; use the `verify` subcommand for that.
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+use bincode::serialized_size;
+
+use crate::digest::{self, Digest};
+use crate::hash::HashMode;
+use crate::index::{FileType, Index, Inode, Keys};
+use crate::recompose::format_decimal_time;
+use crate::union;
+
+/// Load and process an index.
+///
+/// A colon separated list of indexes is loaded as the union of the layers,
+/// top layer first.
+///
+/// # Arguments
+/// * `index` - Path of the index file, or colon separated paths.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys each index must be authenticated with.
+fn load(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+) -> Result<Index> {
+    let mut index = match index.contains(':') {
+        true if index_digest.is_some() => {
+            return Err(anyhow!("--index-digest requires a single index"))
+        }
+        true => {
+            let layers = index
+                .split(':')
+                .map(|path| Index::from_file(&path.to_string(), &None, keys))
+                .collect::<Result<Vec<_>>>()?;
+            union::merge(&layers)?
+        }
+        false => Index::from_file(index, index_digest, keys)?,
+    };
+    index.process()?;
+    Ok(index)
+}
+
+/// Format a mode in the style of `ls -l`, e.g. `drwxr-xr-x`.
+///
+/// # Arguments
+/// * `typeflag` - File type.
+/// * `mode` - Permission bits.
+fn format_mode(typeflag: &FileType, mode: u32) -> String {
+    let mut s = String::from(match typeflag {
+        FileType::Directory => 'd',
+        FileType::SymLink => 'l',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        _ => '-',
+    });
+
+    // Each class has read, write and execute bits, with the execute bit
+    // shown differently when combined with a special bit.
+    let classes = [(0o4000, 's', 'S'), (0o2000, 's', 'S'), (0o1000, 't', 'T')];
+    for (i, (special, with_x, without_x)) in classes.iter().enumerate() {
+        let bits = mode >> (6 - 3 * i);
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => *with_x,
+            (false, true) => *without_x,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+/// Format seconds since the epoch as a UTC date and time, e.g.
+/// `2021-12-07 13:45`.
+///
+/// # Arguments
+/// * `secs` - Seconds since the epoch. Negative for times before 1970.
+fn format_time(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // Convert days to a civil date.
+    // See [chrono-Compatible Low-Level Date Algorithms](http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+/// Format an xattr value. Binary values, e.g. `security.capability`, are
+/// shown in hex.
+///
+/// # Arguments
+/// * `value` - The value.
+fn format_xattr_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(s) if !s.contains(char::is_control) => s.to_string(),
+        _ => format!("0x{}", digest::to_hex(value)),
+    }
+}
+
+/// Size of an inode as reported by the file-system.
+fn display_size(inode: &Inode) -> u64 {
+    match inode.typeflag {
+        FileType::Directory => 4096,
+        FileType::SymLink => {
+            inode.extra.as_ref().map_or(0, |e| e.link.len()) as u64
+        }
+        _ => inode.size,
+    }
+}
+
+/// Print one line of a listing in the style of `ls -ln`.
+///
+/// # Arguments
+/// * `index` - The index.
+/// * `ino` - Inode number of the entry.
+/// * `name` - Name to print for the entry.
+fn print_entry(index: &Index, ino: usize, name: &str) {
+    // Hard links show the attributes of their targets.
+    let inode = &index.inodes[ino];
+    let attrs = match inode.typeflag {
+        FileType::HardLink => &index.inodes[inode.target_ino as usize],
+        _ => inode,
+    };
+    let link = match (&attrs.typeflag, &attrs.extra) {
+        (FileType::SymLink, Some(e)) => format!(" -> {}", e.link),
+        _ => String::new(),
+    };
+    println!(
+        "{} {:>3} {:>5} {:>5} {:>10} {} {}{}",
+        format_mode(&attrs.typeflag, attrs.mode),
+        attrs.links,
+        attrs.uid,
+        attrs.gid,
+        display_size(attrs),
+        format_time(attrs.mtime),
+        name,
+        link
+    );
+}
+
+/// Recursively list a directory in the style of `ls -lnaR`.
+///
+/// # Arguments
+/// * `index` - The index.
+/// * `ino` - Inode number of the directory.
+fn list_dir(index: &Index, ino: usize) {
+    let dir = &index.inodes[ino];
+    let first = dir.child_inode as usize;
+    let children = first..first + dir.num_children as usize;
+
+    println!("{}{}:", dir.parent, dir.name);
+    print_entry(index, ino, ".");
+    for child in children.clone() {
+        print_entry(index, child, &index.inodes[child].name);
+    }
+
+    for child in children {
+        if let FileType::Directory = index.inodes[child].typeflag {
+            println!();
+            list_dir(index, child);
+        }
+    }
+}
+
+/// List the contents of an index recursively.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `path` - Directory within the layer to list.
+pub fn ls(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    path: &String,
+) -> Result<()> {
+    let index = load(index, index_digest, keys)?;
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        "/".to_owned() + path
+    };
+    let ino = index.find(&path, 0, index.inodes.len())?;
+    match index.inodes[ino].typeflag {
+        FileType::Directory => list_dir(&index, ino),
+        _ => return Err(anyhow!("{} is not a directory", path)),
+    }
+    Ok(())
+}
+
+/// Print all fields of the inode at a path.
+///
+/// For regular files, the range of hash states covering the contents is
+/// printed too. Hard links additionally print the inode of their target.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `path` - Path within the layer.
+pub fn stat(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    path: &String,
+) -> Result<()> {
+    let layered = index.contains(':');
+    let index = load(index, index_digest, keys)?;
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        "/".to_owned() + path
+    };
+    let ino = index.find(&path, 0, index.inodes.len())?;
+    print_inode(&index, ino);
+    if layered {
+        println!("layer: {}", index.inodes[ino].layer);
+    }
+
+    let inode = &index.inodes[ino];
+    if let FileType::HardLink = inode.typeflag {
+        if inode.target_ino != 0 {
+            println!();
+            print_inode(&index, inode.target_ino as usize);
+        }
+    }
+    Ok(())
+}
+
+/// Print all fields of an inode, one per line.
+///
+/// # Arguments
+/// * `index` - The index.
+/// * `ino` - Inode number.
+fn print_inode(index: &Index, ino: usize) {
+    let inode = &index.inodes[ino];
+    println!("path: {}{}", inode.parent, inode.name);
+    println!("ino: {}", ino);
+    println!("type: {:?}", inode.typeflag);
+    println!(
+        "mode: {:o} ({})",
+        inode.mode,
+        format_mode(&inode.typeflag, inode.mode)
+    );
+    println!("size: {}", inode.size);
+    if matches!(inode.typeflag, FileType::CharDevice | FileType::BlockDevice) {
+        let (major, minor) = inode.device();
+        println!("device: {}:{}", major, minor);
+    }
+    println!("uid: {}", inode.uid);
+    println!("gid: {}", inode.gid);
+    println!("uname: {}", index.names[inode.uname as usize]);
+    println!("gname: {}", index.names[inode.gname as usize]);
+    println!(
+        "mtime: {} ({} UTC)",
+        format_decimal_time(inode.mtime, inode.mtime_nsec),
+        format_time(inode.mtime)
+    );
+    println!("links: {}", inode.links);
+    println!("depth: {}", inode.depth);
+    println!(
+        "offset: {} (byte {})",
+        inode.offset,
+        inode.offset as u64 * 512
+    );
+    println!("child_inode: {}", inode.child_inode);
+    println!("num_children: {}", inode.num_children);
+    println!("target_ino: {}", inode.target_ino);
+    if let Some(extra) = &inode.extra {
+        println!("link: {}", extra.link);
+        if let Some(typeflag) = extra.typeflag {
+            println!("tar typeflag: {}", char::from(typeflag));
+        }
+        for (name, value) in &extra.xattrs {
+            println!("xattr: {}={}", name, format_xattr_value(value));
+        }
+        if let Some(sha256) = &extra.sha256 {
+            println!("sha256: {}", digest::to_hex(sha256));
+        }
+        if let Some(map) = &extra.sparse {
+            println!(
+                "sparse: {} data regions, {} bytes stored",
+                map.len(),
+                inode.stored_size()
+            );
+        }
+    }
+    if let FileType::RegularFile = inode.typeflag {
+        println!("granularity: {:?}", inode.granularity);
+        println!(
+            "hash_states: {}..={}",
+            inode.hash_index,
+            inode.hash_index + inode.num_states()
+        );
+    }
+}
+
+/// Number of largest directories reported by `stats`.
+const NUM_LARGEST_DIRS: usize = 10;
+
+/// Print a summary of an index.
+///
+/// Reports inode counts by type, total size of regular files, number of hash
+/// states, serialized sizes of the parts of the index, maximum depth and the
+/// directories with the most children.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+pub fn stats(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+) -> Result<()> {
+    let file_size = fs::metadata(index)
+        .with_context(|| format!("failed to stat {}", index))?
+        .len();
+    let index = load(index, index_digest, keys)?;
+
+    // The two root inodes are not entries of the layer.
+    let inodes = &index.inodes[2..];
+    let mut counts = BTreeMap::new();
+    for inode in inodes {
+        *counts
+            .entry(format!("{:?}", inode.typeflag))
+            .or_insert(0u64) += 1;
+    }
+    let file_bytes: u64 = inodes
+        .iter()
+        .filter(|i| matches!(i.typeflag, FileType::RegularFile))
+        .map(|i| i.size)
+        .sum();
+    let max_depth = inodes.iter().map(|i| i.depth).max().unwrap_or(0);
+
+    println!("inodes: {}", inodes.len());
+    for (typeflag, count) in &counts {
+        println!("  {}: {}", typeflag, count);
+    }
+    println!("file bytes: {}", file_bytes);
+    println!("hash states: {}", index.hasher.num_states());
+    if index.hasher.mode == HashMode::Merkle {
+        println!("merkle root: {}", index.hasher.root);
+    }
+    println!("max depth: {}", max_depth);
+
+    // Sizes of the parts of the index in the plain storage mode.
+    println!("index file size: {}", file_size);
+    println!("  inode table: {}", serialized_size(&index.inodes)?);
+    println!("  names: {}", serialized_size(&index.names)?);
+    println!("  hash states: {}", serialized_size(&index.hasher)?);
+
+    let mut dirs: Vec<&Inode> = inodes
+        .iter()
+        .filter(|i| matches!(i.typeflag, FileType::Directory))
+        .collect();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.num_children));
+    println!("largest directories:");
+    for dir in dirs.iter().take(NUM_LARGEST_DIRS) {
+        println!("  {} {}{}", dir.num_children, dir.parent, dir.name);
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! A layer: an index together with the tar file backing it.
+//!
+//! Provides verified reads of file contents outside of the FUSE file-system,
+//! e.g. for extracting a layer to a directory.
+use std::cmp::min;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::digest::Digest;
+use crate::hash;
+use crate::index::{FileType, Granularity, Index, Inode, Keys};
+
+/// An index and its backing tar file.
+pub struct Layer {
+    /// Processed index of the layer.
+    pub index: Index,
+
+    /// Tar file backing store for the layer.
+    pub tar: File,
+}
+
+impl Layer {
+    /// Open a layer.
+    ///
+    /// # Arguments
+    /// * `index` - Path of the index file.
+    /// * `index_digest` - Expected digest of the index file.
+    /// * `keys` - Keys the index must be authenticated with.
+    /// * `tar` - Path of the tar file.
+    pub fn open(
+        index: &String,
+        index_digest: &Option<Digest>,
+        keys: &Keys,
+        tar: &String,
+    ) -> Result<Layer> {
+        let mut layer = Layer {
+            index: Index::from_file(index, index_digest, keys)?,
+            tar: File::open(tar)
+                .with_context(|| format!("failed to open {}", tar))?,
+        };
+
+        // Process the index.
+        layer.index.process()?;
+        layer.index.hasher.set_key(hash::read_measure_key(&None)?)?;
+        Ok(layer)
+    }
+
+    /// Find a regular file by path. Hard links are resolved to their
+    /// targets.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file within the layer. A leading '/' is
+    ///   optional.
+    pub fn find_file(&self, path: &String) -> Result<&Inode> {
+        let inodes = &self.index.inodes;
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            "/".to_owned() + path
+        };
+        let ino = self.index.find(&path, 0, inodes.len())?;
+        let inode = match inodes[ino].typeflag {
+            FileType::HardLink => &inodes[inodes[ino].target_ino as usize],
+            _ => &inodes[ino],
+        };
+        if !matches!(inode.typeflag, FileType::RegularFile) {
+            return Err(anyhow!("{} is not a regular file", path));
+        }
+        Ok(inode)
+    }
+
+    /// Read and verify contents of a regular file.
+    ///
+    /// The pages covering the requested range are read from the tar file and
+    /// verified against the saved hash states.
+    ///
+    /// # Arguments
+    /// * `inode` - Inode of the file.
+    /// * `offset` - Offset within the file to read from.
+    /// * `size` - Number of bytes to read.
+    /// * `returns` - Bytes read. Fewer than `size` at end of file.
+    pub fn read(
+        &self,
+        inode: &Inode,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>> {
+        // Compute the end offset clipped to file size.
+        let end = min(offset + size, inode.size);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        // Holes of sparse files are not stored. Read the stored contents
+        // covering the range instead.
+        let (range, offset, end) = (
+            offset..end,
+            inode.stored_offset(offset),
+            inode.stored_offset(end),
+        );
+
+        // Starting offset aligned to page boundary. Files verified as a
+        // whole are read in full.
+        let (start, read_end) = match inode.granularity {
+            Granularity::Page => ((offset / 4096) * 4096, end),
+            Granularity::File => (0, inode.stored_size()),
+        };
+
+        // Bytes to read.
+        let bytes = read_end - start;
+
+        // Buffer size. Aligned to 512 byte-boundary. The tar padding after
+        // the end of file is zeros.
+        let buf_size = bytes.div_ceil(512) * 512;
+        let mut buf = vec![0u8; buf_size as usize];
+
+        // Read bytes.
+        let tar_offset = inode.offset as u64 * 512 + start;
+        self.tar
+            .read_exact_at(&mut buf[0..bytes as usize], tar_offset)
+            .with_context(|| {
+                format!(
+                    "failed to read {}{} from backing store",
+                    inode.parent, inode.name
+                )
+            })?;
+
+        // Verify the pages, or the whole file.
+        let hasher = &self.index.hasher;
+        let first_page = (start / 4096) as u32;
+        let result = match inode.granularity {
+            Granularity::Page => hasher
+                .verify_pages(inode.hash_index + first_page, &buf)
+                .map_err(|pos| pos - inode.hash_index),
+            Granularity::File => {
+                match hasher.verify_range(
+                    Some(inode.hash_index),
+                    &buf,
+                    inode.hash_index + 1,
+                ) {
+                    Ok(true) => Ok(()),
+                    _ => Err(0),
+                }
+            }
+        };
+        if let Err(page_num) = result {
+            return Err(anyhow!(
+                "integrity verification failed for {}{} at page {}",
+                inode.parent,
+                inode.name,
+                page_num
+            ));
+        }
+
+        // Drop the part of the first page before offset and the padding.
+        buf.truncate((end - start) as usize);
+        buf.drain(0..(offset - start) as usize);
+        if inode.sparse_map().is_some() {
+            buf = inode.fill_holes(range.start, range.end, &buf);
+        }
+        Ok(buf)
+    }
+}
@@ -0,0 +1,38 @@
+//! Advisory locking of index and tar files.
+//!
+//! When mounted with `--lock`, cc-fs holds shared `flock` locks on the index
+//! and the backing tar file for the lifetime of the mount. Other tooling such
+//! as garbage collectors and re-indexers can detect that the files are in use
+//! by attempting to take an exclusive lock. cc-fs itself takes an exclusive
+//! lock before replacing an index file, and thus refuses to replace an index
+//! that is in use by a mount.
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{anyhow, Result};
+
+/// Lock a file without blocking.
+///
+/// The lock is released when the file is closed.
+///
+/// # Arguments
+/// * `file` - The file to lock.
+/// * `path` - Path of the file. Used in error messages.
+/// * `exclusive` - Take an exclusive lock instead of a shared one.
+pub fn lock(file: &File, path: &String, exclusive: bool) -> Result<()> {
+    let op = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+
+    if unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Err(anyhow!("{} is in use", path)),
+            _ => Err(anyhow!("failed to lock {}: {}", path, e)),
+        };
+    }
+    Ok(())
+}
@@ -8,8 +8,178 @@
 //! folder.
 //! ```bash
 //!  $ cc-fs index layer.tar -d a65a803efce5eec96deeff2d556c6294059e64a6dedd1f2935be9c862f28a319
+//!  hashed with sha256 (sha-ni compression)
 //!  wrote layer.tar.index, size = 19589587 bytes
 //! ```
+//! Indexing is bound by hashing. The SHA extensions of the CPU (SHA-NI for
+//! sha256, AVX2 for sha384 and sha512) are detected at runtime and used if
+//! present. The compression backend in use is printed, and is `portable`
+//! without them.
+//! The digest may also be supplied in the `<algorithm>:<hex>` form emitted by
+//! registries and image tools. `sha256`, `sha384` and `sha512` digests are
+//! accepted. Malformed digests, e.g. of the wrong length, are rejected up front.
+//! ```bash
+//!  $ cc-fs index layer.tar -d sha256:a65a803efce5eec96deeff2d556c6294059e64a6dedd1f2935be9c862f28a319
+//! ```
+//! The hash states are computed with the algorithm of the digest, which is
+//! recorded in the index so that the file-system verifies pages with the same
+//! algorithm. sha384 and sha512 states are twice the size of sha256 states.
+//! Expected digests can also be looked up from a checksum file such as the
+//! `SHA256SUMS` files published by release pipelines. A line naming the path
+//! as given is preferred; otherwise the file name must match exactly one
+//! line. Multiple tar files can be indexed in one go this way.
+//! ```bash
+//!  $ cc-fs index --digest-file SHA256SUMS layer1.tar layer2.tar
+//! ```
+//! The index is written next to the tar file, i.e. to `layer.tar.index` here.
+//! Use `-o/--output` to write it elsewhere. The index is written to a
+//! temporary file first and renamed into place, so a partially written index
+//! is never observed.
+//! ```bash
+//!  $ cc-fs index layer.tar -o /var/lib/cc-fs/layer.index
+//! ```
+//! Use `-` as the path to index a tar stream from stdin without writing it to
+//! disk first. `--output` is required in this case.
+//! ```bash
+//!  $ docker export $id | cc-fs index - -d sha256:<hex> -o layer.index
+//! ```
+//! A folder, e.g. a root file-system extracted ahead of time, is indexed as
+//! the tar stream of its contents that the walk of the folder produces. The
+//! folder is the root of the stream, entries are sorted by name, and owner
+//! names are left out. The stream, and hence the digest, only depends on the
+//! contents and metadata of the folder. Sockets are skipped with a warning.
+//! ```bash
+//!  $ cc-fs index rootfs/
+//!  wrote rootfs.index, size = 1602 bytes
+//! ```
+//! With `--docker-save`, the paths are `docker save` archives. Each layer in
+//! an archive is checked against the digest recorded in the image config,
+//! and added to a layer store (see below) along with its index. The store
+//! defaults to the directory of the archive.
+//! ```bash
+//!  $ docker save ubuntu:22.04 > ubuntu.tar
+//!  $ cc-fs index --docker-save -o layers ubuntu.tar
+//!  tar layers/sha256/<hex>.tar
+//!  index layers/sha256/<hex>.tar.index
+//! ```
+//! With `--batch`, the paths are indexed concurrently, by default as many at
+//! a time as there are CPUs (see `--jobs`), and a summary table is printed at
+//! the end.
+//! ```bash
+//!  $ cc-fs index --batch --digest-file SHA256SUMS layer*.tar
+//! ```
+//! A single tar file is hashed on as many threads as there are CPUs (see
+//! `--hash-threads`) while it is read and parsed. The digest of the layer,
+//! and the hash states of `--hash-mode chained`, are computed in order on one
+//! thread. Leaf hashes of the other modes are computed on the remaining
+//! threads, so that with enough CPUs indexing takes about as long as
+//! computing the digest alone. The index is the same whatever the number of
+//! threads.
+//! ```bash
+//!  $ cc-fs index --hash-mode merkle --hash-threads 8 layer.tar
+//! ```
+//! Tar headers are checked as they are parsed. Files that are not tar files,
+//! or are corrupt or truncated, are rejected with the byte offset of the
+//! offending header. Other violations of the tar format, such as bad header
+//! checksums, unsupported typeflags, malformed pax headers, a missing
+//! end-of-archive marker or data following it, are reported as warnings and
+//! skipped, e.g. for archives written by tools that get them wrong. The
+//! skipped data is still measured. With `--strict`, they are rejected too.
+//! ```bash
+//!  $ cc-fs index layer.tar
+//!  warning: bad header checksum at byte offset 3072: 10542 != 7156
+//!  $ cc-fs index --strict layer.tar
+//!  Error: bad header checksum at byte offset 3072: 10542 != 7156
+//! ```
+//! Archives concatenated with `cat a.tar b.tar` hold end-of-archive markers
+//! followed by more entries. As with `tar --ignore-zeros`, such archives are
+//! indexed as a whole with `--ignore-zeros`.
+//! ```bash
+//!  $ cc-fs index --ignore-zeros layer.tar
+//! ```
+//! Paths of entries are normalized: leading '/' and "./" as well as "."
+//! components are dropped and ".." components are resolved. Archives with
+//! entries or hard links escaping the root, e.g. `../etc/passwd`, are
+//! rejected. An entry for the root itself, e.g. `./`, sets the attributes of
+//! the root directory.
+//! Indexes of deep trees can be made considerably smaller by front-coding the
+//! paths of inodes.
+//! ```bash
+//!  $ cc-fs index --storage front-coded layer.tar
+//! ```
+//! Indexes are written in bincode by default. For consumption by other tooling,
+//! they can instead be written as cbor, json or postcard. Every index file
+//! starts with a 16-byte header recording the version of the index layout, the
+//! format and the compression, followed by the Merkle tree root for indexes
+//! created with `--hash-mode merkle`, a 32-byte digest of the contents (and a
+//! 32-byte HMAC for indexes created with `--key`, and a 64-byte signature for
+//! indexes created with `--sign-key`). Tools must skip these
+//! before parsing. Indexes of a version other than the one written by this
+//! cc-fs are rejected.
+//! ```bash
+//!  $ cc-fs index --format json layer.tar
+//!  $ tail -c +49 layer.tar.index | jq .names
+//! ```
+//! Index files can be compressed with zstd, at the default or a given level.
+//! ```bash
+//!  $ cc-fs index --compress zstd:19 layer.tar
+//! ```
+//! The hash states make up most of an index. With `--split-states`, they are
+//! written to a separate states file and read on demand, a page of states at
+//! a time, rather than held in memory while mounted. The index records the
+//! digest of each page of the states file, against which every read is checked.
+//! ```bash
+//!  $ cc-fs index --split-states layer.tar
+//!  wrote layer.tar.states
+//!  wrote layer.tar.index, size = 7345 bytes
+//! ```
+//! By default a hash state is saved for every page of every file, so that any
+//! page can be verified on its own. With `--whole-file-max-size <bytes>`,
+//! regular files up to the given size get hash states only at their start and
+//! end. Reading any part of such a file reads and verifies the whole file.
+//! Since most files in a layer are small, this shrinks the index while keeping
+//! random-access verification for large files.
+//! ```bash
+//!  $ cc-fs index --whole-file-max-size 65536 layer.tar
+//! ```
+//! With `--granularity file`, all regular files are verified as a whole, which
+//! cuts the hash states down to two per file. A mounted file system reads and
+//! verifies such a file on the first read after it is opened, and serves
+//! further reads from the verified contents until it is closed.
+//! ```bash
+//!  $ cc-fs index --granularity file layer.tar
+//! ```
+//! With `--hash-mode merkle`, the sha256 of each page (and of the tar headers
+//! between files) is saved instead of the intermediate sha256 state after it.
+//! These leaf hashes form a Merkle tree whose root is recorded in the index
+//! header next to the layer digest, and the leaves are checked against the root
+//! whenever the index is loaded. A page is verified against its leaf alone,
+//! without loading the state before it. The index is the same size, and the
+//! layer digest is still computed in a single pass. The root is not derived
+//! from the layer digest, and pages are not verified in parallel.
+//! ```bash
+//!  $ cc-fs index --hash-mode merkle layer.tar
+//!  $ cc-fs stats layer.tar.index | grep merkle
+//!  merkle root: 5b0c0f...
+//! ```
+//! Indexes fetched over an untrusted channel can be made useless without a
+//! secret key held by the tenant. With `--hash-mode hmac`, an HMAC-SHA256
+//! under the key is saved for each page instead of a hash state, and mount
+//! (as well as `verify`, `cat` and `extract`) requires the same key, either in
+//! a file or hex-encoded in the `CC_FS_MEASURE_KEY` environment variable.
+//! ```bash
+//!  $ head -c 32 /dev/urandom > measure.key
+//!  $ cc-fs index --hash-mode hmac --measure-key measure.key layer.tar
+//!  $ cc-fs mount --measure-key measure.key --index layer.tar.index layer.tar m
+//! ```
+//! With `--file-digests`, the sha256 digest of the contents of each regular
+//! file is recorded in the index too, for matching against per-file digests
+//! of other tools such as IMA policies or SBOMs.
+//! ```bash
+//!  $ cc-fs index --file-digests layer.tar
+//!  $ cc-fs stat -i layer.tar.index /etc/hostname | grep sha256
+//!  sha256: 4ca9cbf1c8e1d4dc4cdb8d3b2c4d5d4b5d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a
+//! ```
 //! If the supplied digest does not match the computed digest, then an error is raised.
 //! ```bash
 //! $ cc-fs index layer.tar -d aabbccddeeffaabbccddeeffaabbccddeeffaabbccddeeffaabbccddeeffaabb
@@ -34,8 +204,373 @@
 //! drwxr-xr-x  1 root     root     4.0K Oct  6  2021 var/
 //! ```
 //!
-//! Support for mounting an existing folder and applying index over it, is not
-//! implemented yet.
+//! The index file itself can be pinned to an attested digest. Mount refuses to
+//! proceed if the index file does not hash to the supplied value.
+//! ```bash
+//! $ cc-fs mount --index layer.tar.index --index-digest sha256:<hex> layer.tar m
+//! ```
+//! The layer can be pinned too. Mount refuses to proceed unless the index was
+//! created from a layer with the supplied digest. The index records the layer
+//! digest, so this is only as trustworthy as the index: pin the index or
+//! authenticate it as described below.
+//! ```bash
+//! $ cc-fs mount --index layer.tar.index --key index.key \
+//!     --digest sha256:<hex> layer.tar m
+//! ```
+//!
+//! Every index embeds a sha256 digest of its contents, which catches corrupt
+//! indexes whenever they are loaded. To also authenticate indexes, create them
+//! with a secret key. An HMAC of the index is then embedded, and mounting with
+//! the same key refuses indexes whose HMAC is missing or does not match.
+//! ```bash
+//! $ head -c 32 /dev/urandom > index.key
+//! $ cc-fs index --key index.key layer.tar
+//! $ cc-fs mount --index layer.tar.index --key index.key layer.tar m
+//! ```
+//! Indexes can also be signed with an Ed25519 private key, so that mounting
+//! only requires the public key. Mount with `--pubkey` refuses indexes that are
+//! unsigned or whose signature does not verify.
+//! ```bash
+//! $ openssl genpkey -algorithm ed25519 -out sign.pem
+//! $ openssl pkey -in sign.pem -pubout -out pub.pem
+//! $ cc-fs index --sign-key sign.pem layer.tar
+//! $ cc-fs mount --index layer.tar.index --pubkey pub.pem layer.tar m
+//! ```
+//! The other subcommands that read indexes, such as `extract`, `verify` and
+//! `ls`, take `--key` and `--pubkey` too.
+//!
+//! Use `--timeout` to bound the time taken to load the index and complete the
+//! FUSE handshake. If the file-system is not ready in time, cc-fs exits with
+//! status 124 instead of hanging.
+//!
+//! With `--lock`, shared advisory locks (`flock`) are held on the index and
+//! tar file for as long as the file-system is mounted. Tools such as garbage
+//! collectors can take an exclusive lock to find out whether a layer is in
+//! use. Mount fails if either file is exclusively locked, and `cc-fs index`
+//! refuses to replace an index that is locked by a mount.
+//! ```bash
+//! $ cc-fs mount --lock --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Use `--stats` to write a JSON report of reads, bytes served, unique files
+//! read, and page verifications to a file when the file-system is unmounted.
+//! ```bash
+//! $ cc-fs mount --stats stats.json --index layer.tar.index layer.tar m
+//! $ umount m && cat stats.json
+//! {"opens":12,"reads":40,"bytes":1638400,"files":12,"verified_pages":412,"failed_pages":0,"trusted_pages":0}
+//! ```
+//!
+//! Verification of large reads can be spread over a pool of worker threads
+//! with `--verify-threads`. Use `--pin-cpus` to confine cc-fs to housekeeping
+//! vCPUs so that it does not compete with the confidential workload.
+//! ```bash
+//! $ cc-fs mount --verify-threads 2 --pin-cpus 0-1 --index layer.tar.index layer.tar m
+//! ```
+//!
+//! When built with the `io-uring` feature, the file-system reads the tar file
+//! through io_uring. Each read is split into 32 KiB segments that are
+//! submitted together, which improves throughput of large random reads on
+//! fast NVMe devices. Reads fall back to `pread` if io_uring is unavailable.
+//! ```bash
+//! $ cargo build --release --features io-uring
+//! ```
+//!
+//! With `--backend mmap`, the tar file is mapped into memory and pages are
+//! copied out of the mapping, then verified and served from the copy.
+//! Readahead of the tar file is left to the kernel. Reads of a tar file that
+//! is truncated while mounted fail with EIO.
+//! ```bash
+//! $ cc-fs mount --backend mmap --index layer.tar.index layer.tar m
+//! ```
+//!
+//! By default the pages read are verified on every read that reaches cc-fs,
+//! i.e. on every miss of the kernel page cache. `--verify-policy` trades
+//! integrity checking for performance differently:
+//!   - `on-open` verifies the whole file when it is opened and serves reads
+//!     from the verified contents, held in memory, until it is closed. Suits
+//!     short-lived containers reading small files in full.
+//!   - `once` verifies each page on its first read only, and trusts the tar
+//!     file for later reads of the page. Suits databases rereading pages
+//!     evicted from the page cache, when the tar file is not expected to
+//!     change after mount. Trusted reads are counted as `trusted_pages` in
+//!     the statistics report.
+//! ```bash
+//! $ cc-fs mount --verify-policy once --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Each mount is served by its own cc-fs process, so the resources used by a
+//! container are accounted to that process. The number of files a container
+//! can keep open is capped with `--max-open-files`.
+//! ```bash
+//! $ cc-fs mount --max-open-files 1024 --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Layers built on macOS may store names in NFD form while Linux tools
+//! usually pass NFC. With `--normalize-names`, lookups that fail are retried
+//! with the NFC and NFD forms of the name. Directory listings still return
+//! the stored names.
+//!
+//! A supervisor can open (and seal) the tar file itself and pass the file
+//! descriptor down with `--tar-fd`, in which case the tar path is omitted and
+//! cc-fs needs no access to the path of the tar file. Descriptors opened with
+//! `O_PATH` are reopened for reading through `/proc/self/fd`.
+//! ```bash
+//! $ cc-fs mount --index layer.tar.index --tar-fd 3 m 3<layer.tar
+//! ```
+//!
+//! A panic while serving a request fails only that request with EIO and is
+//! logged to stderr as a JSON line. Use `--on-panic unmount` to instead
+//! unmount the file-system and exit after the first panic.
+//! ```bash
+//! $ cc-fs mount --on-panic unmount --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Contents are verified before they are returned. A read that fails
+//! verification fails with EIO, as do all later reads of the file, and is
+//! logged to stderr. Use `--on-verify-failure unmount` to instead unmount
+//! the file-system and exit, or `--on-verify-failure panic` to panic, which
+//! is then handled according to `--on-panic`.
+//! ```bash
+//! $ cc-fs mount --on-verify-failure unmount --index layer.tar.index \
+//!     layer.tar m
+//! ```
+//!
+//! The kernel caches looked up entries and attributes for a day, so repeated
+//! lookups, e.g. by `tree`, are served from its dentry cache. All changes go
+//! through the file-system, which keeps the caches up to date. Use
+//! `--entry-timeout` and `--attr-timeout` to set other durations in seconds;
+//! 1 second matches the default of libfuse.
+//! ```bash
+//! $ cc-fs mount --entry-timeout 1 --attr-timeout 1 --index layer.tar.index \
+//!     layer.tar m
+//! ```
+//!
+//! Without an upper layer, contents never change, so the kernel is asked to
+//! keep the cached contents of files and listings of directories when they
+//! are opened again. Repeated reads are served from the page cache without
+//! reading or verifying the tar file again. Use `--no-keep-cache` to drop the
+//! caches on every open, e.g. to measure reads that reach the file-system.
+//! ```bash
+//! $ cc-fs mount --no-keep-cache --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Workloads that must bypass the page cache, e.g. databases doing their own
+//! caching, can mount with `--direct-io`. Files are then opened for direct
+//! I/O and every read reaches the file-system. Combined with the default
+//! `--verify-policy every-read`, every read is verified again. Older kernels
+//! do not support shared mappings of files opened for direct I/O.
+//! ```bash
+//! $ cc-fs mount --direct-io --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Use the `umount` subcommand to unmount a file-system. It waits for the
+//! cc-fs process serving the mount to finish, e.g. writing its statistics,
+//! and also removes mounts left behind by a process that died.
+//! ```bash
+//! $ cc-fs umount m
+//! unmounted m
+//! ```
+//!
+//! Layers mark files deleted from lower layers with OCI `.wh.<name>` files
+//! and `.wh..wh..opq` markers, which are shown as stored by default. Use
+//! `--whiteouts hide` to omit them, or `--whiteouts overlay` to show them as
+//! 0:0 character devices and `trusted.overlay.opaque` attributes, so that the
+//! mount can serve as a lower layer of overlayfs.
+//! ```bash
+//! $ cc-fs mount --whiteouts overlay --index layer.tar.index layer.tar l1
+//! $ mount -t overlay overlay -o lowerdir=l2:l1 rootfs
+//! ```
+//!
+//! Alternatively, all layers of an image can be mounted at once, without
+//! overlayfs. Pass colon separated lists of indexes and tar files, top layer
+//! first. The mount shows the union of the layers with whiteouts applied, and
+//! reads of each file are served from, and verified against, its own layer.
+//! `--index-digest` and `--tar-fd` only apply to single layers.
+//! ```bash
+//! $ cc-fs mount --index l2.tar.index:l1.tar.index l2.tar:l1.tar rootfs
+//! ```
+//! The mount is read-only. With `--upper`, the layers are mounted under a
+//! writable upper layer, e.g. for the root file-system of a container.
+//! Files of the layers are copied up, after verification, when first
+//! modified, and removed entries are hidden by whiteouts. Renaming a
+//! directory of the layers fails with `EXDEV`, as with overlayfs. Changes
+//! are held in memory with `--upper tmpfs`, or with `--upper DIR` the
+//! contents of changed files are held in unlinked files in DIR. Either way,
+//! the upper layer is discarded at unmount.
+//! ```bash
+//! $ cc-fs mount --upper tmpfs --index l2.tar.index:l1.tar.index l2.tar:l1.tar rootfs
+//! ```
+//! The changes held in the upper layer can be committed as a new layer, e.g.
+//! to build an image on top of verified layers. The mount writes an OCI
+//! layer tar with whiteouts for removed entries, which is then indexed.
+//! Commits are requested on a unix socket given at mount time, which only the
+//! user running the mount and root may use. The tar must be outside the mount
+//! and must not exist. The new layer can be mounted as the top layer over the
+//! layers below it.
+//! ```bash
+//! $ cc-fs mount --upper tmpfs --commit-socket /run/rootfs.sock --index l2.tar.index:l1.tar.index l2.tar:l1.tar rootfs
+//! $ cc-fs commit /run/rootfs.sock l3.tar
+//! wrote /work/l3.tar, digest = sha256:<hex>
+//! $ cc-fs mount --index l3.tar.index:l2.tar.index:l1.tar.index l3.tar:l2.tar:l1.tar rootfs
+//! ```
+//!
+//! A folder, e.g. a root file-system prepared by a snapshotter, is protected
+//! by mounting its index over it with `--backend dir`. The contents of files
+//! are read from the files in the folder, and every page is verified against
+//! the index as with tar files. Reads of tampered pages fail with EIO. The
+//! index may be that of the folder, or that of a tar file the folder was
+//! extracted from.
+//! ```bash
+//! $ cc-fs index rootfs/
+//! $ cc-fs mount --backend dir --index rootfs.index rootfs m
+//! ```
+//!
+//! A single directory of an index, e.g. model weights, can be exposed with
+//! `--subdir` instead of the whole layer. The directory is mounted as the
+//! root, and nothing outside it can be looked up. Hard links to files
+//! outside the directory are shown as the files they link to.
+//! ```bash
+//! $ cc-fs mount --subdir /usr/share/app --index layer.tar.index layer.tar m
+//! ```
+//!
+//! Files are shown with the owners stored in the layer, usually root. For
+//! rootless and user-namespaced runtimes, owner ids can be shifted into the
+//! range mapped to the user namespace, with `--uid-offset` and
+//! `--gid-offset`, or with `--id-map` and a file of ranges in the form of
+//! `/proc/PID/uid_map` prefixed by `u` or `g`. Ids outside the ranges are
+//! shown as 65534. Owners set in the upper layer are shifted back.
+//! ```bash
+//! $ cc-fs mount --uid-offset 100000 --gid-offset 100000 --index layer.tar.index layer.tar m
+//! ```
+//! FUSE mounts cannot be idmapped by the kernel. Instead, the mappings of a
+//! container runtime are applied by the mount: `--id-map` also takes the OCI
+//! runtime spec of a container (runc, kata), and `--userns` takes a user
+//! namespace, e.g. of the sandbox, whose mappings are read by joining it.
+//! ```bash
+//! $ cc-fs mount --id-map bundle/config.json --index layer.tar.index layer.tar rootfs
+//! $ cc-fs mount --userns /proc/$SANDBOX_PID/ns/user --index layer.tar.index layer.tar rootfs
+//! ```
+//! The owner names recorded in the tar file can be resolved to ids with
+//! `--passwd` and `--group`, e.g. against the image's own databases, as GNU
+//! tar does when extracting. Names found in the databases override the
+//! numeric ids; other names keep them. `extract` takes the same options.
+//! ```bash
+//! $ cc-fs cat --index layer.tar.index layer.tar /etc/passwd > passwd
+//! $ cc-fs cat --index layer.tar.index layer.tar /etc/group > group
+//! $ cc-fs mount --passwd passwd --group group --index layer.tar.index layer.tar m
+//! ```
+//!
+//! # Extracting a layer
+//! Use the `extract` subcommand to extract the contents of a layer to a
+//! directory on hosts where FUSE is unavailable. Every page is verified against
+//! the index during extraction. With `--ima`, the sha256 hash of each file is
+//! written to its `security.ima` attribute so that IMA-appraisal can enforce
+//! integrity of the extracted tree.
+//! ```bash
+//! $ cc-fs extract --index layer.tar.index --ima layer.tar rootfs
+//! extracted layer.tar to rootfs
+//! ```
+//! Modification times can be clamped with `--clamp-mtime <epoch>` or the
+//! `SOURCE_DATE_EPOCH` environment variable for reproducible output.
+//! ```bash
+//! $ SOURCE_DATE_EPOCH=1700000000 cc-fs extract --index layer.tar.index layer.tar rootfs
+//! ```
+//! With `--stamp`, each extracted file records the digest of its verified
+//! contents and of the index in `user.ccfs.digest` and
+//! `user.ccfs.index-digest` extended attributes, so that drift can later be
+//! detected without the original tar file.
+//! ```bash
+//! $ cc-fs extract --index layer.tar.index --stamp layer.tar rootfs
+//! $ getfattr -n user.ccfs.digest rootfs/etc/os-release
+//! ```
+//! Whiteouts are written as OCI `.wh.` files by default. Use
+//! `--whiteouts overlay` to write overlayfs 0:0 character devices and
+//! `trusted.overlay.opaque` attributes instead.
+//! ```bash
+//! $ cc-fs extract --index layer.tar.index --whiteouts overlay layer.tar lower
+//! ```
+//!
+//! # Recomposing a layer
+//! Use the `recompose` subcommand to convert a layer into a canonical tar file
+//! (sorted entries, normalized headers, PAX only where needed) of its verified
+//! contents. The digest of the canonical tar depends only on the contents, so
+//! layers produced by different tools can be compared and re-indexed.
+//! `--clamp-mtime`, `SOURCE_DATE_EPOCH` and `--whiteouts` are honored as for
+//! `extract`.
+//! ```bash
+//! $ cc-fs recompose --index layer.tar.index layer.tar canonical.tar
+//! wrote canonical.tar, digest = sha256:<hex>
+//! ```
+//!
+//! # Verifying a layer
+//! Use the `verify` subcommand to check every file of a layer against the
+//! index without mounting it, e.g. in CI pipelines before layers reach a
+//! confidential VM. The result is reported for each file, and cc-fs exits
+//! with a non-zero code if any file fails verification.
+//! ```bash
+//! $ cc-fs verify --index layer.tar.index layer.tar
+//! ok /etc/os-release
+//! ...
+//! verified 1234 files
+//! ```
+//!
+//! # Verifying a file
+//! Use the `verify-file` subcommand to spot check a single file of a layer
+//! without mounting or scanning the whole layer. The contents of the file and
+//! the tar headers holding its metadata are verified against the index.
+//! ```bash
+//! $ cc-fs verify-file --index layer.tar.index layer.tar /usr/bin/bash
+//! verified /usr/bin/bash
+//! ```
+//!
+//! # Inspecting an index
+//! Use the `ls` subcommand to list the contents of a layer recursively from
+//! its index alone, e.g. for audits on machines where FUSE is not permitted.
+//! ```bash
+//! $ cc-fs ls --index layer.tar.index /etc
+//! /etc:
+//! drwxr-xr-x   2     0     0       4096 2021-12-07 13:45 .
+//! -rw-r--r--   1     0     0        386 2021-10-06 16:40 os-release
+//! ```
+//!
+//! Both subcommands accept a colon separated list of indexes, top layer
+//! first, and then show the union of the layers. Entries of upper layers
+//! replace those of lower layers, and whiteouts and opaque directories
+//! (`.wh..wh..opq`) hide the lower entries below them.
+//! ```bash
+//! $ cc-fs ls --index upper.tar.index:lower.tar.index /etc
+//! ```
+//!
+//! Use the `stat` subcommand to print all fields of the inode at a path,
+//! including its extended attributes and the range of hash states covering
+//! its contents. This helps debug lookup and verification issues.
+//! ```bash
+//! $ cc-fs stat --index layer.tar.index /usr/bin/bash
+//! ```
+//!
+//! Use the `stats` subcommand to summarize an index: inode counts by type,
+//! total file size, number of hash states, the serialized size of each part
+//! of the index, maximum depth and the largest directories.
+//! ```bash
+//! $ cc-fs stats layer.tar.index
+//! ```
+//!
+//! # Printing a file
+//! Use the `cat` subcommand to print a single file of a layer, e.g. a config
+//! file, without mounting it. Only contents that pass verification are
+//! written out.
+//! ```bash
+//! $ cc-fs cat --index layer.tar.index layer.tar /etc/os-release
+//! ```
+//!
+//! # Layer store
+//! Inside a kata VM, the `guest-pull` subcommand receives layers streamed by
+//! the host over vsock and adds them to a layer store. See the `vsock` module
+//! for the protocol.
+//! ```bash
+//! $ cc-fs guest-pull --store /run/cc-fs --port 1025
+//! listening on vsock port 1025
+//! ```
 //!
 //! # Performance
 //! cc-fs has only a tiny overhead compared to computing the sha256sum of a tar
@@ -155,12 +690,53 @@
 //! $ ls -sh large.tar.index
 //! 40M large.tar.index
 //! ````
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
 
+use digest::Digest;
+use extract::ExtractOptions;
+use fs::{Backend, MountOptions, OnVerifyFailure, VerifyPolicy};
+use guard::OnPanic;
+use hash::HashMode;
+use idmap::IdMap;
+use index::{Compression, Format, Granularity, Keys, Storage};
+use owners::Owners;
+use pool::CpuList;
+use recompose::RecomposeOptions;
+use tar::IndexOptions;
+use upper::UpperStore;
+use whiteout::{WhiteoutMode, Whiteouts};
+
+mod cat;
+mod commit;
+mod digest;
+mod docker;
+mod extract;
+mod folder;
+mod guard;
 mod hash;
+mod idmap;
 mod index;
+mod inspect;
+mod layer;
+mod lock;
+mod owners;
+mod pool;
+mod recompose;
+mod stats;
+mod store;
+mod subtree;
 mod tar;
+mod umount;
+mod union;
+mod upper;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod verify;
+mod vsock;
+mod whiteout;
 
 mod fs;
 
@@ -173,34 +749,546 @@ struct Cli {
     command: Commands,
 }
 
+/// Databases to resolve the owner names of files with.
+#[doc(hidden)]
+#[derive(Args)]
+struct OwnerArgs {
+    /// User database, e.g. the /etc/passwd of the image, to resolve the
+    /// owner names of files to user ids with.
+    #[clap(long, name = "passwd")]
+    passwd: Option<String>,
+
+    /// Group database, e.g. the /etc/group of the image, to resolve the
+    /// group names of files to group ids with.
+    #[clap(long, name = "group")]
+    group: Option<String>,
+}
+
+/// Keys to authenticate indexes with.
+#[doc(hidden)]
+#[derive(Args)]
+struct KeyArgs {
+    /// File holding the secret key the index must be authenticated with.
+    #[clap(long, name = "key")]
+    key: Option<String>,
+
+    /// Ed25519 public key in PEM format the index must be signed with.
+    #[clap(long, name = "pubkey")]
+    pubkey: Option<String>,
+}
+
+impl KeyArgs {
+    /// Read the keys.
+    fn read(&self) -> Result<Keys> {
+        Ok(Keys {
+            hmac: index::read_key(&self.key)?,
+            signing: None,
+            verifying: index::read_verifying_key(&self.pubkey)?,
+        })
+    }
+}
+
 #[doc(hidden)]
 #[derive(Subcommand)]
 enum Commands {
     /// Create confidential container file-system index.
     Index {
-        /// Expected digest of the tar file.
-        #[clap(short, long, name = "digest")]
-        digest: Option<String>,
+        /// Expected digest of the tar file, e.g. sha256:<hex>, sha384:<hex> or
+        /// sha512:<hex>. Selects the algorithm of the hash states.
+        /// A digest without an algorithm prefix is treated as sha256.
+        #[clap(short, long, name = "digest", value_parser)]
+        digest: Option<Digest>,
+
+        /// Checksum file (e.g. SHA256SUMS) to look up expected digests in by
+        /// path, or by unique file name.
+        #[clap(long, name = "digest-file", conflicts_with = "digest")]
+        digest_file: Option<String>,
+
+        /// Storage mode of the inode table in the index.
+        #[clap(long, value_enum, default_value = "plain")]
+        storage: Storage,
+
+        /// Serialization format of the index file.
+        #[clap(long, value_enum, default_value = "bincode")]
+        format: Format,
+
+        /// Compression of the index file: none, zstd or zstd:<level>.
+        #[clap(long, name = "compress", default_value = "none", value_parser)]
+        compress: Compression,
+
+        /// File holding a secret key to authenticate the index with.
+        #[clap(long, name = "key", conflicts_with = "docker-save")]
+        key: Option<String>,
+
+        /// Write the hash states to a separate states file next to the index,
+        /// which is read on demand.
+        #[clap(long, conflicts_with = "docker-save")]
+        split_states: bool,
+
+        /// Ed25519 private key in PEM format to sign the index with.
+        #[clap(long, name = "sign-key", conflicts_with = "docker-save")]
+        sign_key: Option<String>,
+
+        /// Regular files up to this size are verified as a whole instead of
+        /// page by page.
+        #[clap(long, name = "whole-file-max-size", default_value = "0")]
+        whole_file_max_size: u64,
+
+        /// Granularity at which regular files are verified. With file, all
+        /// regular files are verified as a whole.
+        #[clap(
+            long,
+            value_enum,
+            default_value = "page",
+            conflicts_with = "whole-file-max-size"
+        )]
+        granularity: Granularity,
+
+        /// What is saved for each page: intermediate hash states, leaf
+        /// hashes of a Merkle tree, or HMACs under --measure-key.
+        #[clap(long, value_enum, default_value = "chained")]
+        hash_mode: HashMode,
+
+        /// File holding the secret key of --hash-mode hmac. Defaults to the
+        /// hex key in CC_FS_MEASURE_KEY.
+        #[clap(long, name = "measure-key", conflicts_with = "docker-save")]
+        measure_key: Option<String>,
+
+        /// Record the sha256 digest of each regular file in the index.
+        #[clap(long, conflicts_with = "docker-save")]
+        file_digests: bool,
+
+        /// Number of threads hashing each tar file. Defaults to the number
+        /// of CPUs, or to 1 with --batch.
+        #[clap(long, name = "hash-threads", conflicts_with = "docker-save")]
+        hash_threads: Option<usize>,
+
+        /// Fail on any violation of the tar format, such as bad header
+        /// checksums, unsupported typeflags, malformed pax headers or data
+        /// after the end of the archive. By default, these are reported as
+        /// warnings and skipped.
+        #[clap(long, conflicts_with = "docker-save")]
+        strict: bool,
+
+        /// Continue parsing past end-of-archive markers, e.g. for archives
+        /// concatenated with `cat a.tar b.tar`.
+        #[clap(long, name = "ignore-zeros", conflicts_with = "docker-save")]
+        ignore_zeros: bool,
+
+        /// Path of the index file. Defaults to the tar path with .index
+        /// appended. Only allowed with a single path. With --docker-save,
+        /// the layer store directory.
+        #[clap(short, long, name = "output")]
+        output: Option<String>,
+
+        /// Index the layers of docker save archives.
+        #[clap(long)]
+        docker_save: bool,
+
+        /// Index the paths concurrently and print a summary.
+        #[clap(long, conflicts_with = "docker-save")]
+        batch: bool,
 
-        /// Path of the tar file/folder.
+        /// Number of paths indexed at the same time with --batch. Defaults
+        /// to the number of CPUs.
+        #[clap(short, long, requires = "batch")]
+        jobs: Option<usize>,
+
+        /// Paths of the tar files/folders. "-" reads a tar file from stdin.
         #[clap(value_parser, name = "path", required = true)]
-        path: String,
+        paths: Vec<String>,
     },
 
     /// Mount confidential container file-system.
     Mount {
-        /// Colon separated list of indexes.
+        /// Colon separated list of indexes, top layer first.
         #[clap(short, long, name = "index")]
         index: String,
 
-        /// Path of the tar file/folder.
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        /// Mount fails unless the index file hashes to this value.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        /// Expected digest of the layer tar file, e.g. sha256:<hex>. Mount
+        /// fails unless the index was created from a layer with this digest.
+        /// Use with --key, --pubkey or --index-digest, which make the index
+        /// trustworthy.
+        #[clap(long, name = "digest", value_parser)]
+        digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: Box<KeyArgs>,
+
+        /// Seconds to wait for the file-system to become ready before exiting
+        /// with status 124.
+        #[clap(long, name = "timeout")]
+        timeout: Option<u64>,
+
+        /// Hold shared advisory locks on the index and tar file while mounted.
+        #[clap(long)]
+        lock: bool,
+
+        /// Write a JSON statistics report to this file at unmount.
+        #[clap(long, name = "stats")]
+        stats: Option<String>,
+
+        /// Number of threads used to verify pages. Pages are verified on the
+        /// FUSE thread if zero.
+        #[clap(long, name = "verify-threads", default_value_t = 0)]
+        verify_threads: usize,
+
+        /// Confine all threads to given CPUs, e.g. 0-1,4.
+        #[clap(long, name = "pin-cpus", value_parser)]
+        pin_cpus: Option<CpuList>,
+
+        /// Already open file descriptor of the tar file. The path of the tar
+        /// file is omitted when this is used.
+        #[clap(long, name = "tar-fd")]
+        tar_fd: Option<i32>,
+
+        /// Maximum number of files open at the same time. Further opens fail
+        /// with EMFILE.
+        #[clap(long, name = "max-open-files")]
+        max_open_files: Option<u64>,
+
+        /// Find names stored in one Unicode normalization form (NFC or NFD)
+        /// when looked up in the other.
+        #[clap(long)]
+        normalize_names: bool,
+
+        /// Seconds the kernel caches looked up entries, and the attributes
+        /// returned with them, for.
+        #[clap(long, default_value = "86400")]
+        entry_timeout: f64,
+
+        /// Seconds the kernel caches attributes for.
+        #[clap(long, default_value = "86400")]
+        attr_timeout: f64,
+
+        /// Do not ask the kernel to keep cached file contents and directory
+        /// listings across opens, e.g. to measure uncached reads.
+        #[clap(long)]
+        no_keep_cache: bool,
+
+        /// Open files for direct I/O, bypassing the page cache, so that
+        /// every read reaches the file-system.
+        #[clap(long)]
+        direct_io: bool,
+
+        /// What to do after a FUSE callback panics. The failing request gets
+        /// EIO either way.
+        #[clap(long, value_enum, default_value = "eio")]
+        on_panic: OnPanic,
+
+        /// When the contents of files are verified.
+        #[clap(long, value_enum, default_value = "every-read")]
+        verify_policy: VerifyPolicy,
+
+        /// What to do after the contents of a file fail verification. The
+        /// failing read gets EIO unless this is panic.
+        #[clap(long, value_enum, default_value = "eio")]
+        on_verify_failure: OnVerifyFailure,
+
+        /// File holding the secret key of an index created with --hash-mode
+        /// hmac. Defaults to the hex key in CC_FS_MEASURE_KEY.
+        #[clap(long, name = "measure-key")]
+        measure_key: Option<String>,
+
+        /// How the tar file is read: into buffers, or from a memory mapping.
+        /// With dir, the path is a folder holding the files of the index.
+        #[clap(long, value_enum, default_value = "pread")]
+        backend: Backend,
+
+        /// How whiteouts are shown: as stored, not at all, or as overlayfs
+        /// 0:0 character devices and opaque attributes.
+        #[clap(long, value_enum, default_value = "keep")]
+        whiteouts: WhiteoutMode,
+
+        /// Writable upper layer over the layers: "tmpfs" to hold changes in
+        /// memory, or a directory to hold the contents of changed files in.
+        /// The mount is read-only without it.
+        #[clap(long, name = "upper", value_parser)]
+        upper: Option<UpperStore>,
+
+        /// Unix socket to accept requests to commit the upper layer on. Only
+        /// the user running the mount and root may connect.
+        #[clap(long, name = "commit-socket", requires = "upper")]
+        commit_socket: Option<String>,
+
+        /// Directory of the index to mount instead of the root, e.g.
+        /// /usr/share/app.
+        #[clap(long, name = "subdir")]
+        subdir: Option<String>,
+
+        /// Offset added to the user ids of files, e.g. the first id of the
+        /// range mapped to a user namespace.
+        #[clap(long, name = "uid-offset", conflicts_with = "id-map")]
+        uid_offset: Option<u32>,
+
+        /// Offset added to the group ids of files.
+        #[clap(long, name = "gid-offset", conflicts_with = "id-map")]
+        gid_offset: Option<u32>,
+
+        /// File of ranges of user and group ids to shift the ids of files
+        /// by, one "u|g FIRST SHIFTED COUNT" per line, or an OCI runtime
+        /// spec with uidMappings and gidMappings.
+        #[clap(long, name = "id-map", conflicts_with = "userns")]
+        id_map: Option<String>,
+
+        /// User namespace, e.g. /proc/PID/ns/user, whose id mappings the ids
+        /// of files are shifted by.
+        #[clap(
+            long,
+            name = "userns",
+            conflicts_with_all = &["uid-offset", "gid-offset"]
+        )]
+        userns: Option<String>,
+
+        #[clap(flatten)]
+        owners: Box<OwnerArgs>,
+
+        /// Path of the tar file/folder. Colon separated list of tar files, in
+        /// the order of the indexes, when several layers are mounted.
         #[clap(value_parser, name = "path", required = true)]
         path: String,
 
+        /// Mount directory.
+        #[clap(value_parser, name = "mountpoint")]
+        mount_point: Option<String>,
+    },
+
+    /// Unmount a confidential container file-system.
+    Umount {
+        /// Seconds to wait for the file-system process to finish.
+        #[clap(long, default_value = "10")]
+        timeout: u64,
+
         /// Mount directory.
         #[clap(value_parser, name = "mountpoint", required = true)]
         mount_point: String,
     },
+
+    /// Commit the upper layer of a mount as a new layer tar and index.
+    Commit {
+        /// What is saved for each page of the new layer: intermediate hash
+        /// states, leaf hashes of a Merkle tree, or HMACs under the key in
+        /// CC_FS_MEASURE_KEY.
+        #[clap(long, value_enum, default_value = "chained")]
+        hash_mode: HashMode,
+
+        /// Path of the index file. Defaults to the tar path with .index
+        /// appended.
+        #[clap(short, long, name = "output")]
+        output: Option<String>,
+
+        /// Commit socket of a mount, as given to --commit-socket.
+        #[clap(value_parser, name = "socket", required = true)]
+        socket: String,
+
+        /// Path of the layer tar file to write. Must not exist.
+        #[clap(value_parser, name = "path", required = true)]
+        path: String,
+    },
+
+    /// Extract verified contents of a layer to a directory.
+    Extract {
+        /// Path of the index file.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Write security.ima hashes of extracted files.
+        #[clap(long)]
+        ima: bool,
+
+        /// Clamp modification times to this many seconds since the epoch.
+        /// Defaults to SOURCE_DATE_EPOCH if set.
+        #[clap(long, name = "clamp-mtime")]
+        clamp_mtime: Option<i64>,
+
+        /// Representation of whiteouts.
+        #[clap(long, value_enum, default_value = "oci")]
+        whiteouts: Whiteouts,
+
+        /// Record the verified digests of extracted files and of the index
+        /// in user.ccfs.* extended attributes.
+        #[clap(long)]
+        stamp: bool,
+
+        #[clap(flatten)]
+        owners: OwnerArgs,
+
+        /// Path of the tar file.
+        #[clap(value_parser, name = "path", required = true)]
+        path: String,
+
+        /// Destination directory.
+        #[clap(value_parser, name = "dest", required = true)]
+        dest: String,
+    },
+
+    /// Write verified contents of a layer as a canonical tar file.
+    Recompose {
+        /// Path of the index file.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Clamp modification times to this many seconds since the epoch.
+        /// Defaults to SOURCE_DATE_EPOCH if set.
+        #[clap(long, name = "clamp-mtime")]
+        clamp_mtime: Option<i64>,
+
+        /// Representation of whiteouts.
+        #[clap(long, value_enum, default_value = "oci")]
+        whiteouts: Whiteouts,
+
+        /// Path of the tar file.
+        #[clap(value_parser, name = "path", required = true)]
+        path: String,
+
+        /// Path of the canonical tar file to write. Use - for stdout.
+        #[clap(value_parser, name = "out", required = true)]
+        out: String,
+    },
+
+    /// Verify all files of a layer against the index.
+    Verify {
+        /// Path of the index file.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Path of the tar file.
+        #[clap(value_parser, name = "path", required = true)]
+        path: String,
+    },
+
+    /// Verify a single file of a layer against the index.
+    VerifyFile {
+        /// Path of the index file.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Path of the tar file.
+        #[clap(value_parser, name = "path", required = true)]
+        path: String,
+
+        /// Path of the file within the layer.
+        #[clap(value_parser, name = "file", required = true)]
+        file: String,
+    },
+
+    /// List the contents of an index recursively.
+    Ls {
+        /// Path of the index file, or colon separated paths of the indexes
+        /// of the layers of an image, top layer first.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Directory within the layer to list.
+        #[clap(value_parser, name = "dir", default_value = "/")]
+        dir: String,
+    },
+
+    /// Print all fields of the inode at a path in an index.
+    Stat {
+        /// Path of the index file, or colon separated paths of the indexes
+        /// of the layers of an image, top layer first.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Path within the layer.
+        #[clap(value_parser, name = "file", required = true)]
+        file: String,
+    },
+
+    /// Summarize an index.
+    Stats {
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Path of the index file.
+        #[clap(value_parser, name = "index", required = true)]
+        index: String,
+    },
+
+    /// Print a single file of a layer, verifying its contents.
+    Cat {
+        /// Path of the index file.
+        #[clap(short, long, name = "index")]
+        index: String,
+
+        /// Expected digest of the index file, e.g. sha256:<hex>.
+        #[clap(long, name = "index-digest", value_parser)]
+        index_digest: Option<Digest>,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Path of the tar file.
+        #[clap(value_parser, name = "path", required = true)]
+        path: String,
+
+        /// Path of the file within the layer.
+        #[clap(value_parser, name = "file", required = true)]
+        file: String,
+    },
+
+    /// Receive layers from the host over vsock into a layer store.
+    GuestPull {
+        /// Root directory of the layer store.
+        #[clap(short, long, name = "store")]
+        store: String,
+
+        /// vsock port to listen on.
+        #[clap(short, long, name = "port")]
+        port: u32,
+    },
 }
 
 #[doc(hidden)]
@@ -208,11 +1296,273 @@ fn main() -> Result<()> {
     // Parse and dispatch commands.
     let cli = Cli::parse();
     match &cli.command {
-        Commands::Index { digest, path } => tar::index(digest, path),
+        Commands::Index {
+            digest,
+            digest_file,
+            storage,
+            format,
+            compress,
+            key,
+            sign_key,
+            split_states,
+            whole_file_max_size,
+            granularity,
+            hash_mode,
+            measure_key,
+            file_digests,
+            hash_threads,
+            strict,
+            ignore_zeros,
+            output,
+            docker_save,
+            batch,
+            jobs,
+            paths,
+        } => tar::index(
+            &IndexOptions {
+                digest: digest.clone(),
+                digest_file: digest_file.clone(),
+                storage: *storage,
+                format: *format,
+                compression: *compress,
+                keys: Keys {
+                    hmac: index::read_key(key)?,
+                    signing: index::read_signing_key(sign_key)?,
+                    verifying: None,
+                },
+                split_states: *split_states,
+                whole_file_max_size: *whole_file_max_size,
+                granularity: *granularity,
+                hash_mode: *hash_mode,
+                // The environment is only consulted for keyed measurement.
+                measure_key: match (hash_mode, measure_key) {
+                    (HashMode::Hmac, _) | (_, Some(_)) => {
+                        hash::read_measure_key(measure_key)?
+                    }
+                    _ => None,
+                },
+                file_digests: *file_digests,
+                hash_threads: *hash_threads,
+                strict: *strict,
+                ignore_zeros: *ignore_zeros,
+                output: output.clone(),
+                docker_save: *docker_save,
+                batch: *batch,
+                jobs: *jobs,
+            },
+            paths,
+        ),
         Commands::Mount {
             index,
+            index_digest,
+            digest,
+            keys,
+            timeout,
+            lock,
+            stats,
+            verify_threads,
+            pin_cpus,
+            tar_fd,
+            max_open_files,
+            normalize_names,
+            entry_timeout,
+            attr_timeout,
+            no_keep_cache,
+            direct_io,
+            on_panic,
+            verify_policy,
+            on_verify_failure,
+            measure_key,
+            backend,
+            whiteouts,
+            upper,
+            commit_socket,
+            subdir,
+            uid_offset,
+            gid_offset,
+            id_map,
+            userns,
+            owners,
             path,
             mount_point,
-        } => fs::mount(index, path, mount_point),
+        } => {
+            // With --tar-fd, the only positional argument is the mount point.
+            let (tar, mount_point) = match (tar_fd, mount_point) {
+                (None, Some(mount_point)) => (path.clone(), mount_point),
+                (Some(fd), None) => (format!("/proc/self/fd/{}", fd), path),
+                (None, None) => return Err(anyhow!("missing mount point")),
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!("tar path and --tar-fd both supplied"))
+                }
+            };
+            // Layers are separated by colons, top layer first.
+            let split = |paths: &str| -> Vec<String> {
+                paths.split(':').map(String::from).collect()
+            };
+            fs::mount(
+                &split(index),
+                &split(&tar),
+                mount_point,
+                &MountOptions {
+                    index_digest: index_digest.clone(),
+                    digest: digest.clone(),
+                    keys: keys.read()?,
+                    timeout: *timeout,
+                    lock: *lock,
+                    stats: stats.clone(),
+                    verify_threads: *verify_threads,
+                    pin_cpus: pin_cpus.clone(),
+                    tar_fd: *tar_fd,
+                    max_open_files: *max_open_files,
+                    normalize_names: *normalize_names,
+                    entry_timeout: Duration::try_from_secs_f64(*entry_timeout)?,
+                    attr_timeout: Duration::try_from_secs_f64(*attr_timeout)?,
+                    keep_cache: !*no_keep_cache,
+                    direct_io: *direct_io,
+                    on_panic: *on_panic,
+                    verify_policy: *verify_policy,
+                    on_verify_failure: *on_verify_failure,
+                    measure_key: hash::read_measure_key(measure_key)?,
+                    backend: *backend,
+                    whiteouts: *whiteouts,
+                    upper: upper.clone(),
+                    commit_socket: commit_socket.clone(),
+                    subdir: subdir.clone(),
+                    ids: match (id_map, userns) {
+                        (Some(path), _) => IdMap::read(path)?,
+                        (_, Some(path)) => IdMap::from_userns(path)?,
+                        _ => IdMap::offsets(*uid_offset, *gid_offset)?,
+                    },
+                    owners: Owners::read(&owners.passwd, &owners.group)?,
+                },
+            )
+        }
+        Commands::Umount {
+            timeout,
+            mount_point,
+        } => umount::umount(mount_point, *timeout),
+        Commands::Commit {
+            hash_mode,
+            output,
+            socket,
+            path,
+        } => commit::commit(
+            socket,
+            path,
+            &IndexOptions {
+                digest: None,
+                digest_file: None,
+                storage: Storage::Plain,
+                format: Format::Bincode,
+                compression: Compression::None,
+                keys: Keys::default(),
+                split_states: false,
+                whole_file_max_size: 0,
+                granularity: Granularity::Page,
+                hash_mode: *hash_mode,
+                measure_key: match hash_mode {
+                    HashMode::Hmac => hash::read_measure_key(&None)?,
+                    _ => None,
+                },
+                file_digests: false,
+                hash_threads: None,
+                strict: true,
+                ignore_zeros: false,
+                output: output.clone(),
+                docker_save: false,
+                batch: false,
+                jobs: None,
+            },
+        ),
+        Commands::Extract {
+            index,
+            index_digest,
+            keys,
+            ima,
+            clamp_mtime,
+            whiteouts,
+            stamp,
+            owners,
+            path,
+            dest,
+        } => extract::extract(
+            index,
+            index_digest,
+            &keys.read()?,
+            path,
+            dest,
+            &ExtractOptions {
+                ima: *ima,
+                clamp_mtime: match clamp_mtime {
+                    Some(epoch) => Some(*epoch),
+                    None => extract::source_date_epoch()?,
+                },
+                whiteouts: *whiteouts,
+                stamp: *stamp,
+                owners: Owners::read(&owners.passwd, &owners.group)?,
+            },
+        ),
+        Commands::Recompose {
+            index,
+            index_digest,
+            keys,
+            clamp_mtime,
+            whiteouts,
+            path,
+            out,
+        } => recompose::recompose(
+            index,
+            index_digest,
+            &keys.read()?,
+            path,
+            out,
+            &RecomposeOptions {
+                clamp_mtime: match clamp_mtime {
+                    Some(epoch) => Some(*epoch),
+                    None => extract::source_date_epoch()?,
+                },
+                whiteouts: *whiteouts,
+            },
+        ),
+        Commands::Verify {
+            index,
+            index_digest,
+            keys,
+            path,
+        } => verify::verify(index, index_digest, &keys.read()?, path),
+        Commands::VerifyFile {
+            index,
+            index_digest,
+            keys,
+            path,
+            file,
+        } => {
+            verify::verify_file(index, index_digest, &keys.read()?, path, file)
+        }
+        Commands::Ls {
+            index,
+            index_digest,
+            keys,
+            dir,
+        } => inspect::ls(index, index_digest, &keys.read()?, dir),
+        Commands::Stat {
+            index,
+            index_digest,
+            keys,
+            file,
+        } => inspect::stat(index, index_digest, &keys.read()?, file),
+        Commands::Stats {
+            index_digest,
+            keys,
+            index,
+        } => inspect::stats(index, index_digest, &keys.read()?),
+        Commands::Cat {
+            index,
+            index_digest,
+            keys,
+            path,
+            file,
+        } => cat::cat(index, index_digest, &keys.read()?, path, file),
+        Commands::GuestPull { store, port } => vsock::serve(store, *port),
     }
 }
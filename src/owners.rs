@@ -0,0 +1,89 @@
+//! Resolution of owner names to ids.
+//!
+//! Tar entries record the owner and group of a file both as numeric ids and
+//! as names. Like GNU tar when extracting, the names can be looked up in a
+//! user and group database, e.g. the `/etc/passwd` and `/etc/group` of the
+//! image, and the ids they resolve to used instead of the numeric ids. Names
+//! that are empty or not found keep their numeric ids.
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::index::Index;
+
+/// Read a database in the form of `/etc/passwd` or `/etc/group`. The first
+/// entry of a name is used.
+///
+/// # Arguments
+/// * `path` - Path of the database.
+/// * `returns` - Ids by name.
+fn read_db(path: &String) -> Result<HashMap<String, u32>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path))?;
+    let mut ids = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        let id = match fields.get(2).map(|id| id.parse::<u32>()) {
+            Some(Ok(id)) if !fields[0].is_empty() => id,
+            _ => return Err(anyhow!("{}:{}: invalid entry", path, n + 1)),
+        };
+        ids.entry(fields[0].to_string()).or_insert(id);
+    }
+    Ok(ids)
+}
+
+/// User and group databases.
+pub struct Owners {
+    /// User ids by user name.
+    users: HashMap<String, u32>,
+
+    /// Group ids by group name.
+    groups: HashMap<String, u32>,
+}
+
+impl Owners {
+    /// Read user and group databases.
+    ///
+    /// # Arguments
+    /// * `passwd` - Path of the user database, if any.
+    /// * `group` - Path of the group database, if any.
+    /// * `returns` - None if neither database is given.
+    pub fn read(
+        passwd: &Option<String>,
+        group: &Option<String>,
+    ) -> Result<Option<Owners>> {
+        if passwd.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        let read = |path: &Option<String>| match path {
+            Some(path) => read_db(path),
+            None => Ok(HashMap::new()),
+        };
+        Ok(Some(Owners {
+            users: read(passwd)?,
+            groups: read(group)?,
+        }))
+    }
+
+    /// Replace the ids of the inodes of an index by the ids their names
+    /// resolve to.
+    ///
+    /// # Arguments
+    /// * `index` - The index.
+    pub fn resolve(&self, index: &mut Index) {
+        for inode in index.inodes.iter_mut() {
+            let uname = &index.names[inode.uname as usize];
+            if let Some(uid) = self.users.get(uname) {
+                inode.uid = *uid;
+            }
+            let gname = &index.names[inode.gname as usize];
+            if let Some(gid) = self.groups.get(gname) {
+                inode.gid = *gid;
+            }
+        }
+    }
+}
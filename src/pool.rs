@@ -0,0 +1,175 @@
+//! Worker pool and CPU placement.
+//!
+//! By default pages are verified on the thread serving the FUSE request. With
+//! `--verify-threads N`, the pages of each read are instead split across a
+//! pool of N worker threads and verified in parallel. The same pool runs the
+//! jobs of batch indexing.
+//!
+//! With `--pin-cpus`, the process is confined to the given CPUs before any
+//! threads are started. All threads, including the FUSE session and the
+//! verification workers, inherit the affinity. This allows cc-fs to be kept on
+//! housekeeping vCPUs away from the confidential workload.
+use std::cmp::min;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+use crate::hash::Hasher;
+
+/// A job run by a worker.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed size pool of worker threads.
+pub struct Pool {
+    /// Queue of jobs shared by the workers.
+    jobs: Sender<Job>,
+
+    /// Number of worker threads.
+    threads: usize,
+}
+
+impl Pool {
+    /// Start a pool of worker threads.
+    ///
+    /// # Arguments
+    /// * `threads` - Number of worker threads. Must be non-zero.
+    pub fn new(threads: usize) -> Result<Pool> {
+        let (jobs, queue) = channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("cc-fs-worker-{}", i))
+                .spawn(move || Pool::work(&queue))?;
+        }
+        Ok(Pool { jobs, threads })
+    }
+
+    /// Run jobs from the queue until the pool is dropped.
+    ///
+    /// # Arguments
+    /// * `queue` - Queue of jobs shared by the workers.
+    fn work(queue: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = match queue.lock() {
+                Ok(queue) => queue.recv(),
+                Err(_) => return,
+            };
+            match job {
+                Ok(job) => job(),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Run a job on one of the workers.
+    ///
+    /// # Arguments
+    /// * `job` - The job.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) -> Result<()> {
+        self.jobs
+            .send(Box::new(job))
+            .map_err(|_| anyhow!("worker pool stopped"))
+    }
+
+    /// Verify pages of a file in parallel.
+    ///
+    /// # Arguments
+    /// * `hasher` - Hasher holding the hash states.
+    /// * `pos` - The position of the `before` state for the first page.
+    /// * `buf` - Pages of data.
+    /// * `returns` - The position of the first page that fails verification.
+    pub fn verify(
+        &self,
+        hasher: &Arc<Hasher>,
+        pos: u32,
+        buf: Arc<Vec<u8>>,
+    ) -> Result<(), u32> {
+        let pages = buf.len().div_ceil(4096);
+        let pages_per_job = pages.div_ceil(self.threads).max(1);
+
+        let (results, received) = channel();
+        let mut jobs = 0;
+        for first in (0..pages).step_by(pages_per_job) {
+            let hasher = hasher.clone();
+            let buf = buf.clone();
+            let results = results.clone();
+            let end = min((first + pages_per_job) * 4096, buf.len());
+            let job = move || {
+                let result = hasher
+                    .verify_pages(pos + first as u32, &buf[first * 4096..end]);
+                let _ = results.send(result);
+            };
+            if self.jobs.send(Box::new(job)).is_err() {
+                return Err(pos + first as u32);
+            }
+            jobs += 1;
+        }
+
+        // Report the earliest failing page.
+        let mut failed: Option<u32> = None;
+        for result in received.iter().take(jobs) {
+            if let Err(page) = result {
+                failed = Some(failed.map_or(page, |p| p.min(page)));
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+}
+
+/// A list of CPUs, e.g. `0-3,6`.
+#[derive(Clone, Debug)]
+pub struct CpuList(pub Vec<usize>);
+
+impl FromStr for CpuList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = vec![];
+        for part in s.split(',') {
+            let (first, last): (usize, usize) = match part.split_once('-') {
+                Some((first, last)) => (first.parse()?, last.parse()?),
+                None => {
+                    let cpu = part.parse()?;
+                    (cpu, cpu)
+                }
+            };
+            if first > last || last >= libc::CPU_SETSIZE as usize {
+                return Err(anyhow!("invalid cpu range {}", part));
+            }
+            cpus.extend(first..=last);
+        }
+        Ok(CpuList(cpus))
+    }
+}
+
+/// Confine the calling thread to given CPUs.
+///
+/// Threads created afterwards inherit the affinity.
+///
+/// # Arguments
+/// * `cpus` - CPUs to run on.
+pub fn pin_cpus(cpus: &CpuList) -> Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in &cpus.0 {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(
+            0,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &set,
+        ) != 0
+        {
+            return Err(anyhow!(
+                "failed to pin to cpus {:?}: {}",
+                cpus.0,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,464 @@
+//! Re-emit the verified contents of a layer as a canonical tar stream.
+//!
+//! Layer tars produced by different tools differ in header formats, entry
+//! order, and extensions even when their contents are the same. Recomposing
+//! writes the contents in a deterministic form whose digest depends only on
+//! the contents and metadata recorded in the index:
+//! - Entries are sorted by path. Parents precede their children.
+//! - Headers are ustar headers. The prefix, device number, and checksum
+//!   padding fields are always zero.
+//! - Modes are limited to permission bits. Times are whole seconds.
+//! - A PAX extended header is emitted only for fields that do not fit in the
+//!   ustar header: long paths, link targets and owner names, large ids,
+//!   times outside of the ustar range, and extended attributes.
+//! - Whiteouts are written in the selected representation, OCI `.wh.` files
+//!   by default or overlayfs character devices and opaque attributes.
+//! - Of a set of hard links, the entry with the smallest path carries the
+//!   contents and the others link to it.
+//! - The archive ends with two zero blocks.
+//!
+//! Every page of every file is verified against the index while it is being
+//! written, and recomposing is aborted on the first mismatch.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::slice;
+
+use anyhow::{Context, Result};
+
+use crate::digest::{Algorithm, Digest, DigestWriter};
+use crate::index::{FileType, Inode, Keys};
+use crate::layer::Layer;
+use crate::tar::PosixHeader;
+use crate::whiteout::*;
+
+/// Chunk size for copying file contents. Multiple of page size.
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Name of PAX extended headers.
+const PAX_HEADER_NAME: &str = "././@PaxHeader";
+
+/// Largest value of an 8 byte octal field.
+const MAX_OCTAL_8: u64 = 0o7777777;
+
+/// Largest value of a 12 byte octal field.
+const MAX_OCTAL_12: u64 = 0o77777777777;
+
+/// Options for recomposing a layer.
+pub struct RecomposeOptions {
+    /// Clamp modification times to this many seconds since the epoch.
+    pub clamp_mtime: Option<i64>,
+
+    /// Representation of whiteouts.
+    pub whiteouts: Whiteouts,
+}
+
+/// Write a number as a zero-padded, null terminated octal field.
+///
+/// # Arguments
+/// * `field` - The field to write to.
+/// * `n` - The number. Must fit in the field.
+fn put_octal(field: &mut [u8], n: u64) {
+    let digits = format!("{:0width$o}", n, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// Copy a string into a null padded field, truncating it if necessary.
+///
+/// # Arguments
+/// * `field` - The field to write to.
+/// * `s` - The string.
+fn put_str(field: &mut [u8], s: &str) {
+    let len = s.len().min(field.len());
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Format a time as a pax decimal number of seconds, with a fractional part
+/// if needed.
+///
+/// # Arguments
+/// * `secs` - Seconds since the epoch, rounded down.
+/// * `nsecs` - Nanoseconds.
+pub fn format_decimal_time(secs: i64, nsecs: u32) -> String {
+    if nsecs == 0 {
+        return secs.to_string();
+    }
+    let (sign, secs, nsecs) = match secs {
+        s if s < 0 => ("-", -(s + 1), 1_000_000_000 - nsecs),
+        s => ("", s, nsecs),
+    };
+    let frac = format!("{:09}", nsecs);
+    format!("{}{}.{}", sign, secs, frac.trim_end_matches('0'))
+}
+
+/// Append a PAX record.
+///
+/// # Arguments
+/// * `records` - Records to append to.
+/// * `key` - Key of the record.
+/// * `value` - Value of the record.
+fn put_pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // The length prefix includes its own digits.
+    let rest = 1 + key.len() + 1 + value.len() + 1;
+    let mut len = rest + 1;
+    while len.to_string().len() + rest > len {
+        len += 1;
+    }
+    records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// Writer of canonical tar streams.
+pub struct TarWriter<'a, W: Write> {
+    /// Underlying writer.
+    pub out: W,
+
+    /// String table of the index.
+    pub names: &'a [String],
+
+    /// Clamp modification times to this many seconds since the epoch.
+    pub clamp_mtime: Option<i64>,
+}
+
+impl<'a, W: Write> TarWriter<'a, W> {
+    /// Write a header with computed checksum.
+    ///
+    /// # Arguments
+    /// * `header` - The header. The checksum field is filled in.
+    fn write_header(&mut self, header: &mut PosixHeader) -> Result<()> {
+        header.magic.copy_from_slice(b"ustar\0");
+        header.version.copy_from_slice(b"00");
+        header.chksum = [b' '; 8];
+
+        let bytes = unsafe {
+            let raw_ptr = header as *const _ as *const u8;
+            slice::from_raw_parts(raw_ptr, mem::size_of::<PosixHeader>())
+        };
+        let sum: u64 = bytes.iter().map(|b| *b as u64).sum();
+        let chksum = format!("{:06o}\0 ", sum);
+        header.chksum.copy_from_slice(chksum.as_bytes());
+
+        let bytes = unsafe {
+            let raw_ptr = header as *const _ as *const u8;
+            slice::from_raw_parts(raw_ptr, mem::size_of::<PosixHeader>())
+        };
+        self.out.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Write zero padding up to the next 512 byte boundary.
+    ///
+    /// # Arguments
+    /// * `len` - Number of bytes written since the last boundary.
+    pub fn write_padding(&mut self, len: u64) -> Result<()> {
+        let padding = (512 - len % 512) % 512;
+        self.out.write_all(&[0u8; 512][..padding as usize])?;
+        Ok(())
+    }
+
+    /// Write a PAX extended header.
+    ///
+    /// # Arguments
+    /// * `records` - The PAX records.
+    fn write_pax(&mut self, records: &[u8]) -> Result<()> {
+        let mut header: PosixHeader = unsafe { mem::zeroed() };
+        put_str(&mut header.name, PAX_HEADER_NAME);
+        put_octal(&mut header.mode, 0o644);
+        put_octal(&mut header.uid, 0);
+        put_octal(&mut header.gid, 0);
+        put_octal(&mut header.size, records.len() as u64);
+        put_octal(&mut header.mtime, 0);
+        header.typeflag = b'x';
+        self.write_header(&mut header)?;
+        self.out.write_all(records)?;
+        self.write_padding(records.len() as u64)
+    }
+
+    /// Write the header of an entry, preceded by a PAX extended header if
+    /// needed.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the entry in the archive.
+    /// * `typeflag` - Type of the entry.
+    /// * `inode` - Inode providing the metadata.
+    /// * `size` - Size of the contents that follow.
+    /// * `link` - Target of a link.
+    /// * `xattrs` - Extended attributes.
+    pub fn write_entry(
+        &mut self,
+        path: &str,
+        typeflag: u8,
+        inode: &Inode,
+        size: u64,
+        link: &str,
+        xattrs: &[(String, Vec<u8>)],
+    ) -> Result<()> {
+        let mut header: PosixHeader = unsafe { mem::zeroed() };
+        let mut records = vec![];
+        let uname = &self.names[inode.uname as usize];
+        let gname = &self.names[inode.gname as usize];
+        let (mtime, mtime_nsec) = match self.clamp_mtime {
+            Some(epoch) if inode.mtime > epoch => (epoch, 0),
+            _ => (inode.mtime, inode.mtime_nsec),
+        };
+
+        if path.len() > header.name.len() {
+            put_pax_record(&mut records, "path", path.as_bytes());
+        }
+        if link.len() > header.linkname.len() {
+            put_pax_record(&mut records, "linkpath", link.as_bytes());
+        }
+        if uname.len() >= header.uname.len() {
+            put_pax_record(&mut records, "uname", uname.as_bytes());
+        }
+        if gname.len() >= header.gname.len() {
+            put_pax_record(&mut records, "gname", gname.as_bytes());
+        }
+        if inode.uid as u64 > MAX_OCTAL_8 {
+            put_pax_record(
+                &mut records,
+                "uid",
+                inode.uid.to_string().as_bytes(),
+            );
+        }
+        if inode.gid as u64 > MAX_OCTAL_8 {
+            put_pax_record(
+                &mut records,
+                "gid",
+                inode.gid.to_string().as_bytes(),
+            );
+        }
+        if size > MAX_OCTAL_12 {
+            put_pax_record(&mut records, "size", size.to_string().as_bytes());
+        }
+        if mtime < 0 || mtime as u64 > MAX_OCTAL_12 || mtime_nsec != 0 {
+            put_pax_record(
+                &mut records,
+                "mtime",
+                format_decimal_time(mtime, mtime_nsec).as_bytes(),
+            );
+        }
+        let mut xattrs = xattrs.to_vec();
+        xattrs.sort();
+        for (name, value) in &xattrs {
+            let key = "SCHILY.xattr.".to_owned() + name;
+            put_pax_record(&mut records, &key, value);
+        }
+        if !records.is_empty() {
+            self.write_pax(&records)?;
+        }
+
+        put_str(&mut header.name, path);
+        put_octal(&mut header.mode, (inode.mode & 0o7777) as u64);
+        put_octal(&mut header.uid, (inode.uid as u64).min(MAX_OCTAL_8));
+        put_octal(&mut header.gid, (inode.gid as u64).min(MAX_OCTAL_8));
+        put_octal(&mut header.size, size.min(MAX_OCTAL_12));
+        put_octal(
+            &mut header.mtime,
+            mtime.clamp(0, MAX_OCTAL_12 as i64) as u64,
+        );
+        header.typeflag = typeflag;
+        put_str(&mut header.linkname, link);
+        if uname.len() < header.uname.len() {
+            put_str(&mut header.uname, uname);
+        }
+        if gname.len() < header.gname.len() {
+            put_str(&mut header.gname, gname);
+        }
+        let (major, minor) = match typeflag {
+            b'3' | b'4' => inode.device(),
+            _ => (0, 0),
+        };
+        put_octal(&mut header.devmajor, (major as u64).min(MAX_OCTAL_8));
+        put_octal(&mut header.devminor, (minor as u64).min(MAX_OCTAL_8));
+        self.write_header(&mut header)
+    }
+}
+
+/// How an entry of the archive is produced from an inode.
+pub enum Kind {
+    /// The inode itself.
+    Inode,
+
+    /// A whiteout in the selected representation.
+    Whiteout,
+
+    /// An OCI opaque marker inside the directory inode.
+    OpaqueMarker,
+}
+
+/// Path of an inode within the archive.
+///
+/// The leading '/' is removed, and directories end with '/'.
+///
+/// # Arguments
+/// * `inode` - The inode.
+/// * `name` - Exported name of the inode.
+fn archive_path(inode: &Inode, name: &str) -> String {
+    let path = inode.parent[1..].to_owned() + name;
+    match inode.typeflag {
+        FileType::Directory => path + "/",
+        _ => path,
+    }
+}
+
+/// Write a layer as a canonical tar stream.
+///
+/// # Arguments
+/// * `layer` - The layer.
+/// * `out` - Writer for the tar stream.
+/// * `options` - Recompose options.
+fn write_tar<W: Write>(
+    layer: &Layer,
+    out: W,
+    options: &RecomposeOptions,
+) -> Result<()> {
+    let inodes = &layer.index.inodes;
+    let mut writer = TarWriter {
+        out,
+        names: &layer.index.names,
+        clamp_mtime: options.clamp_mtime,
+    };
+
+    // Collect the entries and sort them by path. Paths of directories marked
+    // opaque by OCI markers are collected separately.
+    let mut entries: Vec<(String, usize, Kind)> = vec![];
+    let mut opaque: HashSet<String> = HashSet::new();
+    for (ino, inode) in inodes.iter().enumerate().skip(2) {
+        let name = match export_name(inode, options.whiteouts) {
+            Some(name) => name,
+            None => {
+                opaque.insert(inode.parent.clone());
+                continue;
+            }
+        };
+        let path = archive_path(inode, &name);
+        if is_whiteout(inode) {
+            entries.push((path, ino, Kind::Whiteout));
+            continue;
+        }
+        if options.whiteouts == Whiteouts::Oci && is_opaque_dir(inode) {
+            let marker = path.clone() + OPAQUE_WHITEOUT;
+            entries.push((marker, ino, Kind::OpaqueMarker));
+        }
+        entries.push((path, ino, Kind::Inode));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Archive paths of files already written, keyed by the inode holding the
+    // contents.
+    let mut written: HashMap<usize, String> = HashMap::new();
+
+    for (path, ino, kind) in &entries {
+        let inode = &inodes[*ino];
+        let mut xattrs =
+            inode.extra.as_ref().map_or(vec![], |e| e.xattrs.clone());
+        let link = inode.extra.as_ref().map_or("", |e| e.link.as_str());
+
+        match (kind, &inode.typeflag) {
+            (Kind::OpaqueMarker, _) => {
+                writer.write_entry(path, b'0', inode, 0, "", &[])?
+            }
+            (Kind::Whiteout, _) => {
+                let typeflag = match options.whiteouts {
+                    Whiteouts::Oci => b'0',
+                    Whiteouts::Overlay => b'3',
+                };
+                writer.write_entry(path, typeflag, inode, 0, "", &xattrs)?
+            }
+            (_, FileType::Directory) => {
+                // Represent opaque directories as selected.
+                xattrs.retain(|(name, _)| name != OVERLAY_OPAQUE_XATTR);
+                let dir = "/".to_owned() + path;
+                if options.whiteouts == Whiteouts::Overlay
+                    && (opaque.contains(&dir) || is_opaque_dir(inode))
+                {
+                    xattrs.push((OVERLAY_OPAQUE_XATTR.into(), b"y".to_vec()));
+                }
+                writer.write_entry(path, b'5', inode, 0, "", &xattrs)?
+            }
+            (_, FileType::SymLink) => {
+                writer.write_entry(path, b'2', inode, 0, link, &xattrs)?
+            }
+            (_, FileType::CharDevice) => {
+                writer.write_entry(path, b'3', inode, 0, "", &xattrs)?
+            }
+            (_, FileType::BlockDevice) => {
+                writer.write_entry(path, b'4', inode, 0, "", &xattrs)?
+            }
+            (_, FileType::Fifo) => {
+                writer.write_entry(path, b'6', inode, 0, "", &xattrs)?
+            }
+            (_, FileType::RegularFile | FileType::HardLink) => {
+                let target = match inode.typeflag {
+                    FileType::HardLink => inode.target_ino as usize,
+                    _ => *ino,
+                };
+                if let Some(first) = written.get(&target) {
+                    writer.write_entry(path, b'1', inode, 0, first, &xattrs)?;
+                    continue;
+                }
+
+                // The first entry of a set of hard links carries the
+                // contents of the target.
+                let file = &inodes[target];
+                let xattrs =
+                    file.extra.as_ref().map_or(vec![], |e| e.xattrs.clone());
+                writer.write_entry(path, b'0', file, file.size, "", &xattrs)?;
+                let mut offset = 0;
+                while offset < file.size {
+                    let buf = layer.read(file, offset, CHUNK_SIZE)?;
+                    writer.out.write_all(&buf)?;
+                    offset += buf.len() as u64;
+                }
+                writer.write_padding(offset)?;
+                written.insert(target, path.clone());
+            }
+        }
+    }
+
+    // End of archive.
+    writer.out.write_all(&[0u8; 1024])?;
+    Ok(())
+}
+
+/// Recompose a layer into a canonical tar file.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `tar` - Path of the tar file.
+/// * `out` - Path of the tar file to write. `-` for standard output.
+/// * `options` - Recompose options.
+pub fn recompose(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    tar: &String,
+    out: &String,
+    options: &RecomposeOptions,
+) -> Result<()> {
+    let layer = Layer::open(index, index_digest, keys, tar)?;
+
+    if out == "-" {
+        let mut writer =
+            DigestWriter::new(BufWriter::new(io::stdout()), Algorithm::Sha256);
+        write_tar(&layer, &mut writer, options)?;
+        eprintln!("wrote -, digest = {}", writer.finalize()?);
+        return Ok(());
+    }
+
+    let file = File::create(out)
+        .with_context(|| format!("failed to create {}", out))?;
+    let mut writer = DigestWriter::new(BufWriter::new(file), Algorithm::Sha256);
+    if let Err(e) = write_tar(&layer, &mut writer, options) {
+        drop(writer);
+        let _ = std::fs::remove_file(out);
+        return Err(e);
+    }
+    println!("wrote {}, digest = {}", out, writer.finalize()?);
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Per-mount statistics.
+//!
+//! Counters are collected while a file-system is mounted and written as a JSON
+//! report when the file-system is unmounted. cc-fs does not cache file
+//! contents itself; reads served from the kernel page cache never reach cc-fs
+//! and are therefore not counted.
+use std::collections::HashSet;
+use std::fs;
+
+use anyhow::{Context, Result};
+
+/// Statistics of a mounted file-system.
+#[derive(Default)]
+pub struct Stats {
+    /// Number of files opened.
+    pub opens: u64,
+
+    /// Number of read requests served.
+    pub reads: u64,
+
+    /// Number of bytes served.
+    pub bytes: u64,
+
+    /// Inode numbers of the files that have been read.
+    pub files: HashSet<u64>,
+
+    /// Number of pages verified.
+    pub verified_pages: u64,
+
+    /// Number of pages that failed verification.
+    pub failed_pages: u64,
+
+    /// Number of pages read without verification, having been verified by
+    /// an earlier read.
+    pub trusted_pages: u64,
+}
+
+impl Stats {
+    /// Record a read request.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number of the file read.
+    /// * `bytes` - Number of bytes served.
+    pub fn record_read(&mut self, ino: u64, bytes: u64) {
+        self.reads += 1;
+        self.bytes += bytes;
+        self.files.insert(ino);
+    }
+
+    /// Format the statistics as a JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"opens\":{},\"reads\":{},\"bytes\":{},\"files\":{},",
+                "\"verified_pages\":{},\"failed_pages\":{},",
+                "\"trusted_pages\":{}}}\n"
+            ),
+            self.opens,
+            self.reads,
+            self.bytes,
+            self.files.len(),
+            self.verified_pages,
+            self.failed_pages,
+            self.trusted_pages
+        )
+    }
+
+    /// Write the statistics report to given file.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the report file. Overwritten if it exists.
+    pub fn to_file(&self, path: &String) -> Result<()> {
+        fs::write(path, self.to_json())
+            .with_context(|| format!("failed to write {}", path))
+    }
+}
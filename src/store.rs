@@ -0,0 +1,139 @@
+//! Content-addressed store of layers and their indexes.
+//!
+//! Layers received as streams, e.g. by the `guest-pull` subcommand, are added
+//! to the store along with their expected digest. The store indexes each
+//! layer, checks it against the digest and keeps both the tar file and its
+//! index under the digest, ready to be mounted.
+//!
+//! Layout of the store directory:
+//! ```text
+//! <dir>/<algorithm>/<hex>.tar        Layer tar file.
+//! <dir>/<algorithm>/<hex>.tar.index  Index of the layer.
+//! ```
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::digest::Digest;
+use crate::index::{Compression, Format, Keys, Storage};
+use crate::tar::{self, ParseOptions};
+
+/// A directory of layers keyed by digest.
+pub struct Store {
+    /// Root directory of the store.
+    dir: String,
+}
+
+impl Store {
+    /// Open a store, creating the directory if needed.
+    ///
+    /// # Arguments
+    /// * `dir` - Root directory of the store.
+    pub fn new(dir: &String) -> Result<Store> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir))?;
+        Ok(Store {
+            dir: dir.to_string(),
+        })
+    }
+
+    /// Path of the tar file of a layer.
+    ///
+    /// The hex of the digest becomes part of the path, so it is checked to
+    /// consist of the right number of lower-case hex digits only.
+    ///
+    /// # Arguments
+    /// * `digest` - Digest of the layer.
+    pub fn tar_path(&self, digest: &Digest) -> Result<String> {
+        let valid = digest.hex.len() == digest.algorithm.hex_len()
+            && digest
+                .hex
+                .bytes()
+                .all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'));
+        if !valid {
+            return Err(anyhow!("invalid digest {}", digest));
+        }
+        Ok(format!(
+            "{}/{}/{}.tar",
+            self.dir,
+            digest.algorithm.name(),
+            digest.hex
+        ))
+    }
+
+    /// Path of the index of a layer.
+    ///
+    /// # Arguments
+    /// * `digest` - Digest of the layer.
+    pub fn index_path(&self, digest: &Digest) -> Result<String> {
+        Ok(self.tar_path(digest)? + ".index")
+    }
+
+    /// Check whether a layer and its index are present in the store.
+    ///
+    /// # Arguments
+    /// * `digest` - Digest of the layer.
+    pub fn contains(&self, digest: &Digest) -> Result<bool> {
+        Ok(Path::new(&self.index_path(digest)?).exists()
+            && Path::new(&self.tar_path(digest)?).exists())
+    }
+
+    /// Add a layer stream to the store.
+    ///
+    /// The stream is written to a temporary file, indexed and checked against
+    /// the expected digest. The tar and index are then moved into place so
+    /// that partially added layers are never visible.
+    ///
+    /// # Arguments
+    /// * `reader` - Layer stream.
+    /// * `digest` - Expected digest of the layer.
+    pub fn add(&self, reader: &mut dyn Read, digest: &Digest) -> Result<()> {
+        if self.contains(digest)? {
+            return Ok(());
+        }
+
+        let tar_path = self.tar_path(digest)?;
+        let index_path = self.index_path(digest)?;
+        let tmp_tar_path = tar_path.clone() + ".tmp";
+        let tmp_index_path = index_path.clone() + ".tmp";
+
+        // Receive the stream.
+        fs::create_dir_all(format!(
+            "{}/{}",
+            self.dir,
+            digest.algorithm.name()
+        ))?;
+        let mut writer = BufWriter::new(File::create(&tmp_tar_path)?);
+        io::copy(reader, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        // Index and verify the layer. Discard it on failure.
+        let result = tar::create_index(
+            &Some(digest.clone()),
+            &tmp_tar_path,
+            &ParseOptions::default(),
+        )
+        .and_then(|index| {
+            index.to_file(
+                &tmp_index_path,
+                Storage::Plain,
+                Format::Bincode,
+                Compression::None,
+                &Keys::default(),
+            )
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_tar_path);
+            let _ = fs::remove_file(&tmp_index_path);
+            return Err(e);
+        }
+
+        // Move tar before index since presence of index marks completion.
+        fs::rename(&tmp_tar_path, &tar_path)?;
+        fs::rename(&tmp_index_path, &index_path)?;
+        Ok(())
+    }
+}
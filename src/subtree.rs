@@ -0,0 +1,106 @@
+//! Restriction of an index to a subtree.
+//!
+//! A directory of an index can be mounted in place of the whole index. The
+//! directory becomes the root, along with its attributes, and only the
+//! entries below it are kept. Paths are made relative to the directory.
+//! Hard links to files outside of the subtree are replaced by the files they
+//! link to, which share the contents stored in the layer. Symbolic links are
+//! kept as they are.
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::index::{FileType, Index, Inode};
+
+/// Path of an inode, e.g. `/etc/passwd`.
+///
+/// # Arguments
+/// * `inode` - The inode.
+fn path_of(inode: &Inode) -> String {
+    inode.parent.clone() + &inode.name
+}
+
+/// Path a hard link refers to. Links are stored with or without leading '/'.
+///
+/// # Arguments
+/// * `inode` - The hard link.
+fn link_of(inode: &Inode) -> Option<String> {
+    match (&inode.typeflag, &inode.extra) {
+        (FileType::HardLink, Some(e)) if e.link.starts_with('/') => {
+            Some(e.link.clone())
+        }
+        (FileType::HardLink, Some(e)) => Some("/".to_owned() + &e.link),
+        _ => None,
+    }
+}
+
+/// Restrict an unprocessed index to the subtree of a directory.
+///
+/// # Arguments
+/// * `index` - The index.
+/// * `dir` - Path of the directory, e.g. `/usr/share/app`.
+pub fn restrict(index: &mut Index, dir: &str) -> Result<()> {
+    let dir = match dir.trim_end_matches('/') {
+        "" => return Ok(()),
+        d if d.starts_with('/') => d,
+        _ => return Err(anyhow!("subdir {} is not an absolute path", dir)),
+    };
+    let prefix = dir.to_owned() + "/";
+
+    // Later entries replace earlier ones, as when processing the index.
+    let mut by_path: HashMap<String, usize> = HashMap::new();
+    for (ino, inode) in index.inodes.iter().enumerate().skip(2) {
+        by_path.insert(path_of(inode), ino);
+    }
+    let root = match by_path.get(dir).map(|ino| &index.inodes[*ino]) {
+        Some(inode) if matches!(inode.typeflag, FileType::Directory) => inode,
+        Some(_) => return Err(anyhow!("subdir {} is not a directory", dir)),
+        None => return Err(anyhow!("subdir {} not found", dir)),
+    };
+    let root = Inode {
+        name: String::from("/"),
+        parent: String::new(),
+        depth: 0,
+        ..root.clone()
+    };
+
+    let mut inodes = vec![root.clone(), root];
+    for inode in &index.inodes[2..] {
+        let rest = match inode.parent.strip_prefix(&prefix) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let mut inode = inode.clone();
+
+        // Resolve links to files outside of the subtree.
+        let mut target = link_of(&inode);
+        let mut hops = 0;
+        while let Some(link) = target.as_ref() {
+            if link.starts_with(&prefix) {
+                break;
+            }
+            let found = by_path.get(link).map(|ino| &index.inodes[*ino]);
+            match found {
+                Some(file) if hops < index.inodes.len() => {
+                    inode = Inode {
+                        name: inode.name,
+                        parent: inode.parent,
+                        ..file.clone()
+                    };
+                }
+                _ => return Err(anyhow!("invalid hard link to {}", link)),
+            }
+            target = link_of(&inode);
+            hops += 1;
+        }
+        if let (Some(link), Some(extra)) = (target, &mut inode.extra) {
+            extra.link = "/".to_owned() + &link[prefix.len()..];
+        }
+
+        inode.parent = "/".to_owned() + rest;
+        inode.depth = (inode.parent.split('/').count() - 1) as u16;
+        inodes.push(inode);
+    }
+    index.inodes = inodes;
+    Ok(())
+}
@@ -1,43 +1,55 @@
 //! Parse and index tar files.
 //!
 //! See [Tar Format](https://www.ibm.com/docs/en/zos/2.1.0?topic=formats-tar-format-tar-archives) for description of each field of the tar header.
+use std::cmp::min;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Read};
 use std::mem;
-use std::slice;
+use std::path::Path;
 use std::str;
-
-use anyhow::{anyhow, Context, Result};
-
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Error, Result};
+use sha2::{Digest as _, Sha256};
+
+use crate::digest::{self, Algorithm, Digest};
+use crate::docker;
+use crate::folder;
+use crate::hash::{self, HashMode, Pipeline};
 use crate::index::*;
+use crate::pool::Pool;
 
 /// Tar header binary compatible with Posix specification.
 /// See [UStar format](https://en.wikipedia.org/wiki/Tar_(computing)#UStar_format)
 #[repr(C)]
 #[derive(Debug)]
-struct PosixHeader {
+pub struct PosixHeader {
     /// File name. Maximum 100 characters. Null terminated.
-    name: [u8; 100],
+    pub name: [u8; 100],
 
     /// File mode (octal).
-    mode: [u8; 8],
+    pub mode: [u8; 8],
 
     /// Owner user ID (octal).
-    uid: [u8; 8],
+    pub uid: [u8; 8],
 
     /// Owner group ID (octal).
-    gid: [u8; 8],
+    pub gid: [u8; 8],
 
     /// File size in bytes (octal).
     /// Size is zero if the header describes a link.
-    size: [u8; 12],
+    pub size: [u8; 12],
 
     /// Last modification time in Unix time format (octal).
-    mtime: [u8; 12],
+    pub mtime: [u8; 12],
 
     /// Checksum of all the bytes in the header (with blank chksum field).
-    /// Ignored.
-    chksum: [u8; 8],
+    /// Both unsigned and signed sums are accepted.
+    pub chksum: [u8; 8],
 
     /// Type of the file.
     /// Supported values are:
@@ -49,51 +61,62 @@ struct PosixHeader {
     /// | '3'              | Character Device    |
     /// | '4'              | Block Device        |
     /// | '5'              | Directory           |
+    /// | '6'              | FIFO                |
+    /// | '7'              | Contiguous File     |
+    /// | 'S'              | GNU Sparse File     |
     /// | 'x'              | PAX Extended Header |
+    /// | 'g'              | PAX Global Header   |
     ///
-    typeflag: u8,
+    pub typeflag: u8,
 
     /// Target of a link. Maximum 100 characters.
     // Null terminated unless the name takes the full field.
-    linkname: [u8; 100],
+    pub linkname: [u8; 100],
 
     /// Format indicator. E.g USTAR. Null terminated.
-    magic: [u8; 6],
+    pub magic: [u8; 6],
 
     /// Format version number.
-    version: [u8; 2],
+    pub version: [u8; 2],
 
     /// Owner user name. Maximum 32 characters. Null terminated.
-    uname: [u8; 32],
+    pub uname: [u8; 32],
 
     /// Owner group name. Maximum 32 characters. Null terminated.
-    gname: [u8; 32],
+    pub gname: [u8; 32],
 
     /// Device major number. Octal.
-    devmajor: [u8; 8],
+    pub devmajor: [u8; 8],
 
     /// Device minor number. Octal.
-    devminor: [u8; 8],
+    pub devminor: [u8; 8],
 
     /// Filename prefix. 155 characters. Thus allowing maximum 255 character long names.
     /// Null terminated unless the name takes the full field.
     /// Prefix is null unless the name exceeds 100 characters.
-    prefix: [u8; 155],
+    pub prefix: [u8; 155],
 
     /// Padding for 512 byte alignment.
-    padding: [u8; 12],
+    pub padding: [u8; 12],
 }
 
 /// Parse ascii octal number.
-/// A trailing null indicates end of the octal number.
+/// A trailing null or space indicates end of the octal number. Pre-POSIX
+/// archives pad numbers with leading spaces.
+/// Numbers too large for the field are encoded in base-256 by GNU tar.
 fn ascii_octal_to_u64(buf: &[u8]) -> Result<u64> {
+    if buf.first().is_some_and(|c| c & 0x80 != 0) {
+        return u64::try_from(base256_to_i64(buf)?)
+            .map_err(|_| anyhow!("negative number in unsigned field"));
+    }
     let mut n: u64 = 0;
 
-    for c in buf {
+    let start = buf.iter().position(|c| *c != b' ').unwrap_or(buf.len());
+    for c in &buf[start..] {
         let ch = *c;
         if ch >= b'0' && ch <= b'7' {
             n = n * 8 + (ch - b'0') as u64;
-        } else if *c == 0 {
+        } else if *c == 0 || *c == b' ' {
             break;
         } else {
             return Err(anyhow!("illegal octal character {0}", c));
@@ -102,6 +125,21 @@ fn ascii_octal_to_u64(buf: &[u8]) -> Result<u64> {
     Ok(n)
 }
 
+/// Parse GNU base-256 number.
+/// The high bit of the first byte flags the encoding. The remaining bits are
+/// a big-endian two's complement number.
+fn base256_to_i64(buf: &[u8]) -> Result<i64> {
+    // Sign-extend the 7 bits of the first byte.
+    let mut n = ((buf[0] << 1) as i8 >> 1) as i64;
+    for c in &buf[1..] {
+        if n >> 55 != 0 && n >> 55 != -1 {
+            return Err(anyhow!("base-256 number too large"));
+        }
+        n = (n << 8) | *c as i64;
+    }
+    Ok(n)
+}
+
 /// Parse ascii decimal number.
 /// A trailing null indicates end of the decimal number.
 fn ascii_decimal_to_u64(buf: &[u8]) -> Result<u64> {
@@ -110,7 +148,10 @@ fn ascii_decimal_to_u64(buf: &[u8]) -> Result<u64> {
     for c in buf {
         let ch = *c;
         if ch >= b'0' && ch <= b'9' {
-            n = n * 10 + (ch - b'0') as u64;
+            n = n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add((ch - b'0') as u64))
+                .ok_or_else(|| anyhow!("decimal number too large"))?;
         } else if *c == 0 {
             break;
         } else if *c == b'.' {
@@ -122,6 +163,129 @@ fn ascii_decimal_to_u64(buf: &[u8]) -> Result<u64> {
     Ok(n)
 }
 
+/// Parse a pax time, a signed ascii decimal number of seconds with an
+/// optional fractional part, e.g. "1638884700.123456789" or "-1.5".
+/// Digits beyond nanoseconds are ignored.
+///
+/// # Arguments
+/// * `buf` - The time.
+/// * `returns` - Seconds, rounded down, and nanoseconds.
+fn ascii_decimal_to_time(buf: &[u8]) -> Result<(i64, u32)> {
+    let (negative, buf) = match buf.first() {
+        Some(b'-') => (true, &buf[1..]),
+        _ => (false, buf),
+    };
+    let (secs, frac) = match buf.iter().position(|c| *c == b'.') {
+        Some(p) => (&buf[..p], &buf[p + 1..]),
+        None => (buf, &buf[buf.len()..]),
+    };
+    let secs = ascii_decimal_to_u64(secs)? as i64;
+
+    // Scale the fraction to nanoseconds.
+    let digits = &frac[..frac.len().min(9)];
+    let mut nsecs = ascii_decimal_to_u64(digits)? as u32;
+    for _ in digits.len()..9 {
+        nsecs *= 10;
+    }
+    ascii_decimal_to_u64(&frac[digits.len()..])?;
+
+    match (negative, nsecs) {
+        (false, _) => Ok((secs, nsecs)),
+        (true, 0) => Ok((-secs, 0)),
+        (true, _) => Ok((-secs - 1, 1_000_000_000 - nsecs)),
+    }
+}
+
+/// Add zeros to the digest of a file.
+///
+/// # Arguments
+/// * `sha` - Digest of the file.
+/// * `count` - Number of zeros.
+fn digest_zeros(sha: &mut Sha256, count: u64) {
+    let zeros = [0u8; 4096];
+    let mut left = count;
+    while left > 0 {
+        let n = min(left, zeros.len() as u64);
+        sha.update(&zeros[..n as usize]);
+        left -= n;
+    }
+}
+
+/// Add stored contents of a file to its digest. For sparse files, holes
+/// preceding the contents are added as zeros.
+///
+/// # Arguments
+/// * `sha` - Digest of the file.
+/// * `sparse` - Data regions of a sparse file.
+/// * `hashed` - Number of bytes of the file added so far. Updated.
+/// * `stored` - Offset of the contents within the stored contents.
+/// * `data` - The contents.
+fn digest_contents(
+    sha: &mut Sha256,
+    sparse: &Option<Vec<(u64, u64)>>,
+    hashed: &mut u64,
+    stored: u64,
+    data: &[u8],
+) {
+    let map = match sparse {
+        Some(map) => map,
+        None => {
+            sha.update(data);
+            *hashed += data.len() as u64;
+            return;
+        }
+    };
+
+    let mut pos = 0;
+    for (offset, len) in map {
+        // Part of the data region within the contents.
+        let (from, to) =
+            (pos.max(stored), (pos + len).min(stored + data.len() as u64));
+        if from < to {
+            let start = offset + from - pos;
+            digest_zeros(sha, start - *hashed);
+            sha.update(&data[(from - stored) as usize..(to - stored) as usize]);
+            *hashed = start + to - from;
+        }
+        pos += len;
+    }
+}
+
+/// Prefix of pax records holding extended attributes, as written by star and
+/// GNU tar.
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Number of bytes of a bad header shown in errors.
+const HEXDUMP_LEN: usize = 64;
+
+/// Largest size of the data of pax and GNU extension headers, which is read
+/// into memory.
+const MAX_EXTENSION_SIZE: u64 = 1 << 20;
+
+/// Format bytes in the style of `hexdump -C`.
+///
+/// # Arguments
+/// * `buf` - The bytes.
+fn hexdump(buf: &[u8]) -> String {
+    let lines: Vec<String> = buf
+        .chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex: Vec<String> =
+                line.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = line
+                .iter()
+                .map(|b| match b {
+                    b' '..=b'~' => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            format!("{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), text)
+        })
+        .collect();
+    lines.join("\n")
+}
+
 #[doc(hidden)]
 /// Extend one tar string with another.
 fn extend(dest: &mut Vec<u8>, src: &[u8]) {
@@ -135,10 +299,10 @@ fn extend(dest: &mut Vec<u8>, src: &[u8]) {
 }
 
 /// Parses a tar file and creates an index.
-pub struct Parser {
+pub struct Parser<R: Read> {
     /// Tar file reader with buffering.
     /// The contents of the file are read only once, in order.
-    reader: BufReader<File>,
+    reader: BufReader<R>,
 
     /// Current Posix tar header.
     header: PosixHeader,
@@ -149,62 +313,202 @@ pub struct Parser {
     /// Size rounded up to 512 byte boundary.
     rsize: u64,
 
+    /// Size of the next item from a PAX extension, overriding its header.
+    pax_size: Option<u64>,
+
+    /// Records of PAX global extended headers, applied to each entry.
+    globals: Vec<(String, Vec<u8>)>,
+
+    /// Real size of the next item from PAX extensions, if it is a sparse
+    /// file in the PAX 1.0 sparse format.
+    pax_sparse_size: Option<u64>,
+
     /// The current Inode.
     inode: Inode,
 
     /// Extra properties of current inode.
     extra: Extra,
 
+    /// Owner user name of current inode.
+    uname: String,
+
+    /// Owner group name of current inode.
+    gname: String,
+
+    /// Positions of names in the index's string table.
+    names: HashMap<String, u32>,
+
     /// Buffer for reading data.
     buf: Vec<u8>,
 
     /// File-system index
     index: Index,
 
+    /// Regular files up to this size are verified as a whole. Larger files
+    /// are verified page by page.
+    whole_file_max_size: u64,
+
+    /// Record the sha256 digest of each regular file.
+    file_digests: bool,
+
     /// Current offset within the tar file.
-    offset: u32,
+    offset: u64,
+
+    /// Hashing spread over threads. None if hashing on the parsing thread.
+    pipeline: Option<Pipeline>,
+
+    /// Fail on any violation of the tar format instead of warning and
+    /// skipping the offending construct.
+    strict: bool,
+
+    /// Continue parsing past end-of-archive markers.
+    ignore_zeros: bool,
 }
 
-impl Parser {
+impl<R: Read> Parser<R> {
     /// Create new instance of Parser.
     ///
-    /// The number of pages in the file is used as a hint to the hasher.
-    /// A formula derived from oetools-20.04 container's largest layer is
-    /// used to estimate the number of inodes.
-    pub fn new(tar_path: &String) -> Result<Parser> {
-        let file = File::open(tar_path)
-            .with_context(|| format!("failed to open {}", tar_path))?;
-
-        let len = file.metadata().unwrap().len();
-
+    /// The number of pages in the file, if known, is used as a hint to the
+    /// hasher. A formula derived from oetools-20.04 container's largest layer
+    /// is used to estimate the number of inodes.
+    ///
+    /// # Arguments
+    /// * `reader` - Reader of the tar file. Read only once, in order.
+    /// * `len` - Length of the tar file, if known.
+    /// * `algorithm` - Algorithm of the layer digest. The Hasher computes
+    ///   the digest and saves its states in this algorithm.
+    /// * `whole_file_max_size` - Regular files up to this size are verified
+    ///   as a whole.
+    /// * `hash_mode` - What the hasher saves at each save point.
+    /// * `measure_key` - Key of keyed (HMAC) measurements.
+    /// * `file_digests` - Record the sha256 digest of each regular file.
+    pub fn new(
+        reader: R,
+        len: Option<u64>,
+        algorithm: Algorithm,
+        whole_file_max_size: u64,
+        hash_mode: HashMode,
+        measure_key: &Option<Vec<u8>>,
+        file_digests: bool,
+    ) -> Result<Parser<R>> {
         // TODO: Find better hints.
         // We may end up with slightly more states than the actual number of
-        // pages. Therefore, use a factor (1.16).
-        let hint_num_states = ((len as f64 * 1.16 + 4096.0) / 4096.0) as u32;
+        // pages. Therefore, use a factor (1.16). Streams of unknown length
+        // start without a hint.
+        let hint_num_states = match len {
+            Some(len) => ((len as f64 * 1.16 + 4096.0) / 4096.0) as u32,
+            None => 0,
+        };
 
         // Starting out with 0 hint has been observed to use less memory than
         // various hint values.
         let hint_num_inodes = 0;
 
         Ok(Parser {
-            reader: BufReader::new(file),
+            reader: BufReader::new(reader),
             // Use unsafe to zero-initialize since Default trait is not
             // automatically implemented for arrays longer than 32 elements.
             header: unsafe { std::mem::zeroed() },
             size: 0,
             rsize: 0,
+            pax_size: None,
+            pax_sparse_size: None,
+            globals: vec![],
             inode: Inode::default(),
             extra: Extra::default(),
+            uname: String::new(),
+            gname: String::new(),
+            names: HashMap::new(),
             buf: vec![],
-            index: Index::new(hint_num_inodes, hint_num_states)?,
+            index: Index::new(
+                hint_num_inodes,
+                hint_num_states,
+                hash_mode,
+                algorithm,
+                measure_key,
+            )?,
             offset: 0,
+            whole_file_max_size,
+            file_digests,
+            pipeline: None,
+            strict: false,
+            ignore_zeros: false,
         })
     }
 
+    /// Spread hashing over threads. The parsing thread then only reads and
+    /// parses the tar file.
+    ///
+    /// # Arguments
+    /// * `threads` - Number of hashing threads. Hashing stays on the parsing
+    ///   thread if less than 2.
+    pub fn hash_threads(&mut self, threads: usize) -> Result<()> {
+        if threads >= 2 && self.pipeline.is_none() {
+            let hasher = mem::take(&mut self.index.hasher);
+            self.pipeline = Some(Pipeline::new(hasher, threads)?);
+        }
+        Ok(())
+    }
+
+    /// Fail on any violation of the tar format: bad header checksums,
+    /// unsupported typeflags, malformed or unsupported pax records,
+    /// extension headers larger than 1 MiB and a missing or misplaced
+    /// end-of-archive marker or data following it. By default, these are
+    /// reported as warnings and skipped. Skipped data is still measured, so
+    /// the digest is the same either way.
+    ///
+    /// # Arguments
+    /// * `strict` - Whether to fail.
+    pub fn strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Continue parsing past end-of-archive markers. Archives concatenated
+    /// with `cat a.tar b.tar` hold a marker after each part. Zero blocks are
+    /// then skipped wherever they appear.
+    ///
+    /// # Arguments
+    /// * `ignore_zeros` - Whether to continue.
+    pub fn ignore_zeros(&mut self, ignore_zeros: bool) {
+        self.ignore_zeros = ignore_zeros;
+    }
+
+    /// Report a violation of the tar format. Fails in strict mode, and
+    /// prints a warning otherwise.
+    ///
+    /// # Arguments
+    /// * `e` - The violation.
+    fn violation(&self, e: Error) -> Result<()> {
+        if self.strict {
+            return Err(e);
+        }
+        eprintln!("warning: {:#}", e);
+        Ok(())
+    }
+
+    /// Measure a chunk of data.
+    ///
+    /// # Arguments
+    /// * `buf` : Chunk of data. Length must be multiple of the block size.
+    fn measure(&mut self, buf: &[u8]) -> Result<()> {
+        match &mut self.pipeline {
+            Some(pipeline) => pipeline.measure(buf),
+            None => self.index.hasher.measure(buf),
+        }
+    }
+
+    /// Save the hash state.
+    ///
+    /// * `returns` - The position of the saved state.
+    fn save_state(&mut self) -> Result<u32> {
+        match &mut self.pipeline {
+            Some(pipeline) => pipeline.save_state(),
+            None => Ok(self.index.hasher.save_state()),
+        }
+    }
+
     /// Parse the tar file and generate index.
     pub fn parse(&mut self) -> Result<Index> {
-        let header_size = mem::size_of::<PosixHeader>();
-
         // Root node.
         let root = Inode {
             typeflag: FileType::Directory,
@@ -219,174 +523,564 @@ impl Parser {
         self.index.inodes.push(root.clone());
         self.index.inodes.push(root);
 
+        // Number of consecutive zero blocks.
+        let mut zero_blocks = 0;
         loop {
             // Read and measure header.
-            unsafe {
-                let raw_ptr = &mut self.header as *mut _ as *mut u8;
-                let slice = slice::from_raw_parts_mut(raw_ptr, header_size);
-                match self.reader.read_exact(slice) {
-                    // If read is successful, measure header.
-                    Ok(_) => self.index.hasher.measure(slice)?,
-                    _ => break,
+            let mut block = [0u8; mem::size_of::<PosixHeader>()];
+            if !self.read_header(&mut block)? {
+                if zero_blocks < 2 {
+                    self.violation(anyhow!(
+                        "missing end-of-archive marker at byte offset {}",
+                        self.offset
+                    ))?;
+                }
+                break;
+            }
+            self.measure(&block)?;
+            // The header consists of byte arrays only.
+            self.header =
+                unsafe { mem::transmute::<[u8; 512], PosixHeader>(block) };
+            let offset = self.offset;
+            // Update offset.
+            self.offset += 512;
+
+            // Two consecutive zero blocks mark the end of the archive.
+            if block.iter().all(|c| *c == 0) {
+                zero_blocks += 1;
+                if zero_blocks == 2 && !self.ignore_zeros {
+                    self.read_trailer()?;
+                    break;
                 }
-                // Update offset.
-                self.offset += 512;
+                continue;
+            }
+            if zero_blocks == 1 && !self.ignore_zeros {
+                self.violation(anyhow!(
+                    "unexpected zero block at byte offset {}",
+                    offset - 512
+                ))?;
+            }
+            zero_blocks = 0;
+            self.check_magic(&block, offset)?;
+            self.check_checksum(&block, offset)?;
+
+            // Pre-POSIX archives mark regular files with a null typeflag,
+            // and directories with a trailing '/'.
+            if self.header.name[0] != 0
+                && (self.header.typeflag == 0
+                    || self.header.typeflag == b'0' && !self.is_ustar())
+            {
+                let len = self.header.name.iter().position(|c| *c == 0);
+                let last = self.header.name[len.unwrap_or(100) - 1];
+                self.header.typeflag = if last == b'/' { b'5' } else { b'0' };
             }
 
             // Parse header size and round it up to multiple of 512 bytes.
+            // The size of an item may be overridden by a preceding PAX
+            // extension.
             self.size = ascii_octal_to_u64(&self.header.size)?;
-            self.rsize = ((self.size + 512 - 1) / 512) * 512;
+            if matches!(self.header.typeflag, b'0' | b'1' | b'2' | b'5' | b'7')
+            {
+                if let Some(size) = self.pax_size.take() {
+                    self.size = size;
+                }
+            }
+            self.rsize = match self.size.checked_add(512 - 1) {
+                Some(size) => (size / 512) * 512,
+                None => {
+                    return Err(anyhow!(
+                        "size {} too large at byte offset {}",
+                        self.size,
+                        offset
+                    ))
+                }
+            };
 
             // Handle different file types.
-            match self.header.typeflag {
-                // Process PAX extensions.
-                b'x' => {
-                    self.parse_pax()?;
+            let typeflag = self.header.typeflag;
+            let parsed = match typeflag {
+                // Skip extensions too large to be read into memory.
+                b'x' | b'g' | b'L' | b'K' if self.size > MAX_EXTENSION_SIZE => {
+                    self.violation(anyhow!(
+                        "extension of {} bytes too large at byte offset {}",
+                        self.size,
+                        offset
+                    ))
+                    .and_then(|_| self.skip_entry())
                 }
 
+                // Process PAX extensions.
+                b'x' => self.parse_pax(false),
+                b'g' => self.parse_pax(true),
+
                 // Process GNU extensions.
-                b'L' | b'K' => {
-                    self.parse_gnu(self.header.typeflag == b'L')?;
-                }
+                b'L' | b'K' => self.parse_gnu(typeflag == b'L'),
 
                 // Process items that exist only in tar.
-                b'0' | b'1' | b'2' | b'5' => self.parse_item()?,
+                b'0' | b'1' | b'2' | b'3' | b'4' | b'5' | b'6' | b'7'
+                | b'S' => self.parse_item(),
 
                 // End of tar marker
                 0 => continue,
 
                 // Unsupported.
-                _ => {
-                    return Err(anyhow!(
-                        "unsupported typeflag {}",
-                        char::from(self.header.typeflag)
+                _ => self
+                    .violation(anyhow!(
+                        "unsupported typeflag {:?} at byte offset {}",
+                        char::from(typeflag),
+                        offset
                     ))
-                }
+                    .and_then(|_| self.skip_entry()),
+            };
+            if let Err(e) = parsed {
+                let eof = e
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof);
+                return Err(match eof {
+                    true => anyhow!(
+                        "truncated tar file: incomplete entry at byte offset {}",
+                        offset
+                    ),
+                    false => e.context(format!(
+                        "failed to parse tar entry at byte offset {}",
+                        offset
+                    )),
+                });
             }
 
             // Update offset.
-            self.offset += self.rsize as u32;
+            self.offset += self.rsize;
         }
 
-        // Finalize the hash.
+        // Collect the hash states and finalize the hash.
+        if let Some(pipeline) = self.pipeline.take() {
+            self.index.hasher = pipeline.finish()?;
+        }
         self.index.hasher.finalize()?;
 
         // Transfer ownership to caller.
         Ok(std::mem::replace(&mut self.index, Index::default()))
     }
 
-    /// Split a path into filename and directory.
+    /// Read a header block.
     ///
-    /// Removes any trailing '/' from the name component.
-    /// The directory component will start and end with '/'.
-    fn split_path(path: &[u8]) -> Result<(String, String)> {
-        let mut path = str::from_utf8(path)?.to_string();
-        // Remove trailing '/'.
-        if path.ends_with("/") {
-            path.pop();
+    /// # Arguments
+    /// * `block` - Buffer for the header.
+    /// * `returns` - False at the end of the tar file.
+    fn read_header(&mut self, block: &mut [u8]) -> Result<bool> {
+        let mut len = 0;
+        while len < block.len() {
+            match self.reader.read(&mut block[len..]) {
+                Ok(0) if len == 0 => return Ok(false),
+                Ok(0) if self.offset == 0 => {
+                    return Err(anyhow!("not a tar file: only {} bytes", len))
+                }
+                Ok(0) => {
+                    return Err(anyhow!(
+                    "truncated tar file: incomplete header at byte offset {}",
+                    self.offset
+                ))
+                }
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
         }
+        Ok(true)
+    }
 
-        // Split at right most '/' character.
-        let (parent, name) = match path.rfind("/") {
-            Some(p) => (path[0..p + 1].to_string(), path[p + 1..].to_string()),
-            _ => (String::from("/"), path.to_string()),
-        };
+    /// Read and measure the data following the end-of-archive marker. This is
+    /// normally zeros padding the archive to a multiple of the record size.
+    /// Other data is a violation of the format, but is measured too, as the
+    /// digest covers the whole tar file.
+    fn read_trailer(&mut self) -> Result<()> {
+        let mut block = [0u8; 512];
+        let mut reported = false;
+        while self.read_header(&mut block)? {
+            self.measure(&block)?;
+            if !reported && block.iter().any(|c| *c != 0) {
+                reported = true;
+                // A header suggests concatenated archives.
+                let hint = match &block[257..262] {
+                    b"ustar" => " (concatenated archives need --ignore-zeros)",
+                    _ => "",
+                };
+                self.violation(anyhow!(
+                    "unexpected data after end of archive at byte offset {}{}",
+                    self.offset,
+                    hint
+                ))?;
+            }
+            self.offset += 512;
+        }
+        Ok(())
+    }
 
-        if !parent.starts_with("/") {
-            Ok(("/".to_owned() + &parent, name))
-        } else {
-            Ok((parent, name))
+    /// Check the format indicator of the current header. ustar, GNU and
+    /// pre-POSIX (V7) headers are accepted. V7 headers have no indicator.
+    ///
+    /// # Arguments
+    /// * `block` - The header block.
+    /// * `offset` - Offset of the header in the tar file.
+    fn check_magic(&self, block: &[u8], offset: u64) -> Result<()> {
+        match (&self.header.magic, &self.header.version) {
+            (b"ustar\0", b"00")
+            | (b"ustar ", b" \0")
+            | ([0, 0, 0, 0, 0, 0], [0, 0]) => Ok(()),
+            _ => {
+                let what = match offset {
+                    0 => "not a tar file",
+                    _ => "corrupt tar file",
+                };
+                Err(anyhow!(
+                    "{}: unrecognized header at byte offset {}\n{}",
+                    what,
+                    offset,
+                    hexdump(&block[..HEXDUMP_LEN])
+                ))
+            }
+        }
+    }
+
+    /// Check the checksum of the current header. Historic implementations
+    /// summed signed bytes, hence either sum is accepted.
+    ///
+    /// # Arguments
+    /// * `block` - The header block.
+    /// * `offset` - Offset of the header in the tar file.
+    fn check_checksum(&self, block: &[u8], offset: u64) -> Result<()> {
+        let expected = ascii_octal_to_u64(&self.header.chksum)?;
+
+        // The checksum field counts as spaces.
+        let field = 148..156;
+        let rest =
+            || block.iter().enumerate().filter(|(i, _)| !field.contains(i));
+        let unsigned: u64 =
+            rest().map(|(_, b)| *b as u64).sum::<u64>() + 8 * 32;
+        let signed: i64 =
+            rest().map(|(_, b)| *b as i8 as i64).sum::<i64>() + 8 * 32;
+        if expected == unsigned || expected as i64 == signed {
+            return Ok(());
+        }
+
+        self.violation(anyhow!(
+            "bad header checksum at byte offset {}: {:o} != {:o}",
+            offset,
+            unsigned,
+            expected
+        ))
+    }
+
+    /// Check whether the current header is a ustar header, as opposed to a
+    /// pre-POSIX (V7) header. V7 headers end after the link name, and the
+    /// rest of the header is unused.
+    fn is_ustar(&self) -> bool {
+        self.header.magic[..5] == *b"ustar"
+    }
+
+    /// Digest of the tar file in the algorithm the parser was created with.
+    ///
+    /// # Arguments
+    /// * `index` - The index returned by `parse`.
+    pub fn digest(&self, index: &Index) -> Digest {
+        Digest {
+            algorithm: index.hasher.algorithm,
+            hex: index.hasher.digest.clone(),
         }
     }
 
+    /// Normalize a path within the archive.
+    ///
+    /// Leading '/' and "./", empty and "." components are dropped, and ".."
+    /// removes the preceding component. Paths that would escape the root,
+    /// e.g. "../etc/passwd", are rejected.
+    ///
+    /// # Arguments
+    /// * `path` - The path.
+    /// * `returns` - The components of the path. Empty for the root.
+    fn normalize_path(path: &str) -> Result<Vec<&str>> {
+        let mut components = vec![];
+        for component in path.split('/') {
+            match component {
+                "" | "." => (),
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(anyhow!(
+                            "path escapes the root directory: {}",
+                            path
+                        ));
+                    }
+                }
+                c => components.push(c),
+            }
+        }
+        Ok(components)
+    }
+
+    /// Split a path into filename and directory.
+    ///
+    /// The path is normalized first. The directory component will start and
+    /// end with '/'. The root itself has an empty name.
+    fn split_path(path: &[u8]) -> Result<(String, String)> {
+        let path = str::from_utf8(path)?;
+        let mut components = Self::normalize_path(path)?;
+        let name = components.pop().unwrap_or_default().to_string();
+        let mut parent = String::from("/");
+        for component in components {
+            parent.push_str(component);
+            parent.push('/');
+        }
+        Ok((parent, name))
+    }
+
     /// Parse pax extensions.
     ///
-    /// PAX Extended header records (typeflag 'x') are supported. These headers
-    /// affect the following file in the archive.
-    /// Supported tags: mtime, path, linkpath, uname, gname, size, uid, gid.
-    /// Not supported: Character set definition tag, vendor specifi tags,
-    ///                PAX Global extended header records (typeflag 'g').
+    /// PAX Extended header records (typeflag 'x') affect the following file
+    /// in the archive. PAX Global extended header records (typeflag 'g')
+    /// affect all following files, unless overridden. A global record with
+    /// an empty value removes the record.
+    /// Supported tags: mtime, path, linkpath, uname, gname, size, uid, gid,
+    /// SCHILY.xattr tags and the GNU.sparse tags of the 1.0 sparse format.
+    /// Xattr values, such as the `security.capability` blobs of binaries like
+    /// `ping`, are kept as raw bytes.
+    /// Ignored tags: atime, ctime, charset, comment, hdrcharset, the
+    /// SCHILY.dev, SCHILY.ino and SCHILY.nlink tags of star and the
+    /// LIBARCHIVE.creationtime tag, none of which are kept in the index.
+    /// Not supported: Other vendor specific tags.
     /// See [PAX extended header](https://www.ibm.com/docs/en/zos/2.1.0?topic=SSLTBW_2.1.0/com.ibm.zos.v2r1.bpxa500/paxex.htm#paxex)
     /// and [PAX Header Block](https://www.ibm.com/docs/en/zos/2.1.0?topic=SSLTBW_2.1.0/com.ibm.zos.v2r1.bpxa500/paxhead.htm).
-    fn parse_pax(&mut self) -> Result<()> {
+    ///
+    /// # Arguments
+    /// * `global` - Whether the header is a global extended header.
+    fn parse_pax(&mut self, global: bool) -> Result<()> {
         // Read pax data and measure it.
-        self.buf.resize(self.rsize as usize, 0);
-        self.reader.read_exact(&mut self.buf)?;
-        self.index.hasher.measure(&self.buf)?;
+        let mut buf = mem::take(&mut self.buf);
+        buf.resize(self.rsize as usize, 0);
+        self.reader.read_exact(&mut buf)?;
+        self.measure(&buf)?;
+
+        let result = self.apply_pax_records(&buf, global);
+        self.buf = buf;
+        result
+    }
 
-        // Skip past next occurence of given character.
-        let mut p = 0;
-        let mut skip_next = |buf: &Vec<u8>, ch| {
-            while p < buf.len() && buf[p] != ch {
-                p += 1;
+    /// Apply the records of a pax header. Malformed headers and unsupported
+    /// records are violations of the format.
+    ///
+    /// # Arguments
+    /// * `buf` - The extension data.
+    /// * `global` - Whether the header is a global extended header.
+    fn apply_pax_records(&mut self, buf: &[u8], global: bool) -> Result<()> {
+        let offset = self.offset - 512;
+        let records = match Self::pax_records(buf) {
+            Ok(records) => records,
+            Err(e) => {
+                return self.violation(e.context(format!(
+                    "malformed pax header at byte offset {}",
+                    offset
+                )))
             }
-            p += 1;
-            return p;
         };
 
-        loop {
-            // Skip size entry to obtain field name start.
-            let name_start = skip_next(&self.buf, b' ');
-            // Skip = to obtain field name end.
-            let name_end = skip_next(&self.buf, b'=') - 1;
+        for (field, value) in records {
+            if global && value.is_empty() {
+                self.globals.retain(|(f, _)| f != field);
+                self.clear_pax_record(field);
+                continue;
+            }
+            match self.apply_pax_record(field, value) {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.violation(anyhow!(
+                        "unsupported pax field {} at byte offset {}",
+                        field,
+                        offset
+                    ))?;
+                    continue;
+                }
+                Err(e) => {
+                    self.violation(e.context(format!(
+                        "invalid pax field {} at byte offset {}",
+                        field, offset
+                    )))?;
+                    continue;
+                }
+            }
+            if global {
+                self.globals.retain(|(f, _)| f != field);
+                self.globals.push((field.to_string(), value.to_vec()));
+            }
+        }
+        Ok(())
+    }
 
-            let value_start = name_end + 1;
-            // Skip \n to obtain value end.
-            let value_end = skip_next(&self.buf, b'\n') - 1;
+    /// Split pax extension data into records.
+    ///
+    /// Each record is "<length> <field>=<value>\n", where the length counts
+    /// the whole record. Values may hold any bytes, including newlines.
+    ///
+    /// # Arguments
+    /// * `buf` - The extension data.
+    /// * `returns` - The field and value of each record.
+    fn pax_records(buf: &[u8]) -> Result<Vec<(&str, &[u8])>> {
+        let bad = || {
+            anyhow!(
+                "failed to parse pax entry\n{}",
+                String::from_utf8_lossy(buf)
+            )
+        };
+        let mut records = vec![];
+        let mut p = 0;
 
-            // Check parse error.
-            if value_end >= self.buf.len() {
-                return Err(anyhow!(
-                    "failed to parse pax entry\n{}",
-                    str::from_utf8(&self.buf)?
-                ));
+        // The data is padded with nulls.
+        while p < buf.len() && buf[p] != 0 {
+            let space =
+                buf[p..].iter().position(|c| *c == b' ').ok_or_else(bad)?;
+            let len = ascii_decimal_to_u64(&buf[p..p + space])? as usize;
+            let record = buf.get(p..p + len).ok_or_else(bad)?;
+            let (last, record) = record.split_last().ok_or_else(bad)?;
+            let equals =
+                record.iter().position(|c| *c == b'=').ok_or_else(bad)?;
+            if *last != b'\n' || equals <= space {
+                return Err(bad());
             }
+            let field = str::from_utf8(&record[space + 1..equals])?;
+            records.push((field, &record[equals + 1..]));
+            p += len;
+        }
+        Ok(records)
+    }
 
-            let field = str::from_utf8(&self.buf[name_start..name_end])?;
-            let value = &self.buf[value_start..value_end];
-            match field {
-                // See pax Extended Header File Times
-                // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/overrides.html#tag_20_92_13_05
-                "path" => {
-                    (self.inode.parent, self.inode.name) =
-                        Parser::split_path(&value)?
-                }
-                "gid" => self.inode.gid = ascii_octal_to_u64(value)? as u32,
-                "uid" => self.inode.uid = ascii_octal_to_u64(value)? as u32,
-                "mtime" => self.inode.mtime = ascii_decimal_to_u64(value)?,
-                "gname" => {
-                    self.extra.gname = str::from_utf8(value)?.to_string()
-                }
-                "uname" => {
-                    self.extra.uname = str::from_utf8(value)?.to_string()
-                }
-                "linkpath" => {
-                    self.extra.link = str::from_utf8(value)?.to_string()
+    /// Apply a pax record to the current entry.
+    ///
+    /// # Arguments
+    /// * `field` - Field of the record.
+    /// * `value` - Value of the record.
+    /// * `returns` - False if the field is not supported.
+    fn apply_pax_record(&mut self, field: &str, value: &[u8]) -> Result<bool> {
+        match field {
+            // See pax Extended Header File Times
+            // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/overrides.html#tag_20_92_13_05
+            "path" => {
+                (self.inode.parent, self.inode.name) = Self::split_path(&value)?
+            }
+            "gid" => self.inode.gid = ascii_decimal_to_u64(value)? as u32,
+            "uid" => self.inode.uid = ascii_decimal_to_u64(value)? as u32,
+            "size" => {
+                let size = ascii_decimal_to_u64(value)?;
+                if size.checked_add(512 - 1).is_none() {
+                    return Err(anyhow!("size {} too large", size));
                 }
-                _ => {
-                    return Err(anyhow!("unsupported pax field {}", field));
+                self.pax_size = Some(size);
+            }
+            // See [PAX sparse format 1.0](https://www.gnu.org/software/tar/manual/html_node/Sparse-Formats.html).
+            "GNU.sparse.major" | "GNU.sparse.minor" => {
+                let version = ascii_decimal_to_u64(value)?;
+                if version != (field == "GNU.sparse.major") as u64 {
+                    return Err(anyhow!(
+                        "unsupported sparse format {}={}",
+                        field,
+                        version
+                    ));
                 }
-            };
+            }
+            "GNU.sparse.name" => {
+                (self.inode.parent, self.inode.name) = Self::split_path(value)?
+            }
+            "GNU.sparse.realsize" => {
+                self.pax_sparse_size = Some(ascii_decimal_to_u64(value)?)
+            }
+            "mtime" => {
+                (self.inode.mtime, self.inode.mtime_nsec) =
+                    ascii_decimal_to_time(value)?
+            }
+            "gname" => self.gname = str::from_utf8(value)?.to_string(),
+            "uname" => self.uname = str::from_utf8(value)?.to_string(),
+            "linkpath" => self.extra.link = str::from_utf8(value)?.to_string(),
+            // Known fields that are not kept in the index.
+            "atime" | "ctime" | "charset" | "comment" | "hdrcharset" => (),
+            "SCHILY.dev" | "SCHILY.ino" | "SCHILY.nlink" => (),
+            "LIBARCHIVE.creationtime" => (),
+            _ if field.starts_with(XATTR_PREFIX) => {
+                let name = field[XATTR_PREFIX.len()..].to_string();
+                self.extra.xattrs.retain(|(n, _)| *n != name);
+                self.extra.xattrs.push((name, value.to_vec()));
+            }
+            _ => return Ok(false),
+        };
+        Ok(true)
+    }
 
-            // Break if all the fields have been parsed.
-            if value_end + 1 == self.buf.len() || self.buf[value_end + 1] == 0 {
-                break;
+    /// Undo a global pax record already applied to the current entry, once
+    /// a later global header removes it.
+    ///
+    /// # Arguments
+    /// * `field` - Field of the record.
+    fn clear_pax_record(&mut self, field: &str) {
+        match field {
+            "path" | "GNU.sparse.name" => {
+                self.inode.parent.clear();
+                self.inode.name.clear();
+            }
+            "gid" => self.inode.gid = 0,
+            "uid" => self.inode.uid = 0,
+            "size" => self.pax_size = None,
+            "GNU.sparse.realsize" => self.pax_sparse_size = None,
+            "mtime" => (self.inode.mtime, self.inode.mtime_nsec) = (0, 0),
+            "gname" => self.gname.clear(),
+            "uname" => self.uname.clear(),
+            "linkpath" => self.extra.link.clear(),
+            _ if field.starts_with(XATTR_PREFIX) => {
+                let name = &field[XATTR_PREFIX.len()..];
+                self.extra.xattrs.retain(|(n, _)| n != name);
             }
+            _ => (),
         }
+    }
 
-        Ok(())
+    /// Apply the global pax records to the current entry.
+    fn apply_globals(&mut self) -> Result<()> {
+        let globals = mem::take(&mut self.globals);
+        let result = globals.iter().try_for_each(|(field, value)| {
+            self.apply_pax_record(field, value).map(|_| ())
+        });
+        self.globals = globals;
+        result
+    }
+
+    /// Skip the contents of an unsupported entry. The contents are measured,
+    /// and the attributes set by preceding extension headers are dropped.
+    fn skip_entry(&mut self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        let mut left = self.rsize as usize;
+        while left > 0 {
+            let len = min(left, buf.len());
+            self.reader.read_exact(&mut buf[..len])?;
+            self.measure(&buf[..len])?;
+            left -= len;
+        }
+
+        self.inode = Inode::default();
+        self.extra = Extra::default();
+        self.uname.clear();
+        self.gname.clear();
+        self.pax_size = None;
+        self.pax_sparse_size = None;
+        self.apply_globals()
     }
 
     /// Parse GNU LongLink and LongName headers.
     fn parse_gnu(&mut self, is_long_name: bool) -> Result<()> {
         // Resize buf, read and measure string.
-        self.buf.resize(self.rsize as usize, 0u8);
-        self.reader.read_exact(&mut self.buf)?;
-        self.index.hasher.measure(&self.buf)?;
+        let mut buf = mem::take(&mut self.buf);
+        buf.resize(self.rsize as usize, 0u8);
+        self.reader.read_exact(&mut buf)?;
+        self.measure(&buf)?;
+        self.buf = buf;
 
         if is_long_name {
             (self.inode.parent, self.inode.name) =
-                Parser::split_path(&self.buf[0..self.size as usize])?;
+                Self::split_path(&self.buf[0..self.size as usize])?;
         } else {
             self.extra.link =
                 str::from_utf8(&self.buf[0..self.size as usize])?.to_string()
@@ -395,6 +1089,133 @@ impl Parser {
         Ok(())
     }
 
+    /// Parse the map of an old GNU sparse file (typeflag 'S').
+    ///
+    /// The header holds up to 4 entries of the map. While the last entry
+    /// byte is set, the header is followed by extension headers of up to 21
+    /// entries each. The size in the header is the number of bytes stored,
+    /// and the real size of the file follows the map in the header.
+    /// See [GNU tar format](https://www.gnu.org/software/tar/manual/html_node/Standard.html).
+    fn parse_sparse(&mut self) -> Result<()> {
+        // Old GNU headers overlay these fields on the prefix.
+        let prefix = &self.header.prefix;
+        let mut entries = prefix[41..137].to_vec();
+        let mut extended = prefix[137] != 0;
+        let real_size = ascii_octal_to_u64(&prefix[138..150])?;
+
+        let mut map = vec![];
+        loop {
+            for entry in entries.chunks(24) {
+                if entry[0] == 0 {
+                    break;
+                }
+                map.push((
+                    ascii_octal_to_u64(&entry[..12])?,
+                    ascii_octal_to_u64(&entry[12..])?,
+                ));
+            }
+            if !extended {
+                break;
+            }
+
+            // Read and measure an extension header.
+            let mut block = [0u8; 512];
+            self.reader.read_exact(&mut block)?;
+            self.measure(&block)?;
+            self.offset += 512;
+            entries = block[..504].to_vec();
+            extended = block[504] != 0;
+        }
+
+        self.set_sparse_map(map, real_size)
+    }
+
+    /// Parse the map of a sparse file in the PAX 1.0 sparse format.
+    ///
+    /// The map precedes the data regions in the contents of the entry,
+    /// padded to a multiple of 512 bytes. It consists of the number of
+    /// entries followed by the offset and length of each entry, all in
+    /// decimal and terminated by newlines.
+    ///
+    /// # Arguments
+    /// * `real_size` - The real size of the file.
+    fn parse_pax_sparse(&mut self, real_size: u64) -> Result<()> {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        let mut block = [0u8; 512];
+        let map = loop {
+            if buf.len() as u64 >= self.size {
+                return Err(anyhow!("truncated sparse map"));
+            }
+            // Read and measure a block of the map.
+            self.reader.read_exact(&mut block)?;
+            self.measure(&block)?;
+            buf.extend_from_slice(&block);
+
+            // The last part is not terminated yet.
+            let lines: Vec<&[u8]> = buf.split(|c| *c == b'\n').collect();
+            let complete = &lines[..lines.len() - 1];
+            let count = match complete.first() {
+                Some(line) => ascii_decimal_to_u64(line)? as usize,
+                None => continue,
+            };
+            if complete.len() > count.saturating_mul(2) {
+                let mut map = Vec::with_capacity(count);
+                for entry in complete[1..].chunks(2).take(count) {
+                    map.push((
+                        ascii_decimal_to_u64(entry[0])?,
+                        ascii_decimal_to_u64(entry[1])?,
+                    ));
+                }
+                break map;
+            }
+        };
+
+        // The data regions follow the map.
+        let len = buf.len() as u64;
+        self.buf = buf;
+        self.offset += len;
+        self.size -= len;
+        self.rsize -= len;
+        self.set_sparse_map(map, real_size)
+    }
+
+    /// Record the map of a sparse file.
+    ///
+    /// # Arguments
+    /// * `map` - Data regions of the file.
+    /// * `real_size` - The real size of the file.
+    fn set_sparse_map(
+        &mut self,
+        map: Vec<(u64, u64)>,
+        real_size: u64,
+    ) -> Result<()> {
+        // The data regions must be in order, within the file, and add up to
+        // the stored contents.
+        let mut end = 0;
+        let mut stored: u64 = 0;
+        for (offset, len) in &map {
+            match offset.checked_add(*len) {
+                Some(region_end)
+                    if *offset >= end && region_end <= real_size =>
+                {
+                    end = region_end
+                }
+                _ => return Err(anyhow!("bad sparse map")),
+            }
+            stored = stored
+                .checked_add(*len)
+                .ok_or_else(|| anyhow!("bad sparse map"))?;
+        }
+        if stored != self.size {
+            return Err(anyhow!("sparse map does not match size"));
+        }
+
+        self.inode.size = real_size;
+        self.inode.extra.get_or_insert_with(Extra::default).sparse = Some(map);
+        Ok(())
+    }
+
     /// Parse tar entry header.
     /// PAX and GNU overrides are preferred over fields from header.
     fn parse_header(&mut self) -> Result<()> {
@@ -408,35 +1229,51 @@ impl Parser {
             self.inode.uid = ascii_octal_to_u64(&self.header.uid)? as u32;
         }
 
+        // Times before 1970 can only be encoded in base-256.
         if self.inode.mtime == 0 {
-            self.inode.mtime = ascii_octal_to_u64(&self.header.mtime)?;
+            self.inode.mtime = match self.header.mtime[0] & 0x80 {
+                0 => ascii_octal_to_u64(&self.header.mtime)? as i64,
+                _ => base256_to_i64(&self.header.mtime)?,
+            };
         }
 
-        if self.header.gname[0] != 0 && self.extra.gname.is_empty() {
+        if self.is_ustar() && self.header.gname[0] != 0 && self.gname.is_empty()
+        {
             // gname is null terminated.
-            self.extra.gname = String::from_utf8(self.header.gname.to_vec())?;
+            self.buf.clear();
+            extend(&mut self.buf, &self.header.gname);
+            self.gname = str::from_utf8(&self.buf)?.to_string();
         }
 
-        if self.header.uname[0] != 0 && self.extra.uname.is_empty() {
+        if self.is_ustar() && self.header.uname[0] != 0 && self.uname.is_empty()
+        {
             // uname is null terminated.
-            self.extra.uname = String::from_utf8(self.header.uname.to_vec())?;
+            self.buf.clear();
+            extend(&mut self.buf, &self.header.uname);
+            self.uname = str::from_utf8(&self.buf)?.to_string();
         }
 
-        // Set size of inode. The PAX size extension is not supported since we
-        // don't expect a single large file in layers (for now).
-        self.inode.size = self.size as u32;
+        // Intern the names.
+        let (uname, gname) = (
+            std::mem::take(&mut self.uname),
+            std::mem::take(&mut self.gname),
+        );
+        self.inode.uname = self.intern(uname);
+        self.inode.gname = self.intern(gname);
+
+        // Set size of inode.
+        self.inode.size = self.size;
 
         if self.inode.name.len() == 0 {
             self.buf.clear();
-            // Add prefix
-            if self.header.prefix[0] != 0 {
+            // Add prefix. GNU headers use the field for other purposes.
+            if self.header.prefix[0] != 0 && self.header.magic == *b"ustar\0" {
                 extend(&mut self.buf, &self.header.prefix);
                 self.buf.push(b'/');
             }
 
             extend(&mut self.buf, &self.header.name);
-            (self.inode.parent, self.inode.name) =
-                Parser::split_path(&self.buf)?;
+            (self.inode.parent, self.inode.name) = Self::split_path(&self.buf)?;
         }
 
         // Figure out depth. `depth` is used for optimized binary search.
@@ -449,12 +1286,31 @@ impl Parser {
             self.extra.link = str::from_utf8(&self.buf)?.to_string();
         }
 
+        // Hard links name another entry of the archive.
+        if self.header.typeflag == b'1' {
+            let target = Self::normalize_path(&self.extra.link)?.join("/");
+            if target.is_empty() {
+                return Err(anyhow!("hard link to the root directory"));
+            }
+            self.extra.link = target;
+        }
+
         self.inode.mode = ascii_octal_to_u64(&self.header.mode)? as u32;
 
+        // Device numbers. Overlay whiteouts are devices 0:0.
+        if matches!(self.header.typeflag, b'3' | b'4') {
+            let device = (
+                ascii_octal_to_u64(&self.header.devmajor)? as u32,
+                ascii_octal_to_u64(&self.header.devminor)? as u32,
+            );
+            if device != (0, 0) {
+                self.extra.device = Some(device);
+            }
+        }
+
         if !self.extra.link.is_empty()
-            || !self.extra.uname.is_empty()
-            || !self.extra.gname.is_empty()
             || !self.extra.xattrs.is_empty()
+            || self.extra.device.is_some()
         {
             self.inode.extra =
                 Some(std::mem::replace(&mut self.extra, Extra::default()));
@@ -463,16 +1319,43 @@ impl Parser {
         Ok(())
     }
 
+    /// Find or add a name in the index's string table.
+    ///
+    /// Returns the position of the name in the table. Empty names are always
+    /// at position 0.
+    fn intern(&mut self, name: String) -> u32 {
+        if name.is_empty() {
+            return 0;
+        }
+
+        let names = &mut self.index.names;
+        *self.names.entry(name).or_insert_with_key(|name| {
+            names.push(name.clone());
+            names.len() as u32 - 1
+        })
+    }
+
     /// Parse a tar item.
     fn parse_item(&mut self) -> Result<()> {
         // Parse the header.
         self.parse_header()?;
 
+        // Contiguous files are regular files to all but a few historic
+        // systems. Keep the original flag.
+        if self.header.typeflag == b'7' {
+            let extra = self.inode.extra.get_or_insert_with(Extra::default);
+            extra.typeflag = Some(b'7');
+            self.header.typeflag = b'0';
+        }
+
         self.inode.typeflag = match self.header.typeflag {
-            b'0' => FileType::RegularFile,
+            b'0' | b'S' => FileType::RegularFile,
             b'1' => FileType::HardLink,
             b'2' => FileType::SymLink,
+            b'3' => FileType::CharDevice,
+            b'4' => FileType::BlockDevice,
             b'5' => FileType::Directory,
+            b'6' => FileType::Fifo,
             _ => {
                 return Err(anyhow!(
                     "unsupported typeflag {}",
@@ -481,18 +1364,50 @@ impl Parser {
             }
         };
 
+        // Sparse files store only their data regions.
+        let is_file = matches!(self.header.typeflag, b'0' | b'S');
+        match (self.header.typeflag, self.pax_sparse_size.take()) {
+            (b'S', _) => self.parse_sparse()?,
+            (b'0', Some(real_size)) => self.parse_pax_sparse(real_size)?,
+            _ => (),
+        }
+
         // Save the hash state prior to start of file.
-        if self.header.typeflag == b'0' {
-            self.inode.hash_index = self.index.hasher.save_state();
-            self.inode.offset = self.offset / 512;
+        if is_file {
+            self.inode.hash_index = self.save_state()?;
+            self.inode.offset = u32::try_from(self.offset / 512)
+                .map_err(|_| anyhow!("tar file too large"))?;
+
+            // Files of a single page have a single state either way.
+            if self.size > 4096 && self.size <= self.whole_file_max_size {
+                self.inode.granularity = Granularity::File;
+            }
         }
+        let per_page = self.inode.granularity == Granularity::Page;
+
+        // The digest of a regular file covers its contents without padding.
+        let mut file_digest = match is_file {
+            true if self.file_digests => Some(Sha256::new()),
+            _ => None,
+        };
+        let mut unhashed = self.size as usize;
+        let sparse = self.inode.sparse_map().cloned();
+        let mut hashed = 0;
 
         // Hash the contents in blocks.
         let mut buf = [0u8; 4096];
         for _i in 0..self.rsize as usize / buf.len() {
             self.reader.read_exact(&mut buf)?;
-            self.index.hasher.measure(&buf)?;
-            self.index.hasher.save_state();
+            self.measure(&buf)?;
+            if per_page {
+                self.save_state()?;
+            }
+            if let Some(sha) = &mut file_digest {
+                let n = min(unhashed, buf.len());
+                let stored = self.size - unhashed as u64;
+                digest_contents(sha, &sparse, &mut hashed, stored, &buf[..n]);
+                unhashed -= n;
+            }
         }
 
         // Round remaining bytes to 512 alignment.
@@ -500,53 +1415,857 @@ impl Parser {
         if remaining > 0 {
             let buf = &mut buf[0..remaining as usize];
             self.reader.read_exact(buf)?;
-            self.index.hasher.measure(&buf)?;
-            self.index.hasher.save_state();
+            self.measure(buf)?;
+            if per_page {
+                self.save_state()?;
+            }
+            if let Some(sha) = &mut file_digest {
+                let stored = self.size - unhashed as u64;
+                let data = &buf[..unhashed];
+                digest_contents(sha, &sparse, &mut hashed, stored, data);
+            }
+        }
+        if let Some(mut sha) = file_digest {
+            // Holes at the end of a sparse file.
+            digest_zeros(&mut sha, self.inode.size - hashed);
+            let extra = self.inode.extra.get_or_insert_with(Extra::default);
+            extra.sha256 = Some(sha.finalize().into());
         }
 
-        self.index
-            .inodes
-            .push(std::mem::replace(&mut self.inode, Inode::default()));
+        // Save the hash state at the end of a file verified as a whole.
+        if !per_page {
+            self.save_state()?;
+        }
 
-        Ok(())
+        // An entry for the root directory, e.g. "./", sets its attributes.
+        let inode = std::mem::take(&mut self.inode);
+        match (inode.name.is_empty(), &inode.typeflag) {
+            (false, _) => self.index.inodes.push(inode),
+            (true, FileType::Directory) => {
+                self.index.inodes[1] = Inode {
+                    name: String::from("/"),
+                    parent: String::new(),
+                    depth: 0,
+                    links: 2,
+                    ..inode
+                }
+            }
+            (true, _) => {
+                return Err(anyhow!("root directory is not a directory"))
+            }
+        }
+
+        // Global PAX records apply to each following entry.
+        self.apply_globals()
     }
 }
 
-/// Create confidential container file-system index for given tar file/folder.
+/// Options for parsing a tar file.
+#[derive(Clone, Default)]
+pub struct ParseOptions {
+    /// Regular files up to this size are verified as a whole.
+    pub whole_file_max_size: u64,
+
+    /// What the hasher saves at each save point.
+    pub hash_mode: HashMode,
+
+    /// Key of keyed (HMAC) measurements.
+    pub measure_key: Option<Vec<u8>>,
+
+    /// Record the sha256 digest of each regular file.
+    pub file_digests: bool,
+
+    /// Number of threads hashing the tar file. Hashing is done on the
+    /// parsing thread if less than 2.
+    pub hash_threads: usize,
+
+    /// Fail on any violation of the tar format instead of warning.
+    pub strict: bool,
+
+    /// Continue parsing past end-of-archive markers.
+    pub ignore_zeros: bool,
+}
+
+/// Options for creating indexes.
+#[derive(Clone)]
+pub struct IndexOptions {
+    /// Expected digest value. Only allowed with a single path.
+    pub digest: Option<Digest>,
+
+    /// Checksum file (e.g. SHA256SUMS) with expected digests.
+    pub digest_file: Option<String>,
+
+    /// Storage mode of the inode table.
+    pub storage: Storage,
+
+    /// Serialization format of the index file.
+    pub format: Format,
+
+    /// Compression of the index file.
+    pub compression: Compression,
+
+    /// Keys to authenticate the index file with.
+    pub keys: Keys,
+
+    /// Write the hash states to a separate states file.
+    pub split_states: bool,
+
+    /// Regular files up to this size are verified as a whole instead of page
+    /// by page.
+    pub whole_file_max_size: u64,
+
+    /// Granularity at which regular files are verified. With
+    /// `Granularity::File`, all regular files are verified as a whole.
+    pub granularity: Granularity,
+
+    /// What the hasher saves at each save point.
+    pub hash_mode: HashMode,
+
+    /// Key of keyed (HMAC) measurements.
+    pub measure_key: Option<Vec<u8>>,
+
+    /// Record the sha256 digest of each regular file.
+    pub file_digests: bool,
+
+    /// Number of threads hashing each tar file. Defaults to the number of
+    /// CPUs, or to 1 in batch mode.
+    pub hash_threads: Option<usize>,
+
+    /// Fail on any violation of the tar format instead of warning.
+    pub strict: bool,
+
+    /// Continue parsing past end-of-archive markers, e.g. for concatenated
+    /// archives.
+    pub ignore_zeros: bool,
+
+    /// Path of the index file. Only allowed with a single path. Defaults to
+    /// the path of the tar file with `.index` appended. For `docker save`
+    /// archives, the layer store directory, defaulting to the directory of
+    /// the archive.
+    pub output: Option<String>,
+
+    /// Treat the paths as `docker save` archives and index each layer in
+    /// them.
+    pub docker_save: bool,
+
+    /// Index the paths concurrently and print a summary.
+    pub batch: bool,
+
+    /// Number of paths indexed at the same time in batch mode. Defaults to
+    /// the number of CPUs.
+    pub jobs: Option<usize>,
+}
+
+/// Create confidential container file-system indexes for given tar
+/// files/folders.
 ///
-/// The tar file/folder is indexed and its digest is computed. If the computed
+/// The expected digest of each path is either the supplied digest, or is
+/// looked up by file name in the supplied checksum file.
+///
+/// # Arguments
+/// * `options` - Index creation options.
+/// * `paths` - Paths to tar files or folders.
+pub fn index(options: &IndexOptions, paths: &Vec<String>) -> Result<()> {
+    if options.digest.is_some() && paths.len() > 1 {
+        return Err(anyhow!("--digest can be used only with a single path"));
+    }
+    if options.output.is_some() && paths.len() > 1 {
+        return Err(anyhow!("--output can be used only with a single path"));
+    }
+    if options.output.is_none() && paths.iter().any(|p| p.eq("-")) {
+        return Err(anyhow!("--output is required when indexing stdin"));
+    }
+    if options.hash_threads == Some(0) {
+        return Err(anyhow!("--hash-threads must be at least 1"));
+    }
+
+    // Layers of docker save archives are checked against the digests
+    // recorded in the archives.
+    if options.docker_save {
+        if options.digest.is_some() || options.digest_file.is_some() {
+            return Err(anyhow!(
+                "digests cannot be supplied with --docker-save"
+            ));
+        }
+        for path in paths {
+            let dir = match &options.output {
+                Some(dir) => dir.to_string(),
+                None => match Path::new(path).parent() {
+                    Some(p) if !p.as_os_str().is_empty() => {
+                        p.to_string_lossy().to_string()
+                    }
+                    _ => String::from("."),
+                },
+            };
+            docker::index(path, &dir)?;
+        }
+        return Ok(());
+    }
+
+    if options.batch {
+        return index_batch(options, paths);
+    }
+
+    for path in paths {
+        let digest = expected_digest(options, path)?;
+        index_path(&digest, options, path)?;
+    }
+
+    Ok(())
+}
+
+/// Expected digest of a path. Either the supplied digest, or looked up in the
+/// supplied checksum file.
+///
+/// # Arguments
+/// * `options` - Index creation options.
+/// * `path` - Path to tar file.
+fn expected_digest(
+    options: &IndexOptions,
+    path: &String,
+) -> Result<Option<Digest>> {
+    match &options.digest_file {
+        Some(f) => Ok(Some(digest::lookup(f, path)?)),
+        _ => Ok(options.digest.clone()),
+    }
+}
+
+/// Index tar files concurrently on a pool of workers.
+///
+/// A summary of all paths is printed once all are done. Fails if any path
+/// fails.
+///
+/// # Arguments
+/// * `options` - Index creation options.
+/// * `paths` - Paths to tar files.
+fn index_batch(options: &IndexOptions, paths: &[String]) -> Result<()> {
+    let jobs = match options.jobs {
+        Some(0) => return Err(anyhow!("--jobs must be at least 1")),
+        Some(jobs) => jobs,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let pool = Pool::new(jobs.min(paths.len()).max(1))?;
+
+    let options = Arc::new(options.clone());
+    let (results, received) = channel();
+    for (i, path) in paths.iter().enumerate() {
+        let options = options.clone();
+        let results = results.clone();
+        let path = path.clone();
+        pool.execute(move || {
+            let start = Instant::now();
+            let result = expected_digest(&options, &path)
+                .and_then(|digest| index_path(&digest, &options, &path));
+            let _ = results.send((i, result, start.elapsed()));
+        })?;
+    }
+    drop(results);
+
+    // Print the summary in the order of the paths.
+    let mut summary: Vec<_> = received.iter().collect();
+    summary.sort_by_key(|(i, _, _)| *i);
+    let width = paths.iter().map(|p| p.len()).max().unwrap_or(0).max(4);
+    println!(
+        "{:<width$} {:>8} {:>12} {:>8}  STATUS",
+        "PATH", "INODES", "INDEX BYTES", "SECONDS"
+    );
+    let mut failed = 0;
+    for (i, result, elapsed) in &summary {
+        let secs = elapsed.as_secs_f64();
+        match result {
+            Ok((inodes, bytes)) => println!(
+                "{:<width$} {:>8} {:>12} {:>8.2}  ok",
+                paths[*i], inodes, bytes, secs
+            ),
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "{:<width$} {:>8} {:>12} {:>8.2}  FAILED: {:#}",
+                    paths[*i], "-", "-", secs, e
+                )
+            }
+        }
+    }
+
+    if failed > 0 || summary.len() < paths.len() {
+        return Err(anyhow!(
+            "{} of {} paths failed",
+            paths.len() - summary.len() + failed,
+            paths.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Index a tar file and check its digest.
+///
+/// The tar file is indexed and its digest is computed. If the computed
 /// digest does not match the expected value failure is raised.
 ///
 /// # Arguments
 /// * `digest` - Expected digest value.
-///    The digest should contain just the hex representation of the sha256
-///    hash without any leading `sha256:` prefix.
-/// * `path` - Path to tar file or folder.
-pub fn index(digest: &Option<String>, path: &String) -> Result<()> {
-    // Parse the tar file.
-    let mut parser = Parser::new(path)?;
-    let index = parser.parse()?;
+///   The algorithm of the digest selects the algorithm used to compute the
+///   digest of the tar file.
+/// * `path` - Path to tar file. The tar file is read from stdin if "-".
+/// * `options` - Parsing options.
+pub fn create_index(
+    digest: &Option<Digest>,
+    path: &String,
+    options: &ParseOptions,
+) -> Result<Index> {
+    // Parse the tar file, or stdin if path is "-". Folders are streamed as
+    // tar files by a thread.
+    let mut walker = None;
+    let (reader, len): (Box<dyn Read>, Option<u64>) = if path.eq("-") {
+        (Box::new(io::stdin().lock()), None)
+    } else if Path::new(path).is_dir() {
+        let (reader, handle) = folder::stream(Path::new(path))?;
+        walker = Some(handle);
+        (Box::new(reader), None)
+    } else {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open {}", path))?;
+        let len = file.metadata()?.len();
+        (Box::new(file), Some(len))
+    };
+    let algorithm = digest.as_ref().map_or(Algorithm::Sha256, |d| d.algorithm);
+    let mut parser = Parser::new(
+        reader,
+        len,
+        algorithm,
+        options.whole_file_max_size,
+        options.hash_mode,
+        &options.measure_key,
+        options.file_digests,
+    )?;
+    parser.hash_threads(options.hash_threads)?;
+    parser.strict(options.strict);
+    parser.ignore_zeros(options.ignore_zeros);
+    let parsed = parser.parse().map(|index| {
+        let computed = parser.digest(&index);
+        (index, computed)
+    });
+
+    // Failures to walk a folder cut its stream short. Report them rather
+    // than the resulting parse error.
+    drop(parser);
+    if let Some(walker) = walker {
+        let walked = walker
+            .join()
+            .map_err(|_| anyhow!("walking {} panicked", path))?;
+        match walked {
+            Err(e) if parsed.is_ok() || !is_broken_pipe(&e) => return Err(e),
+            _ => (),
+        }
+    }
+    let (index, computed) = parsed?;
 
     match &digest {
-        Some(digest) if index.hasher.digest.ne(digest) => {
+        Some(digest) if computed.ne(digest) => {
             return Err(anyhow!(
                 "{}: Computed digest {} != supplied digest {}",
                 path,
-                index.hasher.digest,
-                digest
+                computed.hex,
+                digest.hex
             ));
         }
         _ => (),
     }
 
-    // Write index to file.
-    let index_file_name = &match path.split("/").last() {
-        Some(f) => f.to_owned() + ".index",
-        _ => return Err(anyhow!("invalid path {}", path)),
+    Ok(index)
+}
+
+/// Check whether an error is caused by writing to a pipe without a reader.
+///
+/// # Arguments
+/// * `e` - The error.
+fn is_broken_pipe(e: &Error) -> bool {
+    e.chain()
+        .filter_map(|c| c.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Create confidential container file-system index for given tar file/folder.
+///
+/// # Arguments
+/// * `digest` - Expected digest value.
+/// * `options` - Index creation options.
+/// * `path` - Path to tar file or folder.
+/// * `returns` - Number of inodes and size of the index file.
+fn index_path(
+    digest: &Option<Digest>,
+    options: &IndexOptions,
+    path: &String,
+) -> Result<(usize, u64)> {
+    let whole_file_max_size = match options.granularity {
+        Granularity::Page => options.whole_file_max_size,
+        Granularity::File => u64::MAX,
+    };
+
+    // Paths indexed concurrently already keep the CPUs busy.
+    let hash_threads = match options.hash_threads {
+        Some(threads) => threads,
+        None if options.batch => 1,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let parse_options = ParseOptions {
+        whole_file_max_size,
+        hash_mode: options.hash_mode,
+        measure_key: options.measure_key.clone(),
+        file_digests: options.file_digests,
+        hash_threads,
+        strict: options.strict,
+        ignore_zeros: options.ignore_zeros,
+    };
+    let mut index = create_index(digest, path, &parse_options)?;
+    let algorithm = index.hasher.algorithm;
+    println!(
+        "hashed with {} ({} compression)",
+        algorithm.name(),
+        hash::backend(algorithm)
+    );
+
+    // Write index next to the tar file unless told otherwise.
+    let index_file_name = &match &options.output {
+        Some(output) => output.to_string(),
+        None => path.trim_end_matches('/').to_owned() + ".index",
     };
 
-    let bytes = index.to_file(&index_file_name)?;
+    // The states file replaces the .index suffix of the index, if any.
+    if options.split_states {
+        let base = index_file_name.strip_suffix(".index");
+        let states_file_name =
+            base.unwrap_or(index_file_name).to_owned() + ".states";
+        index.hasher.split_states(&states_file_name)?;
+        println!("wrote {}", states_file_name);
+    }
+
+    let bytes = index.to_file(
+        index_file_name,
+        options.storage,
+        options.format,
+        options.compression,
+        &options.keys,
+    )?;
     println!("wrote {}, size = {} bytes", index_file_name, bytes);
 
-    Ok(())
+    Ok((index.inodes.len() - 2, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ustar header block.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the entry.
+    /// * `typeflag` - Type of the entry.
+    /// * `size` - Size field, in octal or base-256.
+    fn header(name: &str, typeflag: u8, size: [u8; 12]) -> [u8; 512] {
+        let mut block = [0u8; 512];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        block[124..136].copy_from_slice(&size);
+        block[156] = typeflag;
+        block[257..265].copy_from_slice(b"ustar\x0000");
+        set_checksum(&mut block);
+        block
+    }
+
+    /// Compute the checksum of a header block after changing its fields.
+    ///
+    /// # Arguments
+    /// * `block` - The header block.
+    fn set_checksum(block: &mut [u8; 512]) {
+        // The checksum is computed with the checksum field set to spaces.
+        block[148..156].copy_from_slice(b"        ");
+        let sum: u32 = block.iter().map(|c| *c as u32).sum();
+        block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    }
+
+    /// A numeric header field in base-256.
+    ///
+    /// # Arguments
+    /// * `n` - The number.
+    fn base256(n: i64) -> [u8; 12] {
+        let mut field = [if n < 0 { 0xff } else { 0 }; 12];
+        field[0] |= 0x80;
+        field[4..].copy_from_slice(&n.to_be_bytes());
+        field
+    }
+
+    /// A numeric header field in octal.
+    ///
+    /// # Arguments
+    /// * `n` - The number.
+    fn octal(n: u64) -> [u8; 12] {
+        format!("{:011o}\0", n).as_bytes().try_into().unwrap()
+    }
+
+    /// The data of a pax header.
+    ///
+    /// # Arguments
+    /// * `records` - Field and value of each record.
+    fn pax(records: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = vec![];
+        for (field, value) in records {
+            // The length of the record counts its own digits.
+            let rest = field.len() + value.len() + 3;
+            let mut len = rest;
+            while len != rest + len.to_string().len() {
+                len = rest + len.to_string().len();
+            }
+            data.extend(format!("{} {}=", len, field).as_bytes());
+            data.extend(*value);
+            data.push(b'\n');
+        }
+        data
+    }
+
+    /// A tar file of the given headers and contents, followed by the end of
+    /// archive marker.
+    ///
+    /// # Arguments
+    /// * `entries` - Header blocks and the contents following each of them.
+    fn tar(entries: &[(&[u8; 512], &[u8])]) -> Vec<u8> {
+        let mut tar = vec![];
+        for (header, data) in entries {
+            tar.extend(*header);
+            tar.extend(*data);
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        }
+        tar.extend([0u8; 1024]);
+        tar
+    }
+
+    /// Find an inode by name.
+    ///
+    /// # Arguments
+    /// * `index` - The index.
+    /// * `name` - Name of the inode.
+    fn inode<'a>(index: &'a Index, name: &str) -> &'a Inode {
+        index.inodes.iter().find(|i| i.name == name).unwrap()
+    }
+
+    /// Index a tar file.
+    ///
+    /// # Arguments
+    /// * `tar` - Contents of the tar file.
+    /// * `strict` - Whether to fail on violations of the format.
+    fn parse(tar: &[u8], strict: bool) -> Result<Index> {
+        let mut parser = Parser::new(
+            tar,
+            Some(tar.len() as u64),
+            Algorithm::Sha256,
+            0,
+            HashMode::Chained,
+            &None,
+            false,
+        )?;
+        parser.strict(strict);
+        parser.parse()
+    }
+
+    /// Split a path into directory and name.
+    ///
+    /// # Arguments
+    /// * `path` - The path.
+    fn split(path: &str) -> Result<(String, String), String> {
+        Parser::<&[u8]>::split_path(path.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn split_path_normalizes() {
+        let ok = |parent: &str, name: &str| Ok((parent.into(), name.into()));
+        assert_eq!(split("./a"), ok("/", "a"));
+        assert_eq!(split("/a/b"), ok("/a/", "b"));
+        assert_eq!(split("a//./b/"), ok("/a/", "b"));
+        assert_eq!(split("a/../b/c"), ok("/b/", "c"));
+
+        // Paths of the root itself.
+        assert_eq!(split(""), ok("/", ""));
+        assert_eq!(split("./"), ok("/", ""));
+        assert_eq!(split("a/.."), ok("/", ""));
+    }
+
+    #[test]
+    fn split_path_rejects_escapes() {
+        for path in ["..", "../x", "/a/../../b", "a/./../../b"] {
+            assert_eq!(
+                split(path),
+                Err(format!("path escapes the root directory: {}", path))
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_extensions_are_not_read_into_memory() {
+        for typeflag in [b'x', b'g', b'L', b'K'] {
+            let mut tar = header("ext", typeflag, base256(1 << 60)).to_vec();
+            tar.extend([b'a'; 1024]);
+
+            let e = parse(&tar, true).unwrap_err();
+            assert_eq!(
+                format!("{:#}", e),
+                "failed to parse tar entry at byte offset 0: extension of \
+                 1152921504606846976 bytes too large at byte offset 0"
+            );
+
+            // Without --strict, the extension is skipped up to the end of
+            // the file.
+            let e = parse(&tar, false).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "truncated tar file: incomplete entry at byte offset 0"
+            );
+        }
+    }
+
+    #[test]
+    fn base256_numbers() {
+        let n = |buf: &[u8]| base256_to_i64(buf).map_err(|e| e.to_string());
+        assert_eq!(n(&base256(6)), Ok(6));
+        assert_eq!(n(&base256(i64::MAX)), Ok(i64::MAX));
+        assert_eq!(n(&[0xff; 12]), Ok(-1));
+        assert_eq!(n(&base256(-86400)), Ok(-86400));
+
+        // Numbers that do not fit in 64 bits.
+        let mut field = base256(i64::MAX);
+        field[3] = 1;
+        assert_eq!(n(&field), Err("base-256 number too large".into()));
+        let mut field = base256(i64::MIN);
+        field[3] = 0xfe;
+        assert_eq!(n(&field), Err("base-256 number too large".into()));
+
+        // Negative numbers are rejected in unsigned fields.
+        assert_eq!(
+            ascii_octal_to_u64(&base256(-1)).map_err(|e| e.to_string()),
+            Err("negative number in unsigned field".into())
+        );
+    }
+
+    #[test]
+    fn base256_header_fields() {
+        // A file from before 1970, with its size also in base-256.
+        let mut block = header("a", b'0', base256(6));
+        block[136..148].copy_from_slice(&base256(-86400));
+        set_checksum(&mut block);
+
+        let index = parse(&tar(&[(&block, b"hello\n")]), true).unwrap();
+        let a = inode(&index, "a");
+        assert_eq!((a.size, a.mtime), (6, -86400));
+    }
+
+    #[test]
+    fn sparse_maps() {
+        // An old GNU sparse header, with the map after the ustar fields.
+        let sparse = |map: &[(u64, u64)], real_size, stored| {
+            let mut block = header("s", b'S', octal(stored));
+            block[257..265].copy_from_slice(b"ustar  \0");
+            for (i, (offset, len)) in map.iter().enumerate() {
+                let entry = 386 + i * 24;
+                block[entry..entry + 12].copy_from_slice(&octal(*offset));
+                block[entry + 12..entry + 24].copy_from_slice(&octal(*len));
+            }
+            block[483..495].copy_from_slice(&octal(real_size));
+            set_checksum(&mut block);
+            block
+        };
+        let parse_sparse = |map: &[(u64, u64)], real_size, stored| {
+            let data = vec![b'a'; stored as usize];
+            let block = sparse(map, real_size, stored);
+            match parse(&tar(&[(&block, &data)]), true) {
+                Ok(index) => {
+                    let s = inode(&index, "s");
+                    Ok((s.size, s.sparse_map().cloned()))
+                }
+                Err(e) => Err(format!("{:#}", e)),
+            }
+        };
+
+        assert_eq!(
+            parse_sparse(&[(4, 3), (12, 3)], 16, 6),
+            Ok((16, Some(vec![(4, 3), (12, 3)])))
+        );
+
+        // Regions overlapping, out of order or past the end of the file.
+        for map in [[(4, 3), (6, 3)], [(12, 3), (4, 3)], [(4, 3), (14, 3)]] {
+            assert_eq!(
+                parse_sparse(&map, 16, 6),
+                Err("failed to parse tar entry at byte offset 0: bad sparse \
+                     map"
+                .into())
+            );
+        }
+        assert_eq!(
+            parse_sparse(&[(4, 3), (12, 3)], 16, 5),
+            Err(
+                "failed to parse tar entry at byte offset 0: sparse map does \
+                 not match size"
+                    .into()
+            )
+        );
+
+        // A region whose end overflows, in the PAX 1.0 sparse format.
+        let records = pax(&[
+            ("GNU.sparse.major", b"1"),
+            ("GNU.sparse.minor", b"0"),
+            ("GNU.sparse.name", b"s"),
+            ("GNU.sparse.realsize", b"16"),
+        ]);
+        let mut data = b"1\n18446744073709551615\n1\n".to_vec();
+        data.resize(513, 0);
+        let e = parse(
+            &tar(&[
+                (&header("x", b'x', octal(records.len() as u64)), &records),
+                (&header("s", b'0', octal(data.len() as u64)), &data),
+            ]),
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", e),
+            "failed to parse tar entry at byte offset 1024: bad sparse map"
+        );
+    }
+
+    #[test]
+    fn header_checksums() {
+        let parse_header = |block: &[u8; 512], strict| {
+            parse(&tar(&[(block, b"")]), strict)
+                .map(|index| inode(&index, "a").mode)
+                .map_err(|e| format!("{:#}", e))
+        };
+        let mut block = header("a", b'0', octal(0));
+        block[500] = 0xff;
+        set_checksum(&mut block);
+        assert_eq!(parse_header(&block, true), Ok(0o644));
+
+        // Some old tars sum the bytes as signed chars.
+        let unsigned = ascii_octal_to_u64(&block[148..156]).unwrap();
+        let signed = format!("{:06o}\0", unsigned - 0x100);
+        block[148..155].copy_from_slice(signed.as_bytes());
+        assert_eq!(parse_header(&block, true), Ok(0o644));
+
+        // A corrupt header is a violation of the format.
+        block[104] = b'7';
+        assert_eq!(
+            parse_header(&block, true),
+            Err("bad header checksum at byte offset 0: 4216 != 3615".into())
+        );
+        assert_eq!(parse_header(&block, false), Ok(0o744));
+    }
+
+    #[test]
+    fn pax_global_headers() {
+        let ext = |typeflag, records: &[u8]| {
+            header("pax", typeflag, octal(records.len() as u64))
+        };
+        let global = pax(&[("uid", b"1000"), ("gid", b"100")]);
+        let local = pax(&[("uid", b"5")]);
+        let reset = pax(&[("uid", b"")]);
+        let file = |name| header(name, b'0', octal(0));
+        let index = parse(
+            &tar(&[
+                (&ext(b'g', &global), &global),
+                (&file("a"), b""),
+                (&ext(b'x', &local), &local),
+                (&file("b"), b""),
+                (&file("c"), b""),
+                (&ext(b'g', &reset), &reset),
+                (&file("d"), b""),
+            ]),
+            true,
+        )
+        .unwrap();
+
+        // Local headers override global ones for the next entry only, and
+        // global records with empty values are removed.
+        let owner = |name| {
+            let i = inode(&index, name);
+            (i.uid, i.gid)
+        };
+        assert_eq!(owner("a"), (1000, 100));
+        assert_eq!(owner("b"), (5, 100));
+        assert_eq!(owner("c"), (1000, 100));
+        assert_eq!(owner("d"), (0, 100));
+    }
+
+    #[test]
+    fn pax_xattrs() {
+        // Values may hold newlines and equal signs.
+        let records = pax(&[
+            ("SCHILY.xattr.user.a", b"1"),
+            ("SCHILY.xattr.user.b", b"x=y\nz"),
+            ("SCHILY.xattr.user.a", b"2"),
+        ]);
+        assert_eq!(
+            Parser::<&[u8]>::pax_records(&records).unwrap(),
+            [
+                ("SCHILY.xattr.user.a", &b"1"[..]),
+                ("SCHILY.xattr.user.b", b"x=y\nz"),
+                ("SCHILY.xattr.user.a", b"2"),
+            ]
+        );
+
+        let ext = header("pax", b'x', octal(records.len() as u64));
+        let file = header("a", b'0', octal(0));
+        let index =
+            parse(&tar(&[(&ext, &records), (&file, b"")]), true).unwrap();
+        assert_eq!(
+            inode(&index, "a").extra.as_ref().unwrap().xattrs,
+            [
+                ("user.b".to_string(), b"x=y\nz".to_vec()),
+                ("user.a".to_string(), b"2".to_vec()),
+            ]
+        );
+
+        // A record whose length runs past the end of the data.
+        assert!(Parser::<&[u8]>::pax_records(b"30 path=a\n").is_err());
+    }
+
+    #[test]
+    fn pax_binary_xattrs() {
+        // A version 2 capability blob granting cap_setuid and cap_net_raw,
+        // which is not valid UTF-8.
+        let capability = b"\x01\x00\x00\x02\x80\x20\x00\x00\x00\x00\x00\x00\
+                           \x00\x00\x00\x00\x00\x00\x00\x00";
+        let records = pax(&[
+            ("hdrcharset", b"BINARY"),
+            ("SCHILY.xattr.security.capability", capability),
+        ]);
+        let ext = header("pax", b'x', octal(records.len() as u64));
+        let file = header("ping", b'0', octal(0));
+        let index =
+            parse(&tar(&[(&ext, &records), (&file, b"")]), true).unwrap();
+        assert_eq!(
+            inode(&index, "ping").extra.as_ref().unwrap().xattrs,
+            [("security.capability".to_string(), capability.to_vec())]
+        );
+    }
+
+    #[test]
+    fn pax_times() {
+        let time = |s: &str| {
+            ascii_decimal_to_time(s.as_bytes()).map_err(|e| e.to_string())
+        };
+        assert_eq!(time("1638884700"), Ok((1638884700, 0)));
+        assert_eq!(time("1638884700.5"), Ok((1638884700, 500_000_000)));
+        assert_eq!(time("1638884700.123456789"), Ok((1638884700, 123456789)));
+        assert_eq!(time("1.0000000019"), Ok((1, 1)));
+        assert_eq!(time("-1"), Ok((-1, 0)));
+        assert_eq!(time("-1.5"), Ok((-2, 500_000_000)));
+        assert_eq!(time("-0.25"), Ok((-1, 750_000_000)));
+        assert_eq!(time("1.5s"), Err("illegal decimal character 115".into()));
+        assert_eq!(time("1.2.3"), Err("floating point is unsupported.".into()));
+
+        let records = pax(&[("mtime", b"1638884700.25")]);
+        let ext = header("pax", b'x', octal(records.len() as u64));
+        let file = header("a", b'0', octal(0));
+        let index =
+            parse(&tar(&[(&ext, &records), (&file, b"")]), true).unwrap();
+        let a = inode(&index, "a");
+        assert_eq!((a.mtime, a.mtime_nsec), (1638884700, 250_000_000));
+    }
 }
@@ -0,0 +1,139 @@
+//! Unmount a cc-fs file-system and wait for its daemon to finish.
+//!
+//! The mount is looked up in `/proc/self/mountinfo` rather than by stat-ing
+//! the mount point, since stat blocks on a mount whose daemon is hung. The
+//! file-system is unmounted directly if permitted, and via `fusermount -u`
+//! otherwise, as for unprivileged mounts.
+//!
+//! Each FUSE mount has a connection in `/sys/fs/fuse/connections` named after
+//! the minor device number of the mount. The connection persists until the
+//! daemon closes its end of the session, at which point the daemon has run
+//! its cleanup (e.g. writing statistics). Waiting for the connection to go
+//! away is skipped if the fusectl file-system is not mounted.
+//!
+//! Mounts left behind by a daemon that died are removed the same way.
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Directory of FUSE connections in the fusectl file-system.
+const FUSE_CONNECTIONS: &str = "/sys/fs/fuse/connections";
+
+/// Interval between checks for termination of the session.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Undo the octal escaping of spaces, tabs, newlines and backslashes in
+/// mountinfo fields.
+///
+/// # Arguments
+/// * `field` - Escaped field.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|o| {
+            u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok()
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(b)) => {
+                out.push(b);
+                i += 4;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Find the minor device number of the cc-fs mount at a directory.
+///
+/// # Arguments
+/// * `mount_point` - Canonical path of the mount point.
+pub fn find_mount(mount_point: &String) -> Result<u32> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .context("failed to read /proc/self/mountinfo")?;
+
+    // Later mounts shadow earlier ones. Hence use the last match.
+    // Format: id parent major:minor root mount-point options ... - type source
+    let mut found = None;
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 5 || unescape(fields[4]) != *mount_point {
+            continue;
+        }
+        let sep = fields.iter().position(|f| *f == "-");
+        let source = sep.and_then(|s| fields.get(s + 2));
+        found = Some((fields[2], source.copied()));
+    }
+
+    match found {
+        Some((dev, Some("cc-fs"))) => dev
+            .split_once(':')
+            .and_then(|(_, minor)| minor.parse().ok())
+            .ok_or(anyhow!("bad device number {} in mountinfo", dev)),
+        Some(_) => Err(anyhow!("{} is not a cc-fs mount", mount_point)),
+        None => Err(anyhow!("{} is not mounted", mount_point)),
+    }
+}
+
+/// Unmount a cc-fs file-system.
+///
+/// # Arguments
+/// * `mount_point` - The directory the file-system is mounted at.
+/// * `timeout` - Seconds to wait for the daemon to finish.
+pub fn umount(mount_point: &String, timeout: u64) -> Result<()> {
+    // Resolve only the parent, since the mount point itself may be
+    // unresponsive or disconnected.
+    let path = Path::new(mount_point);
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("invalid mount point {}", mount_point))?;
+    let canonical = fs::canonicalize(parent)
+        .with_context(|| format!("failed to resolve {}", mount_point))?
+        .join(name)
+        .to_string_lossy()
+        .to_string();
+    let minor = find_mount(&canonical)?;
+
+    // Unprivileged mounts are unmounted using fusermount.
+    let c_path = CString::new(canonical.as_bytes())?;
+    if unsafe { libc::umount2(c_path.as_ptr(), 0) } != 0 {
+        let status = Command::new("fusermount")
+            .args(["-u", &canonical])
+            .status()
+            .context("failed to run fusermount")?;
+        if !status.success() {
+            return Err(anyhow!("failed to unmount {}", mount_point));
+        }
+    }
+
+    // Wait for the daemon to close the session.
+    let connection = format!("{}/{}", FUSE_CONNECTIONS, minor);
+    let start = Instant::now();
+    while Path::new(&connection).exists() {
+        if start.elapsed() >= Duration::from_secs(timeout) {
+            return Err(anyhow!(
+                "unmounted {} but daemon did not finish within {} seconds",
+                mount_point,
+                timeout
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    println!("unmounted {}", mount_point);
+    Ok(())
+}
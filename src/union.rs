@@ -0,0 +1,175 @@
+//! Union of the layers of an image.
+//!
+//! The layers of an image are stacked, each upper layer over the layers
+//! below it, as overlayfs does:
+//! - An entry of an upper layer replaces the entry at the same path in the
+//!   lower layers. Directories are merged, their children come from all
+//!   layers. An entry that is not a directory replaces a lower directory
+//!   along with its contents.
+//! - A whiteout, an OCI `.wh.<name>` file or an overlayfs 0:0 character
+//!   device, deletes the path from the lower layers.
+//! - An opaque directory, marked by an OCI `.wh..wh..opq` file or the
+//!   `trusted.overlay.opaque` attribute, hides the contents of the directory
+//!   in the lower layers.
+//!
+//! Whiteouts and opaque markers only affect lower layers, and are not part
+//! of the union.
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+
+use crate::index::{FileType, Index, Inode};
+use crate::whiteout::*;
+
+/// Path of an inode, e.g. `/etc/passwd`. The root is `/`.
+fn path_of(inode: &Inode) -> String {
+    inode.parent.clone() + &inode.name
+}
+
+/// Remove the contents of a directory from the union.
+///
+/// # Arguments
+/// * `entries` - Entries of the union by path.
+/// * `dir` - Path of the directory.
+fn remove_contents(entries: &mut BTreeMap<String, Inode>, dir: &str) {
+    let prefix = match dir {
+        "/" => String::from("/"),
+        _ => dir.to_owned() + "/",
+    };
+    let paths: Vec<String> = entries
+        .range(prefix.clone()..)
+        .map(|(path, _)| path)
+        .take_while(|path| path.starts_with(&prefix))
+        .cloned()
+        .collect();
+    for path in paths {
+        entries.remove(&path);
+    }
+}
+
+/// Remove a path and, for directories, its contents from the union.
+///
+/// # Arguments
+/// * `entries` - Entries of the union by path.
+/// * `path` - The path.
+fn remove(entries: &mut BTreeMap<String, Inode>, path: &str) {
+    entries.remove(path);
+    remove_contents(entries, path);
+}
+
+/// Find or add a name in the string table of the union.
+///
+/// # Arguments
+/// * `union` - The union.
+/// * `positions` - Positions of the names in the string table.
+/// * `name` - The name.
+/// * `returns` - The position of the name.
+fn intern(
+    union: &mut Index,
+    positions: &mut HashMap<String, u32>,
+    name: &str,
+) -> u32 {
+    if name.is_empty() {
+        return 0;
+    }
+    *positions.entry(name.to_string()).or_insert_with(|| {
+        union.names.push(name.to_string());
+        union.names.len() as u32 - 1
+    })
+}
+
+/// Merge the layers of an image into a single index.
+///
+/// The user and group names of the inodes are moved into the string table
+/// of the union. Each inode records the layer it comes from. The union has
+/// no hash states and is not processed.
+///
+/// # Arguments
+/// * `layers` - Indexes of the layers, top layer first as in the
+///   `lowerdir` option of overlayfs.
+pub fn merge(layers: &[Index]) -> Result<Index> {
+    if layers.is_empty() {
+        return Err(anyhow!("no layers to merge"));
+    }
+    if layers.len() > u16::MAX as usize {
+        return Err(anyhow!("too many layers: {}", layers.len()));
+    }
+
+    let mut union = Index {
+        names: vec![String::new()],
+        ..Index::default()
+    };
+    let mut positions = HashMap::new();
+    let mut entries: BTreeMap<String, Inode> = BTreeMap::new();
+
+    // The root comes from the top layer.
+    for root in &layers[0].inodes[..2] {
+        let mut root = root.clone();
+        root.uname = intern(
+            &mut union,
+            &mut positions,
+            &layers[0].names[root.uname as usize],
+        );
+        root.gname = intern(
+            &mut union,
+            &mut positions,
+            &layers[0].names[root.gname as usize],
+        );
+        union.inodes.push(root);
+    }
+
+    // Apply the layers bottom up.
+    for (layer, index) in layers.iter().enumerate().rev() {
+        let inodes = &index.inodes[2..];
+
+        // Whiteouts and opaque directories hide entries of lower layers.
+        for inode in inodes {
+            if is_whiteout(inode) {
+                let name = inode.name.strip_prefix(WHITEOUT_PREFIX);
+                remove(
+                    &mut entries,
+                    &(inode.parent.clone() + name.unwrap_or(&inode.name)),
+                );
+            } else if is_opaque_marker(inode) {
+                let dir = match inode.parent.as_str() {
+                    "/" => "/",
+                    p => &p[..p.len() - 1],
+                };
+                remove_contents(&mut entries, dir);
+            } else if is_opaque_dir(inode) {
+                remove_contents(&mut entries, &path_of(inode));
+            }
+        }
+
+        for inode in inodes {
+            if is_whiteout(inode) || is_opaque_marker(inode) {
+                continue;
+            }
+            let path = path_of(inode);
+            if !matches!(inode.typeflag, FileType::Directory) {
+                remove_contents(&mut entries, &path);
+            }
+
+            let mut inode = inode.clone();
+            let names = &index.names;
+            inode.uname = intern(
+                &mut union,
+                &mut positions,
+                &names[inode.uname as usize],
+            );
+            inode.gname = intern(
+                &mut union,
+                &mut positions,
+                &names[inode.gname as usize],
+            );
+            if let Some(extra) = &mut inode.extra {
+                extra.xattrs.retain(|(n, _)| n != OVERLAY_OPAQUE_XATTR);
+            }
+            inode.layer = layer as u16;
+            entries.insert(path, inode);
+        }
+    }
+
+    union.inodes.extend(entries.into_values());
+    Ok(union)
+}
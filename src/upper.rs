@@ -0,0 +1,354 @@
+//! Writable upper layer of a mount.
+//!
+//! With `--upper`, the verified layers stay read-only underneath a writable
+//! upper layer, as with overlayfs. Changes are kept in the upper layer:
+//! - Inodes created in the mount get inode numbers following those of the
+//!   lower layers.
+//! - Inodes of the lower layers are copied up when modified and keep their
+//!   inode numbers. Changes of attributes only copy up the attributes. The
+//!   contents of a regular file are copied up, after verification, when the
+//!   file is first written to or truncated.
+//! - Entries added to, replaced in or removed from a directory are recorded
+//!   per directory. Removed entries of lower layers are recorded as
+//!   whiteouts and hide the lower entries.
+//!
+//! Contents of files are held in memory files (see memfd_create(2)) with
+//! `--upper tmpfs`, which are sparse like files of a tmpfs. With
+//! `--upper DIR`, they are held in files created in DIR, which are unlinked
+//! right away so that nothing is left behind after unmount. Either way, holes
+//! take no space and writes beyond the maximum file size fail with EFBIG. The
+//! names and attributes are held in memory either way. The upper layer is
+//! discarded at unmount.
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::process;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::index::Inode;
+
+/// Where the contents of files of the upper layer are held.
+#[derive(Clone, Debug)]
+pub enum UpperStore {
+    /// In memory files.
+    Tmpfs,
+
+    /// In unlinked files in a directory.
+    Dir(String),
+}
+
+impl FromStr for UpperStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "tmpfs" {
+            return Ok(UpperStore::Tmpfs);
+        }
+        let meta =
+            fs::metadata(s).with_context(|| format!("failed to stat {}", s))?;
+        if !meta.is_dir() {
+            return Err(anyhow!("{} is not a directory", s));
+        }
+        Ok(UpperStore::Dir(s.to_string()))
+    }
+}
+
+/// Contents of a regular file of the upper layer, held in an unlinked or
+/// memory file.
+pub struct Contents(File);
+
+impl Contents {
+    /// Read part of the contents.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset to read from.
+    /// * `size` - Maximum number of bytes to read.
+    /// * `len` - Length of the contents.
+    pub fn read(
+        &self,
+        offset: u64,
+        size: u64,
+        len: u64,
+    ) -> io::Result<Vec<u8>> {
+        let start = offset.min(len);
+        let end = offset.saturating_add(size).min(len);
+        let mut buf = vec![0u8; (end - start) as usize];
+        self.0.read_exact_at(&mut buf, start)?;
+        Ok(buf)
+    }
+
+    /// Write part of the contents. The contents are extended as needed.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset to write at.
+    /// * `data` - Bytes to write.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.0.write_all_at(data, offset)
+    }
+
+    /// Truncate or extend the contents with zeros.
+    ///
+    /// # Arguments
+    /// * `len` - New length.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+}
+
+/// An inode of the upper layer.
+pub struct Node {
+    /// Attributes of the inode. Inodes created in the upper layer have no
+    /// owner names.
+    pub inode: Inode,
+
+    /// Contents of a regular file. None if not copied up from the lower
+    /// layers yet.
+    pub data: Option<Contents>,
+
+    /// Inode number of the directory containing a directory. Directories
+    /// cannot have other hard links. Unused for other inodes.
+    pub parent: u64,
+}
+
+/// The writable upper layer of a mount.
+pub struct Upper {
+    /// Where the contents of files are held.
+    store: UpperStore,
+
+    /// Inodes created or copied up, by inode number.
+    nodes: HashMap<u64, Node>,
+
+    /// Entries added to, replaced in or removed from directories, by inode
+    /// number of the directory. Removed entries of lower layers are None.
+    entries: HashMap<u64, BTreeMap<String, Option<u64>>>,
+
+    /// Number of open handles, by inode number.
+    handles: HashMap<u64, u32>,
+
+    /// Inode number of the first inode created in the upper layer.
+    first_ino: u64,
+
+    /// The next available inode number.
+    next_ino: u64,
+
+    /// Number of files created in the store directory. Used to name them.
+    files: u64,
+}
+
+impl Upper {
+    /// Create an empty upper layer.
+    ///
+    /// # Arguments
+    /// * `store` - Where the contents of files are held.
+    /// * `first_ino` - Inode number of the first inode created in the upper
+    ///   layer. Inodes below are of the lower layers.
+    pub fn new(store: UpperStore, first_ino: u64) -> Upper {
+        Upper {
+            store,
+            nodes: HashMap::new(),
+            entries: HashMap::new(),
+            handles: HashMap::new(),
+            first_ino,
+            next_ino: first_ino,
+            files: 0,
+        }
+    }
+
+    /// Check whether an inode was created in the upper layer, as opposed to
+    /// copied up from the lower layers.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number.
+    pub fn is_new(&self, ino: u64) -> bool {
+        ino >= self.first_ino
+    }
+
+    /// An inode of the upper layer.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number.
+    pub fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(&ino)
+    }
+
+    /// A mutable inode of the upper layer.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number.
+    pub fn node_mut(&mut self, ino: u64) -> Option<&mut Node> {
+        self.nodes.get_mut(&ino)
+    }
+
+    /// Add an inode copied up from the lower layers, unless copied up
+    /// already.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number of the lower inode.
+    /// * `node` - Makes the inode.
+    pub fn copy_up(&mut self, ino: u64, node: impl FnOnce() -> Node) {
+        self.nodes.entry(ino).or_insert_with(node);
+    }
+
+    /// Add an inode created in the upper layer.
+    ///
+    /// # Arguments
+    /// * `node` - The inode.
+    /// * `returns` - Inode number of the inode.
+    pub fn add(&mut self, node: Node) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.nodes.insert(ino, node);
+        ino
+    }
+
+    /// Space used by the upper layer.
+    ///
+    /// # Arguments
+    /// * `returns` - Number of 4096 byte blocks of the contents of files, and
+    ///   number of inodes created.
+    pub fn usage(&self) -> (u64, u64) {
+        let blocks = self
+            .nodes
+            .values()
+            .filter(|n| n.data.is_some())
+            .map(|n| n.inode.size.div_ceil(4096))
+            .sum();
+        let created = self.nodes.keys().filter(|ino| self.is_new(**ino));
+        (blocks, created.count() as u64)
+    }
+
+    /// Number of free 4096 byte blocks of the store: available memory with
+    /// `tmpfs`, or the space available in the directory.
+    pub fn free_blocks(&self) -> io::Result<u64> {
+        let bytes = match &self.store {
+            UpperStore::Tmpfs => unsafe {
+                let mut info: libc::sysinfo = std::mem::zeroed();
+                if libc::sysinfo(&mut info) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                info.freeram as u64 * info.mem_unit as u64
+            },
+            UpperStore::Dir(dir) => unsafe {
+                let c_dir = CString::new(dir.as_str())?;
+                let mut stat: libc::statvfs = std::mem::zeroed();
+                if libc::statvfs(c_dir.as_ptr(), &mut stat) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                stat.f_bavail as u64 * stat.f_frsize as u64
+            },
+        };
+        Ok(bytes / 4096)
+    }
+
+    /// Create empty contents for a regular file.
+    pub fn new_contents(&mut self) -> io::Result<Contents> {
+        match &self.store {
+            UpperStore::Tmpfs => {
+                let name = CString::new("cc-fs-upper")?;
+                let fd = unsafe {
+                    libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC)
+                };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Contents(unsafe { File::from_raw_fd(fd) }))
+            }
+            UpperStore::Dir(dir) => {
+                self.files += 1;
+                let path =
+                    format!("{}/.cc-fs.{}.{}", dir, process::id(), self.files);
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?;
+                fs::remove_file(&path)?;
+                Ok(Contents(file))
+            }
+        }
+    }
+
+    /// The entry of a directory recorded in the upper layer.
+    ///
+    /// # Arguments
+    /// * `dir` - Inode number of the directory.
+    /// * `name` - Name of the entry.
+    /// * `returns` - None if the upper layer has no record of the entry,
+    ///   Some(None) if the entry was removed, or the inode number of the
+    ///   entry.
+    pub fn entry(&self, dir: u64, name: &str) -> Option<Option<u64>> {
+        self.entries.get(&dir).and_then(|e| e.get(name)).copied()
+    }
+
+    /// Entries of a directory recorded in the upper layer, by name.
+    ///
+    /// # Arguments
+    /// * `dir` - Inode number of the directory.
+    pub fn entries(&self, dir: u64) -> Option<&BTreeMap<String, Option<u64>>> {
+        self.entries.get(&dir)
+    }
+
+    /// Record an entry of a directory.
+    ///
+    /// # Arguments
+    /// * `dir` - Inode number of the directory.
+    /// * `name` - Name of the entry.
+    /// * `ino` - Inode number of the entry, or None for a whiteout.
+    pub fn set_entry(&mut self, dir: u64, name: &str, ino: Option<u64>) {
+        self.entries
+            .entry(dir)
+            .or_default()
+            .insert(name.to_string(), ino);
+    }
+
+    /// Forget an entry of a directory.
+    ///
+    /// # Arguments
+    /// * `dir` - Inode number of the directory.
+    /// * `name` - Name of the entry.
+    pub fn clear_entry(&mut self, dir: u64, name: &str) {
+        if let Some(entries) = self.entries.get_mut(&dir) {
+            entries.remove(name);
+        }
+    }
+
+    /// Record that an inode was opened.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number.
+    pub fn opened(&mut self, ino: u64) {
+        *self.handles.entry(ino).or_default() += 1;
+    }
+
+    /// Record that a handle of an inode was released.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number.
+    pub fn released(&mut self, ino: u64) {
+        if let Some(handles) = self.handles.get_mut(&ino) {
+            *handles -= 1;
+            if *handles == 0 {
+                self.handles.remove(&ino);
+            }
+        }
+    }
+
+    /// Drop an inode from the upper layer if it has no links or open handles
+    /// left, and is not known to the kernel.
+    ///
+    /// # Arguments
+    /// * `ino` - Inode number.
+    /// * `looked_up` - Whether the kernel still holds lookups of the inode.
+    pub fn collect(&mut self, ino: u64, looked_up: bool) {
+        let unused = self.nodes.get(&ino).is_some_and(|n| n.inode.links == 0);
+        if unused && !looked_up && !self.handles.contains_key(&ino) {
+            self.nodes.remove(&ino);
+            self.entries.remove(&ino);
+        }
+    }
+}
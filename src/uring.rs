@@ -0,0 +1,379 @@
+//! Reads of the tar file through io_uring.
+//!
+//! A read is split into segments that are submitted together, so that the
+//! device sees them at once rather than one after another. The ring is set up
+//! with the raw system calls, and its queues are mapped with memmap2.
+//!
+//! Built with the `io-uring` feature only. The file-system falls back to
+//! synchronous reads if the kernel does not provide io_uring, e.g. because it
+//! is disabled or filtered by seccomp.
+//!
+//! See [io_uring(7)](https://man7.org/linux/man-pages/man7/io_uring.7.html).
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use memmap2::{MmapOptions, MmapRaw};
+
+/// Number of entries of the submission queue.
+const ENTRIES: u32 = 64;
+
+/// Size of each segment of a read. Reads are split into segments of this
+/// size that are submitted together.
+const SEGMENT_LEN: usize = 32 * 1024;
+
+/// Opcode of a read into a single buffer.
+const IORING_OP_READ: u8 = 22;
+
+/// Wait for completions in `io_uring_enter`.
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+/// mmap offset of the submission queue ring.
+const IORING_OFF_SQ_RING: u64 = 0;
+
+/// mmap offset of the completion queue ring.
+const IORING_OFF_CQ_RING: u64 = 0x8000000;
+
+/// mmap offset of the submission queue entries.
+const IORING_OFF_SQES: u64 = 0x10000000;
+
+/// Offsets of the fields of the submission queue ring.
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+/// Offsets of the fields of the completion queue ring.
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+/// Parameters of `io_uring_setup`.
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+/// Submission queue entry.
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// Completion queue entry.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// An io_uring instance used by a single thread.
+pub struct Ring {
+    /// The ring file descriptor.
+    fd: File,
+
+    /// Mapping of the submission queue ring.
+    sq: MmapRaw,
+
+    /// Mapping of the completion queue ring.
+    cq: MmapRaw,
+
+    /// Mapping of the submission queue entries.
+    sqes: MmapRaw,
+
+    /// Offsets of the fields of the submission queue ring.
+    sq_off: SqOffsets,
+
+    /// Offsets of the fields of the completion queue ring.
+    cq_off: CqOffsets,
+
+    /// Number of submission queue entries.
+    sq_entries: u32,
+}
+
+// The ring is only used by the thread that owns it.
+unsafe impl Send for Ring {}
+
+/// Map a queue of a ring.
+///
+/// # Arguments
+/// * `fd` - The ring file descriptor.
+/// * `offset` - Offset identifying the queue.
+/// * `len` - Size of the queue.
+fn map(fd: &File, offset: u64, len: usize) -> io::Result<MmapRaw> {
+    MmapOptions::new()
+        .offset(offset)
+        .len(len)
+        .populate()
+        .map_raw(fd)
+}
+
+impl Ring {
+    /// Set up a ring.
+    pub fn new() -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { File::from_raw_fd(fd as i32) };
+
+        let sq_len = params.sq_off.array as usize
+            + params.sq_entries as usize * std::mem::size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        Ok(Ring {
+            sq: map(&fd, IORING_OFF_SQ_RING, sq_len)?,
+            cq: map(&fd, IORING_OFF_CQ_RING, cq_len)?,
+            sqes: map(&fd, IORING_OFF_SQES, sqes_len)?,
+            fd,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            sq_entries: params.sq_entries,
+        })
+    }
+
+    /// A 32-bit field of a ring.
+    ///
+    /// # Arguments
+    /// * `ring` - Mapping of the ring.
+    /// * `offset` - Offset of the field.
+    fn field(ring: &MmapRaw, offset: u32) -> &AtomicU32 {
+        unsafe { &*(ring.as_ptr().add(offset as usize) as *const AtomicU32) }
+    }
+
+    /// Queue reads of segments, at most one per free submission entry.
+    ///
+    /// # Arguments
+    /// * `file` - File to read from.
+    /// * `buf` - Buffer to read into.
+    /// * `offset` - Offset in the file of the start of the buffer.
+    /// * `segments` - Start and length of the segments within the buffer.
+    ///   Queued segments are removed.
+    /// * `queued` - Receives the queued segments. The user data of each read
+    ///   is the position of its segment.
+    /// * `returns` - Number of queued reads.
+    fn queue(
+        &self,
+        file: &File,
+        buf: &mut [u8],
+        offset: u64,
+        segments: &mut Vec<(usize, usize)>,
+        queued: &mut Vec<(usize, usize)>,
+    ) -> u32 {
+        let mask = Ring::field(&self.sq, self.sq_off.ring_mask)
+            .load(Ordering::Relaxed);
+        let array = unsafe {
+            self.sq.as_mut_ptr().add(self.sq_off.array as usize) as *mut u32
+        };
+        let sqes = self.sqes.as_mut_ptr() as *mut Sqe;
+        let tail = Ring::field(&self.sq, self.sq_off.tail);
+        let mut next = tail.load(Ordering::Relaxed);
+
+        let count = segments.len().min(self.sq_entries as usize);
+        queued.clear();
+        for (start, len) in segments.drain(..count) {
+            let index = next & mask;
+            unsafe {
+                sqes.add(index as usize).write(Sqe {
+                    opcode: IORING_OP_READ,
+                    fd: file.as_raw_fd(),
+                    off: offset + start as u64,
+                    addr: buf[start..].as_mut_ptr() as u64,
+                    len: len as u32,
+                    user_data: queued.len() as u64,
+                    ..Sqe::default()
+                });
+                array.add(index as usize).write(index);
+            }
+            queued.push((start, len));
+            next = next.wrapping_add(1);
+        }
+        tail.store(next, Ordering::Release);
+        count as u32
+    }
+
+    /// Remove queued reads that have not been submitted.
+    ///
+    /// The kernel only consumes submission entries in `io_uring_enter`, and
+    /// the ring is not shared, so the tail can be moved back.
+    ///
+    /// # Arguments
+    /// * `count` - Number of reads to remove from the end of the queue.
+    fn unqueue(&self, count: u32) {
+        let tail = Ring::field(&self.sq, self.sq_off.tail);
+        let next = tail.load(Ordering::Relaxed).wrapping_sub(count);
+        tail.store(next, Ordering::Release);
+    }
+
+    /// Submit queued reads and wait for completions.
+    ///
+    /// # Arguments
+    /// * `to_submit` - Number of queued reads to submit.
+    /// * `min_complete` - Number of completions to wait for.
+    /// * `returns` - Number of reads submitted.
+    fn enter(&self, to_submit: u32, min_complete: u32) -> io::Result<u32> {
+        loop {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    to_submit,
+                    min_complete,
+                    IORING_ENTER_GETEVENTS,
+                    std::ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as u32);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Read the exact number of bytes required to fill a buffer.
+    ///
+    /// The buffer is split into segments that are read concurrently. Short
+    /// reads are continued. All submitted reads are reaped before returning,
+    /// even on errors, since the kernel writes into the buffer until they
+    /// complete.
+    ///
+    /// # Arguments
+    /// * `file` - File to read from.
+    /// * `buf` - Buffer to read into.
+    /// * `offset` - Offset in the file to read from.
+    pub fn read_exact_at(
+        &self,
+        file: &File,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> io::Result<()> {
+        let mut segments: Vec<(usize, usize)> = (0..buf.len())
+            .step_by(SEGMENT_LEN)
+            .map(|start| (start, SEGMENT_LEN.min(buf.len() - start)))
+            .collect();
+        let cq_mask = Ring::field(&self.cq, self.cq_off.ring_mask)
+            .load(Ordering::Relaxed);
+        let cqes = unsafe {
+            self.cq.as_ptr().add(self.cq_off.cqes as usize) as *const Cqe
+        };
+        let cq_head = Ring::field(&self.cq, self.cq_off.head);
+        let cq_tail = Ring::field(&self.cq, self.cq_off.tail);
+
+        let mut queued = vec![];
+        let mut error = None;
+        while !segments.is_empty() {
+            let count =
+                self.queue(file, buf, offset, &mut segments, &mut queued);
+            let mut submitted = 0;
+            while submitted < count {
+                match self.enter(count - submitted, 0) {
+                    Ok(n) => submitted += n,
+                    Err(e) => {
+                        self.unqueue(count - submitted);
+                        error = Some(e);
+                        break;
+                    }
+                }
+            }
+
+            // Reap all completions before the buffer can be released. If the
+            // kernel refuses to wait, poll rather than give up on reads in
+            // flight.
+            let mut reaped = 0;
+            while reaped < submitted {
+                let mut head = cq_head.load(Ordering::Relaxed);
+                let tail = cq_tail.load(Ordering::Acquire);
+                if head == tail {
+                    if self.enter(0, 1).is_err() {
+                        unsafe { libc::sched_yield() };
+                    }
+                    continue;
+                }
+                while head != tail {
+                    let cqe = unsafe { &*cqes.add((head & cq_mask) as usize) };
+                    let (start, len) = queued[cqe.user_data as usize];
+                    match cqe.res {
+                        res if res < 0 => {
+                            error = Some(io::Error::from_raw_os_error(-res))
+                        }
+                        0 => {
+                            error = Some(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "failed to fill whole buffer",
+                            ))
+                        }
+                        res if (res as usize) < len => {
+                            let done = res as usize;
+                            segments.push((start + done, len - done));
+                        }
+                        _ => (),
+                    }
+                    head = head.wrapping_add(1);
+                    reaped += 1;
+                }
+                cq_head.store(head, Ordering::Release);
+            }
+            if let Some(e) = error {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Verify files of a layer against its index without mounting it.
+//!
+//! The contents of a file are verified page by page. The tar headers
+//! preceding the contents, which hold the metadata of the file, are verified
+//! by hashing them from the state saved at the end of the preceding file to
+//! the state saved at the start of the file.
+//!
+//! A whole layer is verified by verifying every regular file in it. Since the
+//! headers of all other entries lie between regular files, they are verified
+//! along the way, except for entries following the last regular file.
+use std::os::unix::fs::FileExt;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::digest::Digest;
+use crate::index::{FileType, Inode, Keys};
+use crate::layer::Layer;
+
+/// Chunk size for reading file contents. Multiple of page size.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Verify the tar headers preceding the contents of a regular file.
+///
+/// # Arguments
+/// * `layer` - The layer.
+/// * `inode` - Inode of the regular file.
+pub fn verify_headers(layer: &Layer, inode: &Inode) -> Result<()> {
+    // Find the regular file preceding this one in the tar file.
+    let prev = layer
+        .index
+        .inodes
+        .iter()
+        .chain(&layer.index.replaced)
+        .filter(|i| matches!(i.typeflag, FileType::RegularFile))
+        .filter(|i| i.offset < inode.offset)
+        .max_by_key(|i| i.offset);
+
+    // The headers start at the end of the preceding file, or at the start
+    // of the tar file.
+    let (before, start) = match prev {
+        Some(p) => (
+            Some(p.hash_index + p.num_states()),
+            p.offset as u64 * 512 + p.stored_size().div_ceil(512) * 512,
+        ),
+        None => (None, 0),
+    };
+    let end = inode.offset as u64 * 512;
+    if before.map_or(0, |b| b + 1) != inode.hash_index || start > end {
+        return Err(anyhow!(
+            "inconsistent index entry for {}{}",
+            inode.parent,
+            inode.name
+        ));
+    }
+
+    let mut buf = vec![0u8; (end - start) as usize];
+    layer.tar.read_exact_at(&mut buf, start).with_context(|| {
+        format!("failed to read headers of {}{}", inode.parent, inode.name)
+    })?;
+    if !layer
+        .index
+        .hasher
+        .verify_range(before, &buf, inode.hash_index)?
+    {
+        return Err(anyhow!(
+            "integrity verification failed for headers of {}{}",
+            inode.parent,
+            inode.name
+        ));
+    }
+    Ok(())
+}
+
+/// Verify a single file of a layer.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `tar` - Path of the tar file.
+/// * `path` - Path of the file within the layer.
+pub fn verify_file(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    tar: &String,
+    path: &String,
+) -> Result<()> {
+    let layer = Layer::open(index, index_digest, keys, tar)?;
+    let inode = layer.find_file(path)?;
+
+    verify_inode(&layer, inode)?;
+
+    println!("verified {}", path);
+    Ok(())
+}
+
+/// Verify the headers and contents of a regular file.
+///
+/// # Arguments
+/// * `layer` - The layer.
+/// * `inode` - Inode of the regular file.
+fn verify_inode(layer: &Layer, inode: &Inode) -> Result<()> {
+    verify_headers(layer, inode)?;
+
+    let mut offset = 0;
+    while offset < inode.size {
+        offset += layer.read(inode, offset, CHUNK_SIZE)?.len() as u64;
+    }
+    Ok(())
+}
+
+/// Verify all files of a layer.
+///
+/// Prints the result for each regular file, including files replaced by
+/// later entries with the same path. Fails if any file fails verification.
+///
+/// # Arguments
+/// * `index` - Path of the index file.
+/// * `index_digest` - Expected digest of the index file.
+/// * `keys` - Keys the index must be authenticated with.
+/// * `tar` - Path of the tar file.
+pub fn verify(
+    index: &String,
+    index_digest: &Option<Digest>,
+    keys: &Keys,
+    tar: &String,
+) -> Result<()> {
+    let layer = Layer::open(index, index_digest, keys, tar)?;
+
+    let mut files = 0;
+    let mut failed = 0;
+    let replaced = layer.index.replaced.iter().map(|i| (i, " (replaced)"));
+    let inodes = layer.index.inodes[2..].iter().map(|i| (i, ""));
+    for (inode, note) in inodes.chain(replaced) {
+        if !matches!(inode.typeflag, FileType::RegularFile) {
+            continue;
+        }
+        files += 1;
+        match verify_inode(&layer, inode) {
+            Ok(()) => println!("ok {}{}{}", inode.parent, inode.name, note),
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "FAILED {}{}{}: {:#}",
+                    inode.parent, inode.name, note, e
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} files failed verification",
+            failed,
+            files
+        ));
+    }
+    println!("verified {} files", files);
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Guest-side layer pulling over vsock.
+//!
+//! When running inside a kata VM, cc-fs can listen on a vsock port for layer
+//! streams sent by the host shim. Each layer received is verified against its
+//! expected digest, indexed, and added to a layer store. The paths of the
+//! stored tar and index are reported back so that the layer can be mounted.
+//!
+//! # Protocol
+//! One layer is transferred per connection.
+//! 1. The host sends the expected digest of the layer followed by a newline,
+//!    e.g. `sha256:<hex>\n`.
+//! 2. The host sends the layer tar stream and then shuts down its write side
+//!    of the connection.
+//! 3. cc-fs replies with `ok <tar path> <index path>\n` on success or
+//!    `error <message>\n` on failure, and closes the connection.
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use anyhow::{anyhow, Result};
+
+use crate::digest::Digest;
+use crate::store::Store;
+
+/// Maximum length of the digest line.
+const MAX_HEADER_LENGTH: u64 = 256;
+
+/// Create a vsock socket listening on given port of any CID.
+///
+/// # Arguments
+/// * `port` - vsock port to listen on.
+fn listen(port: u32) -> Result<File> {
+    unsafe {
+        let fd = libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(anyhow!(
+                "failed to create vsock socket: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        // Take ownership so that the socket is closed on error.
+        let socket = File::from_raw_fd(fd);
+
+        let mut addr: libc::sockaddr_vm = mem::zeroed();
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_port = port;
+        addr.svm_cid = libc::VMADDR_CID_ANY;
+        if libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        ) != 0
+            || libc::listen(fd, 16) != 0
+        {
+            return Err(anyhow!(
+                "failed to listen on vsock port {}: {}",
+                port,
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(socket)
+    }
+}
+
+/// Receive one layer from a connection and add it to the store.
+///
+/// # Arguments
+/// * `store` - Store to add the layer to.
+/// * `conn` - The connection.
+/// * `returns` - The digest of the layer.
+fn receive(store: &Store, conn: &File) -> Result<Digest> {
+    let mut reader = BufReader::new(conn);
+
+    // Read the digest line.
+    let mut header = String::new();
+    (&mut reader)
+        .take(MAX_HEADER_LENGTH)
+        .read_line(&mut header)?;
+    if !header.ends_with('\n') {
+        return Err(anyhow!("missing digest"));
+    }
+    let digest: Digest = header.trim_end().parse()?;
+
+    // The rest of the stream is the layer.
+    store.add(&mut reader, &digest)?;
+    Ok(digest)
+}
+
+/// Serve layer pulls on a vsock port.
+///
+/// Connections are handled one at a time. Failures are reported to the peer
+/// and do not stop the server.
+///
+/// # Arguments
+/// * `dir` - Root directory of the layer store.
+/// * `port` - vsock port to listen on.
+pub fn serve(dir: &String, port: u32) -> Result<()> {
+    let store = Store::new(dir)?;
+    let socket = listen(port)?;
+    println!("listening on vsock port {}", port);
+
+    loop {
+        let fd = unsafe {
+            libc::accept(
+                socket.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if fd < 0 {
+            eprintln!("accept failed: {}", std::io::Error::last_os_error());
+            continue;
+        }
+        let mut conn = unsafe { File::from_raw_fd(fd) };
+
+        let received = receive(&store, &conn).and_then(|digest| {
+            let tar_path = store.tar_path(&digest)?;
+            let index_path = store.index_path(&digest)?;
+            Ok((digest, tar_path, index_path))
+        });
+        let reply = match received {
+            Ok((digest, tar_path, index_path)) => {
+                println!("stored {}", digest);
+                format!("ok {} {}\n", tar_path, index_path)
+            }
+            Err(e) => {
+                eprintln!("failed to receive layer: {:#}", e);
+                format!("error {:#}\n", e)
+            }
+        };
+        let _ = conn.write_all(reply.as_bytes());
+    }
+}
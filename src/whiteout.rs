@@ -0,0 +1,158 @@
+//! Representation of whiteouts in exported layers.
+//!
+//! Layers mark paths deleted from lower layers with whiteouts. OCI layers use
+//! empty `.wh.<name>` files for deleted paths, and an empty `.wh..wh..opq`
+//! file for directories whose lower contents are hidden. overlayfs instead
+//! uses 0:0 character devices named after the deleted paths, and the
+//! `trusted.overlay.opaque` extended attribute on opaque directories.
+//!
+//! Whiteouts in either representation are recognized in the index and can be
+//! exported in the representation expected by the consumer. Mounted layers
+//! can show them as stored, hide them, or show them in the overlayfs
+//! representation so that a mount can serve as a lower layer of overlayfs.
+use clap::ValueEnum;
+
+use crate::index::{Extra, FileType, Index, Inode};
+
+/// Name prefix of OCI whiteout files.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of OCI opaque directory markers.
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Extended attribute marking opaque directories in overlayfs.
+pub const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// Representation of whiteouts.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whiteouts {
+    /// `.wh.<name>` files and `.wh..wh..opq` markers.
+    Oci,
+
+    /// 0:0 character devices and `trusted.overlay.opaque` attributes.
+    Overlay,
+}
+
+/// How mounted layers show whiteouts.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteoutMode {
+    /// As stored in the layer.
+    Keep,
+
+    /// Not at all. Whiteouts and opaque markers are omitted.
+    Hide,
+
+    /// As 0:0 character devices and `trusted.overlay.opaque` attributes,
+    /// for consumption by overlayfs.
+    Overlay,
+}
+
+/// Check whether an inode is an OCI opaque directory marker.
+pub fn is_opaque_marker(inode: &Inode) -> bool {
+    matches!(inode.typeflag, FileType::RegularFile)
+        && inode.name == OPAQUE_WHITEOUT
+}
+
+/// Check whether an inode is a whiteout in either representation.
+pub fn is_whiteout(inode: &Inode) -> bool {
+    match inode.typeflag {
+        FileType::CharDevice => inode.device() == (0, 0),
+        FileType::RegularFile => {
+            inode.name.starts_with(WHITEOUT_PREFIX) && !is_opaque_marker(inode)
+        }
+        _ => false,
+    }
+}
+
+/// Check whether a directory inode is marked opaque by the overlayfs
+/// attribute.
+pub fn is_opaque_dir(inode: &Inode) -> bool {
+    matches!(inode.typeflag, FileType::Directory)
+        && inode.extra.as_ref().is_some_and(|e| {
+            e.xattrs.iter().any(|(name, value)| {
+                name == OVERLAY_OPAQUE_XATTR && value == b"y"
+            })
+        })
+}
+
+/// Name of an inode when exported with given whiteout representation.
+///
+/// # Arguments
+/// * `inode` - The inode.
+/// * `whiteouts` - Representation of whiteouts.
+/// * `returns` - The name, or None if the inode is exported only as an
+///   attribute of its parent directory.
+pub fn export_name(inode: &Inode, whiteouts: Whiteouts) -> Option<String> {
+    match whiteouts {
+        Whiteouts::Overlay if is_opaque_marker(inode) => None,
+        Whiteouts::Overlay if is_whiteout(inode) => Some(
+            inode
+                .name
+                .strip_prefix(WHITEOUT_PREFIX)
+                .unwrap_or(&inode.name)
+                .to_string(),
+        ),
+        Whiteouts::Oci
+            if is_whiteout(inode)
+                && !inode.name.starts_with(WHITEOUT_PREFIX) =>
+        {
+            Some(WHITEOUT_PREFIX.to_owned() + &inode.name)
+        }
+        _ => Some(inode.name.clone()),
+    }
+}
+
+/// Translate the whiteouts of an index for mounting. Must be called before
+/// the index is processed.
+///
+/// # Arguments
+/// * `index` - The index.
+/// * `mode` - How whiteouts are shown.
+pub fn translate(index: &mut Index, mode: WhiteoutMode) {
+    match mode {
+        WhiteoutMode::Keep => (),
+        WhiteoutMode::Hide => {
+            index
+                .inodes
+                .retain(|i| !is_whiteout(i) && !is_opaque_marker(i));
+            for inode in &mut index.inodes {
+                if let Some(extra) = &mut inode.extra {
+                    extra.xattrs.retain(|(n, _)| n != OVERLAY_OPAQUE_XATTR);
+                }
+            }
+        }
+        WhiteoutMode::Overlay => {
+            // Opaque markers become attributes of their parents.
+            let opaque: Vec<String> = index
+                .inodes
+                .iter()
+                .filter(|i| is_opaque_marker(i))
+                .map(|i| i.parent.clone())
+                .collect();
+            index.inodes.retain(|i| !is_opaque_marker(i));
+
+            for inode in &mut index.inodes {
+                if matches!(inode.typeflag, FileType::Directory)
+                    && !is_opaque_dir(inode)
+                    && opaque.iter().any(|p| inode.path_eq(p))
+                {
+                    let extra = inode.extra.get_or_insert_with(Extra::default);
+                    extra
+                        .xattrs
+                        .push((OVERLAY_OPAQUE_XATTR.into(), b"y".to_vec()));
+                }
+                if is_whiteout(inode)
+                    && matches!(inode.typeflag, FileType::RegularFile)
+                {
+                    if let Some(name) = inode.name.strip_prefix(WHITEOUT_PREFIX)
+                    {
+                        inode.name = name.to_string();
+                    }
+                    inode.typeflag = FileType::CharDevice;
+                    inode.mode = 0;
+                    inode.size = 0;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Tar files whose sizes are only stored in PAX extended headers.
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+
+/// Run cc-fs, failing the test unless it succeeds.
+///
+/// # Arguments
+/// * `args` - Arguments of cc-fs.
+/// * `returns` - The standard output of cc-fs.
+fn cc_fs(args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_cc-fs"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "cc-fs {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+/// Run cc-fs, failing the test if it succeeds.
+///
+/// # Arguments
+/// * `args` - Arguments of cc-fs.
+/// * `returns` - The standard error of cc-fs.
+fn cc_fs_fails(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_cc-fs"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "cc-fs {:?} succeeded", args);
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+/// Path of a temporary index file.
+///
+/// # Arguments
+/// * `name` - Name of the test.
+fn index_path(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("cc-fs-{}-{}.index", name, process::id()))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// The header size field of `big.txt` is zeroed, and its size is only in the
+/// PAX `size` record. The entry after it must still be found.
+#[test]
+fn pax_size_overrides_header_size() {
+    let tar = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/pax-size.tar");
+    let index = index_path("pax-size");
+
+    cc_fs(&["index", "--output", &index, tar]);
+    let big = cc_fs(&["cat", "--index", &index, tar, "/big.txt"]);
+    let after = cc_fs(&["cat", "--index", &index, tar, "/after.txt"]);
+    fs::remove_file(&index).unwrap();
+
+    assert_eq!(big, b"0123456789".repeat(500));
+    assert_eq!(after, b"after\n");
+}
+
+/// The PAX `size` record of `big.txt` is 2^64 - 1, which cannot be rounded up
+/// to whole blocks. The record is rejected, and the size in the header used.
+#[test]
+fn pax_size_overflow_is_rejected() {
+    let tar = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/pax-size-overflow.tar"
+    );
+    let index = index_path("pax-size-overflow");
+
+    let e = cc_fs_fails(&["index", "--strict", "--output", &index, tar]);
+    assert!(e.contains("size 18446744073709551615 too large"), "{}", e);
+
+    cc_fs(&["index", "--output", &index, tar]);
+    let big = cc_fs(&["cat", "--index", &index, tar, "/big.txt"]);
+    let after = cc_fs(&["cat", "--index", &index, tar, "/after.txt"]);
+    fs::remove_file(&index).unwrap();
+
+    assert_eq!(big, b"hello\n");
+    assert_eq!(after, b"after\n");
+}